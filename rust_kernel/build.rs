@@ -18,10 +18,27 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Err("Failed to assemble entry".into());
     }
 
+    // Identify the build with the current commit, the kernel gives it to
+    // userland through sys_uname
+    let build_id = Command::new("git")
+        .args(&["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+
+    // `cargo run --features selftest` starts the check tasks at boot
+    let features = match std::env::var_os("CARGO_FEATURE_SELFTEST") {
+        Some(_) => "selftest",
+        None => "",
+    };
+
     if !Command::new("cargo")
         .current_dir("kernel_core")
+        .env("SECOS_BUILD_ID", build_id.trim())
         .args(
-            &["build", "--release", 
+            &["build", "--release", "--features", features,
             "--target-dir", build_dir.canonicalize()?.to_str().unwrap()]
         ).status()?.success() {
         return Err("Failed to compile kernel".into());
@@ -0,0 +1,220 @@
+//! Cycle counts of syscalls and of context switches, measured with the time
+//! stamp counter. Measures are only taken between `BENCH_START` and
+//! `BENCH_STOP`, otherwise the hooks only test a flag
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use crate::cpu::*;
+use crate::cpuid::cpu_features;
+use crate::fpu::*;
+use crate::paging::physmem::PhysMem;
+use crate::sync::InterruptGuard;
+use crate::syscalls::*;
+use crate::{print, println, PERIPHERALS};
+
+/// Commands of `SYS_BENCH`
+pub const BENCH_STOP : u32 = 0;
+pub const BENCH_START : u32 = 1;
+pub const BENCH_DUMP : u32 = 2;
+pub const BENCH_START_NO_GLOBAL : u32 = 3;
+
+/// Min, average and max of a series of cycle counts
+struct Latency {
+    count : u64,
+    total : u64,
+    min : u64,
+    max : u64,
+}
+
+impl Latency {
+    const fn new() -> Self {
+        Latency {
+            count : 0,
+            total : 0,
+            min : u64::MAX,
+            max : 0,
+        }
+    }
+
+    fn add(&mut self, cycles : u64) {
+        self.count += 1;
+        self.total += cycles;
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+    }
+
+    fn print(&self, what : &str) {
+        if self.count == 0 {
+            println!("bench : {} : no measure", what);
+            return;
+        }
+        println!("bench : {} : {} measures, min {} avg {} max {} cycles",
+                 what, self.count, self.min, self.total / self.count,
+                 self.max);
+    }
+}
+
+/// Measures are taken
+static mut BENCH_ENABLED : bool = false;
+
+/// From the entry of `handle_syscall` to the return of the handler. Syscalls
+/// that block also count the time the other tasks ran
+static mut SYSCALL_LATENCY : Latency = Latency::new();
+
+/// From the scheduler choosing to switch to the return of `switch_to` in the
+/// next task. Switches to tasks that never ran are not measured, they resume
+/// elsewhere
+static mut SWITCH_LATENCY : Latency = Latency::new();
+
+/// Timestamp taken before the switch in progress, 0 if none is measured
+static mut SWITCH_START : u64 = 0;
+
+/// CR4.PGE was cleared by `BENCH_START_NO_GLOBAL`, and is set back when the
+/// measures stop
+static mut GLOBAL_PAGES_OFF : bool = false;
+
+/// Register the bench syscall
+pub fn bench_init() {
+    register_syscall(SYS_BENCH, "bench", &[SyscallArg::Uint],
+                     |ctx| sys_bench(ctx.regs.ecx));
+}
+
+/// Timestamp starting a measure, 0 when measures are disabled
+#[inline]
+pub fn bench_start() -> u64 {
+    if unsafe { BENCH_ENABLED } {
+        rdtsc()
+    } else {
+        0
+    }
+}
+
+/// End the syscall measure started at `start`
+#[inline]
+pub fn bench_syscall_done(start : u64) {
+    unsafe {
+        if start != 0 && BENCH_ENABLED {
+            SYSCALL_LATENCY.add(rdtsc() - start);
+        }
+    }
+}
+
+/// Called by the scheduler right before switching to another task
+#[inline]
+pub fn bench_switch_start() {
+    unsafe { SWITCH_START = bench_start(); }
+}
+
+/// Called by the task resumed by a switch
+#[inline]
+pub fn bench_switch_done() {
+    unsafe {
+        if SWITCH_START != 0 && BENCH_ENABLED {
+            SWITCH_LATENCY.add(rdtsc() - SWITCH_START);
+        }
+        SWITCH_START = 0;
+    }
+}
+
+/// Start measuring from scratch with `BENCH_START`, stop with `BENCH_STOP`
+/// and print the measures over serial with `BENCH_DUMP`. The syscall that
+/// stops the measures is not counted. `BENCH_START_NO_GLOBAL` measures with
+/// CR4.PGE cleared until the stop, so that each switch flushes the kernel
+/// mappings from the TLB too, as without global pages
+fn sys_bench(cmd : u32) -> i32 {
+    unsafe {
+        match cmd {
+            BENCH_STOP => {
+                BENCH_ENABLED = false;
+                if GLOBAL_PAGES_OFF {
+                    Cr4::set_bits(Cr4::PGE);
+                    GLOBAL_PAGES_OFF = false;
+                }
+            }
+            BENCH_START | BENCH_START_NO_GLOBAL => {
+                SYSCALL_LATENCY = Latency::new();
+                SWITCH_LATENCY = Latency::new();
+                SWITCH_START = 0;
+                BENCH_ENABLED = true;
+
+                // Clearing PGE flushes the whole TLB, global pages included
+                if cmd == BENCH_START_NO_GLOBAL && !GLOBAL_PAGES_OFF &&
+                        cpu_features().pge {
+                    Cr4::clear_bits(Cr4::PGE);
+                    GLOBAL_PAGES_OFF = true;
+                }
+            }
+            BENCH_DUMP => {
+                SYSCALL_LATENCY.print("syscall");
+                SWITCH_LATENCY.print("context switch");
+                println!("bench : {} FPU switches on #NM since boot",
+                         fpu_switches());
+            }
+            _ => return -EINVAL,
+        }
+    }
+    0
+}
+
+/// Time `npages` allocations of single physical pages, then their frees, and
+/// print the measures. Panics if the pages can't be allocated
+pub fn bench_phys_alloc(npages : usize) {
+    let mut pages = Vec::new();
+    pages.try_reserve_exact(npages).expect("bench : no memory for the pages");
+
+    let mut alloc = Latency::new();
+    for _ in 0..npages {
+        let start = rdtsc();
+        let page = unsafe { PhysMem::try_alloc_phys() };
+        alloc.add(rdtsc() - start);
+        pages.push(page.expect("bench : out of physical pages"));
+    }
+
+    let mut free = Latency::new();
+    for &page in pages.iter() {
+        let start = rdtsc();
+        unsafe { PhysMem::free_phys(page); }
+        free.add(rdtsc() - start);
+    }
+
+    alloc.print("physical page allocation");
+    free.print("physical page free");
+}
+
+/// Time `count` switches of the FPU done eagerly, saving and loading the
+/// registers, then lazily, only setting TS, and print the measures. A lazy
+/// switch costs a #NM and an eager switch more, but only when the next task
+/// uses the FPU
+pub fn bench_fpu_switch(count : usize) {
+    if !fpu_enabled() {
+        println!("bench : no FPU to switch");
+        return;
+    }
+
+    // TS is cleared so that the kernel can save the registers without #NM.
+    // They are loaded back unchanged, whoever owns them
+    let _guard = InterruptGuard::new();
+    let cr0 = Cr0::read();
+    unsafe { asm!("clts"); }
+
+    let mut state = FpuState::initial();
+    let mut eager = Latency::new();
+    for _ in 0..count {
+        let start = rdtsc();
+        fpu_save(&mut state);
+        fpu_restore(&state);
+        eager.add(rdtsc() - start);
+    }
+
+    let mut lazy = Latency::new();
+    for _ in 0..count {
+        let start = rdtsc();
+        unsafe { Cr0(cr0.0 | Cr0::TS).write(); }
+        lazy.add(rdtsc() - start);
+        unsafe { asm!("clts"); }
+    }
+    unsafe { cr0.write(); }
+
+    eager.print("eager FPU switch");
+    lazy.print("lazy FPU switch");
+}
@@ -4,6 +4,7 @@ use crate::segmem::GdtPointer;
 use crate::interrupts::IdtPointer;
 use crate::{PERIPHERALS, println, print};
 use crate::paging::pagemem::PhysAddr;
+use crate::cpuid::cpu_features;
 use core::arch::asm;
 
 #[inline]
@@ -22,6 +23,49 @@ pub unsafe fn in8(addr : u16) -> u8 {
     val
 }
 
+#[inline]
+pub unsafe fn out16(addr : u16, val : u16) {
+    asm!("out dx, ax",
+         in("dx") addr,
+         in("ax") val);
+}
+
+#[inline]
+pub unsafe fn in16(addr : u16) -> u16 {
+    let val : u16;
+    asm!("in ax, dx",
+         in("dx") addr,
+         out("ax") val);
+    val
+}
+
+#[inline]
+pub unsafe fn out32(addr : u16, val : u32) {
+    asm!("out dx, eax",
+         in("dx") addr,
+         in("eax") val);
+}
+
+#[inline]
+pub unsafe fn in32(addr : u16) -> u32 {
+    let val : u32;
+    asm!("in eax, dx",
+         in("dx") addr,
+         out("eax") val);
+    val
+}
+
+/// Port of the POST codes of the BIOS, which no device listens to once
+/// the system booted
+const POST_PORT : u16 = 0x80;
+
+/// Wait about a microsecond, for a slow device to handle the last I/O. The
+/// write to `POST_PORT` takes that long on the ISA bus
+#[inline]
+pub fn io_wait() {
+    unsafe { out8(POST_PORT, 0); }
+}
+
 #[inline]
 pub fn halt() -> ! {
     println!("halted!");
@@ -32,6 +76,74 @@ pub fn halt() -> ! {
     }
 }
 
+/// Disable interrupts
+#[inline]
+pub fn disable_interrupts() {
+    unsafe {
+        asm!("cli");
+    }
+}
+
+/// Enable interrupts
+#[inline]
+pub fn enable_interrupts() {
+    unsafe {
+        asm!("sti");
+    }
+}
+
+/// Flag of eflags set while interrupts are enabled
+pub const EFLAGS_IF : u32 = 1 << 9;
+
+/// Read eflags
+#[inline]
+pub fn read_eflags() -> u32 {
+    let eflags : u32;
+    unsafe {
+        asm!("pushfd
+              pop {}", out(reg) eflags);
+    }
+    eflags
+}
+
+/// Returns true if interrupts are enabled
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    read_eflags() & EFLAGS_IF != 0
+}
+
+/// Disable interrupts and return eflags as it was before, for
+/// `restore_interrupts`. No interrupt can come between the read and the cli
+#[inline]
+pub fn save_and_disable_interrupts() -> u32 {
+    let eflags : u32;
+    unsafe {
+        asm!("pushfd
+              cli
+              pop {}", out(reg) eflags);
+    }
+    eflags
+}
+
+/// Enable interrupts again if they were enabled in `eflags`, given by
+/// `save_and_disable_interrupts`
+#[inline]
+pub fn restore_interrupts(eflags : u32) {
+    if eflags & EFLAGS_IF != 0 {
+        enable_interrupts();
+    }
+}
+
+/// Enable interrupts, wait for the next one and disable them again
+#[inline]
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("sti
+              hlt
+              cli");
+    }
+}
+
 #[inline]
 pub fn get_gdt(pointer : &GdtPointer) {
     unsafe {
@@ -148,6 +260,13 @@ pub fn set_gs(gs : u16) {
     }
 }
 
+#[inline]
+pub fn get_idt(pointer : &mut IdtPointer) {
+    unsafe {
+        asm!("sidt [{}]", in(reg) pointer);
+    }
+}
+
 #[inline]
 pub fn set_idt(idt : &IdtPointer) {
     unsafe {
@@ -160,7 +279,7 @@ pub fn flush_tss() {
     unsafe {
         asm!("mov ax, {selector}
               ltr ax",
-              selector = const 0x28 | 3);
+              selector = const crate::segmem::TSS_SELECTOR | 3);
     }
 }
 
@@ -186,6 +305,15 @@ pub fn get_esp() -> u32 {
     }
 }
 
+#[inline]
+pub fn get_ebp() -> u32 {
+    unsafe {
+        let val : u32;
+        asm!("mov {}, ebp", out(reg) val);
+        val
+    }
+}
+
 #[inline]
 pub fn set_esp(val : u32) {
     unsafe {
@@ -193,20 +321,360 @@ pub fn set_esp(val : u32) {
     }
 }
 
+/// Get the physical address of the page directory in use, without the
+/// flags of cr3
 #[inline]
 pub fn get_cr3() -> PhysAddr {
+    Cr3::read().pgd()
+}
+
+#[inline]
+pub fn get_cr2() -> u32 {
     unsafe {
         let val : u32;
-        asm!("mov {}, cr3", out(reg) val);
-        PhysAddr(val)
+        asm!("mov {}, cr2", out(reg) val);
+        val
+    }
+}
+
+/// Control register 0, the operating modes of the CPU
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cr0(pub u32);
+
+impl Cr0 {
+    /// Protected mode
+    pub const PE : u32 = 1 << 0;
+
+    /// Monitor coprocessor, wait checks the TS flag
+    pub const MP : u32 = 1 << 1;
+
+    /// Emulation, FPU instructions raise #NM
+    pub const EM : u32 = 1 << 2;
+
+    /// Task switched, FPU instructions raise #NM
+    pub const TS : u32 = 1 << 3;
+
+    /// Numeric error, FPU errors are reported with #MF
+    pub const NE : u32 = 1 << 5;
+
+    /// Write protect, ring 0 writes to read-only pages fault
+    pub const WP : u32 = 1 << 16;
+
+    /// Paging
+    pub const PG : u32 = 1 << 31;
+
+    /// Read cr0
+    #[inline]
+    pub fn read() -> Self {
+        let val : u32;
+        unsafe { asm!("mov {}, cr0", out(reg) val); }
+        Self(val)
+    }
+
+    /// Load cr0 with this value
+    #[inline]
+    pub unsafe fn write(self) {
+        asm!("mov cr0, {}", in(reg) self.0);
+    }
+
+    /// Returns true if all the `bits` are set
+    pub const fn contains(self, bits : u32) -> bool {
+        self.0 & bits == bits
+    }
+
+    /// Set the `bits` of cr0, leaving the others alone
+    #[inline]
+    pub unsafe fn set_bits(bits : u32) {
+        Self(Self::read().0 | bits).write();
+    }
+
+    /// Clear the `bits` of cr0, leaving the others alone
+    #[inline]
+    pub unsafe fn clear_bits(bits : u32) {
+        Self(Self::read().0 & !bits).write();
     }
 }
 
+/// Control register 3, the physical address of the page directory and the
+/// cache flags of the accesses to it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cr3(pub u32);
+
+impl Cr3 {
+    /// Page-level write through of the page directory
+    pub const PWT : u32 = 1 << 3;
+
+    /// Page-level cache disable of the page directory
+    pub const PCD : u32 = 1 << 4;
+
+    /// Bits of the physical address of the page directory
+    pub const PGD_MASK : u32 = !0xfff;
+
+    /// Make the value of cr3 using the page directory at `pgd`, aligned on
+    /// a page, with the `flags`
+    pub const fn new(pgd : PhysAddr, flags : u32) -> Self {
+        Self(pgd.0 & Self::PGD_MASK | flags & (Self::PWT | Self::PCD))
+    }
+
+    /// Read cr3
+    #[inline]
+    pub fn read() -> Self {
+        let val : u32;
+        unsafe { asm!("mov {}, cr3", out(reg) val); }
+        Self(val)
+    }
+
+    /// Load cr3 with this value, which flushes the TLB but for the global
+    /// pages
+    #[inline]
+    pub unsafe fn write(self) {
+        asm!("mov cr3, {}", in(reg) self.0);
+    }
+
+    /// Get the physical address of the page directory
+    pub const fn pgd(self) -> PhysAddr {
+        PhysAddr(self.0 & Self::PGD_MASK)
+    }
+
+    /// Get the flags, without the address of the page directory
+    pub const fn flags(self) -> u32 {
+        self.0 & !Self::PGD_MASK
+    }
+}
+
+const _ : () = assert!(Cr3::new(PhysAddr(0x1234_5fff), Cr3::PCD).0 ==
+                       0x1234_5000 | Cr3::PCD);
+const _ : () = assert!(Cr3(0x1234_5018).pgd().0 == 0x1234_5000);
+const _ : () = assert!(Cr3(0x1234_5018).flags() == Cr3::PWT | Cr3::PCD);
+
+/// Control register 4, the extensions of the CPU
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cr4(pub u32);
+
+impl Cr4 {
+    /// 4 MB pages
+    pub const PSE : u32 = 1 << 4;
+
+    /// Global pages, kept in the TLB when cr3 changes
+    pub const PGE : u32 = 1 << 7;
+
+    /// The OS uses fxsave and fxrstor
+    pub const OSFXSR : u32 = 1 << 9;
+
+    /// The OS handles the SIMD floating point exceptions
+    pub const OSXMMEXCPT : u32 = 1 << 10;
+
+    /// Read cr4. The CPUs before the Pentium don't have it
+    #[inline]
+    pub fn read() -> Self {
+        let val : u32;
+        unsafe { asm!("mov {}, cr4", out(reg) val); }
+        Self(val)
+    }
+
+    /// Load cr4 with this value
+    #[inline]
+    pub unsafe fn write(self) {
+        asm!("mov cr4, {}", in(reg) self.0);
+    }
+
+    /// Returns true if all the `bits` are set
+    pub const fn contains(self, bits : u32) -> bool {
+        self.0 & bits == bits
+    }
+
+    /// Set the `bits` of cr4, leaving the others alone
+    #[inline]
+    pub unsafe fn set_bits(bits : u32) {
+        Self(Self::read().0 | bits).write();
+    }
+
+    /// Clear the `bits` of cr4, leaving the others alone
+    #[inline]
+    pub unsafe fn clear_bits(bits : u32) {
+        Self(Self::read().0 & !bits).write();
+    }
+}
+
+/// Read the debug status register, telling which debug condition fired
 #[inline]
-pub fn get_cr2() -> u32 {
+pub fn get_dr6() -> u32 {
     unsafe {
         let val : u32;
-        asm!("mov {}, cr2", out(reg) val);
+        asm!("mov {}, dr6", out(reg) val);
+        val
+    }
+}
+
+#[inline]
+pub unsafe fn set_dr6(val : u32) {
+    asm!("mov dr6, {}", in(reg) val);
+}
+
+/// Read the debug control register, enabling the hardware breakpoints
+#[inline]
+pub fn get_dr7() -> u32 {
+    unsafe {
+        let val : u32;
+        asm!("mov {}, dr7", out(reg) val);
         val
     }
 }
+
+#[inline]
+pub unsafe fn set_dr7(val : u32) {
+    asm!("mov dr7, {}", in(reg) val);
+}
+
+/// Read the address of the hardware breakpoint `idx`, from 0 to 3
+#[inline]
+pub fn get_dr(idx : usize) -> u32 {
+    let val : u32;
+    unsafe {
+        match idx {
+            0 => asm!("mov {}, dr0", out(reg) val),
+            1 => asm!("mov {}, dr1", out(reg) val),
+            2 => asm!("mov {}, dr2", out(reg) val),
+            3 => asm!("mov {}, dr3", out(reg) val),
+            _ => panic!("No debug register dr{}", idx),
+        }
+    }
+    val
+}
+
+/// Set the address of the hardware breakpoint `idx`, from 0 to 3
+#[inline]
+pub unsafe fn set_dr(idx : usize, val : u32) {
+    match idx {
+        0 => asm!("mov dr0, {}", in(reg) val),
+        1 => asm!("mov dr1, {}", in(reg) val),
+        2 => asm!("mov dr2, {}", in(reg) val),
+        3 => asm!("mov dr3, {}", in(reg) val),
+        _ => panic!("No debug register dr{}", idx),
+    }
+}
+
+/// Invalidate the TLB entry for the page containing `addr`
+#[inline]
+pub fn invlpg(addr : u32) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) addr);
+    }
+}
+
+/// Code segment loaded by sysenter. sysenter also loads the kernel data
+/// segment right after it, and sysexit the user code and data segments
+/// after that
+pub const IA32_SYSENTER_CS : u32 = 0x174;
+
+/// Kernel stack pointer loaded by sysenter
+pub const IA32_SYSENTER_ESP : u32 = 0x175;
+
+/// Entry point of sysenter
+pub const IA32_SYSENTER_EIP : u32 = 0x176;
+
+/// Physical address of the registers of the local APIC, and its enable bit
+pub const IA32_APIC_BASE : u32 = 0x1b;
+
+/// Errors of `read_msr` and `write_msr`
+#[derive(Debug, PartialEq, Eq)]
+pub enum MsrError {
+    /// The CPU has no MSRs, rdmsr and wrmsr would raise #UD
+    Unsupported,
+}
+
+/// Read the model specific register `msr`. The CPU must have MSRs, and
+/// `msr` must exist or rdmsr raises #GP
+#[inline]
+pub unsafe fn rdmsr(msr : u32) -> u64 {
+    let low : u32;
+    let high : u32;
+    asm!("rdmsr",
+         in("ecx") msr,
+         out("eax") low,
+         out("edx") high);
+    (high as u64) << 32 | low as u64
+}
+
+/// Write `val` in the model specific register `msr`. The CPU must have MSRs,
+/// and `msr` must exist and accept `val` or wrmsr raises #GP
+#[inline]
+pub unsafe fn wrmsr(msr : u32, val : u64) {
+    asm!("wrmsr",
+         in("ecx") msr,
+         in("eax") val as u32,
+         in("edx") (val >> 32) as u32);
+}
+
+/// Read the model specific register `msr`, or fail if the CPU has no MSRs.
+/// `msr` must still exist
+pub unsafe fn read_msr(msr : u32) -> Result<u64, MsrError> {
+    if !cpu_features().msr {
+        return Err(MsrError::Unsupported);
+    }
+    Ok(rdmsr(msr))
+}
+
+/// Write `val` in the model specific register `msr`, or fail if the CPU
+/// has no MSRs. `msr` must still exist and accept `val`
+pub unsafe fn write_msr(msr : u32, val : u64) -> Result<(), MsrError> {
+    if !cpu_features().msr {
+        return Err(MsrError::Unsupported);
+    }
+    wrmsr(msr, val);
+    Ok(())
+}
+
+/// Read the time stamp counter, which counts CPU cycles
+#[inline]
+pub fn rdtsc() -> u64 {
+    let (low, high) : (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high);
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Flag of eflags that can only be toggled if the CPU has cpuid
+const EFLAGS_ID : u32 = 1 << 21;
+
+/// Returns true if the CPU has cpuid, which is the case if the ID flag of
+/// eflags can be toggled. The 386 and the early 486 don't have it
+pub fn cpuid_supported() -> bool {
+    let (before, after) : (u32, u32);
+    unsafe {
+        asm!("pushfd
+              pushfd
+              pop {before}
+              mov {after}, {before}
+              xor {after}, {id}
+              push {after}
+              popfd
+              pushfd
+              pop {after}
+              popfd",
+             before = out(reg) before,
+             after = out(reg) after,
+             id = const EFLAGS_ID);
+    }
+    (before ^ after) & EFLAGS_ID != 0
+}
+
+/// Execute cpuid for the leaf `leaf` and the subleaf `subleaf`, which only
+/// some leaves use. Returns eax, ebx, ecx and edx. Check `cpuid_supported`
+/// first
+#[inline]
+pub fn cpuid(leaf : u32, subleaf : u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx) : (u32, u32, u32, u32);
+    // LLVM may keep ebx for itself, it is saved around cpuid instead
+    unsafe {
+        asm!("mov {ebx:e}, ebx",
+             "cpuid",
+             "xchg {ebx:e}, ebx",
+             ebx = out(reg) ebx,
+             inout("eax") leaf => eax,
+             inout("ecx") subleaf => ecx,
+             out("edx") edx);
+    }
+    (eax, ebx, ecx, edx)
+}
@@ -0,0 +1,202 @@
+//! Features of the CPU, read once at boot with cpuid. The code enabling a
+//! feature checks here that the CPU has it, and either does without it or
+//! panics telling what is missing. Without cpuid, like on a 386, the CPU is
+//! taken for one without any of them
+
+use crate::cpu::{cpuid, cpuid_supported, read_msr, IA32_APIC_BASE};
+use crate::{print, println, PERIPHERALS};
+
+/// cpuid(1) edx flag of the x87 FPU
+const CPUID_FPU : u32 = 1 << 0;
+
+/// cpuid(1) edx flag of 4 MB pages
+const CPUID_PSE : u32 = 1 << 3;
+
+/// cpuid(1) edx flag of the time stamp counter
+const CPUID_TSC : u32 = 1 << 4;
+
+/// cpuid(1) edx flag of the model specific registers, rdmsr and wrmsr
+const CPUID_MSR : u32 = 1 << 5;
+
+/// cpuid(1) edx flag of the local APIC
+const CPUID_APIC : u32 = 1 << 9;
+
+/// cpuid(1) edx flag of sysenter and sysexit
+const CPUID_SEP : u32 = 1 << 11;
+
+/// cpuid(1) edx flag of global pages
+const CPUID_PGE : u32 = 1 << 13;
+
+/// cpuid(1) edx flag of fxsave and fxrstor
+const CPUID_FXSR : u32 = 1 << 24;
+
+/// cpuid(1) edx flag of SSE
+const CPUID_SSE : u32 = 1 << 25;
+
+/// Bits of `IA32_APIC_BASE` holding the physical address of the APIC
+const APIC_BASE_ADDR : u64 = 0xffff_f000;
+
+/// Bit of `IA32_APIC_BASE` enabling the APIC
+const APIC_BASE_ENABLE : u64 = 1 << 11;
+
+/// What the CPU is and what it supports
+#[derive(Clone, Copy, Debug)]
+pub struct CpuFeatures {
+    /// Vendor string of cpuid(0), empty without cpuid
+    pub vendor : [u8; 12],
+
+    /// Highest basic leaf of cpuid
+    pub max_leaf : u32,
+
+    /// Family, with the extended family added
+    pub family : u32,
+
+    /// Model, with the extended model on the families 6 and 15
+    pub model : u32,
+
+    /// Stepping
+    pub stepping : u32,
+
+    /// x87 FPU
+    pub fpu : bool,
+
+    /// Time stamp counter and rdtsc
+    pub tsc : bool,
+
+    /// Model specific registers, rdmsr and wrmsr
+    pub msr : bool,
+
+    /// 4 MB pages
+    pub pse : bool,
+
+    /// Global pages
+    pub pge : bool,
+
+    /// sysenter and sysexit
+    pub sep : bool,
+
+    /// fxsave and fxrstor
+    pub fxsr : bool,
+
+    /// SSE
+    pub sse : bool,
+
+    /// Local APIC
+    pub apic : bool,
+}
+
+impl CpuFeatures {
+    /// Features of a CPU without cpuid
+    const fn none() -> Self {
+        Self {
+            vendor : [0; 12],
+            max_leaf : 0,
+            family : 0,
+            model : 0,
+            stepping : 0,
+            fpu : false,
+            tsc : false,
+            msr : false,
+            pse : false,
+            pge : false,
+            sep : false,
+            fxsr : false,
+            sse : false,
+            apic : false,
+        }
+    }
+
+    /// Read the features of the CPU with cpuid
+    fn detect() -> Self {
+        let mut features = Self::none();
+        if !cpuid_supported() {
+            return features;
+        }
+
+        // cpuid(0) gives the vendor in ebx, edx then ecx
+        let (max_leaf, ebx, ecx, edx) = cpuid(0, 0);
+        features.max_leaf = max_leaf;
+        features.vendor[0..4].copy_from_slice(&ebx.to_le_bytes());
+        features.vendor[4..8].copy_from_slice(&edx.to_le_bytes());
+        features.vendor[8..12].copy_from_slice(&ecx.to_le_bytes());
+        if max_leaf < 1 {
+            return features;
+        }
+
+        let (signature, _, _, edx) = cpuid(1, 0);
+        features.stepping = signature & 0xf;
+        features.model = (signature >> 4) & 0xf;
+        features.family = (signature >> 8) & 0xf;
+        if features.family == 0xf {
+            features.family += (signature >> 20) & 0xff;
+        }
+        if features.family == 0x6 || features.family >= 0xf {
+            features.model |= ((signature >> 16) & 0xf) << 4;
+        }
+
+        features.fpu = edx & CPUID_FPU != 0;
+        features.tsc = edx & CPUID_TSC != 0;
+        features.msr = edx & CPUID_MSR != 0;
+        features.pse = edx & CPUID_PSE != 0;
+        features.pge = edx & CPUID_PGE != 0;
+        features.sep = edx & CPUID_SEP != 0;
+        features.fxsr = edx & CPUID_FXSR != 0;
+        features.sse = edx & CPUID_SSE != 0;
+        features.apic = edx & CPUID_APIC != 0;
+        features
+    }
+
+    /// Get the vendor string, "unknown" if it is empty or not ASCII
+    pub fn vendor(&self) -> &str {
+        match core::str::from_utf8(&self.vendor) {
+            Ok(vendor) if self.vendor[0] != 0 => vendor,
+            _ => "unknown",
+        }
+    }
+}
+
+/// Features of the CPU, filled by `cpu_features_init`
+static mut CPU_FEATURES : Option<CpuFeatures> = None;
+
+/// Read the features of the CPU and print them. Must be called before the
+/// code enabling any of them
+pub fn cpu_features_init() {
+    let features = CpuFeatures::detect();
+    unsafe { CPU_FEATURES = Some(features); }
+
+    if !cpuid_supported() {
+        println!("cpu : no cpuid, assuming no extension");
+        return;
+    }
+    println!("cpu : {} family {:#x} model {:#x} stepping {}, max leaf {:#x}",
+             features.vendor(), features.family, features.model,
+             features.stepping, features.max_leaf);
+
+    // The missing features are printed with a dash
+    print!("cpu :");
+    for (name, present) in [("fpu", features.fpu), ("tsc", features.tsc),
+                            ("msr", features.msr), ("pse", features.pse),
+                            ("pge", features.pge), ("sep", features.sep),
+                            ("fxsr", features.fxsr), ("sse", features.sse),
+                            ("apic", features.apic)] {
+        print!(" {}{}", if present { "" } else { "-" }, name);
+    }
+    println!();
+
+    // The base of the APIC only exists on the CPUs with one
+    if features.apic {
+        if let Ok(base) = unsafe { read_msr(IA32_APIC_BASE) } {
+            println!("cpu : local APIC at {:#x}, {}", base & APIC_BASE_ADDR,
+                     if base & APIC_BASE_ENABLE != 0 { "enabled" }
+                     else { "disabled" });
+        }
+    }
+}
+
+/// Get the features of the CPU. Panics before `cpu_features_init`
+pub fn cpu_features() -> &'static CpuFeatures {
+    unsafe {
+        CPU_FEATURES.as_ref()
+            .expect("CPU features read before cpu_features_init")
+    }
+}
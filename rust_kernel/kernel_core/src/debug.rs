@@ -0,0 +1,278 @@
+//! Breakpoints for debugging. int3 and the hardware breakpoints of the debug
+//! registers print the state of the interrupted code, then let it go on.
+//! The hardware breakpoints are not switched with the tasks, so they watch
+//! an address for all of them. The panic handler dumps the stack and the
+//! frames found through ebp, the kernel keeps the frame pointers for it
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use crate::cpu::*;
+use crate::interrupts::*;
+use crate::serial::PanicWriter;
+use crate::sysenter::in_sysenter_entry;
+use crate::tasks::{kernel_stack_bounds, running_task};
+use crate::{print, println, PERIPHERALS};
+
+/// Number of hardware breakpoints, dr0 to dr3
+pub const HW_BREAKPOINTS : usize = 4;
+
+/// DR6 bit set when the hardware breakpoint `n` fired, for n below 4
+const DR6_HIT : u32 = 0xf;
+
+/// DR6 bit set when a debug register was accessed while DR7_GD was set
+const DR6_ACCESS : u32 = 1 << 13;
+
+/// DR6 bit set after a single step of the trap flag
+const DR6_SINGLE_STEP : u32 = 1 << 14;
+
+/// DR6 bit set after a switch to a task with the debug trap flag
+const DR6_TASK_SWITCH : u32 = 1 << 15;
+
+/// Flag raising a debug exception after each instruction
+const EFLAGS_TF : u32 = 1 << 8;
+
+/// Flag restarting an instruction without checking its instruction
+/// breakpoint again
+const EFLAGS_RF : u32 = 1 << 16;
+
+/// Access watched by a hardware breakpoint
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    /// Execution of the instruction at the address
+    Exec,
+
+    /// Writes to the address
+    Write,
+
+    /// Reads and writes to the address
+    ReadWrite,
+}
+
+impl WatchKind {
+    /// Get the R/W bits of DR7 of the kind
+    fn bits(self) -> u32 {
+        match self {
+            WatchKind::Exec => 0b00,
+            WatchKind::Write => 0b01,
+            WatchKind::ReadWrite => 0b11,
+        }
+    }
+
+    /// Get the kind of the R/W bits `bits`, `None` for I/O breakpoints
+    fn from_bits(bits : u32) -> Option<Self> {
+        match bits {
+            0b00 => Some(WatchKind::Exec),
+            0b01 => Some(WatchKind::Write),
+            0b11 => Some(WatchKind::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+/// Errors of `set_hw_breakpoint`
+#[derive(Debug, PartialEq, Eq)]
+pub enum BreakpointError {
+    /// There is no hardware breakpoint with this index
+    InvalidIndex,
+
+    /// The length is not 1, 2 or 4, or not 1 for an instruction
+    InvalidLength,
+
+    /// The address is not aligned on the length
+    Unaligned,
+}
+
+/// Number of dwords from the top of the stack printed on a panic
+const PANIC_STACK_DWORDS : u32 = 32;
+
+/// Max number of frames printed on a panic
+const PANIC_MAX_FRAMES : usize = 32;
+
+/// Number of times a hardware breakpoint fired
+static mut HW_HITS : u32 = 0;
+
+/// Set once the kernel panicked
+static mut PANICKING : bool = false;
+
+/// Register the handlers of the debug exception and of int3
+pub fn debug_init() {
+    register_interrupt_handler(DEBUG_VECTOR, handle_debug);
+    register_interrupt_handler(BREAKPOINT_VECTOR, handle_breakpoint);
+}
+
+/// Arm the hardware breakpoint `idx` on the `len` bytes at `addr`, for the
+/// accesses of `kind`. Replaces the breakpoint that was there
+pub fn set_hw_breakpoint(idx : usize, addr : u32, kind : WatchKind,
+                         len : u32) -> Result<(), BreakpointError> {
+    if idx >= HW_BREAKPOINTS {
+        return Err(BreakpointError::InvalidIndex);
+    }
+    let len_bits = match len {
+        1 => 0b00,
+        2 => 0b01,
+        4 => 0b11,
+        _ => return Err(BreakpointError::InvalidLength),
+    };
+    if kind == WatchKind::Exec && len != 1 {
+        return Err(BreakpointError::InvalidLength);
+    }
+    if !addr.is_multiple_of(len) {
+        return Err(BreakpointError::Unaligned);
+    }
+
+    // Each breakpoint has a local enable bit, and 4 bits of kind and length
+    // from bit 16
+    let shift = 16 + 4 * idx;
+    let mut dr7 = get_dr7() & !(0xf << shift);
+    dr7 |= (kind.bits() | len_bits << 2) << shift | 1 << (2 * idx);
+    unsafe {
+        set_dr(idx, addr);
+        set_dr7(dr7);
+    }
+    Ok(())
+}
+
+/// Disarm the hardware breakpoint `idx`
+pub fn clear_hw_breakpoint(idx : usize) {
+    assert!(idx < HW_BREAKPOINTS, "No hardware breakpoint {}", idx);
+    unsafe {
+        set_dr7(get_dr7() & !(1 << (2 * idx)));
+        set_dr(idx, 0);
+    }
+}
+
+/// Get the number of times a hardware breakpoint fired
+pub fn hw_breakpoint_hits() -> u32 {
+    unsafe { HW_HITS }
+}
+
+/// Print the task interrupted by `ctx` and the state of its registers
+fn dump_debug_context(ctx : &InterruptContext) {
+    let mode = if ctx.frame.cs & 3 == 3 { "user" } else { "kernel" };
+    match running_task() {
+        Some(task) => println!("debug : {} code of task {} (pid {})", mode,
+                               task.name(), task.pid),
+        None => println!("debug : {} code before the first task", mode),
+    }
+    print!("{}", ctx);
+}
+
+/// Handle int3. The saved eip is already past it, so returning resumes the
+/// code
+fn handle_breakpoint(ctx : &mut InterruptContext) {
+    println!("debug : breakpoint @{:#x}", ctx.frame.ip.wrapping_sub(1));
+    dump_debug_context(ctx);
+}
+
+/// Handle the debug exception, telling which condition of DR6 raised it.
+/// An instruction breakpoint fires before the instruction runs, so RF is
+/// set to run it once without firing again. sysenter keeps the trap flag of
+/// userland, a single step in its entry stub only clears it and resumes
+fn handle_debug(ctx : &mut InterruptContext) {
+    let dr6 = get_dr6();
+    let dr7 = get_dr7();
+    if dr6 & DR6_SINGLE_STEP != 0 && ctx.frame.cs & 3 == 0 &&
+            in_sysenter_entry(ctx.frame.ip) {
+        ctx.frame.eflags &= !EFLAGS_TF;
+        if dr6 & (DR6_HIT | DR6_ACCESS | DR6_TASK_SWITCH) == 0 {
+            unsafe { set_dr6(0); }
+            return;
+        }
+    }
+    for idx in (0..HW_BREAKPOINTS).filter(|idx| dr6 & DR6_HIT & 1 << idx != 0) {
+        let control = (dr7 >> (16 + 4 * idx)) & 0xf;
+        let kind = match WatchKind::from_bits(control & 0b11) {
+            Some(WatchKind::Exec) => "exec",
+            Some(WatchKind::Write) => "write",
+            Some(WatchKind::ReadWrite) => "read/write",
+            None => "io",
+        };
+        println!("debug : hardware breakpoint {} ({} of {:#x}) @{:#x}", idx,
+                 kind, get_dr(idx), ctx.frame.ip);
+        unsafe { HW_HITS += 1; }
+    }
+    if dr6 & DR6_SINGLE_STEP != 0 {
+        println!("debug : single step @{:#x}", ctx.frame.ip);
+    }
+    if dr6 & DR6_ACCESS != 0 {
+        println!("debug : debug register access @{:#x}", ctx.frame.ip);
+    }
+    if dr6 & DR6_TASK_SWITCH != 0 {
+        println!("debug : task switch @{:#x}", ctx.frame.ip);
+    }
+    dump_debug_context(ctx);
+
+    // The CPU never clears DR6 itself
+    unsafe { set_dr6(0); }
+    ctx.frame.eflags |= EFLAGS_RF;
+}
+
+/// Print the panic `info` and the running task, the top of the stack and the
+/// return addresses of the frames chained by ebp. If the panic happened in
+/// an interrupt handler, the context of the interrupt is printed too. Only
+/// the frames inside the stack we run on are followed. Must be called with
+/// interrupts disabled, and prints without the lock of the serial port. A
+/// panic in the middle of the dump only prints its message
+pub fn panic_dump(info : &PanicInfo) {
+    let mut w = PanicWriter;
+    if unsafe { core::mem::replace(&mut PANICKING, true) } {
+        let _ = writeln!(w, "[PANIC] while panicking : {}", info);
+        return;
+    }
+    let _ = writeln!(w, "[PANIC] {}", info);
+    match running_task() {
+        Some(task) => {
+            let _ = writeln!(w, "in task {} (pid {})", task.name(), task.pid);
+        }
+        None => {
+            let _ = writeln!(w, "before the first task");
+        }
+    }
+
+    // Out of the stack of the task, like on the boot or double fault stack,
+    // trust the page of esp only
+    let esp = get_esp();
+    let (low, high) = match kernel_stack_bounds() {
+        Some((low, high)) if (low..high).contains(&esp) => (low, high),
+        _ => (esp, (esp | 0xfff) + 1),
+    };
+
+    let _ = write!(w, "stack [{:#x} - {:#x}] :", low, high);
+    let end = core::cmp::min(high, esp + 4 * PANIC_STACK_DWORDS);
+    for (i, addr) in (esp..end).step_by(4).enumerate() {
+        if i % 4 == 0 {
+            let _ = write!(w, "\n  {:#010x} :", addr);
+        }
+        let _ = write!(w, " {:08x}", unsafe { *(addr as *const u32) });
+    }
+    let _ = writeln!(w);
+
+    // Each frame holds the ebp of its caller then the return address. The
+    // frames of `interrupt_handler` return in a stub, which pushed the
+    // context right above
+    let _ = writeln!(w, "backtrace :");
+    let mut frame = get_ebp();
+    let mut intr_context = None;
+    for depth in 0..PANIC_MAX_FRAMES {
+        if !frame.is_multiple_of(4) || frame < low || frame > high - 8 {
+            break;
+        }
+        let (next, ret) = unsafe {
+            (*(frame as *const u32), *((frame + 4) as *const u32))
+        };
+        let _ = writeln!(w, "  #{:<2} {:#010x}", depth, ret);
+        if intr_context.is_none() && is_interrupt_return(ret) {
+            intr_context = Some(frame + 8);
+        }
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+
+    if let Some(addr) = intr_context {
+        let ctx = unsafe { &*(addr as *const InterruptContext) };
+        let _ = write!(w, "in interrupt {}, error code {:#x}\n{}", ctx.nr,
+                       ctx.err, ctx);
+    }
+}
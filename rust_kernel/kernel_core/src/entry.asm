@@ -1,15 +1,38 @@
 [bits 32]
 
+; Page directory entry flags : present, writable, 4 MB page
+%define EARLY_PDE_FLAGS 0x83
+; Entries of the temporary slots table and of the recursive mapping, must
+; match TEMP_PDE_INDEX and RECURSIVE_PDE_INDEX
+%define TEMP_PDE_INDEX      1022
+%define RECURSIVE_PDE_INDEX 1023
+
+%define CR4_PSE         (1 << 4)
+%define CR0_PG          (1 << 31)
+%define EFLAGS_ID       (1 << 21)
+%define CPUID_PSE       (1 << 3)
+
 section .kernel_stack align=16 nobits alloc write
 resb 0x2000
 
 ; section .user_stack align=16 nobits alloc write
 ; resb 0x6000
 
+; Page directory used until rust_main builds the kernel one, followed by
+; the page table of its temporary slots
+section .bss align=4096 nobits alloc write
+early_pgd:
+resb 0x1000
+early_temp_ptb:
+resb 0x1000
+
 section .text
 
 extern __kernel_start__
 extern rust_main
+extern rust_unsupported_cpu
+extern kernel_phys_window_base
+extern kernel_phys_window_size
 
 global entry
 entry:
@@ -17,8 +40,68 @@ entry:
     mov     esp, __kernel_start__
     push    0
     popf
+
+    ; Physical memory is only used through the window, map it before any
+    ; rust code runs. The kernel is linked at its physical address, so the
+    ; first 4 MB holding it are identity mapped too. Requires 4 MB pages.
+    ; Like any page directory, it is mapped on itself and has temporary
+    ; slots, used to build the kernel page directory
+    mov     edi, early_pgd
+    mov     ecx, 2048
+    xor     eax, eax
+    rep stosd
+    mov     dword [early_pgd], EARLY_PDE_FLAGS
+    ; Present and writable page tables
+    mov     dword [early_pgd + TEMP_PDE_INDEX*4], early_temp_ptb + 3
+    mov     dword [early_pgd + RECURSIVE_PDE_INDEX*4], early_pgd + 3
+
+    mov     edi, [kernel_phys_window_base]
+    shr     edi, 22
+    shl     edi, 2
+    add     edi, early_pgd
+    mov     ecx, [kernel_phys_window_size]
+    shr     ecx, 22
+    mov     eax, EARLY_PDE_FLAGS
+.map_window:
+    stosd
+    add     eax, 0x400000
+    loop    .map_window
+
+    ; Without cpuid, or without 4 MB pages, let rust tell what is missing
+    ; before the early page directory is used
+    pushfd
+    pop     eax
+    mov     edx, eax
+    xor     eax, EFLAGS_ID
+    push    eax
+    popfd
+    pushfd
+    pop     eax
+    xor     eax, edx
+    test    eax, EFLAGS_ID
+    jz      .unsupported
+    push    ebx
+    mov     eax, 1
+    cpuid
+    pop     ebx
+    test    edx, CPUID_PSE
+    jz      .unsupported
+
+    mov     eax, cr4
+    or      eax, CR4_PSE
+    mov     cr4, eax
+    mov     eax, early_pgd
+    mov     cr3, eax
+    mov     eax, cr0
+    or      eax, CR0_PG
+    mov     cr0, eax
+
     mov     ecx, ebx
     call    rust_main
+    jmp     halt
+
+.unsupported:
+    call    rust_unsupported_cpu
 
 halt:
     hlt
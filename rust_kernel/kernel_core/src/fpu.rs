@@ -0,0 +1,175 @@
+//! State of the x87 FPU and of the SSE registers. The kernel is built
+//! without floating point, so the registers only hold the state of a user
+//! task. It is switched lazily: `switch_to` sets the TS flag of cr0, and
+//! the first FPU instruction of the next task raises #NM. Only then the
+//! registers are saved in the task owning them and the ones of the running
+//! task loaded, so tasks that don't use the FPU never pay for it
+
+use core::arch::asm;
+use crate::cpu::*;
+use crate::cpuid::cpu_features;
+use crate::interrupts::*;
+use crate::tasks::{current_task, find_task};
+use crate::{print, println, PERIPHERALS};
+
+/// Saved registers of the FPU, in the format of fxsave or of fnsave if the
+/// CPU doesn't have fxsave
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct FpuState([u8; 512]);
+
+/// State of the FPU after initialization, given to new tasks
+static mut INITIAL_STATE : FpuState = FpuState([0; 512]);
+
+/// Set if the CPU has an FPU
+static mut FPU_ENABLED : bool = false;
+
+/// Pid of the task whose state is in the registers of the FPU, 0 if none
+static mut FPU_OWNER : u32 = 0;
+
+/// Mirror of the TS flag of cr0, to skip writing it when it doesn't change
+static mut TS_SET : bool = false;
+
+/// Number of #NM handled, each one switching the state of the FPU
+static mut FPU_SWITCHES : u64 = 0;
+
+/// Set if the state is saved with fxsave, which includes the SSE registers
+static mut FXSR_ENABLED : bool = false;
+
+impl FpuState {
+    /// Get the state of an initialized FPU
+    pub fn initial() -> Self {
+        unsafe { INITIAL_STATE }
+    }
+}
+
+/// Enable the FPU, and SSE if the CPU supports it
+pub fn fpu_init() {
+    let cpu = cpu_features();
+    if !cpu.fpu {
+        println!("no FPU, floating point instructions raise #NM");
+        return;
+    }
+
+    unsafe {
+        Cr0::clear_bits(Cr0::EM);
+        Cr0::set_bits(Cr0::MP | Cr0::NE);
+        if cpu.fxsr {
+            let mut bits = Cr4::OSFXSR;
+            if cpu.sse {
+                bits |= Cr4::OSXMMEXCPT;
+            }
+            Cr4::set_bits(bits);
+            FXSR_ENABLED = true;
+        }
+        FPU_ENABLED = true;
+
+        asm!("fninit");
+        fpu_save(&mut *core::ptr::addr_of_mut!(INITIAL_STATE));
+    }
+    register_interrupt_handler(DEVICE_NOT_AVAILABLE_VECTOR,
+                               handle_device_not_available);
+
+    println!("FPU enabled, SSE {}",
+             if cpu.sse { "enabled" } else { "absent" });
+}
+
+/// Check if the CPU has an FPU
+pub fn fpu_enabled() -> bool {
+    unsafe { FPU_ENABLED }
+}
+
+/// Save the registers of the FPU in `state`, leaving them unchanged
+pub fn fpu_save(state : &mut FpuState) {
+    unsafe {
+        if FXSR_ENABLED {
+            asm!("fxsave [{}]", in(reg) state.0.as_mut_ptr());
+        } else if FPU_ENABLED {
+            // fnsave reinitializes the FPU, so load the state back
+            asm!("fnsave [{0}]
+                  frstor [{0}]", in(reg) state.0.as_mut_ptr());
+        }
+    }
+}
+
+/// Load the registers of the FPU from `state`
+pub fn fpu_restore(state : &FpuState) {
+    unsafe {
+        if FXSR_ENABLED {
+            asm!("fxrstor [{}]", in(reg) state.0.as_ptr());
+        } else if FPU_ENABLED {
+            asm!("frstor [{}]", in(reg) state.0.as_ptr());
+        }
+    }
+}
+
+/// Set or clear the TS flag of cr0
+fn set_ts(set : bool) {
+    unsafe {
+        if TS_SET == set {
+            return;
+        }
+        if set {
+            Cr0::set_bits(Cr0::TS);
+        } else {
+            asm!("clts");
+        }
+        TS_SET = set;
+    }
+}
+
+/// Called by `switch_to` before switching to the task `next`. The first FPU
+/// instruction it runs raises #NM, unless its state is still in the FPU
+pub fn fpu_switch(next : u32) {
+    if unsafe { FPU_ENABLED } {
+        set_ts(unsafe { FPU_OWNER } != next);
+    }
+}
+
+/// Handle #NM, raised by the first FPU instruction of a task since it was
+/// switched to. The registers are saved in the task owning them, if it is
+/// still alive, and the state of the running task is loaded. The kernel is
+/// built without floating point, so #NM from ring 0 is a bug
+fn handle_device_not_available(ctx : &mut InterruptContext) {
+    assert!(ctx.frame.cs & 3 == 3, "FPU used by the kernel @{:#x}",
+            ctx.frame.ip);
+
+    set_ts(false);
+    let task = current_task();
+    unsafe {
+        if FPU_OWNER != task.pid {
+            if let Some(owner) = find_task(FPU_OWNER) {
+                fpu_save(&mut owner.fpu_state);
+            }
+            fpu_restore(&task.fpu_state);
+            FPU_OWNER = task.pid;
+        }
+        FPU_SWITCHES += 1;
+    }
+}
+
+/// Get the state of the FPU of the task `pid`, saved in `saved` unless the
+/// task owns the registers. The task must be the running one then, so TS is
+/// clear and they can be saved without #NM
+pub fn fpu_state_of(pid : u32, saved : &FpuState) -> FpuState {
+    let mut state = *saved;
+    if unsafe { FPU_OWNER } == pid {
+        fpu_save(&mut state);
+    }
+    state
+}
+
+/// Forget that the task `pid` owns the registers of the FPU, called when it
+/// is freed. Its state is dropped instead of saved at the next #NM
+pub fn fpu_release(pid : u32) {
+    unsafe {
+        if FPU_OWNER == pid {
+            FPU_OWNER = 0;
+        }
+    }
+}
+
+/// Get the number of times the state of the FPU was switched by #NM
+pub fn fpu_switches() -> u64 {
+    unsafe { FPU_SWITCHES }
+}
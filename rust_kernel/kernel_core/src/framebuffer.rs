@@ -0,0 +1,41 @@
+//! Framebuffer set up by the boot loader, mapped as device memory for the
+//! drivers that will draw in it
+
+use crate::multiboot::{Framebuffer, MultibootInfo};
+use crate::paging::pagemem::{PhysAddr, VirtAddr};
+use crate::paging::virtmem::VirtMem;
+use crate::{print, println, PERIPHERALS};
+
+/// The framebuffer and the address it is mapped at in the kernel address
+/// space, once `framebuffer_init` found one
+static mut FRAMEBUFFER : Option<(Framebuffer, VirtAddr)> = None;
+
+/// Map the framebuffer described by the boot loader in `vspace`, the kernel
+/// address space. Framebuffers above 4 GB are ignored
+pub fn framebuffer_init(info : &MultibootInfo, vspace : &mut VirtMem) {
+    let fb = match info.framebuffer() {
+        Some(fb) if fb.addr + fb.size() as u64 <= u32::MAX as u64 => fb,
+        Some(fb) => {
+            println!("framebuffer : {:#x} out of reach", fb.addr);
+            return;
+        }
+        None => return,
+    };
+
+    match vspace.map_mmio(PhysAddr(fb.addr as u32), fb.size()) {
+        Ok(vaddr) => {
+            println!("framebuffer : {}x{}x{} type {} at {:#x}, mapped at \
+                      {:#x}", fb.width, fb.height, fb.bpp, fb.ty, fb.addr,
+                     vaddr.0);
+            unsafe { FRAMEBUFFER = Some((fb, vaddr)); }
+        }
+        Err(err) => println!("framebuffer : can't map {:#x} : {:?}", fb.addr,
+                             err),
+    }
+}
+
+/// Get the framebuffer and the address it is mapped at in the kernel
+/// address space, if there is one
+pub fn framebuffer() -> Option<(Framebuffer, VirtAddr)> {
+    unsafe { FRAMEBUFFER }
+}
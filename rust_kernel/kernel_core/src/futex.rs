@@ -0,0 +1,133 @@
+//! Futexes, to wait until another task changes a value in memory. Waiters
+//! are keyed on the physical address behind the user address, so tasks
+//! mapping the same shared page at different addresses use the same futex
+
+use crate::paging::pagemem::*;
+use crate::paging::virtmem::*;
+use crate::syscalls::*;
+use crate::tasks::*;
+use crate::uaccess::*;
+
+/// A task blocked on a futex
+#[derive(Clone, Copy, PartialEq)]
+struct FutexWaiter {
+    /// Pid of the blocked task
+    pid : u32,
+
+    /// Physical address of the futex
+    paddr : u32,
+}
+
+/// Max number of tasks blocked on futexes at the same time
+const MAX_FUTEX_WAITERS : usize = 32;
+
+/// Tasks blocked on a futex, oldest first
+static mut FUTEX_WAITERS : [Option<FutexWaiter>; MAX_FUTEX_WAITERS] =
+    [None; MAX_FUTEX_WAITERS];
+
+/// Register the futex syscalls
+pub fn futex_init() {
+    use SyscallArg::*;
+
+    register_syscall(SYS_FUTEX_WAIT, "futex_wait", &[Addr, Uint], |ctx| {
+        sys_futex_wait(ctx.regs.ecx, ctx.regs.edx)
+    });
+    register_syscall(SYS_FUTEX_WAKE, "futex_wake", &[Addr, Uint], |ctx| {
+        sys_futex_wake(ctx.regs.ecx, ctx.regs.edx)
+    });
+}
+
+/// Get the physical address of the futex at the user address `vaddr`, which
+/// must be aligned and readable
+fn futex_paddr(vaddr : u32) -> Result<u32, i32> {
+    if vaddr & 3 != 0 {
+        return Err(-EINVAL);
+    }
+    check_user_range(vaddr, 4, false)?;
+
+    // A page shared copy-on-write after a fork changes when it is written
+    // to, so the futex is keyed on the copy of the task right away
+    let vspace = VirtMem::get_current();
+    vspace.break_cow(VirtAddr(vaddr));
+    let pte = vspace.get_pte(VirtAddr(vaddr)).ok_or(-EFAULT)?;
+    Ok(pte.get_paddr().0 | (vaddr & 0xfff))
+}
+
+/// Remove `waiter` from the waiters
+fn remove_waiter(waiter : FutexWaiter) {
+    let waiters = unsafe { &mut FUTEX_WAITERS };
+    if let Some(idx) = waiters.iter().position(|&x| x == Some(waiter)) {
+        waiters.copy_within(idx + 1.., idx);
+        waiters[MAX_FUTEX_WAITERS - 1] = None;
+    }
+}
+
+/// Block until the futex at `vaddr` is woken up, if it still contains
+/// `expected`. Fails with EAGAIN if the value changed, EINVAL if the address
+/// is not aligned, EFAULT if it is not readable and ENOMEM if too many tasks
+/// already wait on futexes
+fn sys_futex_wait(vaddr : u32, expected : u32) -> i32 {
+    let paddr = match futex_paddr(vaddr) {
+        Ok(paddr) => paddr,
+        Err(err) => return err,
+    };
+
+    // Nothing can change the value between this read and the moment we are
+    // queued, since there is only one core and syscalls are not preempted
+    let mut value = [0u8; 4];
+    if let Err(err) = copy_from_user(&mut value, vaddr) {
+        return err;
+    }
+    if u32::from_le_bytes(value) != expected {
+        return -EAGAIN;
+    }
+
+    let waiter = FutexWaiter {
+        pid : current_task().pid,
+        paddr : paddr,
+    };
+    unsafe {
+        match FUTEX_WAITERS.iter_mut().find(|x| x.is_none()) {
+            Some(slot) => *slot = Some(waiter),
+            None => return -ENOMEM,
+        }
+    }
+
+    // Another event, like a message, can wake us up too. Only a wake on the
+    // futex removes us from the waiters
+    loop {
+        block_current();
+
+        if unsafe { !FUTEX_WAITERS.contains(&Some(waiter)) } {
+            return 0;
+        }
+    }
+}
+
+/// Wake up at most `count` tasks waiting on the futex at `vaddr`, oldest
+/// first. Returns the number of tasks woken up
+fn sys_futex_wake(vaddr : u32, count : u32) -> i32 {
+    let paddr = match futex_paddr(vaddr) {
+        Ok(paddr) => paddr,
+        Err(err) => return err,
+    };
+
+    let mut woken = 0;
+    while woken < count {
+        let waiter = unsafe {
+            FUTEX_WAITERS.iter().flatten().find(|x| x.paddr == paddr).copied()
+        };
+        let waiter = match waiter {
+            Some(waiter) => waiter,
+            None => break,
+        };
+
+        remove_waiter(waiter);
+        if let Some(task) = find_task(waiter.pid) {
+            wake_up(task);
+        }
+        woken += 1;
+    }
+
+    woken as i32
+}
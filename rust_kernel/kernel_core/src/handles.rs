@@ -0,0 +1,114 @@
+//! Per-task handle tables. A handle is a small integer that names a kernel
+//! object for the task that owns it, so a task can only use the objects it
+//! created or inherited
+
+use crate::shm::*;
+use crate::sem::*;
+use crate::syscalls::*;
+use crate::tasks::current_task;
+
+/// Number of handles a task can have open at the same time
+pub const MAX_HANDLES : usize = 16;
+
+/// A kernel object a handle refers to. Every handle holds a reference on its
+/// object
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KernelObject {
+    /// A shared memory object, identified by its id in the shm module
+    Shm(usize),
+
+    /// A semaphore, identified by its id in the sem module
+    Sem(usize),
+}
+
+impl KernelObject {
+    /// Take a reference on the object for a new handle
+    fn get(&self) {
+        match *self {
+            KernelObject::Shm(id) => shm_get(id),
+            KernelObject::Sem(id) => sem_get(id),
+        }
+    }
+
+    /// Drop the reference of a handle that is closed
+    fn put(&self) {
+        match *self {
+            KernelObject::Shm(id) => shm_put(id),
+            KernelObject::Sem(id) => sem_put(id),
+        }
+    }
+}
+
+/// The handles of a task, a handle is an index in this table
+#[derive(Debug)]
+pub struct HandleTable {
+    entries : [Option<KernelObject>; MAX_HANDLES],
+}
+
+impl HandleTable {
+    /// Create an empty handle table
+    pub const fn new() -> Self {
+        Self {
+            entries : [None; MAX_HANDLES],
+        }
+    }
+
+    /// Create a handle for `object`, taking a reference on it. Returns
+    /// `None` if the table is full
+    pub fn insert(&mut self, object : KernelObject) -> Option<u32> {
+        let handle = self.entries.iter().position(|x| x.is_none())?;
+        object.get();
+        self.entries[handle] = Some(object);
+        Some(handle as u32)
+    }
+
+    /// Get the object of `handle`
+    pub fn get(&self, handle : u32) -> Option<KernelObject> {
+        *self.entries.get(handle as usize)?
+    }
+
+    /// Close `handle`. Returns false if it was not open
+    pub fn close(&mut self, handle : u32) -> bool {
+        match self.entries.get_mut(handle as usize).and_then(|x| x.take()) {
+            Some(object) => {
+                object.put();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Create a copy of this table for a forked task
+    pub fn dup(&self) -> Self {
+        for object in self.entries.iter().flatten() {
+            object.get();
+        }
+        Self {
+            entries : self.entries,
+        }
+    }
+
+    /// Close every handle of the table
+    pub fn close_all(&mut self) {
+        for handle in 0..MAX_HANDLES {
+            self.close(handle as u32);
+        }
+    }
+}
+
+/// Register the syscalls working on any handle
+pub fn handles_init() {
+    register_syscall(SYS_CLOSE, "close", &[SyscallArg::Uint], |ctx| {
+        sys_close(ctx.regs.ecx)
+    });
+}
+
+/// Close `handle`. Fails with EBADF if it is not an open handle of the
+/// current task
+fn sys_close(handle : u32) -> i32 {
+    if current_task().handles.close(handle) {
+        0
+    } else {
+        -EBADF
+    }
+}
@@ -0,0 +1,152 @@
+//! Kernel heap, backing the collections of the `alloc` crate. Allocations
+//! are taken from a list of free blocks sorted by address, and the heap
+//! grows by runs of contiguous physical pages used through the physical
+//! memory window. The pages given by `alloc_virt_pages` are only mapped in
+//! one address space, while the window is mapped in all of them, so the
+//! heap stays usable by a syscall whatever the current task. Pages are never
+//! given back to the physical allocator
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::null_mut;
+use crate::paging::pagemem::PAGE_SIZE;
+use crate::paging::physmem::PhysMem;
+use crate::sync::{InterruptGuard, PreemptGuard};
+
+/// Min number of pages added to the heap when it grows
+const HEAP_GROW_PAGES : usize = 4;
+
+/// A free block of memory, stored at the start of the memory it describes
+struct FreeBlock {
+    /// Size of the block in bytes, including this header
+    size : usize,
+
+    /// Next free block, at a higher address
+    next : *mut FreeBlock,
+}
+
+/// Blocks are aligned on and sized in multiples of this, so that anything
+/// left over by an allocation can hold a `FreeBlock`
+const BLOCK_ALIGN : usize = size_of::<FreeBlock>();
+
+/// The free blocks, sorted by address
+static mut FREE_LIST : *mut FreeBlock = null_mut();
+
+/// The allocator of the `alloc` crate
+pub struct KernelHeap;
+
+#[cfg(not(test))]
+#[global_allocator]
+static KERNEL_HEAP : KernelHeap = KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+
+        // Neither another task nor an interrupt handler may walk the free
+        // list meanwhile. Preemption is disabled first, so that enabling it
+        // again can only schedule once interrupts are restored
+        let _preempt = PreemptGuard::new();
+        let _interrupts = InterruptGuard::new();
+
+        let ptr = alloc_block(size, align);
+        if !ptr.is_null() || !grow(size, align) {
+            return ptr;
+        }
+        alloc_block(size, align)
+    }
+
+    unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout) {
+        let (size, _) = block_layout(layout);
+
+        let _preempt = PreemptGuard::new();
+        let _interrupts = InterruptGuard::new();
+        free_block(ptr as usize, size);
+    }
+}
+
+/// Round `value` up to a multiple of `align`, a power of 2
+fn align_up(value : usize, align : usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Get the size and the alignment of the block holding `layout`
+fn block_layout(layout : Layout) -> (usize, usize) {
+    let size = align_up(layout.size().max(1), BLOCK_ALIGN);
+    (size, layout.align().max(BLOCK_ALIGN))
+}
+
+/// Take `size` bytes aligned on `align` from the first free block that can
+/// hold them. Returns null if none can
+unsafe fn alloc_block(size : usize, align : usize) -> *mut u8 {
+    let mut prev : *mut FreeBlock = null_mut();
+    let mut block = FREE_LIST;
+    while !block.is_null() {
+        let start = block as usize;
+        let end = start + (*block).size;
+        let alloc_start = align_up(start, align);
+        if alloc_start + size <= end {
+            // Remove the block from the list, then give back the memory
+            // around the allocation
+            let next = (*block).next;
+            if prev.is_null() {
+                FREE_LIST = next;
+            } else {
+                (*prev).next = next;
+            }
+            if alloc_start > start {
+                free_block(start, alloc_start - start);
+            }
+            if alloc_start + size < end {
+                free_block(alloc_start + size, end - alloc_start - size);
+            }
+            return alloc_start as *mut u8;
+        }
+
+        prev = block;
+        block = (*block).next;
+    }
+    null_mut()
+}
+
+/// Add the `size` bytes at `addr` to the free list, merged with the free
+/// blocks right before and after them
+unsafe fn free_block(addr : usize, size : usize) {
+    let mut prev : *mut FreeBlock = null_mut();
+    let mut next = FREE_LIST;
+    while !next.is_null() && (next as usize) < addr {
+        prev = next;
+        next = (*next).next;
+    }
+
+    let block = addr as *mut FreeBlock;
+    block.write(FreeBlock { size, next });
+    if !next.is_null() && addr + size == next as usize {
+        (*block).size += (*next).size;
+        (*block).next = (*next).next;
+    }
+
+    if prev.is_null() {
+        FREE_LIST = block;
+    } else if prev as usize + (*prev).size == addr {
+        (*prev).size += (*block).size;
+        (*prev).next = (*block).next;
+    } else {
+        (*prev).next = block;
+    }
+}
+
+/// Add enough pages to the heap for an allocation of `size` bytes aligned
+/// on `align`. Returns false if there is not enough physical memory
+unsafe fn grow(size : usize, align : usize) -> bool {
+    let npages = (size + align).div_ceil(PAGE_SIZE).max(HEAP_GROW_PAGES);
+    match PhysMem::try_alloc_phys_contiguous(npages) {
+        Ok(paddr) => {
+            let addr = PhysMem::translate(paddr, npages * PAGE_SIZE)
+                .expect("Heap pages outside of RAM");
+            free_block(addr as usize, npages * PAGE_SIZE);
+            true
+        }
+        Err(_) => false,
+    }
+}
@@ -1,15 +1,255 @@
+#[cfg(not(test))]
 use core::arch::global_asm;
-use crate::cpu::{set_idt, get_cr2, get_ds, get_es, get_fs, get_gs, get_cr3};
-use crate::tasks::schedule;
+use crate::cpu::{set_idt, get_idt, get_cr2, Cr3};
+use crate::cpu::{get_ds, get_es, get_fs, get_gs};
+use crate::tasks::{schedule, account_tick, quantum_expired};
+use crate::tasks::{check_kernel_stack_overflow, current_task, exit_current};
+use crate::segmem::*;
+use crate::paging::{kernel_vspace, kernel_ro_range, is_kernel_ro};
 use crate::paging::pagemem::*;
 use crate::paging::virtmem::*;
+use crate::paging::physmem::PhysMem;
+use crate::uaccess::page_chunks;
 use crate::syscalls::*;
 use crate::pic::*;
+use crate::{print, println, PERIPHERALS};
+use crate::vsys::vsys_update_ticks;
+use crate::sync::{preemptible, set_need_resched};
+use crate::tasks::{user_task_running, dump_tasks};
+use crate::watchdog::watchdog_tick;
+use crate::work::run_work_on_exit;
 
 /// Present = 1, Descriptor Privilege Level = Ring 0, Type = 32 Interrupt
 const X86_INTR_GATE : u8 = 0x8e;
-/// Present = 1, Descriptor Privilege Level = Ring 3, Type = 32 Interrupt
-const X86_INTR_GATE_R3 : u8 = 0xee;
+/// Present = 1, Descriptor Privilege Level = Ring 0, Type = 32 Trap
+const X86_TRAP_GATE : u8 = 0x8f;
+/// Present = 1, Descriptor Privilege Level = Ring 0, Type = Task gate
+const X86_TASK_GATE : u8 = 0x85;
+
+/// Software interrupt printing the task table, allowed from userland
+pub const TASK_DUMP_VECTOR : u32 = 0x81;
+
+/// Software interrupt of the syscalls, allowed from userland
+pub const SYSCALL_VECTOR : u8 = 0x80;
+
+/// Vector of the debug exception, raised by the hardware breakpoints
+pub const DEBUG_VECTOR : u8 = 0x1;
+
+/// Vector of the non-maskable interrupt
+pub const NMI_VECTOR : u8 = 0x2;
+
+/// Vector of int3, allowed from userland
+pub const BREAKPOINT_VECTOR : u8 = 0x3;
+
+/// Vector of the device not available exception, raised by the FPU
+pub const DEVICE_NOT_AVAILABLE_VECTOR : u8 = 0x7;
+
+/// Vector of the general protection fault exception
+pub const GP_FAULT_VECTOR : u8 = 0xd;
+
+/// Vector of the page fault exception
+pub const PAGE_FAULT_VECTOR : u8 = 0xe;
+
+/// Vector of IRQ 0, the first of the 16 IRQs of the PICs
+pub const IRQ_BASE : u8 = 0x20;
+
+/// Selector of the kernel code segment, where every handler runs
+const KERNEL_CODE_SELECTOR : u16 = 0x8;
+
+/// Gate type of a vector
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum GateKind {
+    /// Interrupts are disabled on entry
+    Interrupt,
+
+    /// Interrupts stay as they were on entry
+    Trap,
+}
+
+/// Gate of a vector that is not a ring 0 interrupt gate to its stub
+struct GateConfig {
+    vector : u8,
+    kind : GateKind,
+
+    /// Highest ring allowed to raise the vector with `int`
+    dpl : u8,
+
+    /// Code segment of the handler
+    selector : u16,
+}
+
+/// Gates applied by `interrupts_init` over the default ones. Syscalls keep
+/// interrupts disabled until they are done, int3 only prints, so it can be
+/// interrupted
+const GATES : [GateConfig; 3] = [
+    GateConfig { vector : SYSCALL_VECTOR, kind : GateKind::Interrupt,
+                 dpl : 3, selector : KERNEL_CODE_SELECTOR },
+    GateConfig { vector : TASK_DUMP_VECTOR as u8, kind : GateKind::Interrupt,
+                 dpl : 3, selector : KERNEL_CODE_SELECTOR },
+    GateConfig { vector : BREAKPOINT_VECTOR, kind : GateKind::Trap,
+                 dpl : 3, selector : KERNEL_CODE_SELECTOR },
+];
+
+
+/// Function handling the interrupts of a vector
+pub type InterruptHandler = fn(&mut InterruptContext);
+
+/// What an IRQ handler did with its interrupt, telling the dispatcher if it
+/// still has to send the EOI
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IrqReturn {
+    /// The device raised the IRQ and was serviced, the dispatcher sends the
+    /// EOI
+    Handled,
+
+    /// The device was serviced and the handler sent the EOI itself, like
+    /// the timer which must before it switches to another task
+    HandledNoEoi,
+
+    /// The device didn't raise the IRQ. The dispatcher sends the EOI and
+    /// counts the IRQ as unclaimed
+    NotMine,
+}
+
+/// Function handling an IRQ
+pub type IrqHandler = fn(&mut InterruptContext) -> IrqReturn;
+
+/// Handlers indexed by interrupt vector
+static mut HANDLER_TABLE : [Option<InterruptHandler>; 256] = [None; 256];
+
+/// Handlers indexed by IRQ
+static mut IRQ_HANDLERS : [Option<IrqHandler>; 16] = [None; 16];
+
+/// Number of IRQs no handler claimed, by IRQ
+static mut UNCLAIMED_IRQS : [u32; 16] = [0; 16];
+
+/// Number of interrupts received, by vector. Spurious IRQs are counted too
+static mut INTR_COUNTS : [u64; 256] = [0; 256];
+
+/// Number of exceptions raised by userland that killed the task
+static mut USER_EXCEPTIONS : u64 = 0;
+
+/// Names of the exceptions, by vector
+const EXCEPTION_NAMES : [&str; 32] = [
+    "divide error", "debug", "nmi", "breakpoint", "overflow",
+    "bound range exceeded", "invalid opcode", "device not available",
+    "double fault", "coprocessor segment overrun", "invalid tss",
+    "segment not present", "stack segment fault", "general protection",
+    "page fault", "reserved", "x87 floating point", "alignment check",
+    "machine check", "simd floating point", "virtualization", "reserved",
+    "reserved", "reserved", "reserved", "reserved", "reserved", "reserved",
+    "reserved", "reserved", "security", "reserved",
+];
+
+/// Page fault error code bit set when the page was present
+const PF_PRESENT : u32 = 1 << 0;
+
+/// Page fault error code bit set when the access was a write
+const PF_WRITE : u32 = 1 << 1;
+
+/// Page fault error code bit set when the access came from ring 3
+const PF_USER : u32 = 1 << 2;
+
+/// Page fault error code bit set when a paging structure has a reserved bit
+const PF_RESERVED : u32 = 1 << 3;
+
+/// Page fault error code bit set when the access was an instruction fetch
+const PF_FETCH : u32 = 1 << 4;
+
+/// Selector error code bit set when the fault came from an event external
+/// to the program, like an interrupt
+const SEL_EXTERNAL : u32 = 1 << 0;
+
+/// Selector error code bit set when the index is a vector of the IDT
+const SEL_IDT : u32 = 1 << 1;
+
+/// Selector error code bit set when the index is in the LDT, without
+/// `SEL_IDT`
+const SEL_LDT : u32 = 1 << 2;
+
+/// Number of bytes of code shown at the faulting instruction
+const FAULT_CODE_BYTES : usize = 16;
+
+/// Text of a selector error code, like "external, idt vector 0x90"
+struct SelectorError(u32);
+
+impl core::fmt::Display for SelectorError {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        let err = self.0;
+        if err == 0 {
+            return write!(f, "no selector");
+        }
+        if err & SEL_EXTERNAL != 0 {
+            write!(f, "external, ")?;
+        }
+        let index = (err & 0xffff) >> 3;
+        if err & SEL_IDT != 0 {
+            write!(f, "idt vector {:#x}", index)
+        } else if err & SEL_LDT != 0 {
+            write!(f, "ldt index {}", index)
+        } else {
+            write!(f, "gdt index {} (selector {:#x})", index, err & 0xfff8)
+        }
+    }
+}
+
+/// Text of a page fault error code, like "user write to non-present page"
+struct PageFaultError(u32);
+
+impl core::fmt::Display for PageFaultError {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        let err = self.0;
+        let mode = if err & PF_USER != 0 { "user" } else { "kernel" };
+        let access = if err & PF_FETCH != 0 {
+            "instruction fetch from"
+        } else if err & PF_WRITE != 0 {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if err & PF_PRESENT != 0 {
+            "present"
+        } else {
+            "non-present"
+        };
+        write!(f, "{} {} {} page", mode, access, page)?;
+        if err & PF_RESERVED != 0 {
+            write!(f, ", reserved bit set")?;
+        }
+        Ok(())
+    }
+}
+
+/// Names of the flags set in a page table entry
+struct PageFlags(u32);
+
+impl core::fmt::Display for PageFlags {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        const NAMES : [(u32, &str); 9] = [
+            (PAGE_PRESENT, "present"), (PAGE_WRITE, "write"),
+            (PAGE_USER, "user"), (PAGE_LARGE, "large"),
+            (PAGE_GLOBAL, "global"), (PAGE_SHARED, "shared"),
+            (PAGE_COW, "cow"), (PAGE_LAZY, "lazy"),
+            (PAGE_ACCESSED, "accessed"),
+        ];
+        write!(f, "{:#x}", self.0)?;
+        for &(flag, name) in NAMES.iter() {
+            if self.0 & flag != 0 {
+                // Present pages reuse the bit of lazy pages
+                let name = if flag == PAGE_LAZY && self.0 & PAGE_PRESENT != 0 {
+                    "borrowed"
+                } else {
+                    name
+                };
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stack of the double fault task
+static mut DOUBLE_FAULT_STACK : [u32; 1024] = [0; 1024];
 
 /// Structure describing the IDT Pointer
 /// Can be used by set_idt
@@ -52,6 +292,26 @@ impl From<u64> for IdtEntry {
     }
 }
 
+impl core::fmt::Display for IdtEntry {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        let offset = self.offset();
+        let kind = match self.type_attr & 0xf {
+            0x5 => "task gate",
+            0x6 => "16 bits interrupt gate",
+            0x7 => "16 bits trap gate",
+            0xe => "interrupt gate",
+            0xf => "trap gate",
+            _ => "invalid gate",
+        };
+        write!(f, "{} {:#x}:{:#x} dpl {}", kind, self.selector, offset,
+               (self.type_attr >> 5) & 3)?;
+        if self.type_attr & 0x80 == 0 {
+            write!(f, " not present")?;
+        }
+        Ok(())
+    }
+}
+
 impl IdtEntry {
     const fn null() -> Self {
         Self {
@@ -74,6 +334,67 @@ impl IdtEntry {
             offset2 : (offset >> 16) as u16,
         }
     }
+
+    /// Create an interrupt gate to `handler` in the code segment
+    /// `selector`, which `int` can raise from rings up to `dpl`. Interrupts
+    /// are disabled on entry
+    fn interrupt_gate(handler : unsafe extern fn(), selector : u16,
+                      dpl : u8) -> Self {
+        Self::new(handler, selector, X86_INTR_GATE | (dpl & 3) << 5)
+    }
+
+    /// Create a trap gate like `interrupt_gate`, keeping interrupts enabled
+    /// on entry if they were
+    fn trap_gate(handler : unsafe extern fn(), selector : u16,
+                 dpl : u8) -> Self {
+        Self::new(handler, selector, X86_TRAP_GATE | (dpl & 3) << 5)
+    }
+
+    /// Get the address of the handler of the gate
+    fn offset(&self) -> u32 {
+        (self.offset2 as u32) << 16 | self.offset1 as u32
+    }
+
+    /// Check that the gate is present and of a known type, and that it
+    /// leads to kernel code, or to a TSS for a task gate
+    fn check(&self) -> Result<(), &'static str> {
+        if self.type_attr & 0x80 == 0 {
+            return Err("not present");
+        }
+        if self.zero != 0 {
+            return Err("reserved byte set");
+        }
+        match self.type_attr & 0x1f {
+            0xe | 0xf => {
+                if self.selector != KERNEL_CODE_SELECTOR {
+                    return Err("selector is not the kernel code segment");
+                }
+                let (start, end) = kernel_ro_range();
+                if !(start..end).contains(&self.offset()) {
+                    return Err("handler out of the kernel code");
+                }
+            }
+            0x5 => {
+                if self.selector & 7 != 0 ||
+                        tss_of_selector(self.selector).is_none() {
+                    return Err("selector is not a TSS");
+                }
+            }
+            _ => return Err("unknown gate type"),
+        }
+        Ok(())
+    }
+
+    /// Create a task gate switching to the task of the TSS `selector`
+    fn task_gate(selector : u16) -> Self {
+        Self {
+            offset1 : 0,
+            selector : selector,
+            zero : 0,
+            type_attr : X86_TASK_GATE,
+            offset2 : 0,
+        }
+    }
 }
 
 /// Shape of an interrupt frame in x86 asm
@@ -113,33 +434,214 @@ pub struct InterruptContext {
     pub frame : InterruptFrame,
 }
 
-static mut IDT_ENTRIES : [IdtEntry; 256] = [IdtEntry::null(); 256];
+/// Number of entries of the IDT, one per vector
+const IDT_ENTRY_COUNT : usize = 256;
+
+/// Limit of the IDT, its size in bytes minus one
+const IDT_LIMIT : u16 =
+    (core::mem::size_of::<[IdtEntry; IDT_ENTRY_COUNT]>() - 1) as u16;
+
+static mut IDT_ENTRIES : [IdtEntry; IDT_ENTRY_COUNT] =
+    [IdtEntry::null(); IDT_ENTRY_COUNT];
+
+/// Number of timer interrupts since boot. Only the timer interrupt writes it
+static mut TICKS : u64 = 0;
+
+/// Read the 64 bits `counter`, only incremented by interrupt handlers. It is
+/// read as two 32 bits halves, so the read is retried if an interrupt
+/// changed the high half in the middle of it
+fn read_counter(counter : *const u64) -> u64 {
+    let halves = counter as *const u32;
+    loop {
+        let (high, low, high2) = unsafe {
+            (core::ptr::read_volatile(halves.add(1)),
+             core::ptr::read_volatile(halves),
+             core::ptr::read_volatile(halves.add(1)))
+        };
+        if high == high2 {
+            return (high as u64) << 32 | low as u64;
+        }
+    }
+}
+
+/// Get the number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    read_counter(core::ptr::addr_of!(TICKS))
+}
+
+/// Install `handler` for the interrupt vector `vector`. The vectors of the
+/// IRQs get their handlers from `register_irq_handler` instead
+pub fn register_interrupt_handler(vector : u8, handler : InterruptHandler) {
+    assert!(vector.wrapping_sub(IRQ_BASE) >= 16,
+            "Vector {:#x} is an IRQ, use register_irq_handler", vector);
+    unsafe {
+        if HANDLER_TABLE[vector as usize].is_some() {
+            panic!("Interrupt {:#x} is already handled", vector);
+        }
+        HANDLER_TABLE[vector as usize] = Some(handler);
+    }
+}
+
+/// Remove the handler of the interrupt vector `vector`. The interrupt
+/// panics again once it is raised
+pub fn unregister_interrupt_handler(vector : u8) {
+    unsafe {
+        if HANDLER_TABLE[vector as usize].take().is_none() {
+            panic!("Interrupt {:#x} has no handler", vector);
+        }
+    }
+}
+
+/// Install `handler` for the IRQ `irq`, then unmask it. Every IRQ is masked
+/// until its driver registers it. The dispatcher sends the EOI once the
+/// handler returned, as told by its `IrqReturn`
+pub fn register_irq_handler(irq : u8, handler : IrqHandler) {
+    assert!(irq < 16, "Registering invalid IRQ {}", irq);
+    unsafe {
+        if IRQ_HANDLERS[irq as usize].is_some() {
+            panic!("IRQ {} is already handled", irq);
+        }
+        IRQ_HANDLERS[irq as usize] = Some(handler);
+    }
+    Pic::unmask(irq as u32);
+}
+
+/// Mask the IRQ `irq` again, then remove its handler
+pub fn unregister_irq_handler(irq : u8) {
+    assert!(irq < 16, "Unregistering invalid IRQ {}", irq);
+    Pic::mask(irq as u32);
+    unsafe {
+        if IRQ_HANDLERS[irq as usize].take().is_none() {
+            panic!("IRQ {} has no handler", irq);
+        }
+    }
+}
+
+/// Run the handler of the IRQ `irq`, then send the EOI unless the handler
+/// did. An IRQ nobody claims still gets it, or its line would stay in
+/// service and block the IRQs of lower priority
+fn dispatch_irq(ctx : &mut InterruptContext, irq : u8) {
+    // A spurious IRQ has no handler to run
+    if (irq == 7 || irq == 15) && Pic::check_spurious(irq as u32) {
+        return;
+    }
+
+    let ret = match unsafe { IRQ_HANDLERS[irq as usize] } {
+        Some(handler) => handler(ctx),
+        None => IrqReturn::NotMine,
+    };
+    if ret == IrqReturn::NotMine {
+        unsafe { UNCLAIMED_IRQS[irq as usize] += 1; }
+    }
+    if ret != IrqReturn::HandledNoEoi {
+        Pic::notify_eoi(irq as u32);
+    }
+}
+
+/// Returns true if `addr` is the return address of the call of
+/// `interrupt_handler` by a stub, found on the stack right below the
+/// `InterruptContext` the stub pushed
+pub fn is_interrupt_return(addr : u32) -> bool {
+    let start = vec_interrupts as *const u32 as u32;
+    let end = vec_interrupts_end as *const u32 as u32;
+    (start..end).contains(&addr)
+}
+
+/// Get the number of interrupts received by the vector `vector`
+pub fn interrupt_count(vector : u8) -> u64 {
+    read_counter(unsafe { core::ptr::addr_of!(INTR_COUNTS[vector as usize]) })
+}
+
+/// Get the number of times the IRQ `irq` was raised without a handler
+/// claiming it
+pub fn unclaimed_irqs(irq : u8) -> u32 {
+    unsafe { core::ptr::read_volatile(&UNCLAIMED_IRQS[irq as usize]) }
+}
 
 /// Rust function called to handle an interrupt
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "fastcall" fn interrupt_handler(ctx : &mut InterruptContext) {
-    let mut handled = true;
-    match ctx.nr {
-        // Double fault
-        0x8 => handle_double_fault(ctx),
-        // Page fault
-        0xe => handle_page_fault(ctx),
-        // Hardware timer interrupt
-        0x20 => handle_timer_intr(ctx),
-        // Int 0x80 : syscall
-        0x80 => handle_syscall(ctx),
-        _ => handled = false,
+    // An add and an adc, the statistics must not slow down the interrupts.
+    // The readers use `read_counter` in case one comes in between
+    INTR_COUNTS[ctx.nr as u8 as usize] += 1;
+
+    // The work runs after the EOI, so that the IRQs can come meanwhile
+    let irq = ctx.nr.wrapping_sub(IRQ_BASE as u32);
+    if irq < 16 {
+        dispatch_irq(ctx, irq as u8);
+        run_work_on_exit(ctx.frame.eflags);
+        return;
+    }
+
+    if let Some(handler) = HANDLER_TABLE.get(ctx.nr as usize).copied()
+            .flatten() {
+        handler(ctx);
+        run_work_on_exit(ctx.frame.eflags);
+        return;
     }
 
-    if !handled {
-        interrupt_panic(ctx);
+    // An exception raised by userland only kills the task
+    if ctx.nr < 32 && ctx.frame.cs & 3 == 3 {
+        kill_current(ctx);
     }
+    interrupt_panic(ctx);
+}
+
+/// Terminate the current task because of the exception of `ctx`, raised in
+/// userland. Its parent gets `EXIT_KILLED` plus the exception number as exit
+/// code, and the scheduler runs another task
+fn kill_current(ctx : &InterruptContext) -> ! {
+    unsafe { USER_EXCEPTIONS += 1; }
+    let task = current_task();
+    print!("task {} (pid {}) killed : exception {}, error code {:#x} @{:#x}",
+           task.name(), task.pid, ctx.nr, ctx.err, ctx.frame.ip);
+    if ctx.nr == 0xe {
+        print!(", cr2 {:#x}", get_cr2());
+    }
+    println!();
+    exit_current(EXIT_KILLED + ctx.nr as i32);
+}
+
+/// Print the number of interrupts received by each vector that got some,
+/// then the spurious and unclaimed IRQs and the exceptions that killed a user
+/// task
+pub fn dump_irq_stats() {
+    println!("{:>6} {:<28} {:>12}", "vector", "name", "count");
+    for vector in 0..=u8::MAX as usize {
+        let count = interrupt_count(vector as u8);
+        if count == 0 {
+            continue;
+        }
+        print!("{:>#6x} ", vector);
+        let irq = vector.wrapping_sub(IRQ_BASE as usize);
+        match vector as u8 {
+            v if (v as usize) < EXCEPTION_NAMES.len() =>
+                print!("{:<28}", EXCEPTION_NAMES[v as usize]),
+            _ if irq < 16 => print!("irq {:<24}", irq),
+            SYSCALL_VECTOR => print!("{:<28}", "syscall"),
+            v if v as u32 == TASK_DUMP_VECTOR => print!("{:<28}", "task dump"),
+            _ => print!("{:<28}", "software"),
+        }
+        println!(" {:>12}", count);
+    }
+    let user_exceptions =
+        read_counter(core::ptr::addr_of!(USER_EXCEPTIONS));
+    let unclaimed : u32 = (0..16).map(unclaimed_irqs).sum();
+    println!("spurious irqs : {}, unclaimed irqs : {}, user exceptions : {}",
+             Pic::spurious_count(), unclaimed, user_exceptions);
 }
 
 fn interrupt_panic(ctx : &InterruptContext) {
-    panic!(r#"
-Interrupt {}, error code {:#x}
-Registers state:
+    panic!("\nInterrupt {}, error code {:#x}\n{}", ctx.nr, ctx.err, ctx);
+}
+
+/// The state of the registers, the segment registers and cr3 being the ones
+/// of the interrupt handler. ss:esp is only the one of the interrupted code
+/// if the interrupt came from userland
+impl core::fmt::Display for InterruptContext {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, r#"Registers state:
     eax {:#010x} ecx {:#010x} edx {:#010x} ebx {:#010x}
     esp {:#010x} ebp {:#010x} esi {:#010x} edi {:#010x}
     
@@ -151,692 +653,367 @@ Registers state:
     fs     {:#x}
     gs     {:#x}
     cr3    {:#x}
-"#, 
-    ctx.nr, ctx.err, ctx.regs.eax, ctx.regs.ecx, ctx.regs.edx, 
-    ctx.regs.ebx, ctx.regs.esp, ctx.regs.ebp, ctx.regs.esi, 
-    ctx.regs.edi, ctx.frame.cs, ctx.frame.ip, ctx.frame.ss, 
-    ctx.frame.sp, ctx.frame.eflags, get_ds(), get_es(), get_fs(), get_gs(),
-    get_cr3().0
-    );
+"#,
+        self.regs.eax, self.regs.ecx, self.regs.edx, self.regs.ebx,
+        self.regs.esp, self.regs.ebp, self.regs.esi, self.regs.edi,
+        self.frame.cs, self.frame.ip, self.frame.ss, self.frame.sp,
+        self.frame.eflags, get_ds(), get_es(), get_fs(), get_gs(),
+        Cr3::read().0)
+    }
 }
 
-/// Handle the clock interrupt
-fn handle_timer_intr(ctx : &InterruptContext) {
+/// Handle the clock interrupt, registered by `pit_init`. The EOI is sent
+/// before the scheduler may switch away, the next tick would wait for the
+/// interrupted task to run again else
+pub fn handle_timer_intr(ctx : &mut InterruptContext) -> IrqReturn {
+    unsafe { TICKS += 1; }
+    vsys_update_ticks(ticks());
+    account_tick();
     Pic::notify_eoi(0);
-    schedule();
+    watchdog_tick(ctx.frame.cs & 3 == 0 && user_task_running());
+
+    // Let the running task finish its time slice, and the section it is
+    // in if it disabled preemption
+    if quantum_expired() {
+        if preemptible() {
+            schedule();
+        } else {
+            set_need_resched();
+        }
+    }
+    IrqReturn::HandledNoEoi
+}
+
+/// Handle double faults through a task gate, so that they run on a stack of
+/// their own. Must be called once the kernel address space exists
+pub fn double_fault_init() {
+    unsafe {
+        let stack_top = DOUBLE_FAULT_STACK.as_ptr()
+            .add(DOUBLE_FAULT_STACK.len()) as u32;
+        setup_double_fault_tss(kernel_vspace().get_pgd_paddr().0,
+                               double_fault_task as *const u32 as u32,
+                               stack_top);
+        IDT_ENTRIES[8] = IdtEntry::task_gate(DOUBLE_FAULT_TSS_SELECTOR);
+    }
+    validate_idt();
 }
 
-/// Handle double fault
-fn handle_double_fault(ctx : &InterruptContext) {
-    panic!("double fault !");
+/// Code of the double fault task. The task switch saved the state of the
+/// faulting code in the TSS named by the back link of the double fault TSS
+extern "C" fn double_fault_task() -> ! {
+    let source = match double_fault_source() {
+        Some(tss) => tss,
+        None => panic!("double fault from an unknown task"),
+    };
+    println!("double fault : cr2 {:#x}, state of the faulting code :\n{}",
+             get_cr2(), source);
+
+    let esp = source.saved_esp();
+    check_kernel_stack_overflow(get_cr2(), esp);
+    panic!("double fault ! (esp {:#x})", esp);
 }
 
 /// Page fault handler
-fn handle_page_fault(ctx : &InterruptContext) {
+fn handle_page_fault(ctx : &mut InterruptContext) {
     let faulting_addr = VirtAddr(get_cr2());
     
     let vspace = VirtMem::get_current();
 
-    panic!("Page fault @{:#x}", faulting_addr.0);
+    // The CPU pushed the error code and the frame without sp and ss, then
+    // the interrupt number was pushed before the registers
+    if ctx.err & PF_USER == 0 {
+        let esp = ctx.regs.esp + 5 * core::mem::size_of::<u32>() as u32;
+        check_kernel_stack_overflow(faulting_addr.0, esp);
+        dump_page_fault(ctx, &vspace, faulting_addr);
+        if ctx.err & PF_WRITE != 0 && is_kernel_ro(faulting_addr) {
+            let (start, end) = kernel_ro_range();
+            panic!("Write to the protected kernel code and read-only data \
+                    [{:#x}-{:#x}) @{:#x}", start, end, faulting_addr.0);
+        }
+        panic!("Page fault @{:#x}", faulting_addr.0);
+    }
+
+    // The first access to a page reserved by mmap or sbrk
+    if ctx.err & PF_PRESENT == 0 &&
+            vspace.fill_lazy(faulting_addr, ctx.err & PF_WRITE != 0) {
+        return;
+    }
+
+    // A write to a page shared copy-on-write, retry it on a copy
+    let cow_fault = PF_PRESENT | PF_WRITE;
+    if ctx.err & cow_fault == cow_fault && vspace.break_cow(faulting_addr) {
+        return;
+    }
+
+    // Grow the user stack and retry the access
+    let task = current_task();
+    if ctx.err & PF_PRESENT == 0 && task.grow_stack(faulting_addr.0) {
+        return;
+    }
+
+    dump_page_fault(ctx, &vspace, faulting_addr);
+    kill_current(ctx);
+}
+
+/// General protection fault handler. The fault is explained, then the task
+/// is killed if it came from userland, else the kernel panics
+fn handle_gp_fault(ctx : &mut InterruptContext) {
+    let user = ctx.frame.cs & 3 == 3;
+    println!("general protection fault : {} @{:#x}:{:#x}",
+             SelectorError(ctx.err), ctx.frame.cs, ctx.frame.ip);
+
+    // Describe the descriptor the selector points to
+    let index = ((ctx.err & 0xffff) >> 3) as usize;
+    if ctx.err & SEL_IDT != 0 {
+        match unsafe { IDT_ENTRIES.get(index) } {
+            Some(entry) => println!("general protection fault : \
+                                     idt[{:#x}] {}", index, entry),
+            None => println!("general protection fault : past the idt"),
+        }
+    } else if ctx.err & SEL_LDT != 0 {
+        println!("general protection fault : the kernel has no ldt");
+    } else if ctx.err != 0 {
+        match gdt_descriptor(index) {
+            Some(desc) => println!("general protection fault : gdt[{}] {}",
+                                   index, desc),
+            None => println!("general protection fault : past the gdt"),
+        }
+    }
+
+    // The segments are flat, so eip is the linear address of the code
+    let mut code = [0u8; FAULT_CODE_BYTES];
+    let len = read_fault_code(&VirtMem::get_current(), ctx.frame.ip, user,
+                              &mut code);
+    print!("general protection fault : code");
+    for byte in &code[..len] {
+        print!(" {:02x}", byte);
+    }
+    if len == 0 {
+        print!(" not mapped");
+    }
+    println!();
+
+    if user {
+        kill_current(ctx);
+    }
+    interrupt_panic(ctx);
+}
+
+/// Read the code at `ip` in `vspace` into `buf`, stopping at the first page
+/// that is not mapped, or not user accessible for `user` code. The pages
+/// are read through their physical address, so a bad mapping can't fault
+/// again. Returns the number of bytes read
+fn read_fault_code(vspace : &VirtMem, ip : u32, user : bool,
+                   buf : &mut [u8]) -> usize {
+    let len = core::cmp::min(buf.len(), (u32::MAX - ip) as usize + 1);
+    let mut flags = PAGE_PRESENT;
+    if user {
+        flags |= PAGE_USER;
+    }
+
+    for (vaddr, offset, size) in page_chunks(ip, len) {
+        let mapping = match vspace.translate(VirtAddr(vaddr)) {
+            Some(mapping) if mapping.flags & flags == flags => mapping,
+            _ => return offset,
+        };
+        let page = match mapping.page {
+            Some(page) => page,
+            None => return offset,
+        };
+
+        // A large page has no page table entry
+        let page_mask = if mapping.pte.is_some() { 0xfff } else { 0x3fffff };
+        let paddr = PhysAddr(page.0 + (vaddr & page_mask));
+        match PhysMem::translate(paddr, size) {
+            Ok(src) => unsafe {
+                core::ptr::copy_nonoverlapping(src,
+                    buf[offset..].as_mut_ptr(), size);
+            },
+            Err(_) => return offset,
+        }
+    }
+    len
+}
+
+/// Print the page fault of `ctx` at `addr` and how `vspace` maps `addr`
+fn dump_page_fault(ctx : &InterruptContext, vspace : &VirtMem,
+                   addr : VirtAddr) {
+    println!("page fault : {} @{:#x}, cr2 {:#x}", PageFaultError(ctx.err),
+             ctx.frame.ip, addr.0);
+
+    let mapping = match vspace.translate(addr) {
+        Some(mapping) => mapping,
+        None => {
+            println!("page fault : no page table for {:#x}", addr.0);
+            return;
+        }
+    };
+    print!("page fault : pde @{:#x}", mapping.pde.0);
+    if let Some(pte) = mapping.pte {
+        print!(", pte @{:#x}", pte.0);
+    }
+    if let Some(page) = mapping.page {
+        print!(", page {:#x}", page.0);
+    }
+    println!(", flags {}", PageFlags(mapping.flags));
+
+    // The mappings around the faulting address
+    let around = 8 * PAGE_SIZE as u32;
+    vspace.dump(VirtAddr((addr.0 & !0xfff).saturating_sub(around)),
+                VirtAddr((addr.0 | 0xfff).saturating_add(around)));
 }
 
 /// Create and load an IDT
 pub fn interrupts_init() {
-    // Initialize the IDT with the handlers
-    for (i, &handler) in INTR_HANDLERS.iter().enumerate() {
+    // Initialize the IDT with the handlers, the table of the stubs is only
+    // written by the assembler. They are ring 0 interrupt gates unless
+    // `GATES` says otherwise
+    let handlers = unsafe { &INTR_HANDLERS };
+    for (i, &handler) in handlers.iter().enumerate() {
         // This is unsafe because we mutate a static and it can be subject
         // to race conditions. Since there is only one core, race conditions
         // can't happen. If there was multiple cores, we should be careful
         // to use a mutex or just init this table once and never touch it again
         unsafe {
-            IDT_ENTRIES[i] = IdtEntry::new(handler, 0x8, X86_INTR_GATE);
+            IDT_ENTRIES[i] = IdtEntry::interrupt_gate(handler,
+                                                      KERNEL_CODE_SELECTOR, 0);
         }
     }
-
-    // Allow the 128th interrupt to be fired from userland since it is 
-    // used to make a syscall
-    unsafe {
-        IDT_ENTRIES[128].type_attr = X86_INTR_GATE_R3;
+    for gate in GATES.iter() {
+        let handler = handlers[gate.vector as usize];
+        let entry = match gate.kind {
+            GateKind::Interrupt =>
+                IdtEntry::interrupt_gate(handler, gate.selector, gate.dpl),
+            GateKind::Trap =>
+                IdtEntry::trap_gate(handler, gate.selector, gate.dpl),
+        };
+        unsafe { IDT_ENTRIES[gate.vector as usize] = entry; }
     }
+    register_interrupt_handler(GP_FAULT_VECTOR, handle_gp_fault);
+    register_interrupt_handler(PAGE_FAULT_VECTOR, handle_page_fault);
+    register_interrupt_handler(TASK_DUMP_VECTOR as u8, |_| dump_tasks());
 
     // Create the table pointer and load it in the idt register
     let idt_pointer = unsafe {
         IdtPointer {
-            limit : (INTR_HANDLERS.len() as u16) * 8 - 1,
+            limit : IDT_LIMIT,
             base : IDT_ENTRIES.as_ptr() as u32,
         }
     };
 
     set_idt(&idt_pointer);
+    validate_idt();
+}
+
+/// Read the idt register
+fn current_idt() -> IdtPointer {
+    let mut idtp = IdtPointer { limit : 0, base : 0 };
+    get_idt(&mut idtp);
+    idtp
 }
 
-/// IDT Handlers table
-static INTR_HANDLERS : [unsafe extern fn(); 256] = [
-    vec_interrupt_0,  vec_interrupt_1,  vec_interrupt_2,
-    vec_interrupt_3,  vec_interrupt_4,  vec_interrupt_5,
-    vec_interrupt_6,  vec_interrupt_7,  vec_interrupt_8,
-    vec_interrupt_9,  vec_interrupt_10,  vec_interrupt_11,
-    vec_interrupt_12,  vec_interrupt_13,  vec_interrupt_14,
-    vec_interrupt_15,  vec_interrupt_16,  vec_interrupt_17,
-    vec_interrupt_18,  vec_interrupt_19,  vec_interrupt_20,
-    vec_interrupt_21,  vec_interrupt_22,  vec_interrupt_23,
-    vec_interrupt_24,  vec_interrupt_25,  vec_interrupt_26,
-    vec_interrupt_27,  vec_interrupt_28,  vec_interrupt_29,
-    vec_interrupt_30,  vec_interrupt_31,  vec_interrupt_32,
-    vec_interrupt_33,  vec_interrupt_34,  vec_interrupt_35,
-    vec_interrupt_36,  vec_interrupt_37,  vec_interrupt_38,
-    vec_interrupt_39,  vec_interrupt_40,  vec_interrupt_41,
-    vec_interrupt_42,  vec_interrupt_43,  vec_interrupt_44,
-    vec_interrupt_45,  vec_interrupt_46,  vec_interrupt_47,
-    vec_interrupt_48,  vec_interrupt_49,  vec_interrupt_50,
-    vec_interrupt_51,  vec_interrupt_52,  vec_interrupt_53,
-    vec_interrupt_54,  vec_interrupt_55,  vec_interrupt_56,
-    vec_interrupt_57,  vec_interrupt_58,  vec_interrupt_59,
-    vec_interrupt_60,  vec_interrupt_61,  vec_interrupt_62,
-    vec_interrupt_63,  vec_interrupt_64,  vec_interrupt_65,
-    vec_interrupt_66,  vec_interrupt_67,  vec_interrupt_68,
-    vec_interrupt_69,  vec_interrupt_70,  vec_interrupt_71,
-    vec_interrupt_72,  vec_interrupt_73,  vec_interrupt_74,
-    vec_interrupt_75,  vec_interrupt_76,  vec_interrupt_77,
-    vec_interrupt_78,  vec_interrupt_79,  vec_interrupt_80,
-    vec_interrupt_81,  vec_interrupt_82,  vec_interrupt_83,
-    vec_interrupt_84,  vec_interrupt_85,  vec_interrupt_86,
-    vec_interrupt_87,  vec_interrupt_88,  vec_interrupt_89,
-    vec_interrupt_90,  vec_interrupt_91,  vec_interrupt_92,
-    vec_interrupt_93,  vec_interrupt_94,  vec_interrupt_95,
-    vec_interrupt_96,  vec_interrupt_97,  vec_interrupt_98,
-    vec_interrupt_99,  vec_interrupt_100,  vec_interrupt_101,
-    vec_interrupt_102,  vec_interrupt_103,  vec_interrupt_104,
-    vec_interrupt_105,  vec_interrupt_106,  vec_interrupt_107,
-    vec_interrupt_108,  vec_interrupt_109,  vec_interrupt_110,
-    vec_interrupt_111,  vec_interrupt_112,  vec_interrupt_113,
-    vec_interrupt_114,  vec_interrupt_115,  vec_interrupt_116,
-    vec_interrupt_117,  vec_interrupt_118,  vec_interrupt_119,
-    vec_interrupt_120,  vec_interrupt_121,  vec_interrupt_122,
-    vec_interrupt_123,  vec_interrupt_124,  vec_interrupt_125,
-    vec_interrupt_126,  vec_interrupt_127,  vec_interrupt_128,
-    vec_interrupt_129,  vec_interrupt_130,  vec_interrupt_131,
-    vec_interrupt_132,  vec_interrupt_133,  vec_interrupt_134,
-    vec_interrupt_135,  vec_interrupt_136,  vec_interrupt_137,
-    vec_interrupt_138,  vec_interrupt_139,  vec_interrupt_140,
-    vec_interrupt_141,  vec_interrupt_142,  vec_interrupt_143,
-    vec_interrupt_144,  vec_interrupt_145,  vec_interrupt_146,
-    vec_interrupt_147,  vec_interrupt_148,  vec_interrupt_149,
-    vec_interrupt_150,  vec_interrupt_151,  vec_interrupt_152,
-    vec_interrupt_153,  vec_interrupt_154,  vec_interrupt_155,
-    vec_interrupt_156,  vec_interrupt_157,  vec_interrupt_158,
-    vec_interrupt_159,  vec_interrupt_160,  vec_interrupt_161,
-    vec_interrupt_162,  vec_interrupt_163,  vec_interrupt_164,
-    vec_interrupt_165,  vec_interrupt_166,  vec_interrupt_167,
-    vec_interrupt_168,  vec_interrupt_169,  vec_interrupt_170,
-    vec_interrupt_171,  vec_interrupt_172,  vec_interrupt_173,
-    vec_interrupt_174,  vec_interrupt_175,  vec_interrupt_176,
-    vec_interrupt_177,  vec_interrupt_178,  vec_interrupt_179,
-    vec_interrupt_180,  vec_interrupt_181,  vec_interrupt_182,
-    vec_interrupt_183,  vec_interrupt_184,  vec_interrupt_185,
-    vec_interrupt_186,  vec_interrupt_187,  vec_interrupt_188,
-    vec_interrupt_189,  vec_interrupt_190,  vec_interrupt_191,
-    vec_interrupt_192,  vec_interrupt_193,  vec_interrupt_194,
-    vec_interrupt_195,  vec_interrupt_196,  vec_interrupt_197,
-    vec_interrupt_198,  vec_interrupt_199,  vec_interrupt_200,
-    vec_interrupt_201,  vec_interrupt_202,  vec_interrupt_203,
-    vec_interrupt_204,  vec_interrupt_205,  vec_interrupt_206,
-    vec_interrupt_207,  vec_interrupt_208,  vec_interrupt_209,
-    vec_interrupt_210,  vec_interrupt_211,  vec_interrupt_212,
-    vec_interrupt_213,  vec_interrupt_214,  vec_interrupt_215,
-    vec_interrupt_216,  vec_interrupt_217,  vec_interrupt_218,
-    vec_interrupt_219,  vec_interrupt_220,  vec_interrupt_221,
-    vec_interrupt_222,  vec_interrupt_223,  vec_interrupt_224,
-    vec_interrupt_225,  vec_interrupt_226,  vec_interrupt_227,
-    vec_interrupt_228,  vec_interrupt_229,  vec_interrupt_230,
-    vec_interrupt_231,  vec_interrupt_232,  vec_interrupt_233,
-    vec_interrupt_234,  vec_interrupt_235,  vec_interrupt_236,
-    vec_interrupt_237,  vec_interrupt_238,  vec_interrupt_239,
-    vec_interrupt_240,  vec_interrupt_241,  vec_interrupt_242,
-    vec_interrupt_243,  vec_interrupt_244,  vec_interrupt_245,
-    vec_interrupt_246,  vec_interrupt_247,  vec_interrupt_248,
-    vec_interrupt_249,  vec_interrupt_250,  vec_interrupt_251,
-    vec_interrupt_252,  vec_interrupt_253,  vec_interrupt_254,
-    vec_interrupt_255,
+/// Check that the idt register holds the whole `IDT_ENTRIES`, and that each
+/// entry passes `IdtEntry::check`. Panics telling the first problem found
+pub fn validate_idt() {
+    let idtp = current_idt();
+    let (limit, base) = (idtp.limit, idtp.base);
+    let entries = unsafe { &*core::ptr::addr_of!(IDT_ENTRIES) };
+    if limit != IDT_LIMIT || base != entries.as_ptr() as u32 {
+        panic!("IDT : idtr holds {:#x} limit {:#x}, expected {:#x} limit \
+                {:#x}", base, limit, entries.as_ptr() as u32, IDT_LIMIT);
+    }
+    for (vector, entry) in entries.iter().enumerate() {
+        if let Err(err) = entry.check() {
+            panic!("IDT : vector {:#x} ({}) : {}", vector, entry, err);
+        }
+    }
+}
+
+/// Print the entries of the IDT loaded in the idt register
+pub fn print_current_idt() {
+    let idtp = current_idt();
+    let (limit, base) = (idtp.limit, idtp.base);
+    println!("idt size : {:#x}", limit);
+    println!("idt base : {:#x}", base);
+    for i in 0..(limit as usize + 1) / 8 {
+        let entry = unsafe { &*(base as *const IdtEntry).add(i) };
+        println!("{:#04x} : {}", i, entry);
+    }
+}
+
+/// Exceptions for which the CPU pushes an error code. The stubs of the
+/// other vectors push -1 in its place, so that all the contexts look the same
+const ERROR_CODE_VECTORS : [u8; 10] = [
+    8,  // Double fault
+    10, // Invalid TSS
+    11, // Segment not present
+    12, // Stack segment fault
+    13, // General protection fault
+    14, // Page fault
+    17, // Alignment check
+    21, // Control protection
+    29, // VMM communication
+    30, // Security exception
 ];
 
-// Link with macro-defined handlers in global_asm!
+/// Bit `n` set if the exception `n` has an error code
+const ERROR_CODE_MASK : u32 = {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < ERROR_CODE_VECTORS.len() {
+        mask |= 1 << ERROR_CODE_VECTORS[i];
+        i += 1;
+    }
+    mask
+};
+
+// Link with the stubs and their table defined in global_asm!
 extern {
-    fn vec_interrupt_0(); // Divide by zero
-    fn vec_interrupt_1(); // Debug
-    fn vec_interrupt_2(); // Non-maskable interrupt
-    fn vec_interrupt_3(); // Breakpoint
-    fn vec_interrupt_4(); // Overflow
-    fn vec_interrupt_5(); // Bound range exceeded
-    fn vec_interrupt_6(); // Invalid opcode
-    fn vec_interrupt_7(); // Device not available
-    fn vec_interrupt_8(); // Double fault
-    fn vec_interrupt_9(); // Coprocessor Segment Overrun
-    fn vec_interrupt_10(); // Invalid TSS
-    fn vec_interrupt_11(); // Segment not present
-    fn vec_interrupt_12(); // Stack segment fault
-    fn vec_interrupt_13(); // General Protection Fault
-    fn vec_interrupt_14(); // Page Fault
-    fn vec_interrupt_15(); // Reserved
-    fn vec_interrupt_16(); // x87 floating point exception
-    fn vec_interrupt_17(); // Alignment check
-    fn vec_interrupt_18(); // Machine Check
-    fn vec_interrupt_19(); // SIMD Floating point exception
-    fn vec_interrupt_20(); // Virtualization Exception
-    fn vec_interrupt_21(); // Reserved
-    fn vec_interrupt_22(); // Reserved
-    fn vec_interrupt_23(); // Reserved
-    fn vec_interrupt_24(); // Reserved
-    fn vec_interrupt_25(); // Reserved
-    fn vec_interrupt_26(); // Reserved
-    fn vec_interrupt_27(); // Reserved
-    fn vec_interrupt_28(); // Reserved
-    fn vec_interrupt_29(); // Reserved
-    fn vec_interrupt_30(); // Security Exception
-    fn vec_interrupt_31(); // Reserved
-	fn vec_interrupt_32();
-	fn vec_interrupt_33();
-	fn vec_interrupt_34();
-	fn vec_interrupt_35();
-	fn vec_interrupt_36();
-	fn vec_interrupt_37();
-	fn vec_interrupt_38();
-	fn vec_interrupt_39();
-	fn vec_interrupt_40();
-	fn vec_interrupt_41();
-	fn vec_interrupt_42();
-	fn vec_interrupt_43();
-	fn vec_interrupt_44();
-	fn vec_interrupt_45();
-	fn vec_interrupt_46();
-	fn vec_interrupt_47();
-	fn vec_interrupt_48();
-	fn vec_interrupt_49();
-	fn vec_interrupt_50();
-	fn vec_interrupt_51();
-	fn vec_interrupt_52();
-	fn vec_interrupt_53();
-	fn vec_interrupt_54();
-	fn vec_interrupt_55();
-	fn vec_interrupt_56();
-	fn vec_interrupt_57();
-	fn vec_interrupt_58();
-	fn vec_interrupt_59();
-	fn vec_interrupt_60();
-	fn vec_interrupt_61();
-	fn vec_interrupt_62();
-	fn vec_interrupt_63();
-	fn vec_interrupt_64();
-	fn vec_interrupt_65();
-	fn vec_interrupt_66();
-	fn vec_interrupt_67();
-	fn vec_interrupt_68();
-	fn vec_interrupt_69();
-	fn vec_interrupt_70();
-	fn vec_interrupt_71();
-	fn vec_interrupt_72();
-	fn vec_interrupt_73();
-	fn vec_interrupt_74();
-	fn vec_interrupt_75();
-	fn vec_interrupt_76();
-	fn vec_interrupt_77();
-	fn vec_interrupt_78();
-	fn vec_interrupt_79();
-	fn vec_interrupt_80();
-	fn vec_interrupt_81();
-	fn vec_interrupt_82();
-	fn vec_interrupt_83();
-	fn vec_interrupt_84();
-	fn vec_interrupt_85();
-	fn vec_interrupt_86();
-	fn vec_interrupt_87();
-	fn vec_interrupt_88();
-	fn vec_interrupt_89();
-	fn vec_interrupt_90();
-	fn vec_interrupt_91();
-	fn vec_interrupt_92();
-	fn vec_interrupt_93();
-	fn vec_interrupt_94();
-	fn vec_interrupt_95();
-	fn vec_interrupt_96();
-	fn vec_interrupt_97();
-	fn vec_interrupt_98();
-	fn vec_interrupt_99();
-	fn vec_interrupt_100();
-	fn vec_interrupt_101();
-	fn vec_interrupt_102();
-	fn vec_interrupt_103();
-	fn vec_interrupt_104();
-	fn vec_interrupt_105();
-	fn vec_interrupt_106();
-	fn vec_interrupt_107();
-	fn vec_interrupt_108();
-	fn vec_interrupt_109();
-	fn vec_interrupt_110();
-	fn vec_interrupt_111();
-	fn vec_interrupt_112();
-	fn vec_interrupt_113();
-	fn vec_interrupt_114();
-	fn vec_interrupt_115();
-	fn vec_interrupt_116();
-	fn vec_interrupt_117();
-	fn vec_interrupt_118();
-	fn vec_interrupt_119();
-	fn vec_interrupt_120();
-	fn vec_interrupt_121();
-	fn vec_interrupt_122();
-	fn vec_interrupt_123();
-	fn vec_interrupt_124();
-	fn vec_interrupt_125();
-	fn vec_interrupt_126();
-	fn vec_interrupt_127();
-	fn vec_interrupt_128(); // Syscall
-	fn vec_interrupt_129();
-	fn vec_interrupt_130();
-	fn vec_interrupt_131();
-	fn vec_interrupt_132();
-	fn vec_interrupt_133();
-	fn vec_interrupt_134();
-	fn vec_interrupt_135();
-	fn vec_interrupt_136();
-	fn vec_interrupt_137();
-	fn vec_interrupt_138();
-	fn vec_interrupt_139();
-	fn vec_interrupt_140();
-	fn vec_interrupt_141();
-	fn vec_interrupt_142();
-	fn vec_interrupt_143();
-	fn vec_interrupt_144();
-	fn vec_interrupt_145();
-	fn vec_interrupt_146();
-	fn vec_interrupt_147();
-	fn vec_interrupt_148();
-	fn vec_interrupt_149();
-	fn vec_interrupt_150();
-	fn vec_interrupt_151();
-	fn vec_interrupt_152();
-	fn vec_interrupt_153();
-	fn vec_interrupt_154();
-	fn vec_interrupt_155();
-	fn vec_interrupt_156();
-	fn vec_interrupt_157();
-	fn vec_interrupt_158();
-	fn vec_interrupt_159();
-	fn vec_interrupt_160();
-	fn vec_interrupt_161();
-	fn vec_interrupt_162();
-	fn vec_interrupt_163();
-	fn vec_interrupt_164();
-	fn vec_interrupt_165();
-	fn vec_interrupt_166();
-	fn vec_interrupt_167();
-	fn vec_interrupt_168();
-	fn vec_interrupt_169();
-	fn vec_interrupt_170();
-	fn vec_interrupt_171();
-	fn vec_interrupt_172();
-	fn vec_interrupt_173();
-	fn vec_interrupt_174();
-	fn vec_interrupt_175();
-	fn vec_interrupt_176();
-	fn vec_interrupt_177();
-	fn vec_interrupt_178();
-	fn vec_interrupt_179();
-	fn vec_interrupt_180();
-	fn vec_interrupt_181();
-	fn vec_interrupt_182();
-	fn vec_interrupt_183();
-	fn vec_interrupt_184();
-	fn vec_interrupt_185();
-	fn vec_interrupt_186();
-	fn vec_interrupt_187();
-	fn vec_interrupt_188();
-	fn vec_interrupt_189();
-	fn vec_interrupt_190();
-	fn vec_interrupt_191();
-	fn vec_interrupt_192();
-	fn vec_interrupt_193();
-	fn vec_interrupt_194();
-	fn vec_interrupt_195();
-	fn vec_interrupt_196();
-	fn vec_interrupt_197();
-	fn vec_interrupt_198();
-	fn vec_interrupt_199();
-	fn vec_interrupt_200();
-	fn vec_interrupt_201();
-	fn vec_interrupt_202();
-	fn vec_interrupt_203();
-	fn vec_interrupt_204();
-	fn vec_interrupt_205();
-	fn vec_interrupt_206();
-	fn vec_interrupt_207();
-	fn vec_interrupt_208();
-	fn vec_interrupt_209();
-	fn vec_interrupt_210();
-	fn vec_interrupt_211();
-	fn vec_interrupt_212();
-	fn vec_interrupt_213();
-	fn vec_interrupt_214();
-	fn vec_interrupt_215();
-	fn vec_interrupt_216();
-	fn vec_interrupt_217();
-	fn vec_interrupt_218();
-	fn vec_interrupt_219();
-	fn vec_interrupt_220();
-	fn vec_interrupt_221();
-	fn vec_interrupt_222();
-	fn vec_interrupt_223();
-	fn vec_interrupt_224();
-	fn vec_interrupt_225();
-	fn vec_interrupt_226();
-	fn vec_interrupt_227();
-	fn vec_interrupt_228();
-	fn vec_interrupt_229();
-	fn vec_interrupt_230();
-	fn vec_interrupt_231();
-	fn vec_interrupt_232();
-	fn vec_interrupt_233();
-	fn vec_interrupt_234();
-	fn vec_interrupt_235();
-	fn vec_interrupt_236();
-	fn vec_interrupt_237();
-	fn vec_interrupt_238();
-	fn vec_interrupt_239();
-	fn vec_interrupt_240();
-	fn vec_interrupt_241();
-	fn vec_interrupt_242();
-	fn vec_interrupt_243();
-	fn vec_interrupt_244();
-	fn vec_interrupt_245();
-	fn vec_interrupt_246();
-	fn vec_interrupt_247();
-	fn vec_interrupt_248();
-	fn vec_interrupt_249();
-	fn vec_interrupt_250();
-	fn vec_interrupt_251();
-	fn vec_interrupt_252();
-	fn vec_interrupt_253();
-	fn vec_interrupt_254();
-	fn vec_interrupt_255();
+    /// IDT Handlers table, the stub of every vector
+    static INTR_HANDLERS : [unsafe extern fn(); 256];
+
+    /// Bounds of the code of the stubs
+    fn vec_interrupts();
+    fn vec_interrupts_end();
+
     pub fn resume_from_intr();
 }
 
+#[cfg(not(test))]
 global_asm!(r#"
 .extern interrupt_handler
 
-.macro define_int_handler int_id has_error_code
-.global vec_interrupt_\int_id
-vec_interrupt_\int_id:
-.if !\has_error_code
-    push -1         // push error code
-.endif
-    push \int_id    // push interrupt number
-    pusha           // save gprs
-    mov ecx, esp    // set ecx to the @ of the interrupt_context structure
-    call interrupt_handler
-    jmp resume_from_intr
-.endm
-
 .global resume_from_intr
 resume_from_intr:
     popa            // restore gprs
     add esp, 8      // pop interrupt number and error code
     iretd
 
-define_int_handler 0, 0
-define_int_handler 1, 0
-define_int_handler 2, 0
-define_int_handler 3, 0
-define_int_handler 4, 0
-define_int_handler 5, 0
-define_int_handler 6, 0
-define_int_handler 7, 0
-define_int_handler 8, 1
-define_int_handler 9, 0
-define_int_handler 10, 1
-define_int_handler 11, 1
-define_int_handler 12, 1
-define_int_handler 13, 1
-define_int_handler 14, 1
-define_int_handler 15, 0
-define_int_handler 16, 0
-define_int_handler 17, 1
-define_int_handler 18, 0
-define_int_handler 19, 0
-define_int_handler 20, 0
-define_int_handler 21, 0
-define_int_handler 22, 0
-define_int_handler 23, 0
-define_int_handler 24, 0
-define_int_handler 25, 0
-define_int_handler 26, 0
-define_int_handler 27, 0
-define_int_handler 28, 0
-define_int_handler 29, 0
-define_int_handler 30, 1
-define_int_handler 31, 0
-define_int_handler 32, 0
-define_int_handler 33, 0
-define_int_handler 34, 0
-define_int_handler 35, 0
-define_int_handler 36, 0
-define_int_handler 37, 0
-define_int_handler 38, 0
-define_int_handler 39, 0
-define_int_handler 40, 0
-define_int_handler 41, 0
-define_int_handler 42, 0
-define_int_handler 43, 0
-define_int_handler 44, 0
-define_int_handler 45, 0
-define_int_handler 46, 0
-define_int_handler 47, 0
-define_int_handler 48, 0
-define_int_handler 49, 0
-define_int_handler 50, 0
-define_int_handler 51, 0
-define_int_handler 52, 0
-define_int_handler 53, 0
-define_int_handler 54, 0
-define_int_handler 55, 0
-define_int_handler 56, 0
-define_int_handler 57, 0
-define_int_handler 58, 0
-define_int_handler 59, 0
-define_int_handler 60, 0
-define_int_handler 61, 0
-define_int_handler 62, 0
-define_int_handler 63, 0
-define_int_handler 64, 0
-define_int_handler 65, 0
-define_int_handler 66, 0
-define_int_handler 67, 0
-define_int_handler 68, 0
-define_int_handler 69, 0
-define_int_handler 70, 0
-define_int_handler 71, 0
-define_int_handler 72, 0
-define_int_handler 73, 0
-define_int_handler 74, 0
-define_int_handler 75, 0
-define_int_handler 76, 0
-define_int_handler 77, 0
-define_int_handler 78, 0
-define_int_handler 79, 0
-define_int_handler 80, 0
-define_int_handler 81, 0
-define_int_handler 82, 0
-define_int_handler 83, 0
-define_int_handler 84, 0
-define_int_handler 85, 0
-define_int_handler 86, 0
-define_int_handler 87, 0
-define_int_handler 88, 0
-define_int_handler 89, 0
-define_int_handler 90, 0
-define_int_handler 91, 0
-define_int_handler 92, 0
-define_int_handler 93, 0
-define_int_handler 94, 0
-define_int_handler 95, 0
-define_int_handler 96, 0
-define_int_handler 97, 0
-define_int_handler 98, 0
-define_int_handler 99, 0
-define_int_handler 100, 0
-define_int_handler 101, 0
-define_int_handler 102, 0
-define_int_handler 103, 0
-define_int_handler 104, 0
-define_int_handler 105, 0
-define_int_handler 106, 0
-define_int_handler 107, 0
-define_int_handler 108, 0
-define_int_handler 109, 0
-define_int_handler 110, 0
-define_int_handler 111, 0
-define_int_handler 112, 0
-define_int_handler 113, 0
-define_int_handler 114, 0
-define_int_handler 115, 0
-define_int_handler 116, 0
-define_int_handler 117, 0
-define_int_handler 118, 0
-define_int_handler 119, 0
-define_int_handler 120, 0
-define_int_handler 121, 0
-define_int_handler 122, 0
-define_int_handler 123, 0
-define_int_handler 124, 0
-define_int_handler 125, 0
-define_int_handler 126, 0
-define_int_handler 127, 0
-define_int_handler 128, 0
-define_int_handler 129, 0
-define_int_handler 130, 0
-define_int_handler 131, 0
-define_int_handler 132, 0
-define_int_handler 133, 0
-define_int_handler 134, 0
-define_int_handler 135, 0
-define_int_handler 136, 0
-define_int_handler 137, 0
-define_int_handler 138, 0
-define_int_handler 139, 0
-define_int_handler 140, 0
-define_int_handler 141, 0
-define_int_handler 142, 0
-define_int_handler 143, 0
-define_int_handler 144, 0
-define_int_handler 145, 0
-define_int_handler 146, 0
-define_int_handler 147, 0
-define_int_handler 148, 0
-define_int_handler 149, 0
-define_int_handler 150, 0
-define_int_handler 151, 0
-define_int_handler 152, 0
-define_int_handler 153, 0
-define_int_handler 154, 0
-define_int_handler 155, 0
-define_int_handler 156, 0
-define_int_handler 157, 0
-define_int_handler 158, 0
-define_int_handler 159, 0
-define_int_handler 160, 0
-define_int_handler 161, 0
-define_int_handler 162, 0
-define_int_handler 163, 0
-define_int_handler 164, 0
-define_int_handler 165, 0
-define_int_handler 166, 0
-define_int_handler 167, 0
-define_int_handler 168, 0
-define_int_handler 169, 0
-define_int_handler 170, 0
-define_int_handler 171, 0
-define_int_handler 172, 0
-define_int_handler 173, 0
-define_int_handler 174, 0
-define_int_handler 175, 0
-define_int_handler 176, 0
-define_int_handler 177, 0
-define_int_handler 178, 0
-define_int_handler 179, 0
-define_int_handler 180, 0
-define_int_handler 181, 0
-define_int_handler 182, 0
-define_int_handler 183, 0
-define_int_handler 184, 0
-define_int_handler 185, 0
-define_int_handler 186, 0
-define_int_handler 187, 0
-define_int_handler 188, 0
-define_int_handler 189, 0
-define_int_handler 190, 0
-define_int_handler 191, 0
-define_int_handler 192, 0
-define_int_handler 193, 0
-define_int_handler 194, 0
-define_int_handler 195, 0
-define_int_handler 196, 0
-define_int_handler 197, 0
-define_int_handler 198, 0
-define_int_handler 199, 0
-define_int_handler 200, 0
-define_int_handler 201, 0
-define_int_handler 202, 0
-define_int_handler 203, 0
-define_int_handler 204, 0
-define_int_handler 205, 0
-define_int_handler 206, 0
-define_int_handler 207, 0
-define_int_handler 208, 0
-define_int_handler 209, 0
-define_int_handler 210, 0
-define_int_handler 211, 0
-define_int_handler 212, 0
-define_int_handler 213, 0
-define_int_handler 214, 0
-define_int_handler 215, 0
-define_int_handler 216, 0
-define_int_handler 217, 0
-define_int_handler 218, 0
-define_int_handler 219, 0
-define_int_handler 220, 0
-define_int_handler 221, 0
-define_int_handler 222, 0
-define_int_handler 223, 0
-define_int_handler 224, 0
-define_int_handler 225, 0
-define_int_handler 226, 0
-define_int_handler 227, 0
-define_int_handler 228, 0
-define_int_handler 229, 0
-define_int_handler 230, 0
-define_int_handler 231, 0
-define_int_handler 232, 0
-define_int_handler 233, 0
-define_int_handler 234, 0
-define_int_handler 235, 0
-define_int_handler 236, 0
-define_int_handler 237, 0
-define_int_handler 238, 0
-define_int_handler 239, 0
-define_int_handler 240, 0
-define_int_handler 241, 0
-define_int_handler 242, 0
-define_int_handler 243, 0
-define_int_handler 244, 0
-define_int_handler 245, 0
-define_int_handler 246, 0
-define_int_handler 247, 0
-define_int_handler 248, 0
-define_int_handler 249, 0
-define_int_handler 250, 0
-define_int_handler 251, 0
-define_int_handler 252, 0
-define_int_handler 253, 0
-define_int_handler 254, 0
-define_int_handler 255, 0
-"#);
+// The address of each stub is added to the table after it
+.pushsection .rodata.intr_handlers, "a"
+.balign 4
+.global INTR_HANDLERS
+INTR_HANDLERS:
+.popsection
+
+.global vec_interrupts
+vec_interrupts:
+.set intr_vector, 0
+.rept 256
+1:
+.if intr_vector >= 32 || (({error_code_mask} >> intr_vector) & 1) == 0
+    push -1         // push error code
+.endif
+    push offset intr_vector // push interrupt number
+    pusha           // save gprs
+    mov ecx, esp    // set ecx to the @ of the interrupt_context structure
+    call interrupt_handler
+    jmp resume_from_intr
+.pushsection .rodata.intr_handlers, "a"
+    .long 1b
+.popsection
+.set intr_vector, intr_vector + 1
+.endr
+.global vec_interrupts_end
+vec_interrupts_end:
+"#, error_code_mask = const ERROR_CODE_MASK);
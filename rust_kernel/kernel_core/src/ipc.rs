@@ -0,0 +1,180 @@
+//! Message passing between tasks. Every task has a mailbox in kernel memory
+//! holding the messages sent to it until it receives them. A message sent to
+//! a task waiting for one is copied straight into its buffer instead
+
+use crate::syscalls::*;
+use crate::tasks::*;
+use crate::uaccess::*;
+
+/// Max size in bytes of a message
+pub const MAX_MESSAGE_SIZE : usize = 64;
+
+/// Number of messages a mailbox can hold
+const MAILBOX_SIZE : usize = 4;
+
+/// A message waiting in a mailbox
+#[derive(Debug, Clone, Copy)]
+struct Message {
+    /// Pid of the task that sent the message
+    sender : u32,
+
+    /// Size in bytes of the message
+    len : usize,
+
+    /// Content of the message
+    data : [u8; MAX_MESSAGE_SIZE],
+}
+
+impl Message {
+    /// Create an empty message, used to fill new mailboxes
+    const fn empty() -> Self {
+        Self {
+            sender : 0,
+            len : 0,
+            data : [0; MAX_MESSAGE_SIZE],
+        }
+    }
+}
+
+/// Fixed size queue of the messages sent to a task
+#[derive(Debug)]
+pub struct Mailbox {
+    /// Ring buffer of messages
+    messages : [Message; MAILBOX_SIZE],
+
+    /// Index of the oldest message
+    head : usize,
+
+    /// Number of messages in the mailbox
+    count : usize,
+
+    /// Address and size of the buffer of the task while it waits in
+    /// `sys_recv` with an empty mailbox
+    receiver : Option<(u32, usize)>,
+
+    /// Pid of the sender of the message copied to the buffer of the task,
+    /// until the task wakes up
+    delivered : Option<u32>,
+}
+
+impl Mailbox {
+    /// Create an empty mailbox
+    pub const fn new() -> Self {
+        Self {
+            messages : [Message::empty(); MAILBOX_SIZE],
+            head : 0,
+            count : 0,
+            receiver : None,
+            delivered : None,
+        }
+    }
+
+    /// Get the oldest message of the mailbox
+    fn front(&self) -> Option<&Message> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(&self.messages[self.head])
+    }
+
+    /// Remove the oldest message of the mailbox
+    fn pop(&mut self) {
+        if self.count != 0 {
+            self.head = (self.head + 1) % MAILBOX_SIZE;
+            self.count -= 1;
+        }
+    }
+
+    /// Add `msg` at the end of the mailbox. Returns false if the mailbox is
+    /// full
+    fn push(&mut self, msg : Message) -> bool {
+        if self.count == MAILBOX_SIZE {
+            return false;
+        }
+        let idx = (self.head + self.count) % MAILBOX_SIZE;
+        self.messages[idx] = msg;
+        self.count += 1;
+        true
+    }
+}
+
+/// Register the ipc syscalls
+pub fn ipc_init() {
+    use SyscallArg::*;
+
+    register_syscall(SYS_SEND, "send", &[Uint, Addr, Uint], |ctx| {
+        sys_send(ctx.regs.ecx, ctx.regs.edx, ctx.regs.ebx as usize)
+    });
+    register_syscall(SYS_RECV, "recv", &[Addr, Uint], |ctx| {
+        sys_recv(ctx.regs.ecx, ctx.regs.edx as usize)
+    });
+}
+
+/// Send the `len` bytes at `buf` to the task `pid`. Fails with EINVAL if the
+/// message is too big, ESRCH if there is no such task, EAGAIN if its mailbox
+/// is full and EFAULT if the buffer is not readable
+fn sys_send(pid : u32, buf : u32, len : usize) -> i32 {
+    if len > MAX_MESSAGE_SIZE {
+        return -EINVAL;
+    }
+
+    let sender = current_task().pid;
+    let target = match find_task(pid) {
+        Some(task) if task.state() != TaskState::Zombie => task,
+        _ => return -ESRCH,
+    };
+
+    let mut msg = Message {
+        sender : sender,
+        len : len,
+        data : [0; MAX_MESSAGE_SIZE],
+    };
+    if let Err(err) = copy_from_user(&mut msg.data[..len], buf) {
+        return err;
+    }
+
+    // A task waiting for a message gets it in its buffer right away. If the
+    // buffer is bad, the message is queued and the receiver gets the error
+    if let Some((buf, len)) = target.mailbox.receiver.take() {
+        let len = core::cmp::min(len, msg.len);
+        if copy_to_vspace(target.vspace(), buf, &msg.data[..len]).is_ok() {
+            target.mailbox.delivered = Some(sender);
+            wake_up(target);
+            return 0;
+        }
+    }
+
+    if !target.mailbox.push(msg) {
+        return -EAGAIN;
+    }
+
+    // Wake up the target if it waits for a message
+    wake_up(target);
+
+    0
+}
+
+/// Wait for a message and copy at most `len` bytes of it to `buf`. Returns
+/// the pid of the sender. Fails with EFAULT if the buffer is not writable,
+/// in which case the message stays in the mailbox
+fn sys_recv(buf : u32, len : usize) -> i32 {
+    loop {
+        let task = current_task();
+        task.mailbox.receiver = None;
+        if let Some(sender) = task.mailbox.delivered.take() {
+            return sender as i32;
+        }
+        if let Some(msg) = task.mailbox.front() {
+            let len = core::cmp::min(len, msg.len);
+            if let Err(err) = copy_to_user(buf, &msg.data[..len]) {
+                return err;
+            }
+            let sender = msg.sender;
+            task.mailbox.pop();
+            return sender as i32;
+        }
+
+        task.mailbox.receiver = Some((buf, len));
+        block_current();
+    }
+}
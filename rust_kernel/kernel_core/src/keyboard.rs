@@ -0,0 +1,315 @@
+//! PS/2 keyboard on IRQ 1. Scancodes of set 1 are translated to ASCII with
+//! the US layout and queued until a task reads them with `SYS_READ`. Arrow
+//! keys are queued as the escape sequences of a VT100 terminal
+
+use crate::cpu::in8;
+use crate::interrupts::*;
+use crate::sync::InterruptGuard;
+use crate::syscalls::*;
+use crate::tasks::{block_current, current_task, sleep_current};
+use crate::tasks::{find_task, wake_up, wake_up_deferred};
+use crate::uaccess::*;
+use crate::work::queue_work;
+
+/// IRQ of the keyboard
+pub const KEYBOARD_IRQ : u8 = 1;
+
+/// Data port of the PS/2 controller, holding the last scancode
+const PS2_DATA : u16 = 0x60;
+
+/// Status port of the PS/2 controller
+const PS2_STATUS : u16 = 0x64;
+
+/// Status bit set while a byte waits in the data port
+const PS2_OUTPUT_FULL : u8 = 1 << 0;
+
+/// Size in bytes of the queue of typed characters
+const KEYBOARD_BUFFER_SIZE : usize = 128;
+
+/// Max number of tasks waiting for a typed character
+const MAX_KEYBOARD_WAITERS : usize = 8;
+
+/// Prefix of the scancodes of the extended keys
+const SCANCODE_EXTENDED : u8 = 0xe0;
+
+/// Scancode bit set when the key is released
+const SCANCODE_RELEASE : u8 = 0x80;
+
+/// Scancodes of the modifier keys, the right control key is extended
+const SCANCODE_CTRL : u8 = 0x1d;
+const SCANCODE_LEFT_SHIFT : u8 = 0x2a;
+const SCANCODE_RIGHT_SHIFT : u8 = 0x36;
+const SCANCODE_CAPS_LOCK : u8 = 0x3a;
+
+/// Characters of the scancodes below 0x3a, 0 for keys without one
+const US_LAYOUT : [u8; 0x3a] =
+    *b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0\
+       asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+
+/// Same as `US_LAYOUT` while shift is held
+const US_LAYOUT_SHIFT : [u8; 0x3a] =
+    *b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0\
+       ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// Escape sequences of the extended arrow keys, by scancode
+const ARROWS : [(u8, &[u8]); 4] = [
+    (0x48, b"\x1b[A"), (0x50, b"\x1b[B"), (0x4d, b"\x1b[C"), (0x4b, b"\x1b[D"),
+];
+
+/// Modifiers held, queue of the characters not read yet and the tasks
+/// waiting for them
+struct Keyboard {
+    /// Left and right shift keys held
+    left_shift : bool,
+    right_shift : bool,
+
+    /// Left and right control keys held
+    left_ctrl : bool,
+    right_ctrl : bool,
+
+    /// Caps lock toggled on
+    caps_lock : bool,
+
+    /// The previous byte was the extended prefix
+    extended : bool,
+
+    /// Ring buffer of characters
+    buffer : [u8; KEYBOARD_BUFFER_SIZE],
+
+    /// Index of the oldest character
+    head : usize,
+
+    /// Number of characters in the buffer
+    count : usize,
+
+    /// Number of characters dropped because the buffer was full
+    dropped : u32,
+
+    /// Pids of the tasks blocked until a character is typed
+    waiters : [Option<u32>; MAX_KEYBOARD_WAITERS],
+}
+
+impl Keyboard {
+    /// Create a keyboard without modifiers and with an empty buffer
+    const fn new() -> Self {
+        Self {
+            left_shift : false,
+            right_shift : false,
+            left_ctrl : false,
+            right_ctrl : false,
+            caps_lock : false,
+            extended : false,
+            buffer : [0; KEYBOARD_BUFFER_SIZE],
+            head : 0,
+            count : 0,
+            dropped : 0,
+            waiters : [None; MAX_KEYBOARD_WAITERS],
+        }
+    }
+
+    /// Add the task `pid` to the waiters. Returns false if there is no room
+    fn add_waiter(&mut self, pid : u32) -> bool {
+        if self.waiters.contains(&Some(pid)) {
+            return true;
+        }
+        match self.waiters.iter_mut().find(|waiter| waiter.is_none()) {
+            Some(slot) => *slot = Some(pid),
+            None => return false,
+        }
+        true
+    }
+
+    /// Wake up the waiters through the scheduler, when no work can be
+    /// queued. The ones it has no room for stay waiting until the next key
+    fn wake_waiters_deferred(&mut self) {
+        for waiter in self.waiters.iter_mut() {
+            if let Some(pid) = *waiter {
+                if wake_up_deferred(pid) {
+                    *waiter = None;
+                }
+            }
+        }
+    }
+
+    /// Queue `chars`, all of them or none if they don't fit, so that escape
+    /// sequences are never cut
+    fn push(&mut self, chars : &[u8]) {
+        if self.count + chars.len() > KEYBOARD_BUFFER_SIZE {
+            self.dropped += chars.len() as u32;
+            return;
+        }
+        for &c in chars {
+            let idx = (self.head + self.count) % KEYBOARD_BUFFER_SIZE;
+            self.buffer[idx] = c;
+            self.count += 1;
+        }
+    }
+
+    /// Move the oldest characters to `buf`, as many as fit. Returns the
+    /// number of characters moved
+    fn pop(&mut self, buf : &mut [u8]) -> usize {
+        let len = core::cmp::min(buf.len(), self.count);
+        for c in buf[..len].iter_mut() {
+            *c = self.buffer[self.head];
+            self.head = (self.head + 1) % KEYBOARD_BUFFER_SIZE;
+        }
+        self.count -= len;
+        len
+    }
+
+    /// Update the modifiers and queue the character of the byte `scancode`
+    /// read from the keyboard
+    fn handle_scancode(&mut self, scancode : u8) {
+        if scancode == SCANCODE_EXTENDED {
+            self.extended = true;
+            return;
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        let pressed = scancode & SCANCODE_RELEASE == 0;
+        let key = scancode & !SCANCODE_RELEASE;
+
+        if extended {
+            // The fake shifts sent around some extended keys are ignored
+            match key {
+                SCANCODE_CTRL => self.right_ctrl = pressed,
+                _ if pressed => {
+                    if let Some((_, seq)) = ARROWS.iter()
+                            .find(|&&(code, _)| code == key) {
+                        self.push(seq);
+                    }
+                }
+                _ => {},
+            }
+            return;
+        }
+
+        match key {
+            SCANCODE_LEFT_SHIFT => self.left_shift = pressed,
+            SCANCODE_RIGHT_SHIFT => self.right_shift = pressed,
+            SCANCODE_CTRL => self.left_ctrl = pressed,
+            SCANCODE_CAPS_LOCK if pressed => self.caps_lock = !self.caps_lock,
+            _ if pressed && (key as usize) < US_LAYOUT.len() => {
+                if let Some(c) = self.translate(key) {
+                    self.push(&[c]);
+                }
+            }
+            _ => {},
+        }
+    }
+
+    /// Get the character of the key `key` with the current modifiers
+    fn translate(&self, key : u8) -> Option<u8> {
+        let c = US_LAYOUT[key as usize];
+        if c == 0 {
+            return None;
+        }
+
+        // Caps lock only changes letters, and shift undoes it
+        let shift = self.left_shift || self.right_shift;
+        let upper = if c.is_ascii_lowercase() {
+            shift != self.caps_lock
+        } else {
+            shift
+        };
+        let c = if upper { US_LAYOUT_SHIFT[key as usize] } else { c };
+
+        if (self.left_ctrl || self.right_ctrl) && c.is_ascii_alphabetic() {
+            return Some(c & 0x1f);
+        }
+        Some(c)
+    }
+}
+
+/// State of the keyboard, changed by its interrupt handler
+static mut KEYBOARD : Keyboard = Keyboard::new();
+
+/// Register the keyboard interrupt handler and `SYS_READ`
+pub fn keyboard_init() {
+    // A byte left by the boot loader would keep the interrupt from firing
+    unsafe {
+        while in8(PS2_STATUS) & PS2_OUTPUT_FULL != 0 {
+            in8(PS2_DATA);
+        }
+    }
+    register_irq_handler(KEYBOARD_IRQ, handle_keyboard_intr);
+
+    register_syscall(SYS_READ, "read", &[SyscallArg::Addr, SyscallArg::Uint],
+                     |ctx| {
+        sys_read(ctx.regs.ecx, ctx.regs.edx as usize)
+    });
+}
+
+/// Handle the keyboard interrupt, raised for every byte of a scancode. This
+/// may interrupt a change of the queues of the scheduler, so blocked readers
+/// are woken up by deferred work
+fn handle_keyboard_intr(_ctx : &mut InterruptContext) -> IrqReturn {
+    let scancode = unsafe { in8(PS2_DATA) };
+    unsafe {
+        KEYBOARD.handle_scancode(scancode);
+        let waiting = KEYBOARD.waiters.iter().any(|waiter| waiter.is_some());
+        if KEYBOARD.count != 0 && waiting &&
+                !queue_work(wake_keyboard_readers, 0) {
+            KEYBOARD.wake_waiters_deferred();
+        }
+    }
+    IrqReturn::Handled
+}
+
+/// Work queued by the keyboard interrupt, waking up the tasks waiting for a
+/// typed character
+fn wake_keyboard_readers(_arg : u32) {
+    let _guard = InterruptGuard::new();
+    for waiter in unsafe { KEYBOARD.waiters.iter_mut() } {
+        if let Some(task) = waiter.take().and_then(find_task) {
+            wake_up(task);
+        }
+    }
+}
+
+/// Move the oldest typed characters to `buf`, as many as fit, without
+/// waiting. Returns the number of characters moved
+pub fn keyboard_read(buf : &mut [u8]) -> usize {
+    // The interrupt handler must not queue a character meanwhile
+    let _guard = InterruptGuard::new();
+    unsafe { KEYBOARD.pop(buf) }
+}
+
+/// Get the number of characters dropped because nobody read them in time
+pub fn keyboard_dropped() -> u32 {
+    unsafe { KEYBOARD.dropped }
+}
+
+/// Read at most `len` typed characters into `buf`, waiting for at least one.
+/// Returns the number of characters read, or fails with EFAULT if the
+/// buffer is not writable
+fn sys_read(buf : u32, len : usize) -> i32 {
+    let len = core::cmp::min(len, KEYBOARD_BUFFER_SIZE);
+    if len == 0 {
+        return 0;
+    }
+
+    // Fail before taking characters from the buffer, they would be lost
+    if let Err(err) = check_user_range(buf, len, true) {
+        return err;
+    }
+
+    let mut chars = [0u8; KEYBOARD_BUFFER_SIZE];
+    let count = loop {
+        // Interrupts stay disabled from the check to the block, so that a
+        // character can't arrive in between
+        let _guard = InterruptGuard::new();
+        let count = unsafe { KEYBOARD.pop(&mut chars[..len]) };
+        if count != 0 {
+            break count;
+        }
+        if unsafe { KEYBOARD.add_waiter(current_task().pid) } {
+            block_current();
+        } else {
+            sleep_current(1);
+        }
+    };
+    match copy_to_user(buf, &chars[..count]) {
+        Ok(()) => count as i32,
+        Err(err) => err,
+    }
+}
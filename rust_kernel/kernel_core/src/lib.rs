@@ -1,12 +1,14 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 #![feature(asm_const)]
 #![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
 
 mod pic;
 mod cpu;
+mod cpuid;
 mod serial;
 mod multiboot;
 mod utils;
@@ -15,8 +17,38 @@ mod segmem;
 mod interrupts;
 mod tasks;
 mod paging;
+#[cfg(not(test))]
 mod userland_tasks;
 mod syscalls;
+mod uaccess;
+mod shm;
+mod ipc;
+mod handles;
+mod sem;
+mod futex;
+mod sysenter;
+mod uname;
+mod vsys;
+mod power;
+mod pit;
+mod fpu;
+mod sync;
+mod watchdog;
+mod bench;
+mod meminfo;
+mod framebuffer;
+mod heap;
+mod keyboard;
+mod rtc;
+mod debug;
+mod nmi;
+mod time;
+mod work;
+// The check tasks start user tasks, which only build for the kernel
+#[cfg(all(feature = "selftest", not(test)))]
+mod selftest;
+
+extern crate alloc;
 
 use core::panic::PanicInfo;
 use core::arch::asm;
@@ -29,6 +61,7 @@ use crate::segmem::*;
 use crate::interrupts::*;
 use crate::paging::virtmem::*;
 use crate::paging::*;
+use crate::pit::*;
 //use crate::userland_tasks::*;
 
 #[no_mangle]
@@ -39,10 +72,16 @@ static mbh : [u32; 3] = [
     0_u32.wrapping_sub(MBH_MAGIC + MBH_FLAGS),
 ];
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(_info : &PanicInfo) -> ! {
-    println!("[PANIC] {}", _info);
-    cpu::halt();
+    cpu::disable_interrupts();
+    debug::panic_dump(_info);
+
+    // Not `cpu::halt`, which prints through `PERIPHERALS`
+    loop {
+        unsafe { asm!("hlt"); }
+    }
 }
 
 extern "C" { 
@@ -80,17 +119,54 @@ fn print_kernel_mmap(info : &MultibootInfo) {
     }
 }
 
+/// Kernel task printing a few heartbeats before exiting, to show that kernel
+/// tasks are scheduled along with the user ones
+fn heartbeat_task() {
+    for beat in 1..=3 {
+        tasks::kthread_sleep(2 * frequency());
+        println!("kernel heartbeat {} at {} ms, {} spurious IRQs", beat,
+                 uptime_ms(), Pic::spurious_count());
+    }
+}
+
+/// Kernel task printing back the bytes received by the serial port, blocked
+/// while there are none
+fn serial_echo_task() {
+    loop {
+        let byte = read_byte_blocking();
+        print!("{}", byte as char);
+    }
+}
+
+/// Called by the asm bootstrap code instead of `rust_main` if the CPU can't
+/// run the kernel, paging is not enabled yet. Prints the features of the
+/// CPU and panics
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn rust_unsupported_cpu() -> ! {
+    serial_init();
+    cpuid::cpu_features_init();
+    panic!("The kernel needs a CPU with cpuid and 4 MB pages");
+}
+
 /// First rust function called after asm bootstrap code
 /// We use the fastcall convention to pass the mbi_ptr given by GRUB to 
 /// rust_main as the first argument in the ecx register in asm code
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "fastcall" fn rust_main(mbi_ptr : &MultibootInfo) {
 
     // Init the serial port so we can use the print!() and println!() macros
     serial_init();
+
+    // Read what the CPU supports before enabling any of it
+    cpuid::cpu_features_init();
     
     //print_kernel_mmap(mbi_ptr);
 
+    // Give the RAM of the boot loader memory map to the physical allocator
+    unsafe { paging::physmem::PhysMem::init(mbi_ptr); }
+
     // Init the gdt with the following segments
     //  0x00 null
     //  0x08 kernel code segment
@@ -98,31 +174,133 @@ pub extern "fastcall" fn rust_main(mbi_ptr : &MultibootInfo) {
     //  0x18 user code segment
     //  0x20 user data segment
     //  0x28 TSS segment
+    //  0x30 double fault TSS segment
     gdt_init();
 
+    // Enable the FPU and SSE so that tasks can use floating point
+    fpu::fpu_init();
+
     // Creates an IDT and initialize the idt register
     interrupts_init();
+    debug::debug_init();
+    nmi::nmi_init();
 
     // Remap IRQ[00-07] to IDT[0x20-0x27] and IRQ[08-15] to IDT[0x28-0x2f]
-    Pic::remap(0x20, 0x28);
+    // Every IRQ stays masked until its driver registers it
+    Pic::remap(IRQ_BASE, IRQ_BASE + 8);
+    Pic::set_masks(0xffff);
+
+    // Program the timer first, the info page and uname give its frequency
+    // and the wall time is counted in ticks
+    pit_init(TIMER_FREQUENCY);
+    time::calibrate_tsc();
+
+    // Fill the syscall table
+    syscalls::syscalls_init();
+    shm::shm_init();
+    ipc::ipc_init();
+    handles::handles_init();
+    sem::sem_init();
+    futex::futex_init();
+    sysenter::sysenter_init();
+    vsys::vsys_init();
+    uname::uname_init();
+    power::power_init();
+    bench::bench_init();
+    meminfo::meminfo_init();
+    rtc::rtc_init();
+    keyboard::keyboard_init();
+    serial_irq_init();
 
     // Create the kernel page directory, setup to identity map physical memory
     // for the first 128 MB
-    let mut kernel_vspace = VirtMem::new();
-    setup_identity_mapping(&kernel_vspace);
+    let mut kernel_vspace = VirtMem::new()
+        .expect("No memory for the kernel address space");
+    setup_identity_mapping(&kernel_vspace)
+        .expect("No memory for the kernel identity mapping");
+    set_kernel_vspace(&kernel_vspace);
+    double_fault_init();
 
     // Set the cr3 register to use the previously created page directory
     switch_vspace(&kernel_vspace);
 
-    // Enable paging
+    // Enable paging. The boot code already did, with an early page directory
     enable_paging();
 
-    tasks::Task::new(b"first_task", userland_tasks::task1);
-    tasks::Task::new(b"second_task", userland_tasks::task2);
+    // Read accesses to the pages reserved by mmap and sbrk map the zero page
+    zero_page_init();
 
-    tasks::schedule();
+    // Drivers will reach the framebuffer of the boot loader through it
+    framebuffer::framebuffer_init(mbi_ptr, &mut kernel_vspace);
+    multiboot::dump_multiboot_info(mbi_ptr, &mut kernel_vspace);
+
+    // The identity mappings are made of a few runs of pages, mostly large
+    println!("kernel address space :");
+    kernel_vspace.dump(paging::pagemem::VirtAddr(0),
+                       paging::pagemem::VirtAddr(u32::MAX));
 
-    loop {}
+    // Time the creation of the user tasks, and count the memory it takes
+    let start = cpu::rdtsc();
+    let free_pages = paging::physmem::PhysMem::free_pages();
+    let user_tasks : &[tasks::BootTask] = &[
+        (b"first_task", userland_tasks::task1),
+        (b"heap_task", userland_tasks::task3),
+        (b"yield_task", userland_tasks::task4),
+        (b"sleep_task", userland_tasks::task5),
+        (b"exit_task", userland_tasks::task6),
+        (b"fork_task", userland_tasks::task7),
+        (b"uaccess_task", userland_tasks::task8),
+        (b"mmap_task", userland_tasks::task9),
+        // Its children are killed by exceptions raised in userland
+        (b"mprotect_task", userland_tasks::task10),
+        (b"shm_task", userland_tasks::task11),
+        (b"ticks_task", userland_tasks::task12),
+        (b"futex_task", userland_tasks::task13),
+        (b"priority_task", userland_tasks::task14),
+        (b"lifecycle_task", userland_tasks::task15),
+        (b"stack_task", userland_tasks::task16),
+        (b"fpu_task", userland_tasks::task17),
+        (b"tls_task", userland_tasks::task18),
+        (b"bench_task", userland_tasks::task19),
+        (b"cow_task", userland_tasks::task20),
+        (b"lazy_task", userland_tasks::task21),
+        (b"meminfo_task", userland_tasks::task22),
+        (b"zero_page_task", userland_tasks::task23),
+        (b"hostile_map_task", userland_tasks::task24),
+        (b"ipc_copy_task", userland_tasks::task25),
+        (b"echo_task", userland_tasks::task26),
+        (b"clock_task", userland_tasks::task27),
+        (b"gp_fault_task", userland_tasks::task28),
+        (b"breakpoint_task", userland_tasks::task29),
+        (b"irq_stats_task", userland_tasks::task30),
+    ];
+    for &(name, code_addr) in user_tasks.iter() {
+        tasks::Task::new(name, code_addr)
+            .expect("No memory for a user task");
+    }
+    let cycles = cpu::rdtsc() - start;
+    println!("user tasks created in {} cycles ({} us) with {} pages", cycles,
+             time::cycles_to_ns(cycles) / 1000,
+             free_pages - paging::physmem::PhysMem::free_pages());
+
+    #[cfg(feature = "selftest")]
+    selftest::spawn_selftest_tasks();
+
+    let kernel_tasks : &[tasks::BootTask] = &[
+        (b"heartbeat", heartbeat_task),
+        (b"serial_echo", serial_echo_task),
+    ];
+    for &(name, code_addr) in kernel_tasks.iter() {
+        tasks::Task::new_kernel(name, code_addr)
+            .expect("No memory for a kernel task");
+    }
+    tasks::spawn_reaper_task();
+    work::spawn_worker_task();
+    tasks::spawn_idle_task();
+
+    // The boot code is never resumed once the first task runs
+    tasks::schedule();
+    unreachable!();
     
     //cpu::halt();
 }
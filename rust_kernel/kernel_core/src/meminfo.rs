@@ -0,0 +1,110 @@
+//! Memory usage given to userland by `SYS_MEMINFO`, and printed by the
+//! kernel to hunt leaks. The userland tasks use the definitions of this
+//! module too, so both sides always agree on the layout of `MemInfo`
+
+use crate::paging::physmem::PhysMem;
+use crate::paging::virtmem::VirtMem;
+use crate::syscalls::*;
+use crate::paging::pagemem::VirtAddr;
+use crate::tasks::{current_task, dump_task_vspace, for_each_task_vspace};
+use crate::uaccess::*;
+use crate::{print, println, PERIPHERALS};
+
+/// Memory usage returned by `SYS_MEMINFO`, counted in pages
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemInfo {
+    /// Pages of physical memory managed by the kernel
+    pub total_pages : u32,
+
+    /// Pages of physical memory that are free
+    pub free_pages : u32,
+
+    /// Pages of physical memory that are allocated
+    pub used_pages : u32,
+
+    /// Present pages in the address space of the calling task
+    pub mapped_pages : u32,
+
+    /// Present pages private to the calling task in its userland
+    pub private_pages : u32,
+}
+
+impl MemInfo {
+    /// Create an empty `MemInfo` for userland to pass to `SYS_MEMINFO`
+    pub const fn empty() -> Self {
+        Self {
+            total_pages : 0,
+            free_pages : 0,
+            used_pages : 0,
+            mapped_pages : 0,
+            private_pages : 0,
+        }
+    }
+}
+
+/// Register the meminfo and vspace dump syscalls
+pub fn meminfo_init() {
+    register_syscall(SYS_MEMINFO, "meminfo", &[SyscallArg::Addr], |ctx| {
+        sys_meminfo(ctx.regs.ecx)
+    });
+    register_syscall(SYS_VSPACE_DUMP, "vspace_dump",
+                     &[SyscallArg::Uint, SyscallArg::Addr, SyscallArg::Addr],
+                     |ctx| {
+        sys_vspace_dump(ctx.regs.ecx, ctx.regs.edx, ctx.regs.ebx)
+    });
+}
+
+/// Print the page counts of the physical allocator, then the pages mapped
+/// in the address space of every task
+pub fn dump_meminfo() {
+    let stats = PhysMem::stats();
+    println!("physical pages : {} total, {} free, {} used",
+             stats.total, stats.free, stats.used);
+
+    println!("{:>4} {:<16} {:>8} {:>8}", "pid", "name", "mapped", "private");
+    for_each_task_vspace(|pid, name, vspace| {
+        println!("{:>4} {:<16} {:>8} {:>8}", pid, name, vspace.mapped_pages(),
+                 vspace.private_pages());
+    });
+}
+
+/// Fill the `MemInfo` at `buf` with the memory usage of the kernel and of
+/// the calling task. A null `buf` prints the memory usage of every task
+/// instead. Fails with EFAULT if the buffer is not writable
+fn sys_meminfo(buf : u32) -> i32 {
+    if buf == 0 {
+        dump_meminfo();
+        return 0;
+    }
+
+    let stats = PhysMem::stats();
+    let vspace = VirtMem::get_current();
+    let info = MemInfo {
+        total_pages : stats.total as u32,
+        free_pages : stats.free as u32,
+        used_pages : stats.used as u32,
+        mapped_pages : vspace.mapped_pages() as u32,
+        private_pages : vspace.private_pages() as u32,
+    };
+
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&info as *const MemInfo as *const u8,
+                                    core::mem::size_of::<MemInfo>())
+    };
+    match copy_to_user(buf, bytes) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
+
+/// Print the mappings of `[start, end)` in the address space of the task
+/// `pid`, or of the calling task if `pid` is 0. Fails with ESRCH if there is
+/// no such task
+fn sys_vspace_dump(pid : u32, start : u32, end : u32) -> i32 {
+    let pid = if pid == 0 { current_task().pid } else { pid };
+    if !dump_task_vspace(pid, VirtAddr(start), VirtAddr(end)) {
+        return -ESRCH;
+    }
+    0
+}
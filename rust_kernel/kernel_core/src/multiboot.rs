@@ -1,3 +1,8 @@
+use crate::paging::pagemem::PhysAddr;
+use crate::paging::virtmem::VirtMem;
+use crate::utils::hexdump;
+use crate::{print, println, PERIPHERALS};
+
 pub const MBH_MAGIC : u32 = 464367618;
 pub const MBH_FLAGS : u32 = 3;
 
@@ -31,12 +36,12 @@ pub struct MultibootInfo {
 
     vbe_control_info : u32,
     vbe_mode_info : u32,
-    vbe_mode : u32,
-    vbe_interface_seg : u32,
-    vbe_interface_off : u32,
-    vbe_interface_len : u32,
+    vbe_mode : u16,
+    vbe_interface_seg : u16,
+    vbe_interface_off : u16,
+    vbe_interface_len : u16,
 
-    framebuffer_addr : u32,
+    framebuffer_addr : u64,
     framebuffer_pitch : u32,
     framebuffer_width : u32,
     framebuffer_height : u32,
@@ -115,3 +120,105 @@ pub struct multiboot_mmap_entry {
     pub len : u64,
     pub ty : u32,
 }
+
+/// The `mmap_length` and `mmap_addr` fields of `MultibootInfo` are valid
+pub const MBI_FLAG_MMAP : u32 = 1 << 6;
+
+/// Type of the memory map entries of RAM free to use
+pub const MMAP_AVAILABLE : u32 = 1;
+
+/// Call `f` with the address, the length and the type of every entry of the
+/// memory map given by the boot loader. Does nothing if there is none.
+/// Entries are read unaligned, their size lets the boot loader extend them
+pub fn for_each_mmap_entry<F : FnMut(u64, u64, u32)>(info : &MultibootInfo,
+                                                     mut f : F) {
+    if info.flags & MBI_FLAG_MMAP == 0 {
+        return;
+    }
+
+    let mut entry = info.mmap_addr as usize;
+    let end = entry + info.mmap_length as usize;
+    while entry < end {
+        unsafe {
+            let field = |offset : usize| (entry + offset) as *const u8;
+            let size = core::ptr::read_unaligned(field(0) as *const u32);
+            let addr = core::ptr::read_unaligned(field(4) as *const u64);
+            let len = core::ptr::read_unaligned(field(12) as *const u64);
+            let ty = core::ptr::read_unaligned(field(20) as *const u32);
+            f(addr, len, ty);
+
+            // The size doesn't count its own field
+            entry += size as usize + core::mem::size_of::<u32>();
+        }
+    }
+}
+
+/// The framebuffer fields of `MultibootInfo` are valid
+pub const MBI_FLAG_FRAMEBUFFER : u32 = 1 << 12;
+
+/// Framebuffer set up by the boot loader
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    /// Physical address of the framebuffer
+    pub addr : u64,
+
+    /// Bytes per line
+    pub pitch : u32,
+
+    /// Width and height in pixels, or in characters for a text mode
+    pub width : u32,
+    pub height : u32,
+
+    /// Bits per pixel
+    pub bpp : u8,
+
+    /// 0 for indexed colors, 1 for RGB, 2 for EGA text
+    pub ty : u8,
+}
+
+impl Framebuffer {
+    /// Size of the framebuffer in bytes
+    pub fn size(&self) -> usize {
+        self.pitch as usize * self.height as usize
+    }
+}
+
+impl MultibootInfo {
+    /// Get the framebuffer given by the boot loader, if there is one
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        if self.flags & MBI_FLAG_FRAMEBUFFER == 0 {
+            return None;
+        }
+        Some(Framebuffer {
+            addr : self.framebuffer_addr,
+            pitch : self.framebuffer_pitch,
+            width : self.framebuffer_width,
+            height : self.framebuffer_height,
+            bpp : self.framebuffer_bpp,
+            ty : self.framebuffer_type,
+        })
+    }
+}
+
+/// Print the raw bytes of the multiboot info structure `info`, reading it
+/// through a temporary mapping of its physical memory in `vspace`, which
+/// must be the current address space
+pub fn dump_multiboot_info(info : &MultibootInfo, vspace : &mut VirtMem) {
+    let paddr = info as *const MultibootInfo as u32;
+    let size = core::mem::size_of::<MultibootInfo>();
+    let (vaddr, offset) = match vspace.map_phys_range(PhysAddr(paddr), size,
+                                                      0) {
+        Ok(mapping) => mapping,
+        Err(err) => {
+            println!("multiboot : can't map info at {:#x} : {:?}", paddr, err);
+            return;
+        }
+    };
+
+    println!("multiboot : info at {:#x}, mapped at {:#x}", paddr, vaddr.0);
+    let bytes = unsafe {
+        core::slice::from_raw_parts((vaddr.0 + offset) as *const u8, size)
+    };
+    hexdump(bytes, paddr);
+    vspace.unmap_phys_range(vaddr, offset as usize + size);
+}
@@ -0,0 +1,98 @@
+//! Non-maskable interrupt. The chipset raises it for memory parity and I/O
+//! channel errors, which port B tells apart, and emulators for their
+//! watchdogs. The handler prints what it can about it, then only resumes
+//! the NMIs the kernel raised itself
+
+use core::arch::asm;
+use crate::cpu::*;
+use crate::interrupts::*;
+use crate::tasks::running_task;
+use crate::{print, println, PERIPHERALS};
+
+/// System control port B
+const PORT_B : u16 = 0x61;
+
+/// Port B bit set on a memory parity error
+const PORT_B_PARITY : u8 = 1 << 7;
+
+/// Port B bit set on an I/O channel check, raised by an expansion card
+const PORT_B_IOCHK : u8 = 1 << 6;
+
+/// CMOS register selection port, whose high bit masks the NMIs
+const CMOS_ADDRESS : u16 = 0x70;
+
+/// Bit of `CMOS_ADDRESS` keeping the NMIs from reaching the CPU
+const CMOS_NMI_DISABLE : u8 = 1 << 7;
+
+/// Set by `inject_nmi` until its NMI is handled
+static mut SELF_NMI : bool = false;
+
+/// Number of NMIs handled and resumed
+static mut NMI_RESUMED : u32 = 0;
+
+/// Register the NMI handler
+pub fn nmi_init() {
+    register_interrupt_handler(NMI_VECTOR, handle_nmi);
+}
+
+/// Let the NMIs reach the CPU, or keep them away. The selected CMOS
+/// register is left at 0, the RTC selects the one it reads anyway. The RTC
+/// reads enable the NMIs again
+pub fn set_nmi_enabled(enabled : bool) {
+    let val = if enabled { 0 } else { CMOS_NMI_DISABLE };
+    unsafe {
+        out8(CMOS_ADDRESS, val);
+        in8(CMOS_ADDRESS + 1);
+    }
+}
+
+/// Raise an NMI that the handler resumes, to check it. Without a local
+/// APIC the CPU can't send itself one, so the NMIs are enabled through
+/// `CMOS_ADDRESS` and the handler is run by int 2 instead. Unlike a real
+/// NMI, it doesn't block the next ones until the iret
+pub fn inject_nmi() {
+    set_nmi_enabled(true);
+    unsafe {
+        SELF_NMI = true;
+        asm!("int {}", const NMI_VECTOR);
+    }
+}
+
+/// Get the number of NMIs handled and resumed
+pub fn nmi_resumed() -> u32 {
+    unsafe { core::ptr::read_volatile(&NMI_RESUMED) }
+}
+
+/// Handle an NMI. The errors of port B and the interrupted code are
+/// printed, then the CPU halts, unless it was raised by `inject_nmi` and
+/// port B reports no error
+fn handle_nmi(ctx : &mut InterruptContext) {
+    let status = unsafe { in8(PORT_B) };
+    let expected = unsafe { core::mem::replace(&mut SELF_NMI, false) };
+    let errors = status & (PORT_B_PARITY | PORT_B_IOCHK);
+
+    println!("nmi : {} @{:#x}, port b {:#04x}",
+             if expected { "raised by the kernel" } else { "unexpected" },
+             ctx.frame.ip, status);
+    if status & PORT_B_PARITY != 0 {
+        println!("nmi : memory parity error");
+    }
+    if status & PORT_B_IOCHK != 0 {
+        println!("nmi : I/O channel check");
+    }
+
+    let mode = if ctx.frame.cs & 3 == 3 { "user" } else { "kernel" };
+    match running_task() {
+        Some(task) => println!("nmi : {} code of task {} (pid {})", mode,
+                               task.name(), task.pid),
+        None => println!("nmi : {} code before the first task", mode),
+    }
+    print!("{}", ctx);
+
+    if !expected || errors != 0 {
+        println!("nmi : halting");
+        halt();
+    }
+
+    unsafe { NMI_RESUMED += 1; }
+}
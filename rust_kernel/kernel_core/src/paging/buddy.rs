@@ -0,0 +1,304 @@
+//! Buddy allocator of blocks of 2^order pages, identified by their index.
+//! A block of order n is aligned on 2^n pages, and its buddy is the other
+//! half of the block of order n + 1 holding it. Freed buddies merge back.
+//! The allocator only does the index math, the links of its free lists are
+//! kept by a `FreeBlocks` storage
+
+/// Largest order of a block, 1024 pages
+pub const MAX_ORDER : usize = 10;
+
+/// Index of the end of a free list
+const NONE : usize = usize::MAX;
+
+/// Storage of the free lists of a `Buddy`, indexed by page. Only the first
+/// page of a free block is asked about, so the links can be stored in the
+/// free memory itself
+pub trait FreeBlocks {
+    /// Get the next and the previous free block of the list holding the
+    /// free block at `index`
+    fn links(&self, index : usize) -> (usize, usize);
+
+    /// Set the next and the previous free block of the free block at `index`
+    fn set_links(&mut self, index : usize, next : usize, prev : usize);
+
+    /// Get the order of the free block at `index`, `None` if no free block
+    /// starts at `index`
+    fn free_order(&self, index : usize) -> Option<usize>;
+
+    /// Set the order of the free block at `index`, `None` once it is
+    /// allocated or merged
+    fn set_free_order(&mut self, index : usize, order : Option<usize>);
+}
+
+/// Get the smallest order of a block of at least `npages` pages
+pub fn order_for_pages(npages : usize) -> usize {
+    npages.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Get the index of the buddy of the block of order `order` at `index`
+pub fn buddy_of(index : usize, order : usize) -> usize {
+    index ^ (1 << order)
+}
+
+/// Buddy allocator of the pages `[0, pages)`, with a free list per order
+pub struct Buddy<M : FreeBlocks> {
+    /// Links of the free lists
+    blocks : M,
+
+    /// First free block of each order
+    heads : [usize; MAX_ORDER + 1],
+
+    /// Number of pages managed by the allocator, free ones and allocated
+    /// ones. Pages never added are not counted
+    pages : usize,
+
+    /// Number of free pages, in all the free lists
+    free_pages : usize,
+
+    /// Number of indices, all of them are below
+    limit : usize,
+}
+
+impl<M : FreeBlocks> Buddy<M> {
+    /// Create an allocator without any page, of indices below `limit`
+    pub const fn new(blocks : M, limit : usize) -> Self {
+        Self {
+            blocks,
+            heads : [NONE; MAX_ORDER + 1],
+            pages : 0,
+            free_pages : 0,
+            limit,
+        }
+    }
+
+    /// Give the pages `[start, end)` to the allocator, as the largest
+    /// aligned blocks fitting
+    pub fn add_range(&mut self, start : usize, end : usize) {
+        assert!(end <= self.limit, "Buddy range {:#x}-{:#x} over the limit",
+                start, end);
+        let mut index = start;
+        while index < end {
+            let mut order = MAX_ORDER;
+            while !index.is_multiple_of(1 << order) ||
+                    index + (1 << order) > end {
+                order -= 1;
+            }
+            self.pages += 1 << order;
+            self.free(index, order);
+            index += 1 << order;
+        }
+    }
+
+    /// Allocate a block of order `order`, splitting a larger one if there is
+    /// no free block of that order. Returns the index of its first page
+    pub fn alloc(&mut self, order : usize) -> Option<usize> {
+        let mut from = (order..=MAX_ORDER).find(|&o| self.heads[o] != NONE)?;
+        let index = self.heads[from];
+        self.remove(index, from);
+
+        // Give back the upper halves
+        while from > order {
+            from -= 1;
+            self.push(index + (1 << from), from);
+        }
+        self.free_pages -= 1 << order;
+        Some(index)
+    }
+
+    /// Free the allocated block of order `order` at `index`, merging it with
+    /// its free buddies
+    pub fn free(&mut self, index : usize, order : usize) {
+        assert!(order <= MAX_ORDER && index.is_multiple_of(1 << order) &&
+                index + (1 << order) <= self.limit,
+                "Freeing invalid block {:#x} of order {}", index, order);
+        assert!(self.blocks.free_order(index).is_none(),
+                "Freeing free block {:#x}", index);
+        self.free_pages += 1 << order;
+
+        let mut index = index;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = buddy_of(index, order);
+            if buddy >= self.limit ||
+                    self.blocks.free_order(buddy) != Some(order) {
+                break;
+            }
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+
+    /// Get the number of pages managed by the allocator
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Get the number of free pages
+    pub fn free_pages(&self) -> usize {
+        self.free_pages
+    }
+
+    /// Get the number of free blocks of order `order`, walking their list
+    pub fn free_blocks(&self, order : usize) -> usize {
+        let mut count = 0;
+        let mut index = self.heads[order];
+        while index != NONE {
+            count += 1;
+            index = self.blocks.links(index).0;
+        }
+        count
+    }
+
+    /// Put the block at `index` on top of the free list of `order`
+    fn push(&mut self, index : usize, order : usize) {
+        let head = self.heads[order];
+        if head != NONE {
+            let (next, _) = self.blocks.links(head);
+            self.blocks.set_links(head, next, index);
+        }
+        self.blocks.set_links(index, head, NONE);
+        self.blocks.set_free_order(index, Some(order));
+        self.heads[order] = index;
+    }
+
+    /// Take the block at `index` out of the free list of `order`
+    fn remove(&mut self, index : usize, order : usize) {
+        let (next, prev) = self.blocks.links(index);
+        if prev == NONE {
+            self.heads[order] = next;
+        } else {
+            let (_, prev_prev) = self.blocks.links(prev);
+            self.blocks.set_links(prev, next, prev_prev);
+        }
+        if next != NONE {
+            let (next_next, _) = self.blocks.links(next);
+            self.blocks.set_links(next, next_next, prev);
+        }
+        self.blocks.set_free_order(index, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Free lists kept in vectors instead of in the free pages
+    struct VecBlocks {
+        links : Vec<(usize, usize)>,
+        orders : Vec<Option<usize>>,
+    }
+
+    impl FreeBlocks for VecBlocks {
+        fn links(&self, index : usize) -> (usize, usize) {
+            self.links[index]
+        }
+
+        fn set_links(&mut self, index : usize, next : usize, prev : usize) {
+            self.links[index] = (next, prev);
+        }
+
+        fn free_order(&self, index : usize) -> Option<usize> {
+            self.orders[index]
+        }
+
+        fn set_free_order(&mut self, index : usize, order : Option<usize>) {
+            self.orders[index] = order;
+        }
+    }
+
+    fn buddy(limit : usize) -> Buddy<VecBlocks> {
+        let blocks = VecBlocks {
+            links : vec![(NONE, NONE); limit],
+            orders : vec![None; limit],
+        };
+        Buddy::new(blocks, limit)
+    }
+
+    fn free_blocks(buddy : &Buddy<VecBlocks>) -> Vec<usize> {
+        (0..=MAX_ORDER).map(|order| buddy.free_blocks(order)).collect()
+    }
+
+    #[test]
+    fn order_for_pages_rounds_up() {
+        assert_eq!(order_for_pages(1), 0);
+        assert_eq!(order_for_pages(2), 1);
+        assert_eq!(order_for_pages(3), 2);
+        assert_eq!(order_for_pages(1 << MAX_ORDER), MAX_ORDER);
+    }
+
+    #[test]
+    fn add_range_takes_aligned_blocks() {
+        let mut buddy = buddy(2 << MAX_ORDER);
+        buddy.add_range(1, 2 << MAX_ORDER);
+        assert_eq!(buddy.pages(), (2 << MAX_ORDER) - 1);
+        assert_eq!(buddy.free_pages(), buddy.pages());
+        assert_eq!(free_blocks(&buddy), vec![1; MAX_ORDER + 1]);
+    }
+
+    #[test]
+    fn alloc_splits_and_free_merges() {
+        let mut buddy = buddy(1 << MAX_ORDER);
+        buddy.add_range(0, 1 << MAX_ORDER);
+
+        assert_eq!(buddy.alloc(0), Some(0));
+        assert_eq!(buddy.free_pages(), (1 << MAX_ORDER) - 1);
+        let mut split = vec![1; MAX_ORDER + 1];
+        split[MAX_ORDER] = 0;
+        assert_eq!(free_blocks(&buddy), split);
+
+        // The upper halves were given back, the next page is the buddy
+        assert_eq!(buddy.alloc(0), Some(1));
+        assert_eq!(buddy.alloc(1), Some(2));
+        buddy.free(1, 0);
+        buddy.free(0, 0);
+        buddy.free(2, 1);
+        let mut merged = vec![0; MAX_ORDER + 1];
+        merged[MAX_ORDER] = 1;
+        assert_eq!(free_blocks(&buddy), merged);
+        assert_eq!(buddy.free_pages(), 1 << MAX_ORDER);
+    }
+
+    #[test]
+    fn alloc_until_empty() {
+        let mut buddy = buddy(16);
+        buddy.add_range(0, 16);
+        let mut pages : Vec<usize> = (0..16)
+            .map(|_| buddy.alloc(0).unwrap())
+            .collect();
+        assert_eq!(buddy.alloc(0), None);
+        assert_eq!(buddy.free_pages(), 0);
+
+        pages.sort_unstable();
+        assert_eq!(pages, (0..16).collect::<Vec<_>>());
+        for page in pages {
+            buddy.free(page, 0);
+        }
+        assert_eq!(free_blocks(&buddy)[4], 1);
+    }
+
+    #[test]
+    fn no_merge_past_the_limit() {
+        let mut buddy = buddy(12);
+        buddy.add_range(0, 12);
+        assert_eq!(free_blocks(&buddy)[..4], [0, 0, 1, 1]);
+
+        // The buddy of the block at 8 would be at 12, past the limit
+        assert_eq!(buddy.alloc(2), Some(8));
+        buddy.free(8, 2);
+        assert_eq!(free_blocks(&buddy)[..4], [0, 0, 1, 1]);
+        assert_eq!(buddy.alloc(3), Some(0));
+        assert_eq!(buddy.alloc(3), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Freeing free block")]
+    fn double_free_panics() {
+        let mut buddy = buddy(4);
+        buddy.add_range(0, 4);
+        let index = buddy.alloc(1).unwrap();
+        buddy.free(index, 1);
+        buddy.free(index, 1);
+    }
+}
@@ -1,50 +1,208 @@
+pub mod buddy;
 pub mod physmem;
 pub mod pagemem;
 pub mod virtmem;
 
 use pagemem::*;
 use virtmem::*;
-use core::arch::asm;
+use crate::cpu::{Cr0, Cr3, Cr4};
+use crate::cpuid::cpu_features;
 
 /// The virtual base in the kernel page table where physical memory is 
-/// linearly mapped. If set to 0, virtual memory is identity mapped to
-/// physical memory
-pub const KERNEL_PHYS_WINDOW_BASE : u32 = 0;
+/// linearly mapped, aligned on 4 MB. If set to 0, virtual memory is identity
+/// mapped to physical memory
+pub const KERNEL_PHYS_WINDOW_BASE : u32 = 0xc000_0000;
 
 /// Size of the kernel physical window = 128 Mb (size of ram)
 pub const KERNEL_PHYS_WINDOW_SIZE : u32 = 128 * 1024 * 1024;
 
+/// Size of the identity mapping of the low memory holding the kernel image,
+/// which runs at its physical address. It is part of the window when the
+/// window is at 0
+pub const KERNEL_IMAGE_MAP_SIZE : u32 = LARGE_PAGE_SIZE as u32;
+
+/// The window, for the boot code that maps it in an early page directory
+#[no_mangle]
+static kernel_phys_window_base : u32 = KERNEL_PHYS_WINDOW_BASE;
+#[no_mangle]
+static kernel_phys_window_size : u32 = KERNEL_PHYS_WINDOW_SIZE;
+
 /// Base virtual address to use for dynamic allocations
 pub const KERNEL_VMEM_BASE : u32 = 0x1337_0000;
 
+/// Size of the dynamic allocations area = 256 MB, a multiple of 32 pages
+pub const KERNEL_VMEM_SIZE : u32 = 256 * 1024 * 1024;
+
+/// Number of pages in the dynamic allocations area
+pub const KERNEL_VMEM_PAGES : usize = KERNEL_VMEM_SIZE as usize / PAGE_SIZE;
+
+/// Start and end of the user space, where userland may map memory, minus
+/// the kernel areas inside of it. The kernel maps its own memory anywhere
+pub const USER_SPACE_BASE : u32 = 0x0800_0000;
+pub const USER_SPACE_END : u32 = 0xc000_0000;
+
 /// Base virtual address where to store the virtual allocator bitmap
 pub const KERNEL_VMEM_ALLOCATOR_BITMAP : u32 = 0xdead_0000;
 
+/// Size in pages of the virtual allocator bitmap, which has one bit per page
+/// of the dynamic allocations area
+pub const KERNEL_VMEM_BITMAP_PAGES : usize =
+    (KERNEL_VMEM_PAGES / 8).div_ceil(PAGE_SIZE);
+
+/// Virtual address of the page of kernel information mapped read-only in
+/// every task
+pub const VSYS_PAGE_ADDR : u32 = 0x3fff_f000;
+
 /// The base address of the allocator area
 pub const PHYS_ALLOCATOR_BASE : usize = 0x400_000;
 
+/// End of the physical memory reachable by the legacy ISA DMA, 16 MB
+pub const DMA_ZONE_END : usize = 0x100_0000;
+
+/// Page directory of the kernel address space, shared by the kernel tasks
+static mut KERNEL_PGD : PhysAddr = PhysAddr(0);
+
+extern "C" {
+    static __kernel_ro_start__ : usize;
+    static __kernel_ro_end__ : usize;
+    static __user_task_start__ : usize;
+    static __user_task_end__ : usize;
+}
+
+/// Returns true if the CPU supports 4 MB pages
+fn large_pages_supported() -> bool {
+    cpu_features().pse
+}
+
+/// Returns true if the CPU supports global pages
+fn global_pages_supported() -> bool {
+    cpu_features().pge
+}
+
+/// Flags of the kernel mappings shared by every address space. They are
+/// global when the CPU supports it, so that switching address spaces
+/// doesn't flush them from the TLB
+fn kernel_page_flags() -> u32 {
+    if global_pages_supported() {
+        PAGE_PRESENT | PAGE_BORROWED | PAGE_GLOBAL
+    } else {
+        PAGE_PRESENT | PAGE_BORROWED
+    }
+}
+
+/// Enable paging, with large pages and global pages if the CPU supports
+/// them. The kernel can't write to read-only pages either
 pub fn enable_paging() {
-    unsafe {
-        asm!("mov eax, cr0
-              or eax, 0x80000000
-              mov cr0, eax");
+    if large_pages_supported() {
+        unsafe { Cr4::set_bits(Cr4::PSE); }
+    }
+    if global_pages_supported() {
+        unsafe { Cr4::set_bits(Cr4::PGE); }
     }
+    unsafe { Cr0::set_bits(Cr0::WP | Cr0::PG); }
 }
 
-/// Switch virtual address space
+/// Switch virtual address space. The page directory is cached like the
+/// rest of the memory
 pub fn switch_vspace(vmem : &VirtMem) {
+    unsafe { Cr3::new(vmem.get_pgd_paddr(), 0).write(); }
+}
+
+/// Make `vmem` the kernel address space returned by `kernel_vspace`
+pub fn set_kernel_vspace(vmem : &VirtMem) {
+    unsafe { KERNEL_PGD = vmem.get_pgd_paddr(); }
+}
+
+/// Get the kernel address space
+pub fn kernel_vspace() -> VirtMem {
+    VirtMem::from_pgd(unsafe { KERNEL_PGD })
+}
+
+/// Identity map the physical memory at virtual address 
+/// `KERNEL_PHYS_WINDOW_BASE` on `vmem` address space, and the kernel image
+/// at its physical address. Large pages are used if the CPU supports them,
+/// which saves a page table per 4 MB in every address space. They are split
+/// when one of their pages is remapped. Every address space gets the same
+/// global mappings from here, which stay in the TLB across switches, but
+/// for the user code at its address. Fails with `OutOfMemory` without
+/// memory for the page tables
+pub fn setup_identity_mapping(vmem : &VirtMem) -> Result<(), MappingError> {
+    map_phys_range(vmem, KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE)?;
+    if KERNEL_PHYS_WINDOW_BASE != 0 {
+        map_phys_range(vmem, 0, KERNEL_IMAGE_MAP_SIZE)?;
+    }
+
+    // Write-protect the kernel code and read-only data, at their address
+    // and in the window, replacing their writable mapping
+    let (start, end) = kernel_ro_range();
+    for paddr in (start..end).step_by(PAGE_SIZE) {
+        vmem.try_update_pte(VirtAddr(paddr), paddr | kernel_page_flags())?;
+        if KERNEL_PHYS_WINDOW_BASE != 0 {
+            vmem.try_update_pte(VirtAddr(KERNEL_PHYS_WINDOW_BASE + paddr),
+                                paddr | kernel_page_flags())?;
+        }
+    }
+
+    // The user tasks map the user code for userland at its address, so it
+    // is not mapped the same way everywhere. A global entry of the kernel
+    // mapping would survive the switch to a user task, and fault its
+    // accesses. The window maps it like the rest of the memory
+    let (start, end) = user_code_range();
+    let flags = (kernel_page_flags() & !PAGE_GLOBAL) | PAGE_WRITE;
+    for paddr in (start..end).step_by(PAGE_SIZE) {
+        vmem.try_update_pte(VirtAddr(paddr), paddr | flags)?;
+    }
+    Ok(())
+}
+
+/// Get the page aligned bounds of the kernel code and read-only data
+pub fn kernel_ro_range() -> (u32, u32) {
     unsafe {
-        asm!("mov eax, {}
-              mov cr3, eax",
-              in(reg) vmem.get_pgd_paddr().0);
+        (&__kernel_ro_start__ as *const usize as u32,
+         &__kernel_ro_end__ as *const usize as u32)
     }
 }
 
-/// Identity map the physical memory at virtual address 
-/// `KERNEL_PHYS_WINDOW_BASE` on `vmem` address space
-pub fn setup_identity_mapping(vmem : &VirtMem) {
-    for paddr in (0..1024*1024*128).step_by(PAGE_SIZE) {
-        let vaddr = VirtAddr(KERNEL_PHYS_WINDOW_BASE + paddr);
-        vmem.map_raw(vaddr, paddr | PAGE_PRESENT | PAGE_WRITE);
+/// Get the page aligned bounds of the .user_task section, holding the code
+/// and the data of the userland tasks
+pub fn user_code_range() -> (u32, u32) {
+    unsafe {
+        (&__user_task_start__ as *const usize as u32,
+         &__user_task_end__ as *const usize as u32)
+    }
+}
+
+/// Returns true if `vaddr` is write-protected kernel code or read-only
+/// data, at its address or in the window
+pub fn is_kernel_ro(vaddr : VirtAddr) -> bool {
+    let (start, end) = kernel_ro_range();
+    let paddr = if in_phys_window(vaddr) {
+        vaddr.0 - KERNEL_PHYS_WINDOW_BASE
+    } else {
+        vaddr.0
+    };
+    paddr >= start && paddr < end
+}
+
+/// Map the `size` bytes of physical memory from 0 at `base` on `vmem`
+fn map_phys_range(vmem : &VirtMem, base : u32, size : u32)
+        -> Result<(), MappingError> {
+    let flags = kernel_page_flags() | PAGE_WRITE;
+    if large_pages_supported() {
+        for paddr in (0..size).step_by(LARGE_PAGE_SIZE) {
+            let vaddr = VirtAddr(base + paddr);
+            vmem.map_large_raw(vaddr, paddr | flags);
+        }
+        return Ok(());
+    }
+
+    for paddr in (0..size).step_by(PAGE_SIZE) {
+        let vaddr = VirtAddr(base + paddr);
+        match vmem.map_raw(vaddr, paddr | flags) {
+            Err(MappingError::AlreadyMapped(_)) =>
+                panic!("Physical memory mapped twice"),
+            result => result?,
+        }
     }
+    Ok(())
 }
@@ -1,10 +1,16 @@
 //! Pagination structures and methods
 
 use super::physmem::*;
+use super::virtmem::MappingError;
+use crate::cpu::{get_cr3, invlpg};
+use crate::sync::{preempt_disable, preempt_enable};
 use core::mem::size_of;
 
 pub const PAGE_SIZE : usize = 0x1000;
 
+/// Size of a large page, mapped by a page directory entry with `PAGE_LARGE`
+pub const LARGE_PAGE_SIZE : usize = 0x40_0000;
+
 /// Page table flag indicating the entry is valid
 pub const PAGE_PRESENT: u32 = 1 << 0;
 
@@ -31,6 +37,93 @@ pub const PAGE_DIRTY: u32 = 1 << 6;
 /// Page table flag indicating that this page entry is a large page
 pub const PAGE_LARGE: u32 = 1 << 7;
 
+/// Page table flag of a kernel page kept in the TLB when cr3 changes, once
+/// CR4.PGE is set. The mapping must be the same in every address space, and
+/// changing it requires an invlpg even after a switch of address space
+pub const PAGE_GLOBAL: u32 = 1 << 8;
+
+/// Software page table flag indicating that the page is shared between
+/// several address spaces
+pub const PAGE_SHARED: u32 = 1 << 9;
+
+/// Software page table flag of a private page mapped read-only while it is
+/// shared with another address space after a fork. It becomes writable once
+/// the address space writing to it has its own copy
+pub const PAGE_COW: u32 = 1 << 10;
+
+/// Software page table flag of a user page reserved by mmap or sbrk which is
+/// not present yet. Its page is allocated on the first access, with the
+/// other flags of the entry
+pub const PAGE_LAZY: u32 = 1 << 11;
+
+/// Software page table flag of a present page mapping physical memory that
+/// was not allocated for it, like the identity mappings or device memory.
+/// The page is never freed with its mapping. It reuses the bit of
+/// `PAGE_LAZY`, which only means something for pages that are not present
+pub const PAGE_BORROWED: u32 = 1 << 11;
+
+/// Index of the page directory entry pointing at the page directory itself.
+/// The page tables of the address space in use are then mapped from
+/// `PAGE_TABLES_BASE`, and its page directory at `PAGE_DIRECTORY_ADDR`
+pub const RECURSIVE_PDE_INDEX : usize = 1023;
+pub const PAGE_TABLES_BASE : u32 = 0xffc0_0000;
+pub const PAGE_DIRECTORY_ADDR : u32 = 0xffff_f000;
+
+/// Index of the page directory entry of the page table holding the
+/// temporary mapping slots, at `TEMP_MAP_BASE`. The memory from there to
+/// the end of the address space only holds paging structures
+pub const TEMP_PDE_INDEX : usize = 1022;
+pub const TEMP_MAP_BASE : u32 = 0xff80_0000;
+
+/// Number of pages that can be mapped by `map_temp` at the same time
+const TEMP_MAP_SLOTS : usize = 4;
+
+/// Get the address of the page directory entry of `vaddr` in the address
+/// space in use, through the recursive mapping
+pub fn current_pde_ptr(vaddr : VirtAddr) -> *mut u32 {
+    (PAGE_DIRECTORY_ADDR + (vaddr.0 >> 22) * size_of::<u32>() as u32)
+        as *mut u32
+}
+
+/// Get the address of the page table entry of `vaddr` in the address space
+/// in use, through the recursive mapping. Only valid if the page directory
+/// entry of `vaddr` is present and not a large page
+pub fn current_pte_ptr(vaddr : VirtAddr) -> *mut u32 {
+    (PAGE_TABLES_BASE + (vaddr.0 >> 12) * size_of::<u32>() as u32)
+        as *mut u32
+}
+
+/// Map the physical page `paddr` in a free temporary slot of the address
+/// space in use, to edit the paging structures of another address space.
+/// Preemption is disabled until the matching `unmap_temp`, since the kernel
+/// tasks share their slots
+pub unsafe fn map_temp(paddr : PhysAddr) -> *mut u8 {
+    preempt_disable();
+    for slot in 0..TEMP_MAP_SLOTS {
+        let vaddr = TEMP_MAP_BASE + (slot * PAGE_SIZE) as u32;
+        let pte = current_pte_ptr(VirtAddr(vaddr));
+        if core::ptr::read_volatile(pte) & PAGE_PRESENT == 0 {
+            core::ptr::write_volatile(pte,
+                                      paddr.0 | PAGE_PRESENT | PAGE_WRITE);
+            invlpg(vaddr);
+            return vaddr as *mut u8;
+        }
+    }
+    panic!("No free temporary mapping slot");
+}
+
+/// Release the temporary slot of `ptr`, given by `map_temp`
+pub unsafe fn unmap_temp(ptr : *mut u8) {
+    let vaddr = ptr as u32 & !0xfff;
+    let slots_end = TEMP_MAP_BASE + (TEMP_MAP_SLOTS * PAGE_SIZE) as u32;
+    if vaddr < TEMP_MAP_BASE || vaddr >= slots_end {
+        panic!("Not a temporary mapping : {:#x}", vaddr);
+    }
+    core::ptr::write_volatile(current_pte_ptr(VirtAddr(vaddr)), 0);
+    invlpg(vaddr);
+    preempt_enable();
+}
+
 /// A strongly typed Virtual Address
 #[derive(Debug, Copy, Clone)]
 pub struct VirtAddr(pub u32);
@@ -43,13 +136,19 @@ pub struct PhysAddr(pub u32);
 #[derive(Debug)]
 pub struct Mapping {
     /// Physical address of the page directory entry for this mapping
-    pub pde : Option<PhysAddr>,
+    pub pde : PhysAddr,
 
-    /// Physical address of the page table entry for this mapping
+    /// Physical address of the page table entry for this mapping, `None` for
+    /// a large page
     pub pte : Option<PhysAddr>,
 
-    /// Base address of the physical page backing the virtual memory
+    /// Base address of the physical page backing the virtual memory, `None`
+    /// if the page table entry is not present
     pub page : Option<PhysAddr>,
+
+    /// Flags of the page table entry, or of the page directory entry for a
+    /// large page
+    pub flags : u32,
 }
 
 /// A Page Directory Entry
@@ -107,7 +206,7 @@ impl PageDirectoryEntry {
 }
 
 /// A Page Table Entry
-pub struct PageTableEntry(u32);
+pub struct PageTableEntry(pub u32);
 
 impl PageTableEntry {
     pub fn new(val : u32) -> Self {
@@ -177,12 +276,24 @@ pub struct PageDirectory {
 }
 
 impl PageDirectory {
-    /// Allocate a new `PageDirectory`
-    pub fn new() -> Self {
-        let page = unsafe { PhysMem::alloc_phys_zeroed() };
-        Self {
+    /// Allocate a new `PageDirectory`, mapped on itself and with a page table
+    /// for its temporary slots
+    pub fn new() -> Result<Self, OutOfMemory> {
+        let page = unsafe { PhysMem::try_alloc_phys_zeroed()? };
+        let pgd = Self {
             table : page,
-        }
+        };
+
+        let temp_ptb = match unsafe { PhysMem::try_alloc_phys_zeroed() } {
+            Ok(temp_ptb) => temp_ptb,
+            Err(err) => {
+                unsafe { PhysMem::free_phys(page); }
+                return Err(err);
+            }
+        };
+        pgd.set_entry(TEMP_PDE_INDEX, temp_ptb.0 | PAGE_PRESENT | PAGE_WRITE);
+        pgd.set_entry(RECURSIVE_PDE_INDEX, page.0 | PAGE_PRESENT | PAGE_WRITE);
+        Ok(pgd)
     }
     
     /// Create a `PageDirectory` from a physical address
@@ -192,61 +303,117 @@ impl PageDirectory {
         }
     }
 
+    /// Returns true if this page directory is the one in use, whose
+    /// entries are reached through the recursive mapping
+    pub fn is_current(&self) -> bool {
+        get_cr3().0 & !0xfff == self.table.0
+    }
+
     /// Update the entry at `index` to make it `entry`
     fn set_entry(&self, index : usize, entry : u32) {
-        let entry_paddr = PhysAddr(self.table.0 + 
-                                   (index * size_of::<u32>()) as u32);
-        let entry_vaddr = PhysMem::translate(entry_paddr, size_of::<u32>());
         unsafe {
-            core::ptr::write(entry_vaddr as *mut u32, entry);
+            if self.is_current() {
+                let entries = PAGE_DIRECTORY_ADDR as *mut u32;
+                core::ptr::write_volatile(entries.add(index), entry);
+
+                // The page table of the entry is mapped through it
+                invlpg(PAGE_TABLES_BASE + (index * PAGE_SIZE) as u32);
+            } else {
+                let entries = map_temp(self.table) as *mut u32;
+                core::ptr::write_volatile(entries.add(index), entry);
+                unmap_temp(entries as *mut u8);
+            }
         }
     }
 
-    /// Get the PDE at `index`
-    fn get_entry(&self, index : usize) -> PageDirectoryEntry {
-        let entry_paddr = PhysAddr(self.table.0 + 
-                                   (index * size_of::<u32>()) as u32);
-        let entry_vaddr = PhysMem::translate(entry_paddr, size_of::<u32>());
-        let entry = unsafe {
-            PageDirectoryEntry::new(core::ptr::read(entry_vaddr as *const u32))
-        };
-        entry
-    }
-
-    /// Create a page table entry at `vaddr` of length `size` bytes
+    /// Create a page table entry at `vaddr` of length `size` bytes. Fails
+    /// with the existing entry if a page of the range is already mapped,
+    /// before anything is allocated. Without memory for all of the range,
+    /// the pages already mapped are unmapped and freed
     pub fn map(&self, vaddr : VirtAddr, size : usize, write : bool, 
-                      user : bool) {
+                      user : bool) -> Result<(), MappingError> {
         
+        let start_vaddr = vaddr.0;
         let end_vaddr = vaddr.0 + (size as u32);
+        for vaddr in (start_vaddr..end_vaddr).step_by(PAGE_SIZE) {
+            self.check_unmapped(VirtAddr(vaddr))
+                .map_err(MappingError::AlreadyMapped)?;
+        }
 
         // Iterate over all pages in the mapping 
-        for vaddr in (vaddr.0..end_vaddr).step_by(PAGE_SIZE) {
-            // Alloc a new physical page
-            let page = unsafe { PhysMem::alloc_phys() };
-            // Create a ptb entry corresponding to the allocated page
-            let new_ptb_entry = PageTableEntry::new(
-                page.0 | PAGE_PRESENT |
-                if write { PAGE_WRITE } else { 0 } |
-                if user { PAGE_USER } else { 0 }
-            );
-            // Add this mapping to the page table 
-            unsafe {
-                self.map_raw(VirtAddr(vaddr), new_ptb_entry.0);
+        for vaddr in (start_vaddr..end_vaddr).step_by(PAGE_SIZE) {
+            if let Err(err) = self.map_new_page(VirtAddr(vaddr), write, user) {
+                // The page tables allocated stay, freed with the directory
+                for done in (start_vaddr..vaddr).step_by(PAGE_SIZE) {
+                    unsafe {
+                        if let Some(page) = self.unmap(VirtAddr(done)) {
+                            PhysMem::free_phys(page);
+                        }
+                    }
+                }
+                return Err(err);
             }
         }
+        Ok(())
+    }
+
+    /// Map a newly allocated page at the unmapped `vaddr`
+    fn map_new_page(&self, vaddr : VirtAddr, write : bool, user : bool)
+            -> Result<(), MappingError> {
+        // Alloc a new physical page
+        let page = unsafe { PhysMem::try_alloc_phys() }
+            .map_err(|_| MappingError::OutOfMemory)?;
+        // Create a ptb entry corresponding to the allocated page
+        let new_ptb_entry = PageTableEntry::new(
+            page.0 | PAGE_PRESENT |
+            if write { PAGE_WRITE } else { 0 } |
+            if user { PAGE_USER } else { 0 }
+        );
+        // Add this mapping to the page table 
+        unsafe {
+            self.try_replace_raw(vaddr, new_ptb_entry.0).inspect_err(|_| {
+                PhysMem::free_phys(page);
+            })
+        }
     }
 
-    /// Map a `vaddr` to a raw page table entry `raw`
-    pub unsafe fn map_raw(&self, vaddr : VirtAddr, raw : u32) {
+    /// Fail with the existing entry if a page, present or lazy, is mapped
+    /// at `vaddr`
+    fn check_unmapped(&self, vaddr : VirtAddr) -> Result<(), u32> {
+        match self.get_pte(vaddr) {
+            Some(pte) if pte.0 & (PAGE_PRESENT | PAGE_LAZY) != 0 => Err(pte.0),
+            _ => Ok(()),
+        }
+    }
+
+    /// Map a `vaddr` to a raw page table entry `raw`. Fails with the
+    /// existing entry if a page, present or lazy, is already mapped there
+    pub unsafe fn map_raw(&self, vaddr : VirtAddr, raw : u32)
+            -> Result<(), MappingError> {
+        self.check_unmapped(vaddr).map_err(MappingError::AlreadyMapped)?;
+        self.try_replace_raw(vaddr, raw)
+    }
+
+    /// Map a `vaddr` to a raw page table entry `raw`, replacing the entry
+    /// that may already be there. The TLB is not flushed
+    pub unsafe fn replace_raw(&self, vaddr : VirtAddr, raw : u32) {
+        self.try_replace_raw(vaddr, raw).expect("Out of memory");
+    }
+
+    /// Like `replace_raw`, but fails without memory for the page table
+    pub unsafe fn try_replace_raw(&self, vaddr : VirtAddr, raw : u32)
+            -> Result<(), MappingError> {
         let pgd_index = ((vaddr.0 >> 22) & 0x3ff) as usize;
         let ptb_index = ((vaddr.0 >> 12) & 0x3ff) as usize;
 
-        let mut entry = self.get_entry(pgd_index);
+        let mut entry = self.split_large(pgd_index)
+            .map_err(|_| MappingError::OutOfMemory)?;
 
         // If the entry is not present, allocate a blank page table and update
         // the corresponding PDE
         if entry.0 & PAGE_PRESENT == 0 {
-            let new_ptb = PhysMem::alloc_phys_zeroed();
+            let new_ptb = PhysMem::try_alloc_phys_zeroed()
+                .map_err(|_| MappingError::OutOfMemory)?;
             //println!("allocating new pt at {:#x}", new_ptb.0);
 
             let new_pgd_entry = PageDirectoryEntry::new(
@@ -256,79 +423,509 @@ impl PageDirectory {
             entry = new_pgd_entry;
         }
         
-        // Get the page table from the entry paddr
-        let ptb = PageTable::from_paddr(entry.get_paddr());
+        // Get the page table from the entry
+        let ptb = self.get_table(pgd_index, &entry);
         
         // Update the entry
         ptb.set_entry(ptb_index, raw);
+        Ok(())
     }
 
-    /// Return the physical address of this page table directory
-    pub fn get_paddr(&self) -> PhysAddr {
-        self.table
+    /// Map the large page `vaddr`, aligned on `LARGE_PAGE_SIZE`, to a raw
+    /// page directory entry `raw`. There must be no page table for `vaddr`
+    pub unsafe fn map_large_raw(&self, vaddr : VirtAddr, raw : u32) {
+        let pgd_index = ((vaddr.0 >> 22) & 0x3ff) as usize;
+        let entry = self.get_entry(pgd_index);
+        if entry.0 & PAGE_PRESENT != 0 && entry.0 & PAGE_LARGE == 0 {
+            panic!("Large page over the page table of {:#x}", vaddr.0);
+        }
+        self.set_entry(pgd_index, raw | PAGE_LARGE);
     }
 
-    /// Translate a `vaddr` into its mapping components in the `self` page
-    /// directory
-    pub fn translate(&self, vaddr : VirtAddr) -> Mapping {
-        let mut ret = Mapping {
-            pde :  None,
-            pte :  None,
-            page : None,
-        };
+    /// Replace the large page of the entry at `pgd_index`, if any, with a
+    /// page table mapping the same memory with the same flags, so that its
+    /// pages can be changed one by one. Returns the new entry
+    unsafe fn split_large(&self, pgd_index : usize)
+            -> Result<PageDirectoryEntry, OutOfMemory> {
+        let entry = self.get_entry(pgd_index);
+        if entry.0 & PAGE_PRESENT == 0 || entry.0 & PAGE_LARGE == 0 {
+            return Ok(entry);
+        }
+
+        // The table is filled before the entry references it
+        let ptb_paddr = PhysMem::try_alloc_phys()?;
+        let ptb = PageTable::from_paddr(ptb_paddr);
+        let flags = entry.0 & 0xfff & !PAGE_LARGE;
+        for index in 0..1024 {
+            let page = entry.get_paddr().0 + (index * PAGE_SIZE) as u32;
+            ptb.set_entry(index, page | flags);
+        }
+        drop(ptb);
+
+        let new_entry = PageDirectoryEntry::new(
+            ptb_paddr.0 | PAGE_PRESENT | PAGE_WRITE | PAGE_USER);
+        self.set_entry(pgd_index, new_entry.0);
+        Ok(new_entry)
+    }
+
+    /// Get the page table entry mapping `vaddr`. Returns `None` if there is no
+    /// page table for this address. In a large page, the entry is made from
+    /// the flags of the large page
+    pub fn get_pte(&self, vaddr : VirtAddr) -> Option<PageTableEntry> {
+        let pgd_index = ((vaddr.0 >> 22) & 0x3ff) as usize;
+        let ptb_index = ((vaddr.0 >> 12) & 0x3ff) as usize;
+
+        let entry = self.get_entry(pgd_index);
+        if entry.0 & PAGE_PRESENT == 0 {
+            return None;
+        }
+        if entry.0 & PAGE_LARGE != 0 {
+            let page = entry.get_paddr().0 + (ptb_index * PAGE_SIZE) as u32;
+            return Some(PageTableEntry::new(
+                page | (entry.0 & 0xfff & !PAGE_LARGE)));
+        }
+
+        let ptb = self.get_table(pgd_index, &entry);
+        Some(ptb.get_entry(ptb_index))
+    }
+
+    /// Remove the mapping of `vaddr`, present or lazy. Returns the physical
+    /// page that was mapped, or `None` if no page was present at `vaddr`
+    pub unsafe fn unmap(&self, vaddr : VirtAddr) -> Option<PhysAddr> {
+        let pgd_index = ((vaddr.0 >> 22) & 0x3ff) as usize;
+        let ptb_index = ((vaddr.0 >> 12) & 0x3ff) as usize;
+
+        let entry = self.split_large(pgd_index).expect("Out of memory");
+        if entry.0 & PAGE_PRESENT == 0 {
+            return None;
+        }
+
+        let ptb = self.get_table(pgd_index, &entry);
+        let pte = ptb.get_entry(ptb_index);
+        if pte.0 & (PAGE_PRESENT | PAGE_LAZY) == 0 {
+            return None;
+        }
+
+        // Clear the entry
+        ptb.set_entry(ptb_index, 0);
 
+        if pte.0 & PAGE_PRESENT == 0 {
+            return None;
+        }
+        Some(pte.get_paddr())
+    }
+
+    /// Make the entry at `index` of `other` the same as the one of this page
+    /// directory, so that both map the same memory there. A page table is
+    /// shared and gets a reference, or is copied if it has too many already.
+    /// The entry of `other` must not be present
+    pub unsafe fn share_entry(&self, other : &PageDirectory, index : usize)
+            -> Result<(), OutOfMemory> {
+        let entry = self.get_entry(index);
+        if entry.0 & PAGE_PRESENT == 0 || entry.0 & PAGE_LARGE != 0 ||
+                PhysMem::share_phys(entry.get_paddr()) {
+            other.set_entry(index, entry.0);
+            return Ok(());
+        }
+
+        let copy = PhysMem::try_alloc_phys()?;
+        PhysMem::copy_page(copy, entry.get_paddr());
+        other.set_entry(index, copy.0 | (entry.0 & 0xfff));
+        Ok(())
+    }
+
+    /// Free every page table referenced by this page directory and clear the
+    /// corresponding entries. Pages mapped by these tables or by large pages
+    /// are not freed, nor is the page directory itself. Page tables shared
+    /// with another page directory only lose a reference
+    pub unsafe fn free_page_tables(&self) {
+        for index in 0..1024 {
+            let entry = self.get_entry(index);
+            if entry.0 & PAGE_PRESENT != 0 && entry.0 & PAGE_LARGE == 0 &&
+                    index != RECURSIVE_PDE_INDEX {
+                PhysMem::free_phys(entry.get_paddr());
+            }
+            self.set_entry(index, 0);
+        }
+    }
+}
+
+/// Entries of a page table, read by the walks of `PageTables`
+pub trait TableEntries {
+    /// Get the PTE at `index`
+    fn get_entry(&self, index : usize) -> PageTableEntry;
+}
+
+/// A page directory and its page tables, as walked by `translate` and
+/// `mappings`. These only do the index math, the implementation reaches the
+/// entries, the recursive mapping or temporary slots for a `PageDirectory`
+pub trait PageTables : Sized {
+    /// A page table, kept while it is walked
+    type Table : TableEntries;
+
+    /// Return the physical address of the page directory
+    fn get_paddr(&self) -> PhysAddr;
+
+    /// Get the PDE at `index`
+    fn get_entry(&self, index : usize) -> PageDirectoryEntry;
+
+    /// Get the page table of the present entry `entry` at `index`
+    fn get_table(&self, index : usize, entry : &PageDirectoryEntry)
+        -> Self::Table;
+
+    /// Iterate over the present pages mapped in `[start, end)`, large pages
+    /// included, in increasing address order. The recursive mapping is
+    /// skipped
+    fn mappings(&self, start : VirtAddr, end : VirtAddr)
+            -> Mappings<'_, Self> {
+        Mappings {
+            pgd : self,
+            next : (start.0 & !0xfff) as u64,
+            end : end.0 as u64,
+            lazy : false,
+            table : None,
+        }
+    }
+
+    /// Translate a `vaddr` into its mapping components in the `self` page
+    /// directory. Returns `None` if the page directory entry is not present
+    fn translate(&self, vaddr : VirtAddr) -> Option<Mapping> {
         // Compute pde / pte indicies
         let pde_index = ((vaddr.0 >> 22) & 0x3ff) as usize;
         let pte_index = ((vaddr.0 >> 12) & 0x3ff) as usize;
-        
-        ret.pde = Some(PhysAddr(self.table.0 + 
-                                (pde_index * size_of::<u32>()) as u32));
 
-        // Get the pde
-        let pde = self.get_entry(pde_index);
-        ret.pte = Some(pde.get_paddr());
+        let pde_paddr = PhysAddr(self.get_paddr().0 +
+                                 (pde_index * size_of::<u32>()) as u32);
 
-        let ptb = PageTable::from_paddr(pde.get_paddr());
+        // Get the pde, a large page has no page table
+        let pde = self.get_entry(pde_index);
+        if pde.0 & PAGE_PRESENT == 0 {
+            return None;
+        }
+        if pde.0 & PAGE_LARGE != 0 {
+            return Some(Mapping {
+                pde   : pde_paddr,
+                pte   : None,
+                page  : Some(PhysAddr(pde.get_paddr().0 +
+                                      (pte_index * PAGE_SIZE) as u32)),
+                flags : pde.0 & 0xfff,
+            });
+        }
 
-        // Get the pte
+        // Get the pte, its address field is only meaningful if it is present
+        let ptb = self.get_table(pde_index, &pde);
         let pte = ptb.get_entry(pte_index);
-        ret.page = Some(pte.get_paddr());
-        
-        ret
+        let page = if pte.0 & PAGE_PRESENT != 0 {
+            Some(pte.get_paddr())
+        } else {
+            None
+        };
+
+        Some(Mapping {
+            pde   : pde_paddr,
+            pte   : Some(PhysAddr(pde.get_paddr().0 +
+                                  (pte_index * size_of::<u32>()) as u32)),
+            page,
+            flags : pte.0 & 0xfff,
+        })
+    }
+}
+
+impl PageTables for PageDirectory {
+    type Table = PageTable;
+
+    fn get_paddr(&self) -> PhysAddr {
+        self.table
+    }
+
+    fn get_entry(&self, index : usize) -> PageDirectoryEntry {
+        let entry = unsafe {
+            if self.is_current() {
+                let entries = PAGE_DIRECTORY_ADDR as *const u32;
+                core::ptr::read_volatile(entries.add(index))
+            } else {
+                let entries = map_temp(self.table) as *mut u32;
+                let entry = core::ptr::read_volatile(entries.add(index));
+                unmap_temp(entries as *mut u8);
+                entry
+            }
+        };
+        PageDirectoryEntry::new(entry)
+    }
+
+    fn get_table(&self, index : usize, entry : &PageDirectoryEntry)
+            -> PageTable {
+        if self.is_current() {
+            PageTable::current(index)
+        } else {
+            PageTable::from_paddr(entry.get_paddr())
+        }
+    }
+}
+
+/// Iterator over the mappings of a page directory, created by
+/// `PageTables::mappings`. Yields the virtual address, the physical
+/// address and the flags of each page, `PAGE_LARGE` telling the large ones
+/// apart. The page table being walked stays mapped between two calls, so a
+/// temporary slot is held for page directories that are not in use
+pub struct Mappings<'a, T : PageTables = PageDirectory> {
+    /// The page directory walked
+    pgd : &'a T,
+
+    /// Address of the next page to look at, past 32 bits at the end of the
+    /// address space
+    next : u64,
+
+    /// End of the range
+    end : u64,
+
+    /// Lazy page table entries are yielded too
+    lazy : bool,
+
+    /// The page table being walked and the index of its entry in the page
+    /// directory
+    table : Option<(usize, T::Table)>,
+}
+
+impl<'a, T : PageTables> Mappings<'a, T> {
+    /// Also yield the lazy page table entries, with their raw address bits
+    pub fn with_lazy(mut self) -> Self {
+        self.lazy = true;
+        self
+    }
+}
+
+impl<'a, T : PageTables> Iterator for Mappings<'a, T> {
+    type Item = (VirtAddr, PhysAddr, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.end {
+            let vaddr = self.next as u32;
+            let pde_index = (vaddr >> 22) as usize;
+            if pde_index >= RECURSIVE_PDE_INDEX {
+                break;
+            }
+
+            // Move to the page table of the next entry, releasing the
+            // previous one first
+            let walking = matches!(self.table, Some((index, _))
+                                   if index == pde_index);
+            if !walking {
+                self.table = None;
+                let entry = self.pgd.get_entry(pde_index);
+                let pde_vaddr = (pde_index << 22) as u32;
+                self.next = pde_vaddr as u64 + LARGE_PAGE_SIZE as u64;
+                if entry.0 & PAGE_PRESENT == 0 {
+                    continue;
+                }
+                if entry.0 & PAGE_LARGE != 0 {
+                    return Some((VirtAddr(pde_vaddr), entry.get_paddr(),
+                                 entry.0 & 0xfff));
+                }
+                let table = self.pgd.get_table(pde_index, &entry);
+                self.table = Some((pde_index, table));
+            }
+
+            self.next = vaddr as u64 + PAGE_SIZE as u64;
+            let pte = match &self.table {
+                Some((_, table)) => table.get_entry((vaddr >> 12) as usize
+                                                    & 0x3ff),
+                None => unreachable!(),
+            };
+            if pte.0 & PAGE_PRESENT != 0 ||
+                    (self.lazy && pte.0 & PAGE_LAZY != 0) {
+                return Some((VirtAddr(vaddr), PhysAddr(pte.0 & !0xfff),
+                             pte.0 & 0xfff));
+            }
+        }
+
+        self.next = self.end;
+        self.table = None;
+        None
     }
 }
 
 /// A Page Table
 pub struct PageTable {
-    table : PhysAddr,
+    /// The entries of the table, through the recursive mapping or a
+    /// temporary slot
+    entries : *mut u32,
+
+    /// The entries are mapped in a temporary slot, released on drop
+    temp : bool,
 }
 
 impl PageTable {
-    /// Create a `PageTable` from `paddr`
+    /// Map the `PageTable` at `paddr` in a temporary slot
     fn from_paddr(paddr : PhysAddr) -> Self {
         Self {
-            table : paddr, 
+            entries : unsafe { map_temp(paddr) } as *mut u32,
+            temp : true,
+        }
+    }
+
+    /// Get the page table of the entry at `pde_index` of the page directory
+    /// in use, through the recursive mapping
+    fn current(pde_index : usize) -> Self {
+        Self {
+            entries : (PAGE_TABLES_BASE + (pde_index * PAGE_SIZE) as u32)
+                as *mut u32,
+            temp : false,
         }
     }
 
     /// Update the entry at `index` to make it `entry`
     fn set_entry(&self, index : usize, entry : u32) {
-        let entry_paddr = PhysAddr(self.table.0 + 
-                                   (index * size_of::<u32>()) as u32);
-        let entry_vaddr = PhysMem::translate(entry_paddr, size_of::<u32>());
         unsafe {
-            core::ptr::write(entry_vaddr as *mut u32, entry);
+            core::ptr::write_volatile(self.entries.add(index), entry);
         }
     }
-    
-    /// Get the PTE at `index`
+}
+
+impl TableEntries for PageTable {
     fn get_entry(&self, index : usize) -> PageTableEntry {
-        let entry_paddr = PhysAddr(self.table.0 + 
-                                   (index * size_of::<u32>()) as u32);
-        let entry_vaddr = PhysMem::translate(entry_paddr, size_of::<u32>());
         let entry = unsafe {
-            PageTableEntry::new(core::ptr::read(entry_vaddr as *const u32))
+            core::ptr::read_volatile(self.entries.add(index))
         };
-        entry
+        PageTableEntry::new(entry)
+    }
+}
+
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        if self.temp {
+            unsafe { unmap_temp(self.entries as *mut u8); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Page directory at `PGD` and page tables kept in host memory, by
+    /// physical address
+    struct TestTables {
+        pages : HashMap<u32, Vec<u32>>,
+    }
+
+    const PGD : u32 = 0x1000;
+
+    impl TestTables {
+        fn new() -> Self {
+            let mut tables = Self { pages : HashMap::new() };
+            tables.set(PGD, RECURSIVE_PDE_INDEX, PGD | PAGE_PRESENT);
+            tables
+        }
+
+        /// Set the entry `index` of the table at `table`
+        fn set(&mut self, table : u32, index : usize, entry : u32) {
+            self.pages.entry(table).or_insert_with(|| vec![0; 1024])[index] =
+                entry;
+        }
+    }
+
+    struct TestTable(Vec<u32>);
+
+    impl TableEntries for TestTable {
+        fn get_entry(&self, index : usize) -> PageTableEntry {
+            PageTableEntry::new(self.0[index])
+        }
+    }
+
+    impl PageTables for TestTables {
+        type Table = TestTable;
+
+        fn get_paddr(&self) -> PhysAddr {
+            PhysAddr(PGD)
+        }
+
+        fn get_entry(&self, index : usize) -> PageDirectoryEntry {
+            PageDirectoryEntry::new(self.pages[&PGD][index])
+        }
+
+        fn get_table(&self, _index : usize, entry : &PageDirectoryEntry)
+                -> TestTable {
+            TestTable(self.pages[&entry.get_paddr().0].clone())
+        }
+    }
+
+    /// A page table at 0x2000 for 0x40_0000, mapping 0x40_5000 and a lazy
+    /// page at 0x40_6000, and a large page at 0x80_0000
+    fn tables() -> TestTables {
+        let mut tables = TestTables::new();
+        tables.set(PGD, 1, 0x2000 | PAGE_PRESENT | PAGE_WRITE | PAGE_USER);
+        tables.set(0x2000, 5, 0x5000 | PAGE_PRESENT | PAGE_WRITE);
+        tables.set(0x2000, 6, PAGE_LAZY | PAGE_USER);
+        tables.set(PGD, 2, 0x40_0000 | PAGE_PRESENT | PAGE_LARGE);
+        tables
+    }
+
+    fn all(mappings : Mappings<'_, TestTables>) -> Vec<(u32, u32, u32)> {
+        mappings.map(|(vaddr, paddr, flags)| (vaddr.0, paddr.0, flags))
+            .collect()
+    }
+
+    #[test]
+    fn translate_page() {
+        let mapping = tables().translate(VirtAddr(0x40_5123)).unwrap();
+        assert_eq!(mapping.pde.0, PGD + 4);
+        assert_eq!(mapping.pte.map(|pte| pte.0), Some(0x2000 + 5 * 4));
+        assert_eq!(mapping.page.map(|page| page.0), Some(0x5000));
+        assert_eq!(mapping.flags, PAGE_PRESENT | PAGE_WRITE);
+    }
+
+    #[test]
+    fn translate_missing_page() {
+        let tables = tables();
+        assert!(tables.translate(VirtAddr(0x1000)).is_none());
+
+        // The page table is there, the page is not
+        let mapping = tables.translate(VirtAddr(0x40_6000)).unwrap();
+        assert_eq!(mapping.pte.map(|pte| pte.0), Some(0x2000 + 6 * 4));
+        assert!(mapping.page.is_none());
+        assert_eq!(mapping.flags, PAGE_LAZY | PAGE_USER);
+    }
+
+    #[test]
+    fn translate_large_page() {
+        let mapping = tables().translate(VirtAddr(0x80_3abc)).unwrap();
+        assert_eq!(mapping.pde.0, PGD + 8);
+        assert!(mapping.pte.is_none());
+        assert_eq!(mapping.page.map(|page| page.0), Some(0x40_3000));
+        assert_eq!(mapping.flags, PAGE_PRESENT | PAGE_LARGE);
+    }
+
+    #[test]
+    fn mappings_in_order() {
+        let tables = tables();
+        assert_eq!(all(tables.mappings(VirtAddr(0), VirtAddr(u32::MAX))),
+                   [(0x40_5000, 0x5000, PAGE_PRESENT | PAGE_WRITE),
+                    (0x80_0000, 0x40_0000, PAGE_PRESENT | PAGE_LARGE)]);
+        assert_eq!(all(tables.mappings(VirtAddr(0), VirtAddr(u32::MAX))
+                       .with_lazy())[1],
+                   (0x40_6000, 0, PAGE_LAZY | PAGE_USER));
+    }
+
+    #[test]
+    fn mappings_in_range() {
+        let tables = tables();
+        assert_eq!(all(tables.mappings(VirtAddr(0x40_5800),
+                                       VirtAddr(0x40_6000))),
+                   [(0x40_5000, 0x5000, PAGE_PRESENT | PAGE_WRITE)]);
+        assert_eq!(all(tables.mappings(VirtAddr(0x40_6000),
+                                       VirtAddr(0x80_0000))), []);
+    }
+
+    #[test]
+    fn mappings_skip_the_recursive_mapping() {
+        let mut tables = tables();
+        tables.set(PGD, RECURSIVE_PDE_INDEX - 1,
+                   0xc0_0000 | PAGE_PRESENT | PAGE_LARGE);
+        let last = all(tables.mappings(VirtAddr(0xff00_0000),
+                                       VirtAddr(u32::MAX)));
+        assert_eq!(last, [(0xff80_0000, 0xc0_0000,
+                           PAGE_PRESENT | PAGE_LARGE)]);
     }
 }
@@ -1,69 +1,482 @@
 //! Interactions with physical memory
-//! Bitmap-based physical page allocator
+//! Physical page allocator counting the references to each page. The
+//! references and the free lists only change with interrupts disabled
 
+use core::convert::TryFrom;
+use super::buddy::*;
 use super::pagemem::{PhysAddr, PAGE_SIZE};
 use super::*;
+use crate::multiboot::*;
+use crate::sync::{InterruptGuard, PreemptGuard};
 
+/// Number of pages the allocator can manage, from `PHYS_ALLOCATOR_BASE`
 /// Size calculation : (0x7fe0000 - 0x400000) / 4096
 /// (MAX_USABLE_ADDR - BASE_ALLOCATOR) / PAGE_SIZE
 const BITMAP_SIZE : usize = 0x7be0;
 
-/// A 0 represent a free page, a 1 represent a used page
+/// Number of references to each page, a 0 represent a free page. Pages
+/// shared copy-on-write by forked address spaces have several references
 static mut ALLOCATOR_BITMAP : [u8; BITMAP_SIZE] = [0; BITMAP_SIZE];
 
+/// Order plus one of the free block of the buddy allocator starting at each
+/// page, 0 if none does
+static mut FREE_ORDERS : [u8; BITMAP_SIZE] = [0; BITMAP_SIZE];
+
+/// Free lists of the buddy allocator, linked through the first words of the
+/// free pages, reached in the physical memory window
+struct WindowBlocks;
+
+impl WindowBlocks {
+    /// Get the links stored in the free page at `index`
+    fn links_ptr(index : usize) -> *mut [usize; 2] {
+        PhysMem::translate_mut(PhysMem::page_addr(index), PAGE_SIZE)
+            .expect("Free block outside of RAM") as *mut [usize; 2]
+    }
+}
+
+impl FreeBlocks for WindowBlocks {
+    fn links(&self, index : usize) -> (usize, usize) {
+        let [next, prev] = unsafe {
+            core::ptr::read_volatile(Self::links_ptr(index))
+        };
+        (next, prev)
+    }
+
+    fn set_links(&mut self, index : usize, next : usize, prev : usize) {
+        unsafe {
+            core::ptr::write_volatile(Self::links_ptr(index), [next, prev]);
+        }
+    }
+
+    fn free_order(&self, index : usize) -> Option<usize> {
+        match unsafe { FREE_ORDERS[index] } {
+            0 => None,
+            order => Some(order as usize - 1),
+        }
+    }
+
+    fn set_free_order(&mut self, index : usize, order : Option<usize>) {
+        unsafe {
+            FREE_ORDERS[index] = order.map_or(0, |order| order as u8 + 1);
+        }
+    }
+}
+
+/// Zones of physical memory, each with its own allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below `DMA_ZONE_END`, reachable by the legacy DMA of ISA devices
+    Dma,
+
+    /// Above `DMA_ZONE_END`, for every other use
+    Normal,
+}
+
+/// Number of pages of the allocator in the DMA zone
+const DMA_ZONE_PAGES : usize = (DMA_ZONE_END - PHYS_ALLOCATOR_BASE) / PAGE_SIZE;
+
+// Buddies never straddle the zones
+const _ : () = assert!(DMA_ZONE_PAGES.is_multiple_of(1 << MAX_ORDER));
+
+/// Buddy allocators of the pages of each zone, indexed from
+/// `PHYS_ALLOCATOR_BASE`. They are empty until `PhysMem::init` gives them
+/// the memory of the boot loader map
+static mut ZONES : [Buddy<WindowBlocks>; 2] = [
+    Buddy::new(WindowBlocks, BITMAP_SIZE),
+    Buddy::new(WindowBlocks, BITMAP_SIZE),
+];
+
+/// Get the allocator of `zone`
+unsafe fn zone_buddy(zone : Zone) -> &'static mut Buddy<WindowBlocks> {
+    &mut ZONES[zone as usize]
+}
+
+/// Get the allocator of the page at `index`
+unsafe fn page_buddy(index : usize) -> &'static mut Buddy<WindowBlocks> {
+    zone_buddy(if index < DMA_ZONE_PAGES { Zone::Dma } else { Zone::Normal })
+}
+
+/// Max number of RAM ranges of the boot loader map that are remembered
+const MAX_RAM_RANGES : usize = 32;
+
+/// Start and end of the RAM ranges found by `PhysMem::init`, of which there
+/// are `RAM_RANGE_COUNT`. The memory outside of them is device memory
+static mut RAM_RANGES : [(u64, u64); MAX_RAM_RANGES] = [(0, 0); MAX_RAM_RANGES];
+static mut RAM_RANGE_COUNT : usize = 0;
+
+/// Errors of `PhysMem::translate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslateError {
+    /// The physical address is 0, most likely one never set
+    Null,
+
+    /// The range wraps around the end of the physical address space
+    Overflow,
+
+    /// The range goes past the end of the physical memory window
+    OutsideWindow,
+
+    /// The range is not in the RAM found in the boot loader map
+    NotRam,
+}
+
+/// Check that the `size` bytes at `paddr` are a non-null range fitting in a
+/// physical memory window of `window_size` bytes. Returns the end of the
+/// range. An empty range may end right at the end of the window
+pub fn window_range(paddr : u32, size : usize, window_size : u32)
+        -> Result<u32, TranslateError> {
+    if paddr == 0 {
+        return Err(TranslateError::Null);
+    }
+    let end = u32::try_from(size).ok()
+        .and_then(|size| paddr.checked_add(size))
+        .ok_or(TranslateError::Overflow)?;
+    if end > window_size {
+        return Err(TranslateError::OutsideWindow);
+    }
+    Ok(end)
+}
+
+/// The physical allocator has no free page left for the allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfMemory;
+
+/// Page counts of the physical allocator, returned by `PhysMem::stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysStats {
+    /// Number of pages managed by the allocator
+    pub total : usize,
+
+    /// Number of pages without any reference
+    pub free : usize,
+
+    /// Number of allocated pages, shared ones count once
+    pub used : usize,
+}
+
 /// Empty struct representing physical memory
 pub struct PhysMem;
 
 impl PhysMem {
+    /// Give the RAM available in the memory map of the boot loader to the
+    /// allocator, from `PHYS_ALLOCATOR_BASE` up to the pages it can manage.
+    /// Without a memory map, all of these pages are assumed to be RAM. Each
+    /// zone gets the pages of its range. Must be called before any
+    /// allocation
+    pub unsafe fn init(info : &MultibootInfo) {
+        let base = PHYS_ALLOCATOR_BASE as u64;
+        let limit = base + (BITMAP_SIZE * PAGE_SIZE) as u64;
+        let page = PAGE_SIZE as u64;
+
+        let mut found = false;
+        for_each_mmap_entry(info, |addr, len, ty| {
+            found = true;
+            if ty == MMAP_AVAILABLE && RAM_RANGE_COUNT < MAX_RAM_RANGES {
+                RAM_RANGES[RAM_RANGE_COUNT] = (addr, addr + len);
+                RAM_RANGE_COUNT += 1;
+            }
+
+            let start = ((addr + page - 1) & !(page - 1)).max(base);
+            let end = ((addr + len) & !(page - 1)).min(limit);
+            if ty == MMAP_AVAILABLE && start < end {
+                Self::add_range(((start - base) / page) as usize,
+                                ((end - base) / page) as usize);
+            }
+        });
+        if !found {
+            RAM_RANGES[0] = (0, limit);
+            RAM_RANGE_COUNT = 1;
+            Self::add_range(0, BITMAP_SIZE);
+        }
+    }
+
+    /// Returns true if the `size` bytes at `paddr` overlap RAM found in the
+    /// memory map of the boot loader
+    pub fn is_ram(paddr : PhysAddr, size : usize) -> bool {
+        let start = paddr.0 as u64;
+        let end = start + size as u64;
+        let ranges = unsafe { &RAM_RANGES[..RAM_RANGE_COUNT] };
+        ranges.iter().any(|&(base, limit)| start < limit && end > base)
+    }
+
+    /// Give the pages `[start, end)` to the allocators of their zones
+    unsafe fn add_range(start : usize, end : usize) {
+        let split = end.min(DMA_ZONE_PAGES).max(start);
+        if start < split {
+            zone_buddy(Zone::Dma).add_range(start, split);
+        }
+        if split < end {
+            zone_buddy(Zone::Normal).add_range(split, end);
+        }
+    }
+
     /// Allocate a page of physical memory. Returns the `PhysAddr` of 
     /// allocated page. Panics if no memory is available
     pub unsafe fn alloc_phys() -> PhysAddr {
-        for (i, &page) in ALLOCATOR_BITMAP.iter().enumerate() {
-            if page == 0 {
-                ALLOCATOR_BITMAP[i] = 1;
-                return PhysAddr((PHYS_ALLOCATOR_BASE + i * PAGE_SIZE) as u32);
+        Self::try_alloc_phys().expect("Out of memory")
+    }
+
+    /// Same as `alloc_phys` but fails if no memory is available. The page
+    /// is from the normal zone
+    pub unsafe fn try_alloc_phys() -> Result<PhysAddr, OutOfMemory> {
+        Self::alloc_order(0)
+    }
+
+    /// Allocate a page of physical memory from `zone` only, there is no
+    /// fallback to another zone. Fails if the zone has no free page
+    pub unsafe fn alloc_phys_zone(zone : Zone)
+            -> Result<PhysAddr, OutOfMemory> {
+        Self::alloc_order_zone(0, zone)
+    }
+
+    /// Allocate 2^`order` contiguous pages of physical memory aligned on
+    /// their size, `order` being at most `MAX_ORDER`. Returns the `PhysAddr`
+    /// of the first page. Each page gets a reference, so they can be freed
+    /// one by one with `free_phys` or all together with `free_order`. The
+    /// pages are from the normal zone
+    pub unsafe fn alloc_order(order : usize) -> Result<PhysAddr, OutOfMemory> {
+        Self::alloc_order_zone(order, Zone::Normal)
+    }
+
+    /// Same as `alloc_order` but the pages are from `zone` only
+    pub unsafe fn alloc_order_zone(order : usize, zone : Zone)
+            -> Result<PhysAddr, OutOfMemory> {
+        let _guard = InterruptGuard::new();
+        if order > MAX_ORDER {
+            return Err(OutOfMemory);
+        }
+        let index = zone_buddy(zone).alloc(order).ok_or(OutOfMemory)?;
+        for page in index..index + (1 << order) {
+            if ALLOCATOR_BITMAP[page] != 0 {
+                panic!("Allocating used page : {:#x}",
+                       Self::page_addr(page).0);
             }
+            ALLOCATOR_BITMAP[page] = 1;
         }
-        panic!("Out of memory");
+        Ok(Self::page_addr(index))
+    }
+
+    /// Free the 2^`order` pages at `addr` allocated by `alloc_order`. Each
+    /// page must have a single reference
+    pub unsafe fn free_order(addr : PhysAddr, order : usize) {
+        let _guard = InterruptGuard::new();
+        let index = Self::page_index(addr);
+        for page in index..index + (1 << order) {
+            if ALLOCATOR_BITMAP[page] != 1 {
+                panic!("Freeing page {:#x} of a block with {} references",
+                       Self::page_addr(page).0, ALLOCATOR_BITMAP[page]);
+            }
+            ALLOCATOR_BITMAP[page] = 0;
+        }
+        page_buddy(index).free(index, order);
+    }
+
+    /// Allocate `npages` contiguous pages of physical memory. Returns the
+    /// `PhysAddr` of the first page, or fails if no such range is free. The
+    /// pages past `npages` of the block holding them are freed right away
+    pub unsafe fn try_alloc_phys_contiguous(npages : usize)
+            -> Result<PhysAddr, OutOfMemory> {
+        let _guard = PreemptGuard::new();
+        let order = order_for_pages(npages);
+        let paddr = Self::alloc_order(order)?;
+        for page in npages..1 << order {
+            Self::free_phys(PhysAddr(paddr.0 + (page * PAGE_SIZE) as u32));
+        }
+        Ok(paddr)
     }
 
     /// Same as `alloc_page` but memory will be zeroed
     pub unsafe fn alloc_phys_zeroed() -> PhysAddr {
-        let page = Self::alloc_phys();
-        core::ptr::write_bytes(page.0 as *mut u8, 0, PAGE_SIZE);
-        page
+        Self::try_alloc_phys_zeroed().expect("Out of memory")
+    }
+
+    /// Same as `alloc_phys_zeroed` but fails if no memory is available
+    pub unsafe fn try_alloc_phys_zeroed() -> Result<PhysAddr, OutOfMemory> {
+        let page = Self::try_alloc_phys()?;
+        let alias = Self::translate_mut(page, PAGE_SIZE)
+            .expect("Allocated page outside of RAM");
+        core::ptr::write_bytes(alias, 0, PAGE_SIZE);
+        Ok(page)
     }
 
-    /// Free page of physical memory at `addr`
+    /// Drop a reference to the page of physical memory at `addr`, the page
+    /// is free once it has no reference left
     pub unsafe fn free_phys(addr : PhysAddr) {
+        let _guard = InterruptGuard::new();
+        let index = Self::page_index(addr);
+        if ALLOCATOR_BITMAP[index] == 0 {
+            panic!("Freeing non-allocated page : {:#x} at index {:#x}", 
+                   addr.0, index);
+        }
+
+        ALLOCATOR_BITMAP[index] -= 1;
+        if ALLOCATOR_BITMAP[index] == 0 {
+            page_buddy(index).free(index, 0);
+        }
+    }
+
+    /// Add a reference to the allocated page at `addr`, which must then be
+    /// freed once more. Returns false if the page has too many references
+    pub unsafe fn share_phys(addr : PhysAddr) -> bool {
+        let _guard = InterruptGuard::new();
+        let index = Self::page_index(addr);
+        match ALLOCATOR_BITMAP[index] {
+            0 => panic!("Sharing non-allocated page : {:#x}", addr.0),
+            u8::MAX => false,
+            _ => {
+                ALLOCATOR_BITMAP[index] += 1;
+                true
+            }
+        }
+    }
+
+    /// Get the number of references to the page at `addr`
+    pub fn refcount(addr : PhysAddr) -> u8 {
+        unsafe { ALLOCATOR_BITMAP[Self::page_index(addr)] }
+    }
+
+    /// Get the address of the page at `index` in the allocator
+    fn page_addr(index : usize) -> PhysAddr {
+        PhysAddr((PHYS_ALLOCATOR_BASE + index * PAGE_SIZE) as u32)
+    }
+
+    /// Get the index in the allocator of the page at `addr`
+    fn page_index(addr : PhysAddr) -> usize {
         if addr.0 & 0xfff != 0 {
-            panic!("Freeing non-aligned address : {:#x}", addr.0);
+            panic!("Non-aligned page address : {:#x}", addr.0);
         }
 
-        let index = ((addr.0 - PHYS_ALLOCATOR_BASE as u32) >> 12) as usize;
-        if index > BITMAP_SIZE || addr.0 < PHYS_ALLOCATOR_BASE as u32 {
-            panic!("Freeing a page outside the bounds of the allocator : {:#x}",
+        let index = (addr.0 as usize).wrapping_sub(PHYS_ALLOCATOR_BASE) >> 12;
+        if index >= BITMAP_SIZE || (addr.0 as usize) < PHYS_ALLOCATOR_BASE {
+            panic!("Page outside the bounds of the allocator : {:#x}", 
                    addr.0); 
         }
-        if ALLOCATOR_BITMAP[index] != 1 {
-            panic!("Freeing non-allocated page : {:#x} at index {:#x}", 
-                   addr.0, index);
+        index
+    }
+
+    /// Get the number of free pages of physical memory
+    pub fn free_pages() -> usize {
+        Self::stats().free
+    }
+
+    /// Get the number of free pages of physical memory in `zone`
+    pub fn zone_free_pages(zone : Zone) -> usize {
+        unsafe { zone_buddy(zone).free_pages() }
+    }
+
+    /// Get the page counts of the allocator. They are maintained by the
+    /// allocations and frees, so this doesn't walk the allocator
+    pub fn stats() -> PhysStats {
+        let (total, free) = unsafe {
+            ZONES.iter().fold((0, 0), |(total, free), buddy| {
+                (total + buddy.pages(), free + buddy.free_pages())
+            })
+        };
+        PhysStats {
+            total,
+            free,
+            used : total - free,
+        }
+    }
+
+    /// Panic if the page counts don't match the references of the allocator
+    /// and the free lists of the buddy allocator, which are counted again
+    pub fn check_stats() {
+        let _guard = PreemptGuard::new();
+        let used = unsafe {
+            ALLOCATOR_BITMAP.iter().filter(|&&page| page != 0).count()
+        };
+        let stats = Self::stats();
+        if stats.used != used {
+            panic!("Physical allocator counts {} used pages but {} are \
+                    referenced", stats.used, used);
         }
 
-        ALLOCATOR_BITMAP[index] = 0;
+        let mut listed = 0;
+        for buddy in unsafe { ZONES.iter() } {
+            for order in 0..=MAX_ORDER {
+                listed += buddy.free_blocks(order) << order;
+            }
+        }
+        if stats.free != listed {
+            panic!("Physical allocator counts {} free pages but {} are in \
+                    the free lists", stats.free, listed);
+        }
+    }
+
+    /// Copy the content of the physical page `src` to the physical page `dst`
+    pub unsafe fn copy_page(dst : PhysAddr, src : PhysAddr) {
+        let src = Self::translate(src, PAGE_SIZE)
+            .expect("Copying a page from outside of RAM");
+        let dst = Self::translate_mut(dst, PAGE_SIZE)
+            .expect("Copying a page to outside of RAM");
+        core::ptr::copy_nonoverlapping(src, dst, PAGE_SIZE);
     }
 
     /// Provides a virtual address for `size` bytes of physical memory at 
-    /// `paddr`
+    /// `paddr`, in the window. Fails if the range is not in the window or
+    /// not in RAM
     pub fn translate(paddr : PhysAddr, size : usize) 
-            -> *const u8 {
-        // Make sure the requested data fits inside the window
-        if paddr.0 + (size as u32) > KERNEL_PHYS_WINDOW_SIZE {
-            panic!("Trying to translate paddr {:#x} but size ({:#x}) too big",
-                paddr.0, size);
+            -> Result<*const u8, TranslateError> {
+        let end = window_range(paddr.0, size, KERNEL_PHYS_WINDOW_SIZE)?;
+        let ranges = unsafe { &RAM_RANGES[..RAM_RANGE_COUNT] };
+        let in_ram = ranges.iter().any(|&(base, limit)| {
+            paddr.0 as u64 >= base && end as u64 <= limit
+        });
+        if !in_ram {
+            return Err(TranslateError::NotRam);
         }
+        Ok((paddr.0 + KERNEL_PHYS_WINDOW_BASE) as *const u8)
+    }
 
-        (paddr.0 + KERNEL_PHYS_WINDOW_BASE) as *const u8
+    /// Same as `translate`, for memory written through the window
+    pub fn translate_mut(paddr : PhysAddr, size : usize)
+            -> Result<*mut u8, TranslateError> {
+        Self::translate(paddr, size).map(|alias| alias as *mut u8)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW : u32 = 0x3000_0000;
+
+    #[test]
+    fn window_range_rejects_null() {
+        assert_eq!(window_range(0, PAGE_SIZE, WINDOW),
+                   Err(TranslateError::Null));
+        assert_eq!(window_range(0, 0, WINDOW), Err(TranslateError::Null));
+    }
+
+    #[test]
+    fn window_range_ends_at_the_window_end() {
+        let last = WINDOW - PAGE_SIZE as u32;
+        assert_eq!(window_range(last, PAGE_SIZE, WINDOW), Ok(WINDOW));
+        assert_eq!(window_range(last, PAGE_SIZE + 1, WINDOW),
+                   Err(TranslateError::OutsideWindow));
+        assert_eq!(window_range(WINDOW, 1, WINDOW),
+                   Err(TranslateError::OutsideWindow));
+    }
+
+    #[test]
+    fn window_range_empty() {
+        assert_eq!(window_range(0x1000, 0, WINDOW), Ok(0x1000));
+        assert_eq!(window_range(WINDOW, 0, WINDOW), Ok(WINDOW));
+        assert_eq!(window_range(WINDOW + 1, 0, WINDOW),
+                   Err(TranslateError::OutsideWindow));
+    }
+
+    #[test]
+    fn window_range_overflow() {
+        assert_eq!(window_range(u32::MAX, 0, u32::MAX), Ok(u32::MAX));
+        assert_eq!(window_range(u32::MAX, 1, u32::MAX),
+                   Err(TranslateError::Overflow));
+        assert_eq!(window_range(0xffff_f000, 0xfff, u32::MAX), Ok(u32::MAX));
+        assert_eq!(window_range(0xffff_f000, PAGE_SIZE, u32::MAX),
+                   Err(TranslateError::Overflow));
+        assert_eq!(window_range(1, u32::MAX as usize, u32::MAX),
+                   Err(TranslateError::Overflow));
+    }
+}
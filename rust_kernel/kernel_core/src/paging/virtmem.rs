@@ -3,7 +3,145 @@
 use super::pagemem::*;
 use super::*;
 use super::physmem::*;
-use crate::cpu::get_cr3;
+use crate::cpu::{get_cr3, invlpg};
+use crate::{print, println, PERIPHERALS};
+
+/// Errors that can happen when modifying a virtual address space
+#[derive(Debug)]
+pub enum MappingError {
+    /// The requested range overlaps the kernel identity mapping
+    KernelRange,
+
+    /// A page in the requested range is not mapped
+    NotMapped,
+
+    /// A page in the requested range is already allocated
+    InUse,
+
+    /// The requested range is not in the dynamic allocations area
+    OutOfRange,
+
+    /// A page is already mapped in the requested range, by this page table
+    /// entry
+    AlreadyMapped(u32),
+
+    /// The requested physical range overlaps RAM, not device memory
+    Ram,
+
+    /// A page in the requested range maps memory it doesn't own, borrowed
+    /// or shared, which must not be freed with it
+    NotOwned,
+
+    /// There is no physical memory or free virtual range left for the
+    /// mapping
+    OutOfMemory,
+}
+
+/// Returns true if `[start, end)` overlaps the virtual memory used by the
+/// kernel
+pub fn overlaps_kernel_space(start : u32, end : u32) -> bool {
+    let overlaps = |base : u32, size : u32| start < base + size && end > base;
+    overlaps(KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE) ||
+        overlaps(0, KERNEL_IMAGE_MAP_SIZE) ||
+        overlaps(KERNEL_VMEM_BASE, KERNEL_VMEM_SIZE) ||
+        overlaps(KERNEL_VMEM_ALLOCATOR_BITMAP,
+                 (KERNEL_VMEM_BITMAP_PAGES * PAGE_SIZE) as u32) ||
+        overlaps(VSYS_PAGE_ADDR, PAGE_SIZE as u32) ||
+        end > TEMP_MAP_BASE
+}
+
+/// Returns true if `[start, end)` overlaps memory that userland can't map:
+/// outside of the user space, or a kernel area inside of it. An empty range
+/// overlaps nothing
+pub fn is_kernel_range(start : u32, end : u32) -> bool {
+    start < end && (start < USER_SPACE_BASE || end > USER_SPACE_END ||
+                    overlaps_kernel_space(start, end))
+}
+
+/// Returns true if `[start, end)` is a non-empty range that userland may map
+pub fn is_user_range(start : u32, end : u32) -> bool {
+    start < end && !is_kernel_range(start, end)
+}
+
+/// Returns true if `vaddr` is in the identity mapping of the physical memory
+pub fn in_phys_window(vaddr : VirtAddr) -> bool {
+    let window_end = KERNEL_PHYS_WINDOW_BASE + KERNEL_PHYS_WINDOW_SIZE;
+    vaddr.0 >= KERNEL_PHYS_WINDOW_BASE && vaddr.0 < window_end
+}
+
+/// Returns true if `vaddr` is in the identity mapping of the kernel image
+pub fn in_kernel_image(vaddr : VirtAddr) -> bool {
+    vaddr.0 < KERNEL_IMAGE_MAP_SIZE
+}
+
+/// Returns true if `vaddr` is in the identity mapping of the physical memory
+/// or of the kernel image, which are mapped by whole page directory entries
+pub fn is_kernel_identity(vaddr : VirtAddr) -> bool {
+    in_phys_window(vaddr) || in_kernel_image(vaddr)
+}
+
+/// Returns true if `vaddr` is in the temporary slots or in the recursive
+/// mapping of the paging structures. Their page tables have user entries
+pub fn in_paging_area(vaddr : VirtAddr) -> bool {
+    vaddr.0 >= TEMP_MAP_BASE
+}
+
+/// Returns true if the user page at `vaddr` belongs to the kernel: the
+/// identity mapping of the user code, or the info page. Userland can't unmap
+/// or change such pages
+pub fn is_kernel_owned(vaddr : VirtAddr) -> bool {
+    in_phys_window(vaddr) || in_kernel_image(vaddr) ||
+        in_paging_area(vaddr) || vaddr.0 & !0xfff == VSYS_PAGE_ADDR
+}
+
+/// Returns true if the page at `vaddr` mapped by `pte` is private to its
+/// address space: a present user page not owned by the kernel that is not
+/// shared, nor the zero page. `fork` shares such pages copy-on-write, and
+/// they are freed with the task
+pub fn is_private_page(vaddr : VirtAddr, pte : u32) -> bool {
+    pte & PAGE_PRESENT != 0 && pte & PAGE_USER != 0 && 
+        pte & (PAGE_SHARED | PAGE_BORROWED) == 0 && !is_kernel_owned(vaddr) &&
+        !is_zero_page(pte)
+}
+
+/// Zeroed physical page mapped read-only by every lazy page that was read
+/// but never written to. It is never freed, and its reference count doesn't
+/// change when it is mapped
+static mut ZERO_PAGE : PhysAddr = PhysAddr(0);
+
+/// Allocate the zero page. Must be called before any lazy page is accessed
+pub fn zero_page_init() {
+    unsafe { ZERO_PAGE = PhysMem::alloc_phys_zeroed(); }
+}
+
+/// Returns true if the present page table entry `pte` maps the zero page
+pub fn is_zero_page(pte : u32) -> bool {
+    pte & PAGE_PRESENT != 0 && pte & !0xfff == unsafe { ZERO_PAGE.0 }
+}
+
+/// Print the run of mappings `[vstart, vend)` starting at physical address
+/// `pstart` with `flags`, for `VirtMem::dump`
+fn dump_run(vstart : u32, vend : u32, pstart : u32, flags : u32) {
+    const LETTERS : [(u32, char); 5] = [
+        (PAGE_PRESENT, 'P'), (PAGE_WRITE, 'W'), (PAGE_USER, 'U'),
+        (PAGE_GLOBAL, 'G'), (PAGE_LARGE, 'L'),
+    ];
+    print!("{:#010x}-{:#010x} -> {:#010x}-{:#010x} ", vstart, vend, pstart,
+           pstart + (vend - vstart));
+    for (flag, letter) in LETTERS.iter() {
+        print!("{}", if flags & flag != 0 { *letter } else { '-' });
+    }
+    if flags & PAGE_COW != 0 {
+        print!(" cow");
+    }
+    if flags & PAGE_SHARED != 0 {
+        print!(" shared");
+    }
+    if flags & PAGE_BORROWED != 0 {
+        print!(" borrowed");
+    }
+    println!(" ({} pages)", (vend - vstart) as usize / PAGE_SIZE);
+}
 
 /// A virtual address space 
 pub struct VirtMem {
@@ -11,8 +149,41 @@ pub struct VirtMem {
     pgd : PageDirectory,
 
     /// The virtual allocator bitmap associated with this virtual address
-    /// space
-    allocator_bitmap : &'static mut [u8; PAGE_SIZE],
+    /// space, accessed through the physical memory window so that it can be
+    /// used when the address space is not the one in use. A set bit is an
+    /// allocated page of the dynamic allocations area
+    allocator_bitmap : &'static mut [u32; BITMAP_WORDS],
+}
+
+/// Number of pages tracked by each word of the allocator bitmap
+const PAGES_PER_WORD : usize = 32;
+
+/// Number of words in the allocator bitmap
+const BITMAP_WORDS : usize = KERNEL_VMEM_PAGES / PAGES_PER_WORD;
+
+/// Get the allocator bitmap stored in the contiguous physical pages at
+/// `paddr`
+fn bitmap_from_paddr(paddr : PhysAddr) -> &'static mut [u32; BITMAP_WORDS] {
+    let size = KERNEL_VMEM_BITMAP_PAGES * PAGE_SIZE;
+    unsafe {
+        let alias = PhysMem::translate_mut(paddr, size)
+            .expect("Allocator bitmap outside of RAM");
+        &mut *(alias as *mut [u32; BITMAP_WORDS])
+    }
+}
+
+/// Free the contiguous physical pages of the allocator bitmap at `paddr`
+unsafe fn free_bitmap_pages(paddr : PhysAddr) {
+    for page in 0..KERNEL_VMEM_BITMAP_PAGES {
+        let offset = (page * PAGE_SIZE) as u32;
+        PhysMem::free_phys(PhysAddr(paddr.0 + offset));
+    }
+}
+
+/// Free the page tables of `pgd`, then the page directory itself
+unsafe fn free_page_directory(pgd : &PageDirectory) {
+    pgd.free_page_tables();
+    PhysMem::free_phys(pgd.get_paddr());
 }
 
 impl core::fmt::Debug for VirtMem {
@@ -22,32 +193,58 @@ impl core::fmt::Debug for VirtMem {
 }
 
 impl VirtMem {
-    /// Create a new `VirtMem`
-    pub fn new() -> Self {
-        let pgd = PageDirectory::new();
-        let bitmap = unsafe { PhysMem::alloc_phys_zeroed() };
-        unsafe { pgd.map_raw(VirtAddr(KERNEL_VMEM_ALLOCATOR_BITMAP), 
-                    bitmap.0 | PAGE_PRESENT | PAGE_WRITE); }
-        let bitmap = unsafe { 
-            core::mem::transmute::<u32, &mut [u8; PAGE_SIZE]>
-                (KERNEL_VMEM_ALLOCATOR_BITMAP)
+    /// Create a new `VirtMem`. Without memory for it, what was allocated is
+    /// freed
+    pub fn new() -> Result<Self, OutOfMemory> {
+        let pgd = PageDirectory::new()?;
+        let bitmap = match unsafe {
+            PhysMem::try_alloc_phys_contiguous(KERNEL_VMEM_BITMAP_PAGES)
+        } {
+            Ok(bitmap) => bitmap,
+            Err(err) => {
+                unsafe { free_page_directory(&pgd); }
+                return Err(err);
+            }
         };
-        Self {
-            pgd : pgd,
-            allocator_bitmap : bitmap,
+        for page in 0..KERNEL_VMEM_BITMAP_PAGES {
+            let offset = (page * PAGE_SIZE) as u32;
+            let vaddr = VirtAddr(KERNEL_VMEM_ALLOCATOR_BITMAP + offset);
+            let mapped = unsafe {
+                pgd.map_raw(vaddr, (bitmap.0 + offset) | PAGE_PRESENT |
+                            PAGE_WRITE)
+            };
+
+            // The directory is new, only its page table can be missing
+            if mapped.is_err() {
+                unsafe {
+                    free_bitmap_pages(bitmap);
+                    free_page_directory(&pgd);
+                }
+                return Err(OutOfMemory);
+            }
         }
+
+        let allocator_bitmap = bitmap_from_paddr(bitmap);
+        allocator_bitmap.fill(0);
+        Ok(Self {
+            pgd : pgd,
+            allocator_bitmap : allocator_bitmap,
+        })
     }
     
     /// Get current virtual address space from cr3 register
     pub fn get_current() -> Self {
-        let pgd = PageDirectory::from_paddr(get_cr3());
-        let bitmap = unsafe { 
-            core::mem::transmute::<u32, &mut [u8; PAGE_SIZE]>
-                (KERNEL_VMEM_ALLOCATOR_BITMAP)
-        };
+        Self::from_pgd(get_cr3())
+    }
+
+    /// Get the virtual address space of the page directory at `paddr`
+    pub fn from_pgd(paddr : PhysAddr) -> Self {
+        let pgd = PageDirectory::from_paddr(PhysAddr(paddr.0 & !0xfff));
+        let bitmap = pgd.get_pte(VirtAddr(KERNEL_VMEM_ALLOCATOR_BITMAP))
+            .expect("Address space without allocator bitmap").get_paddr();
         Self {
             pgd : pgd,
-            allocator_bitmap : bitmap,
+            allocator_bitmap : bitmap_from_paddr(bitmap),
         }
     }
 
@@ -57,16 +254,394 @@ impl VirtMem {
         self.pgd.get_paddr()
     }
 
-    /// Add a new virtual memory mapping to the virtual address space 
+    /// Add a new virtual memory mapping to the virtual address space. Fails
+    /// with `AlreadyMapped` if a page of the range is already mapped
     pub fn map(&self, vaddr : VirtAddr, size : usize, write : bool, 
-               user : bool) {
-        self.pgd.map(vaddr, size, write, user);
+               user : bool) -> Result<(), MappingError> {
+        self.pgd.map(vaddr, size, write, user)
+    }
+
+    /// Map a raw pte entry to `vaddr`. Fails with `AlreadyMapped` if a page,
+    /// present or lazy, is already mapped there. Use `update_pte` to replace
+    /// it
+    pub fn map_raw(&self, vaddr : VirtAddr, raw : u32)
+            -> Result<(), MappingError> {
+        unsafe { self.pgd.map_raw(vaddr, raw) }
+    }
+
+    /// Map the large page `vaddr` to a raw page directory entry `raw`
+    pub fn map_large_raw(&self, vaddr : VirtAddr, raw : u32) {
+        unsafe {
+            self.pgd.map_large_raw(vaddr, raw);
+        }
+    }
+
+    /// Get the page table entry mapping `vaddr`, if there is a page table
+    /// for it
+    pub fn get_pte(&self, vaddr : VirtAddr) -> Option<PageTableEntry> {
+        self.pgd.get_pte(vaddr)
+    }
+
+    /// Returns true if this address space is the one in use
+    pub fn is_current(&self) -> bool {
+        self.pgd.is_current()
+    }
+
+    /// Translate `vaddr` into its mapping components
+    pub fn translate(&self, vaddr : VirtAddr) -> Option<Mapping> {
+        self.pgd.translate(vaddr)
+    }
+
+    /// Replace the page table entry mapping `vaddr` with `raw`, whatever it
+    /// was, and flush it from the TLB if this address space is the one in
+    /// use
+    pub fn update_pte(&self, vaddr : VirtAddr, raw : u32) {
+        self.try_update_pte(vaddr, raw).expect("Out of memory");
+    }
+
+    /// Like `update_pte`, but fails with `OutOfMemory` without memory for
+    /// the page table of `vaddr`
+    pub fn try_update_pte(&self, vaddr : VirtAddr, raw : u32)
+            -> Result<(), MappingError> {
+        unsafe { self.pgd.try_replace_raw(vaddr, raw)?; }
+        if self.is_current() {
+            invlpg(vaddr.0);
+        }
+        Ok(())
+    }
+
+    /// Give this address space its own copy of the copy-on-write page at
+    /// `vaddr` and make it writable, after a write to it. Returns false if
+    /// there is no such page at `vaddr`, or no memory for the copy
+    pub fn break_cow(&self, vaddr : VirtAddr) -> bool {
+        let vaddr = VirtAddr(vaddr.0 & !0xfff);
+        let pte = match self.pgd.get_pte(vaddr) {
+            Some(pte) if pte.0 & PAGE_PRESENT != 0 && 
+                pte.0 & PAGE_COW != 0 => pte,
+            _ => return false,
+        };
+        let page = pte.get_paddr();
+        let flags = (pte.0 & 0xfff & !PAGE_COW) | PAGE_WRITE;
+
+        // The zero page is replaced by a zeroed page of this address space
+        if is_zero_page(pte.0) {
+            return self.map_zeroed(vaddr, flags);
+        }
+
+        // The other address spaces already have their copy
+        if PhysMem::refcount(page) == 1 {
+            self.update_pte(vaddr, page.0 | flags);
+            return true;
+        }
+
+        let copy = match unsafe { PhysMem::try_alloc_phys() } {
+            Ok(copy) => copy,
+            Err(OutOfMemory) => return false,
+        };
+        unsafe { PhysMem::copy_page(copy, page); }
+        self.update_pte(vaddr, copy.0 | flags);
+        unsafe { PhysMem::free_phys(page); }
+        true
+    }
+
+    /// Iterate over the present pages mapped in `[start, end)` of this
+    /// address space, large pages included. Yields their virtual address,
+    /// physical address and flags, without allocating
+    pub fn iter_mappings(&self, start : VirtAddr, end : VirtAddr)
+            -> Mappings<'_> {
+        self.pgd.mappings(start, end)
+    }
+
+    /// Iterate over every present or lazy page of this address space, large
+    /// pages included
+    fn all_entries(&self) -> Mappings<'_> {
+        self.pgd.mappings(VirtAddr(0), VirtAddr(u32::MAX)).with_lazy()
+    }
+
+    /// Returns true if a page is mapped at `vaddr`, even if it is lazy
+    pub fn is_mapped(&self, vaddr : VirtAddr) -> bool {
+        match self.pgd.get_pte(vaddr) {
+            Some(pte) => pte.0 & (PAGE_PRESENT | PAGE_LAZY) != 0,
+            None => false,
+        }
+    }
+
+    /// Reserve the page at `vaddr` for userland with the page table flags
+    /// `flags`. Its memory is only allocated when it is first accessed.
+    /// Fails like `map_raw`
+    pub fn map_lazy(&self, vaddr : VirtAddr, flags : u32)
+            -> Result<(), MappingError> {
+        self.map_raw(vaddr, (flags & 0xfff & !PAGE_PRESENT) | PAGE_LAZY)
+    }
+
+    /// Reserve the `npages` pages from `vaddr` like `map_lazy`. If one of
+    /// them is already mapped, the ones reserved before it are unmapped
+    /// again and the error is returned
+    pub fn map_lazy_range(&self, vaddr : VirtAddr, npages : usize,
+                          flags : u32) -> Result<(), MappingError> {
+        for i in 0..npages {
+            let page = VirtAddr(vaddr.0 + (i * PAGE_SIZE) as u32);
+            if let Err(err) = self.map_lazy(page, flags) {
+                if i > 0 {
+                    self.unmap(vaddr, i).expect("Lazy pages vanished");
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Map a page for the lazy page at `vaddr`, on its first access. A
+    /// write gets a zeroed page of its own, a read gets the zero page,
+    /// copied on the first write if the page is writable. Returns false if
+    /// there is no lazy page at `vaddr`, or no memory for it
+    pub fn fill_lazy(&self, vaddr : VirtAddr, write : bool) -> bool {
+        let vaddr = VirtAddr(vaddr.0 & !0xfff);
+        let pte = match self.pgd.get_pte(vaddr) {
+            Some(pte) if pte.0 & (PAGE_PRESENT | PAGE_LAZY) == PAGE_LAZY => {
+                pte
+            }
+            _ => return false,
+        };
+        let flags = (pte.0 & 0xfff & !PAGE_LAZY) | PAGE_PRESENT;
+
+        if write {
+            return self.map_zeroed(vaddr, flags);
+        }
+        let flags = if flags & PAGE_WRITE != 0 {
+            (flags & !PAGE_WRITE) | PAGE_COW
+        } else {
+            flags
+        };
+        self.update_pte(vaddr, unsafe { ZERO_PAGE.0 } | flags);
+        true
+    }
+
+    /// Replace the page at `vaddr` with a new zeroed page mapped with the
+    /// page table flags `flags`. Returns false if there is no memory for it
+    fn map_zeroed(&self, vaddr : VirtAddr, flags : u32) -> bool {
+        let page = match unsafe { PhysMem::try_alloc_phys() } {
+            Ok(page) => page,
+            Err(OutOfMemory) => return false,
+        };
+        unsafe {
+            let alias = PhysMem::translate_mut(page, PAGE_SIZE)
+                .expect("Lazy page filled outside of RAM");
+            core::ptr::write_bytes(alias, 0, PAGE_SIZE);
+        }
+        self.update_pte(vaddr, page.0 | flags);
+        true
+    }
+
+    /// Get the number of pages private to this address space that are
+    /// present, lazy pages that were never accessed don't count
+    pub fn private_pages(&self) -> usize {
+        self.all_entries()
+            .filter(|&(vaddr, paddr, flags)| {
+                is_private_page(vaddr, paddr.0 | flags)
+            })
+            .count()
+    }
+
+    /// Get the number of present pages mapped by the page tables of this
+    /// address space, kernel pages and shared pages included. Large pages
+    /// and lazy pages are not counted
+    pub fn mapped_pages(&self) -> usize {
+        self.iter_mappings(VirtAddr(0), VirtAddr(u32::MAX))
+            .filter(|&(_, _, flags)| flags & PAGE_LARGE == 0)
+            .count()
+    }
+
+    /// Print the present mappings of `[start, end)`, one line per run of
+    /// pages contiguous both in virtual and physical memory and with the
+    /// same flags
+    pub fn dump(&self, start : VirtAddr, end : VirtAddr) {
+        // Accessed and dirty differ between neighbours, do not split on them
+        const DUMP_FLAGS : u32 = PAGE_PRESENT | PAGE_WRITE | PAGE_USER |
+            PAGE_GLOBAL | PAGE_LARGE | PAGE_SHARED | PAGE_COW |
+            PAGE_BORROWED;
+
+        // Virtual start, virtual end, physical start and flags of the run
+        let mut run : Option<(u32, u32, u32, u32)> = None;
+        for (vaddr, paddr, flags) in self.iter_mappings(start, end) {
+            let size = if flags & PAGE_LARGE != 0 {
+                LARGE_PAGE_SIZE
+            } else {
+                PAGE_SIZE
+            } as u32;
+            let flags = flags & DUMP_FLAGS;
+            if let Some((vstart, vend, pstart, rflags)) = run {
+                if vend == vaddr.0 && pstart + (vend - vstart) == paddr.0 &&
+                        rflags == flags {
+                    run = Some((vstart, vend + size, pstart, flags));
+                    continue;
+                }
+                dump_run(vstart, vend, pstart, rflags);
+            }
+            run = Some((vaddr.0, vaddr.0 + size, paddr.0, flags));
+        }
+        if let Some((vstart, vend, pstart, flags)) = run {
+            dump_run(vstart, vend, pstart, flags);
+        }
+    }
+
+    /// Find `npages` contiguous unmapped pages in `[start, end)`. Returns
+    /// the address of the first page
+    pub fn find_free_range(&self, start : u32, end : u32, npages : usize)
+            -> Option<VirtAddr> {
+        let mut base = start;
+        let mut found = 0;
+        for page in (start..end).step_by(PAGE_SIZE) {
+            if self.is_mapped(VirtAddr(page)) {
+                base = page + PAGE_SIZE as u32;
+                found = 0;
+                continue;
+            }
+
+            found += 1;
+            if found == npages {
+                return Some(VirtAddr(base));
+            }
+        }
+        None
+    }
+
+    /// Remove the mappings of `npages` pages starting at `vaddr`, present or
+    /// lazy. The backing physical pages are not freed. Fails without
+    /// modifying anything if the range overlaps the kernel identity mapping
+    /// or if one of the pages is not mapped
+    pub fn unmap(&self, vaddr : VirtAddr, npages : usize) 
+            -> Result<(), MappingError> {
+        let start = vaddr.0 & !0xfff;
+        let end = (npages as u32).checked_mul(PAGE_SIZE as u32)
+            .and_then(|size| start.checked_add(size))
+            .ok_or(MappingError::KernelRange)?;
+
+        // Refuse to touch the physical memory window or the kernel image
+        let window_end = KERNEL_PHYS_WINDOW_BASE + KERNEL_PHYS_WINDOW_SIZE;
+        if (start < window_end && end > KERNEL_PHYS_WINDOW_BASE) ||
+                start < KERNEL_IMAGE_MAP_SIZE {
+            return Err(MappingError::KernelRange);
+        }
+
+        // Make sure the whole range is mapped before removing anything
+        for page in (start..end).step_by(PAGE_SIZE) {
+            if !self.is_mapped(VirtAddr(page)) {
+                return Err(MappingError::NotMapped);
+            }
+        }
+
+        // Only flush the TLB if this address space is the one in use
+        let is_current = self.is_current();
+
+        for page in (start..end).step_by(PAGE_SIZE) {
+            unsafe { self.pgd.unmap(VirtAddr(page)); }
+            if is_current {
+                invlpg(page);
+            }
+        }
+
+        Ok(())
     }
 
-    /// Map a raw pte entry to `vaddr`
-    pub fn map_raw(&self, vaddr : VirtAddr, raw : u32) {
+    /// Create a copy of this address space for a forked task. The page
+    /// tables of the kernel identity mapping are shared, shared pages are
+    /// mapped in both address spaces and the other user pages are shared
+    /// copy-on-write. Kernel pages outside of the identity mapping, like
+    /// kernel stacks, are not duplicated. Without memory for the copy, the
+    /// partial child is freed and fails with `OutOfMemory`. The pages of
+    /// this address space left copy-on-write are made writable again on
+    /// their next write
+    pub fn fork(&self) -> Result<VirtMem, MappingError> {
+        let mut child = VirtMem::new()
+            .map_err(|_| MappingError::OutOfMemory)?;
+        match self.fork_into(&mut child) {
+            Ok(()) => Ok(child),
+            Err(err) => {
+                child.free_private_pages();
+                child.destroy();
+                Err(err)
+            }
+        }
+    }
+
+    /// Copy this address space in the new address space `child`, for
+    /// `fork`. On failure, the pages already mapped in `child` hold their
+    /// reference and are freed with its private pages
+    fn fork_into(&self, child : &mut VirtMem) -> Result<(), MappingError> {
+        // The identity mapping is the same in every address space, and
+        // never changes once built. The user code it holds is kernel owned
+        for index in 0..RECURSIVE_PDE_INDEX {
+            if is_kernel_identity(VirtAddr((index << 22) as u32)) {
+                unsafe { self.pgd.share_entry(&child.pgd, index) }
+                    .map_err(|_| MappingError::OutOfMemory)?;
+            }
+        }
+
+        // Copy the allocator bitmap so that allocations in the child don't
+        // land on the mappings it inherited
+        *child.allocator_bitmap = *self.allocator_bitmap;
+
+        for (vaddr, paddr, flags) in self.all_entries() {
+            let pte = paddr.0 | flags;
+            if pte & PAGE_USER == 0 || is_kernel_identity(vaddr) {
+                continue;
+            }
+
+            let page = PhysAddr(pte & !0xfff);
+            if !is_private_page(vaddr, pte) {
+                // Map the same physical page, or reserve the same lazy page
+                child.map_raw(vaddr, pte)?;
+            } else if unsafe { PhysMem::share_phys(page) } {
+                // Both address spaces use the page until one of them writes
+                // to it. Read-only pages stay so, as long as they are shared
+                let pte = if pte & PAGE_WRITE != 0 {
+                    (pte & !PAGE_WRITE) | PAGE_COW
+                } else {
+                    pte
+                };
+                self.update_pte(vaddr, pte);
+                child.map_raw(vaddr, pte).inspect_err(|_| {
+                    // Drop the reference the child didn't get
+                    unsafe { PhysMem::free_phys(page); }
+                })?;
+            } else {
+                // Map a copy of the page with the same flags
+                let copy = unsafe { PhysMem::try_alloc_phys() }
+                    .map_err(|_| MappingError::OutOfMemory)?;
+                unsafe { PhysMem::copy_page(copy, page); }
+                child.map_raw(vaddr, copy.0 | (pte & 0xfff)).inspect_err(|_| {
+                    unsafe { PhysMem::free_phys(copy); }
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Free the physical pages that belong only to this address space, that
+    /// is the user pages outside of the identity mapping that are not shared.
+    /// Pages still shared copy-on-write lose a reference
+    pub fn free_private_pages(&self) {
+        for (vaddr, paddr, flags) in self.all_entries() {
+            if is_private_page(vaddr, paddr.0 | flags) {
+                unsafe { PhysMem::free_phys(paddr); }
+            }
+        }
+    }
+
+    /// Free the page directory, the page tables and the allocator bitmap of
+    /// this address space. Pages mapped in it are not freed, and it must not
+    /// be the address space in use
+    pub fn destroy(self) {
+        assert!(!self.is_current(),
+                "Trying to destroy the current address space");
+
+        let bitmap = self.pgd.get_pte(VirtAddr(KERNEL_VMEM_ALLOCATOR_BITMAP))
+            .expect("Address space without allocator bitmap").get_paddr();
         unsafe {
-            self.pgd.map_raw(vaddr, raw);
+            free_bitmap_pages(bitmap);
+            free_page_directory(&self.pgd);
         }
     }
 
@@ -74,54 +649,277 @@ impl VirtMem {
     /// Returns the `VirtAddr` of the allocation
     pub fn alloc_virt_pages(&mut self, npages : usize, write : bool, user : bool) 
             -> VirtAddr {
-
-        // Find a free window of size npages
-        let alloc_index = self.allocator_bitmap.windows(npages)
-            .position(|x| x.iter().all(|&y| y == 0))
-            .expect("Couldn't find enough free contiguous virtual pages");
-
-        // Update allocator bitmap
-        self.allocator_bitmap[alloc_index..alloc_index + npages]
-            .iter_mut()
-            .for_each(|x| *x = 1);
-
-        // Determine allocation address
-        let alloc_addr = VirtAddr(KERNEL_VMEM_BASE + 
-                                  ((alloc_index * PAGE_SIZE) as u32)); 
+        let alloc_addr = self.reserve_virt_pages(npages);
         
         // Create the mapping in virtual memory
-        self.map(alloc_addr, npages * PAGE_SIZE, write, user);
+        self.map(alloc_addr, npages * PAGE_SIZE, write, user)
+            .expect("Virtual allocation over an existing mapping");
+
+        alloc_addr
+    }
 
+    /// Same as `alloc_virt_pages`, but the address of the allocation is a
+    /// multiple of `align_pages` pages
+    pub fn alloc_virt_pages_aligned(&mut self, npages : usize,
+                                    align_pages : usize, write : bool,
+                                    user : bool) -> VirtAddr {
+        let alloc_addr = self.reserve_virt_pages_aligned(npages, align_pages);
+        self.map(alloc_addr, npages * PAGE_SIZE, write, user)
+            .expect("Virtual allocation over an existing mapping");
         alloc_addr
     }
 
-    /// Free `npages` pages of memory at `addr`
-    pub fn free_virt_pages(&mut self, addr : VirtAddr, npages : usize) {
-        // Get the allocator bitmap index
-        let bitmap_index = (addr.0 - KERNEL_VMEM_BASE) / (PAGE_SIZE as u32);
-        let bitmap_index = bitmap_index as usize;
+    /// Alloc the `npages` pages of virtual memory at `vaddr`. Fails with
+    /// `InUse` if one of them is already allocated, `OutOfRange` if they
+    /// are not all in the dynamic allocations area, or `AlreadyMapped` if
+    /// one of them is mapped without being allocated
+    pub fn alloc_virt_at(&mut self, vaddr : VirtAddr, npages : usize,
+                         write : bool, user : bool)
+            -> Result<(), MappingError> {
+        self.reserve_virt_at(vaddr, npages)?;
+        if let Err(err) = self.map(vaddr, npages * PAGE_SIZE, write, user) {
+            self.release_virt_pages(vaddr, npages);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Map the existing physical pages holding the `size` bytes at `paddr`,
+    /// at least the page of `paddr`, in the dynamic allocations area with
+    /// the page table flags `flags`. No RAM is allocated, the pages are
+    /// borrowed and `unmap_phys_range` leaves them alone. Returns the
+    /// address of the mapping and the offset of `paddr` in it. Fails with
+    /// `OutOfRange` if the range goes past 4 GB
+    pub fn map_phys_range(&mut self, paddr : PhysAddr, size : usize,
+                          flags : u32)
+            -> Result<(VirtAddr, u32), MappingError> {
+        if paddr.0 as u64 + size as u64 > u32::MAX as u64 + 1 {
+            return Err(MappingError::OutOfRange);
+        }
+        let offset = paddr.0 & 0xfff;
+        let npages = (offset as usize + size.max(1)).div_ceil(PAGE_SIZE);
+        let vaddr = self.reserve_virt_pages(npages);
+        for page in 0..npages {
+            let page_offset = (page * PAGE_SIZE) as u32;
+            let page_addr = VirtAddr(vaddr.0 + page_offset);
+            let frame = (paddr.0 & !0xfff) + page_offset;
+            let raw = frame | (flags & 0xfff) | PAGE_PRESENT |
+                PAGE_BORROWED;
+            if let Err(err) = self.map_raw(page_addr, raw) {
+                if page != 0 {
+                    self.unmap(vaddr, page)
+                        .expect("Physical range mapped then gone");
+                }
+                self.release_virt_pages(vaddr, npages);
+                return Err(err);
+            }
+        }
+        Ok((vaddr, offset))
+    }
+
+    /// Remove the mapping of the `size` bytes at `vaddr` made by
+    /// `map_phys_range`, and give back its virtual memory. `vaddr` may be
+    /// the address of the mapping or of a byte in its first page
+    pub fn unmap_phys_range(&mut self, vaddr : VirtAddr, size : usize) {
+        let start = VirtAddr(vaddr.0 & !0xfff);
+        let npages = ((vaddr.0 & 0xfff) as usize + size.max(1))
+            .div_ceil(PAGE_SIZE);
+        self.unmap(start, npages).expect("Unmapping unmapped physical range");
+        self.release_virt_pages(start, npages);
+    }
+
+    /// Map the `size` bytes of device memory at `paddr` like
+    /// `map_phys_range`, writable and uncached. Fails with `Ram` if the
+    /// range overlaps RAM. Returns the address of the byte at `paddr`
+    pub fn map_mmio(&mut self, paddr : PhysAddr, size : usize)
+            -> Result<VirtAddr, MappingError> {
+        const MMIO_FLAGS : u32 = PAGE_WRITE | PAGE_CACHE_DISABLE |
+            PAGE_WRITE_THROUGH_ENABLE;
+
+        if size == 0 || PhysMem::is_ram(paddr, size) {
+            return Err(MappingError::Ram);
+        }
+        let (vaddr, offset) = self.map_phys_range(paddr, size, MMIO_FLAGS)?;
+        Ok(VirtAddr(vaddr.0 + offset))
+    }
+
+    /// Remove the mapping of the `size` bytes of device memory at `vaddr`
+    /// made by `map_mmio`, and give back its virtual memory
+    pub fn unmap_mmio(&mut self, vaddr : VirtAddr, size : usize) {
+        self.unmap_phys_range(vaddr, size);
+    }
+
+    /// Reserve `npages` pages of virtual memory without mapping them
+    /// Returns the `VirtAddr` of the reservation
+    pub fn reserve_virt_pages(&mut self, npages : usize) -> VirtAddr {
+        self.reserve_virt_pages_aligned(npages, 1)
+    }
+
+    /// Same as `reserve_virt_pages`, but the address of the reservation is
+    /// a multiple of `align_pages` pages
+    pub fn reserve_virt_pages_aligned(&mut self, npages : usize,
+                                      align_pages : usize) -> VirtAddr {
+        self.try_reserve_virt_pages_aligned(npages, align_pages)
+            .expect("Couldn't find enough free contiguous virtual pages")
+    }
+
+    /// Like `reserve_virt_pages`, but fails with `OutOfMemory` if there are
+    /// not enough free contiguous virtual pages
+    pub fn try_reserve_virt_pages(&mut self, npages : usize)
+            -> Result<VirtAddr, MappingError> {
+        self.try_reserve_virt_pages_aligned(npages, 1)
+    }
+
+    /// Like `reserve_virt_pages_aligned`, but fails with `OutOfMemory` if
+    /// there are not enough free contiguous virtual pages
+    pub fn try_reserve_virt_pages_aligned(&mut self, npages : usize,
+                                          align_pages : usize)
+            -> Result<VirtAddr, MappingError> {
+        let alloc_index = self.find_free_pages(npages, align_pages)
+            .ok_or(MappingError::OutOfMemory)?;
+        self.set_pages_used(alloc_index, npages, true);
 
-        // Check that pages in this region are allocated
-        let pages_allocated = self.allocator_bitmap[bitmap_index..bitmap_index + npages]
-                .iter()
-                .position(|x| *x==0)
-                .is_none();
+        // Determine allocation address
+        Ok(VirtAddr(KERNEL_VMEM_BASE + ((alloc_index * PAGE_SIZE) as u32)))
+    }
+
+    /// Reserve the `npages` pages of virtual memory at `vaddr` without
+    /// mapping them. Fails like `alloc_virt_at`
+    pub fn reserve_virt_at(&mut self, vaddr : VirtAddr, npages : usize)
+            -> Result<(), MappingError> {
+        let start = (vaddr.0 as usize) / PAGE_SIZE;
+        let first = KERNEL_VMEM_BASE as usize / PAGE_SIZE;
+        if vaddr.0 & 0xfff != 0 || start < first ||
+                start - first + npages > KERNEL_VMEM_PAGES {
+            return Err(MappingError::OutOfRange);
+        }
+
+        let index = start - first;
+        if (index..index + npages).any(|index| self.is_page_used(index)) {
+            return Err(MappingError::InUse);
+        }
+        self.set_pages_used(index, npages, true);
+        Ok(())
+    }
 
-        // Free backing physical memory
+    /// Free `npages` pages of memory at `addr`. The pages are unmapped and
+    /// flushed from the TLB if this address space is the one in use, so
+    /// that their physical memory can't be reached anymore once reused.
+    /// Fails with `OutOfRange` outside of the dynamic allocations area,
+    /// `NotMapped` if a page is not allocated and `NotOwned` if a page maps
+    /// borrowed or shared memory, without freeing anything
+    pub fn free_virt_pages(&mut self, addr : VirtAddr, npages : usize)
+            -> Result<(), MappingError> {
+        // Check that the pages of this region are allocated and own their
+        // physical memory, before unmapping anything
+        let offset = addr.0.wrapping_sub(KERNEL_VMEM_BASE) as usize;
+        if addr.0 < KERNEL_VMEM_BASE || !offset.is_multiple_of(PAGE_SIZE) ||
+                offset / PAGE_SIZE + npages > KERNEL_VMEM_PAGES {
+            return Err(MappingError::OutOfRange);
+        }
         let start_mapping = addr.0;
         let end_mapping = addr.0 + ((npages * PAGE_SIZE) as u32);
         for virt_page in (start_mapping..end_mapping).step_by(PAGE_SIZE) {
-            let mapping = self.pgd.translate(VirtAddr(virt_page));
-            //println!("Mapping : {:#x?}", mapping);
-            if mapping.page.is_none() {
-                panic!("Trying to free invalid physical memory");
+            let index = Self::bitmap_index(VirtAddr(virt_page), 1);
+            let pte = self.pgd.get_pte(VirtAddr(virt_page))
+                .map_or(0, |pte| pte.0);
+            if !self.is_page_used(index) || pte & PAGE_PRESENT == 0 {
+                return Err(MappingError::NotMapped);
+            }
+            if pte & (PAGE_BORROWED | PAGE_SHARED) != 0 {
+                return Err(MappingError::NotOwned);
             }
-            unsafe { PhysMem::free_phys(mapping.page.unwrap()); }
         }
 
-        // Update allocator bitmap
-        self.allocator_bitmap[bitmap_index..bitmap_index + npages]
-            .iter_mut()
-            .for_each(|x| *x = 0);
+        // Unmap the pages and free backing physical memory
+        let is_current = self.is_current();
+        for virt_page in (start_mapping..end_mapping).step_by(PAGE_SIZE) {
+            let page = unsafe { self.pgd.unmap(VirtAddr(virt_page)) }
+                .expect("Allocated page vanished");
+            if is_current {
+                invlpg(virt_page);
+            }
+            unsafe { PhysMem::free_phys(page); }
+        }
+
+        self.release_virt_pages(addr, npages);
+        Ok(())
+    }
+
+    /// Give back `npages` pages of virtual memory at `addr`, reserved or
+    /// allocated, whose physical memory is already freed
+    pub fn release_virt_pages(&mut self, addr : VirtAddr, npages : usize) {
+        let bitmap_index = Self::bitmap_index(addr, npages);
+        self.set_pages_used(bitmap_index, npages, false);
+    }
+
+    /// Get the index in the allocator bitmap of the page at `addr`. Panics
+    /// if the `npages` pages from there are not all in the dynamic
+    /// allocations area
+    fn bitmap_index(addr : VirtAddr, npages : usize) -> usize {
+        let index = addr.0.wrapping_sub(KERNEL_VMEM_BASE) as usize / PAGE_SIZE;
+        if addr.0 < KERNEL_VMEM_BASE || index + npages > KERNEL_VMEM_PAGES {
+            panic!("Virtual pages outside of the allocator : {:#x} ({} pages)",
+                   addr.0, npages);
+        }
+        index
+    }
+
+    /// Returns true if the page at `index` in the allocator bitmap is used
+    fn is_page_used(&self, index : usize) -> bool {
+        let word = self.allocator_bitmap[index / PAGES_PER_WORD];
+        word & (1 << (index % PAGES_PER_WORD)) != 0
+    }
+
+    /// Mark the `npages` pages from `index` in the allocator bitmap as used
+    /// or free
+    fn set_pages_used(&mut self, index : usize, npages : usize, used : bool) {
+        for index in index..index + npages {
+            let bit = 1 << (index % PAGES_PER_WORD);
+            let word = &mut self.allocator_bitmap[index / PAGES_PER_WORD];
+            if used {
+                *word |= bit;
+            } else {
+                *word &= !bit;
+            }
+        }
+    }
+
+    /// Find the first `npages` contiguous free pages in the allocator
+    /// bitmap, whose address is a multiple of `align_pages` pages. Returns
+    /// the index of the first page
+    fn find_free_pages(&self, npages : usize, align_pages : usize)
+            -> Option<usize> {
+        if npages == 0 || align_pages == 0 {
+            return None;
+        }
+
+        // Index of the first aligned page from `index`. The alignment is the
+        // one of the address, the area itself is only aligned on a page
+        let first = KERNEL_VMEM_BASE as usize / PAGE_SIZE;
+        let align = |index : usize| {
+            (first + index).div_ceil(align_pages) * align_pages - first
+        };
+
+        let mut base = align(0);
+        let mut index = base;
+        while base + npages <= KERNEL_VMEM_PAGES {
+            if index == base + npages {
+                return Some(base);
+            }
+
+            // Skip the words without any free page
+            if index % PAGES_PER_WORD == 0 &&
+                    self.allocator_bitmap[index / PAGES_PER_WORD] == u32::MAX {
+                base = align(index + PAGES_PER_WORD);
+                index = base;
+            } else if self.is_page_used(index) {
+                base = align(index + 1);
+                index = base;
+            } else {
+                index += 1;
+            }
+        }
+        None
     }
 }
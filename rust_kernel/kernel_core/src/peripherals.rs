@@ -1,7 +1,7 @@
 //! Peripherals
 
 use crate::serial::SerialPort;
-use core::mem::replace;
+use crate::sync::*;
 
 /// A structure that holds references to peripherals
 pub struct Peripherals {
@@ -9,14 +9,31 @@ pub struct Peripherals {
 }
 
 impl Peripherals {
-    /// Lock the serial port
+    /// Lock the serial port. Preemption is disabled until it is released,
+    /// so that another task can't find it locked
     pub fn lock_serial(&mut self) -> SerialPort {
-        let p = replace(&mut self.serial, None);
+        preempt_disable();
+        // An interrupt handler printing in the middle of the take would
+        // see a port that is neither taken nor free
+        let p = {
+            let _guard = InterruptGuard::new();
+            self.serial.take()
+        };
         p.unwrap()
     }
 
     /// Unlock the serial port
     pub fn release_serial(&mut self, serial : SerialPort) {
-        let _ = replace(&mut self.serial, Some(serial));
+        {
+            let _guard = InterruptGuard::new();
+            self.serial = Some(serial);
+        }
+        preempt_enable();
+    }
+
+    /// Returns true if the serial port is not locked, so that an interrupt
+    /// handler can print
+    pub fn serial_available(&self) -> bool {
+        self.serial.is_some()
     }
 }
@@ -20,12 +20,27 @@ const ICW4_SFNM : u8 = 0x10;           /* Special fully nested (not) */
 
 const EOI_COMMAND : u8 = 0x20;
 
+/// OCW3 selecting the In-Service Register for the next read of the command
+/// port
+const OCW3_READ_ISR : u8 = 0x0b;
+
+/// OCW3 selecting the Interrupt Request Register for the next read of the
+/// command port, the default
+const OCW3_READ_IRR : u8 = 0x0a;
+
+/// IRQ of the master PIC the slave PIC is wired to
+const CASCADE_IRQ : u32 = 2;
+
+/// Number of spurious IRQ 7 and IRQ 15 received
+static mut SPURIOUS_IRQS : u32 = 0;
+
 /// Empty struct representing the PIC
 pub struct Pic;
 
 impl Pic {
     /// Remap the Programmable Interrupt Controllers to specified 
-    /// vector offsets : `offset1` for master PIC and `offset2` for slave PIC
+    /// vector offsets : `offset1` for master PIC and `offset2` for slave PIC.
+    /// Real PICs need some time to take each init word, QEMU doesn't
     pub fn remap(offset1 : u8, offset2 : u8) {
         unsafe {
 
@@ -37,19 +52,25 @@ impl Pic {
             //      - ICW4 needed
             //      - cascade mode
             cpu::out8(PIC1_COMMAND, ICW1_INIT | ICW1_ICW4);
+            cpu::io_wait();
             cpu::out8(PIC2_COMMAND, ICW1_INIT | ICW1_ICW4);
+            cpu::io_wait();
 
             // Second init word (ICW2) : Vector offset for the PICS
             //      - remap IRQ[00-07] to IDT[offset1-offset1+7]
             //      - remap IRQ[08-15] to IDT[offset2-offset2+7] 
             cpu::out8(PIC1_DATA, offset1);
+            cpu::io_wait();
             cpu::out8(PIC2_DATA, offset2);
+            cpu::io_wait();
 
             // Third init word (ICW3) : Master / Slave wiring
             //      - tell master PIC that there is a slave at IRQ2
             //      - tell slave PIC its cascade identity
             cpu::out8(PIC1_DATA, 4);
+            cpu::io_wait();
             cpu::out8(PIC2_DATA, 2);
+            cpu::io_wait();
 
             // Fourth init word (ICW4) : Environment Info
             //      - x86 mode
@@ -57,7 +78,9 @@ impl Pic {
             //      - not buffered
             //      - not fully nested
             cpu::out8(PIC1_DATA, ICW4_8086);
+            cpu::io_wait();
             cpu::out8(PIC2_DATA, ICW4_8086);
+            cpu::io_wait();
             
             // Restore masks
             cpu::out8(PIC1_DATA, a1);
@@ -65,11 +88,101 @@ impl Pic {
         }
     }
 
-    /// Notify the end of an interrupt to the PIC
+    /// Notify the end of an interrupt to the PIC. The IRQs of the slave PIC
+    /// go through the master one, so both get it
     pub fn notify_eoi(irq : u32) {
         if irq >= 8 {
             unsafe { cpu::out8(PIC2_COMMAND, EOI_COMMAND); }
         }
         unsafe { cpu::out8(PIC1_COMMAND, EOI_COMMAND); }
     }
+
+    /// Get the masks of the 16 IRQ lines, a set bit is a masked IRQ. The
+    /// masks of the slave PIC are in the high byte
+    pub fn get_masks() -> u16 {
+        unsafe {
+            cpu::in8(PIC1_DATA) as u16 | (cpu::in8(PIC2_DATA) as u16) << 8
+        }
+    }
+
+    /// Set the masks of the 16 IRQ lines, like given by `get_masks`
+    pub fn set_masks(masks : u16) {
+        unsafe {
+            cpu::out8(PIC1_DATA, masks as u8);
+            cpu::out8(PIC2_DATA, (masks >> 8) as u8);
+        }
+    }
+
+    /// Stop the PIC from raising the IRQ `irq`
+    pub fn mask(irq : u32) {
+        assert!(irq < 16, "Masking invalid IRQ {}", irq);
+        Self::set_masks(Self::get_masks() | 1 << irq);
+    }
+
+    /// Let the PIC raise the IRQ `irq`. The IRQs of the slave PIC also need
+    /// the cascade line of the master PIC
+    pub fn unmask(irq : u32) {
+        assert!(irq < 16, "Unmasking invalid IRQ {}", irq);
+        let mut masks = Self::get_masks() & !(1 << irq);
+        if irq >= 8 {
+            masks &= !(1 << CASCADE_IRQ);
+        }
+        Self::set_masks(masks);
+    }
+
+    /// Read the register selected by `ocw3` in both PICs, the slave PIC in
+    /// the high byte
+    fn read_register(ocw3 : u8) -> u16 {
+        unsafe {
+            cpu::out8(PIC1_COMMAND, ocw3);
+            cpu::out8(PIC2_COMMAND, ocw3);
+            let reg = cpu::in8(PIC1_COMMAND) as u16 |
+                (cpu::in8(PIC2_COMMAND) as u16) << 8;
+
+            // Leave the reads of the command ports on the IRR
+            cpu::out8(PIC1_COMMAND, OCW3_READ_IRR);
+            cpu::out8(PIC2_COMMAND, OCW3_READ_IRR);
+            reg
+        }
+    }
+
+    /// Get the IRQs waiting for the CPU to take them, like masked ones
+    pub fn get_irr() -> u16 {
+        Self::read_register(OCW3_READ_IRR)
+    }
+
+    /// Get the IRQs in service, taken by the CPU and not given an EOI yet
+    pub fn get_isr() -> u16 {
+        Self::read_register(OCW3_READ_ISR)
+    }
+
+    /// Returns true if the IRQ 7 or 15 being handled is spurious: the PIC
+    /// raised it but no line is in service anymore. Spurious IRQs are
+    /// counted and must not get an EOI from their PIC, though the master PIC
+    /// still expects one for a spurious IRQ 15 of the slave
+    pub fn check_spurious(irq : u32) -> bool {
+        let command = match irq {
+            7 => PIC1_COMMAND,
+            15 => PIC2_COMMAND,
+            _ => return false,
+        };
+        let isr = unsafe {
+            cpu::out8(command, OCW3_READ_ISR);
+            cpu::in8(command)
+        };
+        if isr & (1 << 7) != 0 {
+            return false;
+        }
+
+        unsafe { SPURIOUS_IRQS += 1; }
+        if irq == 15 {
+            unsafe { cpu::out8(PIC1_COMMAND, EOI_COMMAND); }
+        }
+        true
+    }
+
+    /// Get the number of spurious IRQs received
+    pub fn spurious_count() -> u32 {
+        unsafe { SPURIOUS_IRQS }
+    }
 }
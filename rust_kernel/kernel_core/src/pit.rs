@@ -0,0 +1,167 @@
+//! Programmable Interval Timer. Channel 0 raises the timer interrupt, which
+//! drives the tick counter and preemption. Channel 2 times short busy waits
+
+use crate::cpu::*;
+use crate::interrupts::*;
+use crate::rtc::rtc_seconds;
+use crate::tasks::kthread_sleep;
+use crate::{print, println, PERIPHERALS};
+
+/// Data port of channel 0
+const PIT_CHANNEL0 : u16 = 0x40;
+
+/// Mode/command register
+const PIT_COMMAND : u16 = 0x43;
+
+/// Data port of channel 2
+const PIT_CHANNEL2 : u16 = 0x42;
+
+/// Select channel 0, send the reload value low byte first, rate generator
+const PIT_CHANNEL0_RATE : u8 = 0x34;
+
+/// Select channel 2, send the reload value low byte first, interrupt on
+/// terminal count, which raises the output once the count reaches 0
+const PIT_CHANNEL2_ONESHOT : u8 = 0xb0;
+
+/// Port controlling the gate of channel 2 and reading its output
+const PIT_CHANNEL2_CONTROL : u16 = 0x61;
+
+/// Bit of `PIT_CHANNEL2_CONTROL` driving the gate of channel 2
+const PIT_CHANNEL2_GATE : u8 = 1 << 0;
+
+/// Bit of `PIT_CHANNEL2_CONTROL` connecting channel 2 to the speaker
+const PIT_SPEAKER : u8 = 1 << 1;
+
+/// Bit of `PIT_CHANNEL2_CONTROL` reading the output of channel 2
+const PIT_CHANNEL2_OUTPUT : u8 = 1 << 5;
+
+/// Largest reload value, written as 0
+const PIT_MAX_DIVISOR : u32 = 0x1_0000;
+
+/// Frequency in Hz of the oscillator of the PIT
+const PIT_BASE_FREQUENCY : u32 = 1_193_182;
+
+/// Frequency of the timer interrupt in Hz asked at boot. The PIT only
+/// divides its base frequency, `frequency` gives the one it runs at
+pub const TIMER_FREQUENCY : u32 = 1000;
+
+/// Divisor of the base frequency programmed in channel 0
+static mut DIVISOR : u32 = 0;
+
+/// IRQ of channel 0
+pub const TIMER_IRQ : u8 = 0;
+
+/// Number of timer ticks a task runs before the scheduler preempts it
+pub const QUANTUM_TICKS : u32 = 10;
+
+/// Get the divisor of the base frequency closest to `frequency` Hz, within
+/// the ones the PIT accepts, from 18.2 Hz to the base frequency
+fn divisor_for(frequency : u32) -> u32 {
+    let frequency = core::cmp::max(frequency, 1);
+    let divisor = (PIT_BASE_FREQUENCY + frequency / 2) / frequency;
+    divisor.clamp(1, PIT_MAX_DIVISOR)
+}
+
+/// Program channel 0 to raise the timer interrupt at about `frequency` Hz
+/// in rate generator mode, then register its handler. Returns the frequency
+/// it runs at
+pub fn pit_init(frequency : u32) -> u32 {
+    let divisor = divisor_for(frequency);
+    unsafe {
+        DIVISOR = divisor;
+        out8(PIT_COMMAND, PIT_CHANNEL0_RATE);
+        out8(PIT_CHANNEL0, divisor as u8);
+        out8(PIT_CHANNEL0, (divisor >> 8) as u8);
+    }
+
+    // Thousandths of Hz, since the divisor rarely divides the base exactly
+    let millihertz = PIT_BASE_FREQUENCY as u64 * 1000 / divisor as u64;
+    println!("pit : timer at {}.{:03} Hz, {} Hz requested",
+             millihertz / 1000, millihertz % 1000, frequency);
+    register_irq_handler(TIMER_IRQ, handle_timer_intr);
+    self::frequency()
+}
+
+/// Get the frequency of the timer interrupt in Hz, rounded. Panics if the
+/// PIT is not programmed yet
+pub fn frequency() -> u32 {
+    let divisor = unsafe { DIVISOR };
+    assert!(divisor != 0, "PIT frequency read before pit_init");
+    (PIT_BASE_FREQUENCY + divisor / 2) / divisor
+}
+
+/// Convert `ticks` timer ticks to microseconds, with the exact frequency of
+/// the timer
+pub fn ticks_to_us(ticks : u64) -> u64 {
+    let divisor = unsafe { DIVISOR };
+    assert!(divisor != 0, "PIT frequency read before pit_init");
+    ticks * divisor as u64 * 1_000_000 / PIT_BASE_FREQUENCY as u64
+}
+
+/// Convert `ticks` timer ticks to milliseconds
+pub fn ticks_to_ms(ticks : u64) -> u64 {
+    ticks_to_us(ticks) / 1000
+}
+
+/// Convert `ms` milliseconds to timer ticks, rounded up so that sleeping
+/// that many ticks lasts at least `ms`
+pub fn ms_to_ticks(ms : u64) -> u64 {
+    let divisor = unsafe { DIVISOR };
+    assert!(divisor != 0, "PIT frequency read before pit_init");
+    let per_tick = divisor as u64 * 1000;
+    (ms * PIT_BASE_FREQUENCY as u64).div_ceil(per_tick)
+}
+
+/// Get the time since the timer started, in milliseconds
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
+
+/// Busy wait for `us` microseconds, counted by channel 2. It doesn't need
+/// interrupts nor the timer, so it works early at boot, like to calibrate
+/// other clocks
+pub fn delay_us(us : u32) {
+    let mut remaining = us as u64 * PIT_BASE_FREQUENCY as u64 / 1_000_000;
+    while remaining != 0 {
+        let count = core::cmp::min(remaining, 0xffff) as u32;
+        unsafe {
+            // The count is loaded while the gate is low, and runs once it
+            // is high again. The speaker stays off
+            let control = in8(PIT_CHANNEL2_CONTROL) &
+                !(PIT_CHANNEL2_GATE | PIT_SPEAKER);
+            out8(PIT_CHANNEL2_CONTROL, control);
+            out8(PIT_COMMAND, PIT_CHANNEL2_ONESHOT);
+            out8(PIT_CHANNEL2, count as u8);
+            out8(PIT_CHANNEL2, (count >> 8) as u8);
+            out8(PIT_CHANNEL2_CONTROL, control | PIT_CHANNEL2_GATE);
+            while in8(PIT_CHANNEL2_CONTROL) & PIT_CHANNEL2_OUTPUT == 0 {}
+            out8(PIT_CHANNEL2_CONTROL, control);
+        }
+        remaining -= count as u64;
+    }
+}
+
+/// Sleep until the seconds of the RTC change. Returns the tick at which it
+/// was noticed
+fn wait_rtc_second() -> u64 {
+    let seconds = rtc_seconds();
+    while rtc_seconds() == seconds {
+        kthread_sleep(1);
+    }
+    ticks()
+}
+
+/// Kernel task counting the timer ticks during a few seconds of the RTC, to
+/// check that the PIT runs at `frequency`
+pub fn pit_measure_task() {
+    const SECONDS : u64 = 3;
+
+    let start = wait_rtc_second();
+    let mut end = start;
+    for _ in 0..SECONDS {
+        end = wait_rtc_second();
+    }
+
+    println!("pit : {} ticks per second, expected {}",
+             (end - start) / SECONDS, frequency());
+}
@@ -0,0 +1,97 @@
+//! Reboot and power off the machine
+
+use crate::cpu::*;
+use crate::interrupts::IdtPointer;
+use crate::syscalls::*;
+use crate::tasks::current_task;
+use crate::{print, println, PERIPHERALS};
+
+/// Command and status port of the keyboard controller
+const KBC_COMMAND : u16 = 0x64;
+
+/// Set in the keyboard controller status while it has not read its input
+const KBC_INPUT_FULL : u8 = 1 << 1;
+
+/// Keyboard controller command pulsing the reset line of the CPU
+const KBC_RESET : u8 = 0xfe;
+
+/// Port of the isa-debug-exit device, qemu exits with `(code << 1) | 1`
+/// when `code` is written to it
+const DEBUG_EXIT_PORT : u16 = 0xf4;
+
+/// ACPI power management ports of qemu and of older qemu and Bochs, where
+/// `ACPI_SHUTDOWN` powers off the machine
+const ACPI_PM_PORTS : [u16; 2] = [0x604, 0xb004];
+const ACPI_SHUTDOWN : u16 = 0x2000;
+
+/// Commands of `SYS_REBOOT`
+pub const REBOOT_RESTART : u32 = 0;
+pub const REBOOT_POWEROFF : u32 = 1;
+
+/// Register the reboot syscall
+pub fn power_init() {
+    register_syscall(SYS_REBOOT, "reboot", &[SyscallArg::Uint, SyscallArg::Int],
+                     |ctx| sys_reboot(ctx.regs.ecx, ctx.regs.edx as i32));
+}
+
+/// Restart the machine with the keyboard controller. If it doesn't work,
+/// cause a triple fault by raising an interrupt without an IDT
+pub fn reboot() -> ! {
+    println!("Rebooting");
+    disable_interrupts();
+
+    unsafe {
+        // Wait for the controller to be ready for a command
+        while in8(KBC_COMMAND) & KBC_INPUT_FULL != 0 {}
+        out8(KBC_COMMAND, KBC_RESET);
+
+        // Give the controller some time to reset the CPU
+        for _ in 0..0x10000 {
+            in8(KBC_COMMAND);
+        }
+    }
+
+    println!("Keyboard controller reset failed, triple faulting");
+    set_idt(&IdtPointer {
+        limit : 0,
+        base : 0,
+    });
+    unsafe {
+        core::arch::asm!("int3");
+    }
+
+    halt();
+}
+
+/// Power off the machine. Under qemu with the isa-debug-exit device, qemu
+/// exits with a status computed from `code`, so automated runs can tell how
+/// the kernel ended. Otherwise, try the ACPI shutdown of qemu and Bochs
+pub fn poweroff(code : u8) -> ! {
+    println!("Powering off with code {}", code);
+    disable_interrupts();
+
+    unsafe {
+        out8(DEBUG_EXIT_PORT, code);
+        for &port in &ACPI_PM_PORTS {
+            out16(port, ACPI_SHUTDOWN);
+        }
+    }
+
+    println!("Power off failed");
+    halt();
+}
+
+/// Restart the machine with `REBOOT_RESTART`, or power it off with
+/// `REBOOT_POWEROFF` and the status `code`. Only the first task, pid 1, is
+/// allowed to do it, other tasks get EPERM
+fn sys_reboot(cmd : u32, code : i32) -> i32 {
+    if current_task().pid != 1 {
+        return -EPERM;
+    }
+
+    match cmd {
+        REBOOT_RESTART => reboot(),
+        REBOOT_POWEROFF => poweroff(code as u8),
+        _ => -EINVAL,
+    }
+}
@@ -0,0 +1,245 @@
+//! Real time clock of the CMOS. It is read once at boot to get the wall time
+//! then, the timer ticks count the time elapsed since. `SYS_GETTIMEOFDAY`
+//! gives it to userland. The userland tasks use `Timeval` too
+
+use crate::cpu::*;
+use crate::interrupts::ticks;
+use crate::pit::ticks_to_us;
+use crate::syscalls::*;
+use crate::uaccess::*;
+use crate::{print, println, PERIPHERALS};
+
+/// CMOS register selection port
+const CMOS_ADDRESS : u16 = 0x70;
+
+/// CMOS data port
+const CMOS_DATA : u16 = 0x71;
+
+/// CMOS registers of the date and time of the RTC
+const RTC_SECONDS : u8 = 0x00;
+const RTC_MINUTES : u8 = 0x02;
+const RTC_HOURS : u8 = 0x04;
+const RTC_DAY : u8 = 0x07;
+const RTC_MONTH : u8 = 0x08;
+const RTC_YEAR : u8 = 0x09;
+
+/// CMOS register of the century. Its place is given by the ACPI tables and
+/// this is the usual one, but some machines don't have it at all
+const RTC_CENTURY : u8 = 0x32;
+
+/// CMOS register holding the update in progress flag of the RTC
+const RTC_STATUS_A : u8 = 0x0a;
+
+/// CMOS register holding the format of the registers of the RTC
+const RTC_STATUS_B : u8 = 0x0b;
+
+/// The RTC is updating its registers
+const RTC_UPDATE_IN_PROGRESS : u8 = 1 << 7;
+
+/// The hours are counted from 0 to 23, or from 1 to 12 else
+const RTC_24_HOURS : u8 = 1 << 1;
+
+/// The registers are binary, or BCD else
+const RTC_BINARY : u8 = 1 << 2;
+
+/// Bit of the hours set after noon with 12 hours
+const RTC_PM : u8 = 1 << 7;
+
+/// Max number of reads of the RTC until two of them in a row agree
+const RTC_MAX_READS : usize = 16;
+
+/// Seconds from the Unix epoch to the boot, 0 before `rtc_init`
+static mut BOOT_TIME : u64 = 0;
+
+/// Tick at which the RTC was read by `rtc_init`
+static mut BOOT_TICK : u64 = 0;
+
+/// Date and time given by the RTC, in UTC on most of the emulators
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    pub year : u16,
+    pub month : u8,
+    pub day : u8,
+    pub hour : u8,
+    pub minute : u8,
+    pub second : u8,
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year,
+               self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+impl DateTime {
+    /// Get the number of seconds from the Unix epoch to this date
+    pub fn unix_time(&self) -> u64 {
+        // Count the years from March, so that the leap day ends them
+        let (year, month) = if self.month <= 2 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 -
+            year_of_era / 100 + day_of_year;
+
+        // Days from 0000-03-01 to 1970-01-01
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * 86_400 + self.hour as u64 * 3600 + self.minute as u64 * 60 +
+            self.second as u64
+    }
+}
+
+/// Time since the Unix epoch, filled by `SYS_GETTIMEOFDAY`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Timeval {
+    /// Seconds
+    pub sec : u64,
+
+    /// Microseconds, below 1000000
+    pub usec : u32,
+}
+
+impl Timeval {
+    /// Create an empty `Timeval` for userland to pass to `SYS_GETTIMEOFDAY`
+    pub const fn empty() -> Self {
+        Self { sec : 0, usec : 0 }
+    }
+}
+
+/// Read the CMOS register `reg`
+fn cmos_read(reg : u8) -> u8 {
+    unsafe {
+        out8(CMOS_ADDRESS, reg);
+        in8(CMOS_DATA)
+    }
+}
+
+/// Wait for the RTC to be done updating its registers. They stay stable for
+/// about a millisecond after the flag goes down
+fn wait_update_done() {
+    while cmos_read(RTC_STATUS_A) & RTC_UPDATE_IN_PROGRESS != 0 {}
+}
+
+/// Read the seconds of the RTC, as stored by it. Only good to look for
+/// changes since the format is not decoded
+pub fn rtc_seconds() -> u8 {
+    wait_update_done();
+    cmos_read(RTC_SECONDS)
+}
+
+/// Read the registers of the date and time, century last, without decoding
+fn read_registers() -> [u8; 7] {
+    wait_update_done();
+    [RTC_SECONDS, RTC_MINUTES, RTC_HOURS, RTC_DAY, RTC_MONTH, RTC_YEAR,
+     RTC_CENTURY].map(cmos_read)
+}
+
+/// Convert `val` from BCD to binary
+fn from_bcd(val : u8) -> u8 {
+    (val >> 4) * 10 + (val & 0xf)
+}
+
+/// Read the date and time of the RTC. The registers are read until two
+/// reads in a row agree, since the RTC may start an update between the
+/// check of the flag and the reads. Returns `None` if the RTC never agrees
+/// with itself or holds an invalid date, or one before the Unix epoch
+pub fn read_datetime() -> Option<DateTime> {
+    let mut regs = read_registers();
+    let mut reads = 1;
+    loop {
+        let again = read_registers();
+        if again == regs {
+            break;
+        }
+        reads += 1;
+        if reads == RTC_MAX_READS {
+            return None;
+        }
+        regs = again;
+    }
+
+    let status = cmos_read(RTC_STATUS_B);
+    let decode = |val : u8| {
+        if status & RTC_BINARY != 0 { val } else { from_bcd(val) }
+    };
+    let [second, minute, hours, day, month, year, century] = regs;
+
+    // With 12 hours, 12 AM is midnight and 12 PM is noon
+    let mut hour = decode(hours & !RTC_PM);
+    if status & RTC_24_HOURS == 0 {
+        hour %= 12;
+        if hours & RTC_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    // Without a century register, 0x32 holds something else. Only trust
+    // it if it looks like a century, else assume years 1970 to 2069
+    let year = decode(year) as u16;
+    let century = match decode(century) {
+        c @ 19..=30 => c as u16,
+        _ if year >= 70 => 19,
+        _ => 20,
+    };
+
+    let datetime = DateTime {
+        year : century * 100 + year,
+        month : decode(month),
+        day : decode(day),
+        hour,
+        minute : decode(minute),
+        second : decode(second),
+    };
+    let valid = datetime.year >= 1970 && (1..=12).contains(&datetime.month) &&
+        (1..=31).contains(&datetime.day) && datetime.hour < 24 &&
+        datetime.minute < 60 && datetime.second < 60;
+    if valid { Some(datetime) } else { None }
+}
+
+/// Read the RTC to get the wall time of the boot, print it and register
+/// `SYS_GETTIMEOFDAY`. Must be called after `pit_init`
+pub fn rtc_init() {
+    match read_datetime() {
+        Some(datetime) => unsafe {
+            BOOT_TIME = datetime.unix_time();
+            BOOT_TICK = ticks();
+            println!("rtc : booted at {} UTC", datetime);
+        },
+        None => println!("rtc : invalid date, the time starts at 1970"),
+    }
+
+    register_syscall(SYS_GETTIMEOFDAY, "gettimeofday", &[SyscallArg::Addr],
+                     |ctx| sys_gettimeofday(ctx.regs.ecx));
+}
+
+/// Get the wall time in microseconds since the Unix epoch, from the boot
+/// time and the ticks elapsed since
+pub fn time_us() -> u64 {
+    let (boot_time, boot_tick) = unsafe { (BOOT_TIME, BOOT_TICK) };
+    boot_time * 1_000_000 + ticks_to_us(ticks() - boot_tick)
+}
+
+/// Fill the `Timeval` at `buf` with the wall time. Fails with EFAULT if the
+/// buffer is not writable
+fn sys_gettimeofday(buf : u32) -> i32 {
+    let now = time_us();
+    let tv = Timeval {
+        sec : now / 1_000_000,
+        usec : (now % 1_000_000) as u32,
+    };
+
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&tv as *const Timeval as *const u8,
+                                    core::mem::size_of::<Timeval>())
+    };
+    match copy_to_user(buf, bytes) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
@@ -19,12 +19,33 @@ pub const FlagsSize32 : u8 = 1 << 2;
 
 const MAX_GDT_SIZE : usize = 8192;
 
-static mut GDT_ENTRIES : [SegmentDescriptor; 6] = [ 
-    SegmentDescriptor::null_descriptor(); 6
+static mut GDT_ENTRIES : [SegmentDescriptor; 8] = [ 
+    SegmentDescriptor::null_descriptor(); 8
 ];
 
 pub static mut TSS : TssEntry = TssEntry::default();
 
+/// TSS of the task handling double faults. A double fault usually comes from
+/// a fault while pushing an interrupt frame on a broken stack, so it is
+/// handled by a hardware task switch to a stack of its own
+static mut DOUBLE_FAULT_TSS : TssEntry = TssEntry::default();
+
+/// Selector of the TSS of the kernel, loaded in the task register
+pub const TSS_SELECTOR : u16 = 0x28;
+
+/// Selector of the double fault TSS
+pub const DOUBLE_FAULT_TSS_SELECTOR : u16 = 0x30;
+
+/// Index in the GDT of the TLS segment of userland
+const USER_TLS_INDEX : usize = 7;
+
+/// Selector of the TLS segment of userland, loaded in gs. Its base is the
+/// TLS of the running task
+pub const USER_TLS_SELECTOR : u16 = (USER_TLS_INDEX << 3) as u16 | 3;
+
+/// Size in bytes of the TLS segment
+pub const USER_TLS_SIZE : u32 = 0x1000;
+
 /// An entry in the TSS
 #[repr(C)]
 pub struct TssEntry {
@@ -95,6 +116,74 @@ impl TssEntry {
     pub fn update_esp0(&mut self, esp : u32) {
         self.esp0 = esp;
     }
+
+    /// Get the esp saved in this TSS by the last hardware task switch
+    pub fn saved_esp(&self) -> u32 {
+        self.esp
+    }
+
+    /// Get the selector of the TSS of the task that switched to this one
+    /// through a task gate
+    pub fn back_link(&self) -> u16 {
+        self.prev_tss as u16
+    }
+}
+
+/// The state saved in the TSS by the last hardware task switch away from it
+impl core::fmt::Display for TssEntry {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, r#"    eax {:#010x} ecx {:#010x} edx {:#010x} ebx {:#010x}
+    esp {:#010x} ebp {:#010x} esi {:#010x} edi {:#010x}
+
+    cs:eip {:#04x}:{:#010x}
+    ss:esp {:#04x}:{:#010x}
+    eflags {:#x}
+    ds     {:#x}
+    es     {:#x}
+    fs     {:#x}
+    gs     {:#x}
+    cr3    {:#x}
+"#,
+        self.eax, self.ecx, self.edx, self.ebx, self.esp, self.ebp,
+        self.esi, self.edi, self.cs, self.eip, self.ss, self.esp,
+        self.eflags, self.ds, self.es, self.fs, self.gs, self.cr3)
+    }
+}
+
+/// Get the TSS of the GDT selected by `selector`, whatever its privilege
+/// level. `None` if the selector is not one of a TSS
+pub fn tss_of_selector(selector : u16) -> Option<&'static TssEntry> {
+    unsafe {
+        match selector & !7 {
+            TSS_SELECTOR => Some(&TSS),
+            DOUBLE_FAULT_TSS_SELECTOR => Some(&DOUBLE_FAULT_TSS),
+            _ => None,
+        }
+    }
+}
+
+/// Get the TSS holding the state of the code that double faulted, the one
+/// the double fault task was switched from
+pub fn double_fault_source() -> Option<&'static TssEntry> {
+    tss_of_selector(unsafe { DOUBLE_FAULT_TSS.back_link() })
+}
+
+/// Make the double fault task run `eip` on the stack `esp`, in the address
+/// space of the page directory `cr3`
+pub fn setup_double_fault_tss(cr3 : u32, eip : u32, esp : u32) {
+    unsafe {
+        DOUBLE_FAULT_TSS.cr3 = cr3;
+        DOUBLE_FAULT_TSS.eip = eip;
+        DOUBLE_FAULT_TSS.esp = esp;
+        DOUBLE_FAULT_TSS.eflags = 0x2;
+        DOUBLE_FAULT_TSS.cs = 0x8;
+        DOUBLE_FAULT_TSS.ss = 0x10;
+        DOUBLE_FAULT_TSS.ds = 0x10;
+        DOUBLE_FAULT_TSS.es = 0x10;
+        DOUBLE_FAULT_TSS.fs = 0x10;
+        DOUBLE_FAULT_TSS.gs = 0x10;
+        DOUBLE_FAULT_TSS.iomap_base = core::mem::size_of::<TssEntry>() as u16;
+    }
 }
 
 /// Init the GDT
@@ -110,7 +199,12 @@ pub fn gdt_init() {
     gdt_pointer.add_descriptor(2, SegmentDescriptor::kernel_data_desc());
     gdt_pointer.add_descriptor(3, SegmentDescriptor::user_code_desc());
     gdt_pointer.add_descriptor(4, SegmentDescriptor::user_data_desc());
-    gdt_pointer.add_descriptor(5, SegmentDescriptor::tss_desc());
+    gdt_pointer.add_descriptor(5, 
+        SegmentDescriptor::tss_desc(unsafe { &TSS }));
+    gdt_pointer.add_descriptor(6, 
+        SegmentDescriptor::tss_desc(unsafe { &DOUBLE_FAULT_TSS }));
+    gdt_pointer.add_descriptor(USER_TLS_INDEX as isize, 
+        SegmentDescriptor::user_tls_desc(0));
 
     set_gdt(&gdt_pointer);
 
@@ -138,6 +232,19 @@ pub fn gdt_init() {
     flush_tss();
 }
 
+/// Make the TLS segment start at `base`. The segment registers cache their
+/// descriptor, so gs must be loaded again to use the new base
+pub fn set_tls_base(base : u32) {
+    unsafe {
+        GDT_ENTRIES[USER_TLS_INDEX] = SegmentDescriptor::user_tls_desc(base);
+    }
+}
+
+/// Get a copy of the descriptor at `index` in the GDT, `None` past its end
+pub fn gdt_descriptor(index : usize) -> Option<SegmentDescriptor> {
+    unsafe { GDT_ENTRIES.get(index).copied() }
+}
+
 /// Switch the esp0 value in `TSS` 
 #[inline]
 pub fn set_kernel_stack(esp : u32) {
@@ -272,15 +379,22 @@ impl SegmentDescriptor {
         )
     }
 
-    fn tss_desc() -> Self {
-        unsafe {
-            Self::new(
-                &TSS as *const _ as u32,
-                core::mem::size_of::<TssEntry>() as u32,
-                AccessAccessed | AccessExecutable | AccessPresent,
-                0
-            )
-        }
+    fn user_tls_desc(base : u32) -> Self {
+        Self::new(
+            base,
+            USER_TLS_SIZE - 1,
+            AccessPresent | AccessRing3 | AccessSystem | AccessRW,
+            FlagsSize32
+        )
+    }
+
+    fn tss_desc(tss : &TssEntry) -> Self {
+        Self::new(
+            tss as *const _ as u32,
+            core::mem::size_of::<TssEntry>() as u32,
+            AccessAccessed | AccessExecutable | AccessPresent,
+            0
+        )
     }
 
     fn set_flags(&mut self, flags : u8) {
@@ -317,4 +431,54 @@ impl SegmentDescriptor {
         base |= (self.base3 as u32) << 24;
         base
     }
+
+    /// Get the flags of the descriptor, the high half of `limit2_flags`
+    fn get_flags(&self) -> u8 {
+        self.limit2_flags >> 4
+    }
+}
+
+impl core::fmt::Display for SegmentDescriptor {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        // The limit counts 4K pages with page granularity
+        let limit = if self.get_flags() & FlagsPageGranularity != 0 {
+            self.get_limit() << 12 | 0xfff
+        } else {
+            self.get_limit()
+        };
+        write!(f, "base {:#x} limit {:#x} dpl {}", self.get_base(), limit,
+               (self.access >> 5) & 3)?;
+
+        // Without AccessSystem, the low bits give the type of a system
+        // segment instead of the rights of a code or data segment
+        let access = self.access;
+        if access & AccessSystem == 0 {
+            match access & 0xf {
+                0x2 => write!(f, " ldt")?,
+                0x9 => write!(f, " tss")?,
+                0xb => write!(f, " busy tss")?,
+                ty => write!(f, " system type {:#x}", ty)?,
+            }
+        } else if access & AccessExecutable != 0 {
+            write!(f, " code")?;
+            if access & AccessRW != 0 {
+                write!(f, " readable")?;
+            }
+            if access & AccessConforming != 0 {
+                write!(f, " conforming")?;
+            }
+        } else {
+            write!(f, " data")?;
+            if access & AccessRW != 0 {
+                write!(f, " writable")?;
+            }
+        }
+        if self.get_flags() & FlagsSize32 != 0 {
+            write!(f, " 32 bits")?;
+        }
+        if access & AccessPresent == 0 {
+            write!(f, " not present")?;
+        }
+        Ok(())
+    }
 }
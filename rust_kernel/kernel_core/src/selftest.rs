@@ -0,0 +1,733 @@
+//! Kernel tasks checking the kernel at boot, and benchmarks. They are only
+//! built with the `selftest` feature, otherwise the boot only starts the
+//! demos
+
+use core::arch::asm;
+use crate::interrupts::*;
+use crate::paging::*;
+use crate::paging::pagemem::*;
+use crate::paging::physmem::PhysMem;
+use crate::paging::virtmem::*;
+use crate::pic::*;
+use crate::pit::*;
+use crate::serial::*;
+use crate::{bench, cpu, debug, nmi, sync, tasks, userland_tasks, vsys, work};
+use crate::{print, println, PERIPHERALS};
+
+/// Run `body` with preemption disabled, so that no other task allocates
+/// physical memory meanwhile, and panic if it changed the number of free
+/// pages. Then check the statistics of the physical allocator
+pub fn check_leaks<R>(what : &str, body : impl FnOnce() -> R) -> R {
+    let _guard = sync::PreemptGuard::new();
+    let free_pages = PhysMem::free_pages();
+    let result = body();
+
+    let leaked = free_pages as isize - PhysMem::free_pages() as isize;
+    if leaked != 0 {
+        panic!("{} : leaked {} physical pages", what, leaked);
+    }
+    PhysMem::check_stats();
+    result
+}
+
+/// Kernel task switching to the other tasks a few thousand times while the
+/// timer preempts it too, and checking that its registers survive each
+/// switch by redoing the same computation without any switch
+fn switch_stress_task() {
+    const ROUNDS : u32 = 2000;
+
+    let step = |(a, b, c) : (u32, u32, u32), round : u32| {
+        let a = a.wrapping_mul(31).wrapping_add(round);
+        (a, b ^ a.rotate_left(5), c.wrapping_add(b))
+    };
+
+    let pid = tasks::current_task().pid;
+    let seed = core::hint::black_box((pid, !pid, pid << 16));
+    let mut state = seed;
+    for round in 0..ROUNDS {
+        state = step(state, round);
+        tasks::kthread_yield();
+    }
+
+    let mut expected = seed;
+    for round in 0..ROUNDS {
+        expected = step(expected, round);
+    }
+    if state != expected {
+        panic!("switch stress : pid {} state corrupted, {:x?} != {:x?}",
+               pid, state, expected);
+    }
+    println!("switch stress : pid {} survived {} switches", pid, ROUNDS);
+}
+
+/// Kernel task waiting for the demos to settle, then spawning tasks that
+/// exit right away one after the other, and checking that the reaper gives
+/// all their memory back
+fn reaper_check_task() {
+    const ROUNDS : usize = 50;
+
+    // The demos that end are done once the number of tasks stops changing,
+    // and the ones that keep running don't allocate memory anymore
+    let mut count = tasks::task_count();
+    let mut stable_seconds = 0;
+    while stable_seconds < 5 {
+        tasks::kthread_sleep(frequency());
+        let now = tasks::task_count();
+        if now == count {
+            stable_seconds += 1;
+        } else {
+            count = now;
+            stable_seconds = 0;
+        }
+    }
+
+    let free_pages = PhysMem::free_pages();
+    for _ in 0..ROUNDS {
+        let pid = tasks::Task::new(b"reaper_check", userland_tasks::task6)
+            .expect("reaper check : no memory for a task");
+
+        while tasks::find_task(pid).is_some() {
+            tasks::kthread_sleep(1);
+        }
+    }
+
+    // Signed, the tasks still running may have freed pages meanwhile. Only
+    // fewer free pages than before is a leak
+    let leaked = free_pages as isize - PhysMem::free_pages() as isize;
+    if leaked > 0 {
+        panic!("reaper : {} exited tasks leaked {} physical pages",
+               ROUNDS, leaked);
+    }
+    PhysMem::check_stats();
+    println!("reaper : {} exited tasks freed without leaking memory", ROUNDS);
+}
+
+/// Kernel task timing the allocation and the free of physical pages
+fn phys_alloc_bench_task() {
+    bench::bench_phys_alloc(10_000);
+    PhysMem::check_stats();
+}
+
+/// Kernel task comparing the cost of the eager and lazy FPU switches
+fn fpu_switch_bench_task() {
+    bench::bench_fpu_switch(10_000);
+}
+
+/// Kernel task allocating and freeing physical blocks of random orders, some
+/// of them freed page by page. Every block is filled with its address to
+/// catch overlaps, and the free page count must come back to where it was
+fn buddy_stress_task() {
+    use crate::paging::buddy::MAX_ORDER;
+
+    const ROUNDS : u32 = 4000;
+    const SLOTS : usize = 32;
+
+    let fill = |paddr : PhysAddr, order : usize, check : bool| unsafe {
+        let words = (PAGE_SIZE << order) / core::mem::size_of::<u32>();
+        let block = PhysMem::translate_mut(paddr, PAGE_SIZE << order)
+            .expect("buddy : block outside of RAM") as *mut u32;
+        for word in (0..words).step_by(PAGE_SIZE / 4) {
+            let ptr = block.add(word);
+            if !check {
+                core::ptr::write_volatile(ptr, paddr.0);
+            } else if core::ptr::read_volatile(ptr) != paddr.0 {
+                panic!("buddy : block {:#x} of order {} overwritten",
+                       paddr.0, order);
+            }
+        }
+    };
+
+    check_leaks("buddy", || {
+        let mut blocks : [Option<(PhysAddr, usize)>; SLOTS] = [None; SLOTS];
+        let mut state : u32 = 0x2545_f491;
+        for round in 0..ROUNDS {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let slot = state as usize % SLOTS;
+            match blocks[slot].take() {
+                Some((paddr, order)) => {
+                    fill(paddr, order, true);
+                    if round % 2 == 0 {
+                        unsafe { PhysMem::free_order(paddr, order); }
+                    } else {
+                        for page in 0..1 << order {
+                            let addr = paddr.0 + (page * PAGE_SIZE) as u32;
+                            unsafe { PhysMem::free_phys(PhysAddr(addr)); }
+                        }
+                    }
+                }
+                None => {
+                    let order = (state >> 8) as usize % (MAX_ORDER / 2 + 1);
+                    let paddr = match unsafe { PhysMem::alloc_order(order) } {
+                        Ok(paddr) => paddr,
+                        Err(_) => continue,
+                    };
+                    if !(paddr.0 as usize).is_multiple_of(PAGE_SIZE << order) {
+                        panic!("buddy : block {:#x} of order {} misaligned",
+                               paddr.0, order);
+                    }
+                    fill(paddr, order, false);
+                    blocks[slot] = Some((paddr, order));
+                }
+            }
+        }
+        for (paddr, order) in blocks.iter().flatten() {
+            fill(*paddr, *order, true);
+            unsafe { PhysMem::free_order(*paddr, *order); }
+        }
+    });
+    println!("buddy : {} rounds of mixed orders freed without leaking",
+             ROUNDS);
+}
+
+/// Kernel task standing for a driver of an ISA DMA device. It takes every
+/// page of the DMA zone, which must all be below 16 MB, until the zone runs
+/// out, then gives them back
+fn dma_check_task() {
+    use crate::paging::physmem::Zone;
+
+    let free_pages = PhysMem::zone_free_pages(Zone::Dma);
+
+    // The pages are chained through their first word
+    let mut head : Option<PhysAddr> = None;
+    let mut count = 0;
+    while let Ok(page) = unsafe { PhysMem::alloc_phys_zone(Zone::Dma) } {
+        if page.0 as usize + PAGE_SIZE > DMA_ZONE_END {
+            panic!("dma : zone page {:#x} above 16 MB", page.0);
+        }
+        unsafe {
+            let link = PhysMem::translate_mut(page, PAGE_SIZE)
+                .expect("dma : page outside of RAM") as *mut u32;
+            core::ptr::write_volatile(link, head.map_or(0, |head| head.0));
+        }
+        head = Some(page);
+        count += 1;
+    }
+
+    while let Some(page) = head {
+        let next = unsafe {
+            core::ptr::read_volatile(PhysMem::translate(page, PAGE_SIZE)
+                .expect("dma : page outside of RAM") as *const u32)
+        };
+        unsafe { PhysMem::free_phys(page); }
+        head = if next == 0 { None } else { Some(PhysAddr(next)) };
+    }
+
+    if count != free_pages ||
+            PhysMem::zone_free_pages(Zone::Dma) != free_pages {
+        panic!("dma : {} pages taken out of {}, {} free after", count,
+               free_pages, PhysMem::zone_free_pages(Zone::Dma));
+    }
+    println!("dma : {} pages of the DMA zone allocated below 16 MB", count);
+}
+
+/// Kernel task changing mappings of its own address space, whose page tables
+/// are reached through the recursive mapping, and of a new address space,
+/// whose page tables are reached through temporary slots. The changes are
+/// checked with translate
+fn paging_check_task() {
+    const MAGIC : u32 = 0x1337_c0de;
+
+    let read_phys = |paddr : PhysAddr| unsafe {
+        core::ptr::read_volatile(
+            PhysMem::translate(paddr, PAGE_SIZE)
+                .expect("paging : page outside of RAM") as *const u32)
+    };
+
+    // The kernel address space is shared by the kernel tasks
+    let _guard = sync::PreemptGuard::new();
+
+    // A page of the current address space, seen by translate and in the
+    // page table through the recursive mapping
+    let mut vspace = VirtMem::get_current();
+    let page = vspace.alloc_virt_pages(1, true, false);
+    let pte = unsafe { core::ptr::read_volatile(current_pte_ptr(page)) };
+    let paddr = match vspace.translate(page) {
+        Some(Mapping { page : Some(paddr), flags, .. })
+            if paddr.0 == pte & !0xfff && flags & PAGE_WRITE != 0 => paddr,
+        mapping => panic!("paging : {:#x} mapped as {:x?}, pte {:#x}",
+                          page.0, mapping, pte),
+    };
+    unsafe { core::ptr::write_volatile(page.0 as *mut u32, MAGIC); }
+    assert_eq!(read_phys(paddr), MAGIC, "paging : write to {:#x} lost",
+               page.0);
+    vspace.free_virt_pages(page, 1)
+        .expect("paging : free failed");
+    if vspace.translate(page).and_then(|mapping| mapping.page).is_some() {
+        panic!("paging : {:#x} still mapped after free", page.0);
+    }
+
+    // A page of another address space, mapped and written through the
+    // temporary slots
+    let other = VirtMem::new().expect("paging : no memory for a vspace");
+    let vaddr = VirtAddr(tasks::USER_MMAP_BASE);
+    let frame = unsafe { PhysMem::alloc_phys_zeroed() };
+    other.map_raw(vaddr, frame.0 | PAGE_PRESENT | PAGE_USER)
+        .expect("paging : foreign page already mapped");
+    match other.translate(vaddr) {
+        Some(Mapping { page : Some(paddr), flags, .. })
+            if paddr.0 == frame.0 && flags & PAGE_USER != 0 => {},
+        mapping => panic!("paging : foreign {:#x} mapped as {:x?}",
+                          vaddr.0, mapping),
+    }
+    unsafe {
+        let slot = map_temp(frame) as *mut u32;
+        core::ptr::write_volatile(slot, MAGIC);
+        unmap_temp(slot as *mut u8);
+    }
+    assert_eq!(read_phys(frame), MAGIC, "paging : temporary write lost");
+    other.unmap(vaddr, 1).expect("paging : foreign unmap failed");
+    if other.translate(vaddr).and_then(|mapping| mapping.page).is_some() {
+        panic!("paging : foreign {:#x} still mapped after unmap", vaddr.0);
+    }
+    unsafe { PhysMem::free_phys(frame); }
+    other.destroy();
+
+    println!("paging : recursive mapping and temporary slots checked");
+}
+
+/// Kernel task checking the aligned and fixed address allocations of the
+/// virtual allocator on a new address space, which must not leak memory
+fn virt_alloc_check_task() {
+    check_leaks("virt alloc", || {
+        let mut vspace = VirtMem::new()
+            .expect("virt alloc : no memory for a vspace");
+
+        // A page first, so that the next free page is not aligned
+        let single = vspace.alloc_virt_pages(1, true, false);
+        for &align in [2, 4, 16].iter() {
+            let addr = vspace.alloc_virt_pages_aligned(3, align, true, false);
+            if !(addr.0 as usize).is_multiple_of(align * PAGE_SIZE) ||
+                    !vspace.is_mapped(addr) {
+                panic!("virt alloc : {:#x} not aligned on {} pages", addr.0,
+                       align);
+            }
+
+            // A fixed request overlapping the allocation collides with it, the
+            // page right before it was skipped for the alignment
+            let before = VirtAddr(addr.0 - PAGE_SIZE as u32);
+            if !matches!(vspace.alloc_virt_at(before, 2, true, false),
+                         Err(MappingError::InUse)) {
+                panic!("virt alloc : fixed {:#x} overlaps {:#x}", before.0,
+                       addr.0);
+            }
+            vspace.alloc_virt_at(before, 1, true, false)
+                .expect("virt alloc : fixed request on a free page failed");
+            vspace.free_virt_pages(before, 1)
+                .expect("virt alloc : fixed free failed");
+            vspace.free_virt_pages(addr, 3)
+                .expect("virt alloc : aligned free failed");
+        }
+        vspace.free_virt_pages(single, 1)
+            .expect("virt alloc : free failed");
+
+        if !matches!(vspace.reserve_virt_at(VirtAddr(0), 1),
+                     Err(MappingError::OutOfRange)) {
+            panic!("virt alloc : fixed request outside of the area accepted");
+        }
+        vspace.destroy();
+    });
+    println!("virt alloc : aligned and fixed allocations checked");
+}
+
+/// Kernel task creating and freeing user address spaces, with forked copies,
+/// which must give back every page they took
+fn vspace_cycle_check_task() {
+    const ROUNDS : usize = 1000;
+
+    /// Rounds run without preemption, the leaks are checked after each batch
+    const BATCH : usize = 50;
+
+    for _ in 0..ROUNDS / BATCH {
+        check_leaks("vspace cycle", || for _ in 0..BATCH {
+            let vspace = VirtMem::new()
+                .expect("vspace cycle : no memory for a vspace");
+            setup_identity_mapping(&vspace)
+                .expect("vspace cycle : no memory for the identity mapping");
+            vsys::vsys_map(&vspace)
+                .expect("vspace cycle : info page over an existing mapping");
+            vspace.map(VirtAddr(USER_SPACE_BASE), PAGE_SIZE, true, true)
+                .expect("vspace cycle : user page over an existing mapping");
+
+            let child = vspace.fork().expect("vspace cycle : fork failed");
+            tasks::free_user_vspace(child);
+            tasks::free_user_vspace(vspace);
+        });
+    }
+    println!("vspace cycle : {} address spaces freed without leaking memory",
+             ROUNDS);
+}
+
+/// Kernel task registering a handler on a free vector, raising it with a
+/// software interrupt, then removing the handler
+fn intr_handler_check_task() {
+    const VECTOR : u8 = 0x82;
+    static mut RAISED : u32 = 0;
+
+    register_interrupt_handler(VECTOR, |ctx| unsafe {
+        RAISED += 1;
+        ctx.regs.eax = 0x1337;
+    });
+    let eax : u32;
+    unsafe { asm!("int {}", const VECTOR, out("eax") eax); }
+    unregister_interrupt_handler(VECTOR);
+
+    let raised = unsafe { RAISED };
+    if raised != 1 || eax != 0x1337 {
+        panic!("interrupt handler : raised {} times, eax {:#x}", raised, eax);
+    }
+    println!("interrupt handler : vector {:#x} registered and removed",
+             VECTOR);
+}
+
+/// Kernel task checking that the dispatcher sends the EOI of the IRQs. The
+/// timer and the serial IRQ wait in the PIC while masked and come once
+/// unmasked, a raised IRQ without handler is unclaimed, and none is left in
+/// service
+fn irq_eoi_check_task() {
+    const FREE_IRQ : u8 = 5;
+
+    let in_service = || {
+        let _guard = sync::InterruptGuard::new();
+        Pic::get_isr()
+    };
+    let tick_us = 1_000_000 / frequency();
+
+    // Nothing preempts us while the timer is masked
+    let ticks_before = ticks();
+    Pic::mask(TIMER_IRQ as u32);
+    delay_us(2 * tick_us);
+    let held = ticks() == ticks_before && Pic::get_irr() & 1 != 0;
+    Pic::unmask(TIMER_IRQ as u32);
+    delay_us(100);
+    if !held || ticks() == ticks_before {
+        panic!("irq eoi check : masked timer held {}, {} ticks", held,
+               ticks() - ticks_before);
+    }
+
+    // The transmitter is empty, its interrupt is raised at once
+    let serial_vector = IRQ_BASE + SERIAL_IRQ;
+    let count = interrupt_count(serial_vector);
+    let unclaimed = unclaimed_irqs(SERIAL_IRQ);
+    Pic::mask(SERIAL_IRQ as u32);
+    if set_tx_empty_irq(true) {
+        delay_us(100);
+        let held = interrupt_count(serial_vector) == count &&
+            Pic::get_irr() & 1 << SERIAL_IRQ != 0;
+        Pic::unmask(SERIAL_IRQ as u32);
+        delay_us(100);
+        set_tx_empty_irq(false);
+        let count = interrupt_count(serial_vector) - count;
+        if !held || count == 0 || unclaimed_irqs(SERIAL_IRQ) != unclaimed {
+            panic!("irq eoi check : masked serial held {}, {} irqs, {} \
+                    unclaimed", held, count,
+                   unclaimed_irqs(SERIAL_IRQ) - unclaimed);
+        }
+    } else {
+        Pic::unmask(SERIAL_IRQ as u32);
+    }
+
+    // Raised by software, the PIC has no line in service for its EOI
+    let unclaimed = unclaimed_irqs(FREE_IRQ);
+    unsafe { asm!("int {}", const IRQ_BASE + FREE_IRQ); }
+    if unclaimed_irqs(FREE_IRQ) != unclaimed + 1 {
+        panic!("irq eoi check : IRQ {} without handler not unclaimed",
+               FREE_IRQ);
+    }
+
+    let isr = in_service();
+    if isr != 0 {
+        panic!("irq eoi check : IRQs {:#06x} left in service", isr);
+    }
+    println!("irq eoi check : masked IRQs held then delivered, no IRQ left \
+              in service");
+}
+
+/// Kernel task raising an NMI, which the handler must resume
+fn nmi_check_task() {
+    let resumed = nmi::nmi_resumed();
+    nmi::inject_nmi();
+    if nmi::nmi_resumed() != resumed + 1 {
+        panic!("nmi check : injected NMI not resumed");
+    }
+    println!("nmi check : resumed after the injected NMI");
+}
+
+/// Kernel task spinning for several ticks under an `InterruptGuard`, with
+/// a nested one inside. The timer, which preempts, must not tick until the
+/// outer guard is dropped, then deliver the tick it held
+fn interrupt_guard_check_task() {
+    let tick_us = 1_000_000 / frequency();
+    let ticks_before = ticks();
+    let (held, nested) = {
+        let _guard = sync::InterruptGuard::new();
+        {
+            let _nested = sync::InterruptGuard::new();
+            delay_us(2 * tick_us);
+        }
+        let nested = !cpu::interrupts_enabled();
+        delay_us(2 * tick_us);
+        (ticks() == ticks_before, nested)
+    };
+    if !cpu::interrupts_enabled() {
+        panic!("interrupt guard check : interrupts left disabled");
+    }
+    delay_us(100);
+    if !held || !nested || ticks() == ticks_before {
+        panic!("interrupt guard check : held {}, nested {}, {} ticks", held,
+               nested, ticks() - ticks_before);
+    }
+    println!("interrupt guard check : 4 ticks without preemption");
+}
+
+/// Kernel task freeing mappings that don't own their memory: the identity
+/// mapping, a borrowed page of RAM and a shared page. Each free must fail
+/// without touching the physical allocator
+fn borrowed_free_check_task() {
+    check_leaks("borrowed free", || {
+        let mut vspace = VirtMem::get_current();
+
+        let image = VirtAddr(0x10_0000);
+        match vspace.free_virt_pages(image, 1) {
+            Err(MappingError::OutOfRange) => {},
+            res => panic!("borrowed free : identity {:#x} freed : {:?}",
+                          image.0, res),
+        }
+
+        // The same page of RAM, borrowed then shared
+        let frame = unsafe { PhysMem::alloc_phys() };
+        let (borrowed, _) = vspace.map_phys_range(frame, PAGE_SIZE, PAGE_WRITE)
+            .expect("borrowed free : mapping failed");
+        match vspace.free_virt_pages(borrowed, 1) {
+            Err(MappingError::NotOwned) => {},
+            res => panic!("borrowed free : borrowed {:#x} freed : {:?}",
+                          borrowed.0, res),
+        }
+        vspace.unmap_phys_range(borrowed, PAGE_SIZE);
+
+        let shared = vspace.reserve_virt_pages(1);
+        let flags = PAGE_PRESENT | PAGE_WRITE | PAGE_SHARED;
+        vspace.map_raw(shared, frame.0 | flags)
+            .expect("borrowed free : shared page over an existing mapping");
+        match vspace.free_virt_pages(shared, 1) {
+            Err(MappingError::NotOwned) => {},
+            res => panic!("borrowed free : shared {:#x} freed : {:?}", shared.0,
+                          res),
+        }
+        vspace.unmap(shared, 1).expect("borrowed free : shared page vanished");
+        vspace.release_virt_pages(shared, 1);
+
+        if PhysMem::refcount(frame) != 1 {
+            panic!("borrowed free : page {:#x} freed with a mapping", frame.0);
+        }
+        unsafe { PhysMem::free_phys(frame); }
+    });
+    println!("borrowed free : identity, borrowed and shared pages kept");
+}
+
+/// Kernel task forking the kernel address space. The copy must map the
+/// identity mappings like the original, through the same page tables, and
+/// keep the dynamic allocations of the original reserved. Destroying it must
+/// not leak memory nor free the shared page tables
+fn vspace_fork_check_task() {
+    // Mappings are compared on their page table entry, page and flags, the
+    // page directory entries are at different addresses
+    let key = |mapping : Option<Mapping>| mapping.map(|mapping| {
+        (mapping.pte.map(|pte| pte.0), mapping.page.map(|page| page.0),
+         mapping.flags)
+    });
+
+    check_leaks("vspace fork", || {
+        let mut vspace = VirtMem::get_current();
+        let page = vspace.alloc_virt_pages(1, true, false);
+        let mut child = vspace.fork().expect("vspace fork : fork failed");
+
+        let ranges = [(0, KERNEL_IMAGE_MAP_SIZE),
+                      (KERNEL_PHYS_WINDOW_BASE, KERNEL_PHYS_WINDOW_SIZE)];
+        for &(base, size) in ranges.iter() {
+            for vaddr in (base..base + size).step_by(0x1_0000) {
+                let vaddr = VirtAddr(vaddr);
+                let (parent_key, child_key) =
+                    (key(vspace.translate(vaddr)), key(child.translate(vaddr)));
+                if parent_key != child_key {
+                    panic!("vspace fork : {:#x} mapped as {:x?} instead of \
+                            {:x?}", vaddr.0, child_key, parent_key);
+                }
+            }
+        }
+
+        // The page of the original is reserved but not mapped in the copy
+        if child.translate(page).and_then(|mapping| mapping.page).is_some() {
+            panic!("vspace fork : kernel page {:#x} duplicated", page.0);
+        }
+        let other = child.reserve_virt_pages(1);
+        if other.0 == page.0 {
+            panic!("vspace fork : {:#x} given twice", page.0);
+        }
+        child.release_virt_pages(other, 1);
+        child.destroy();
+
+        // The shared page tables still map the original
+        if key(vspace.translate(VirtAddr(0x1000))).is_none() {
+            panic!("vspace fork : identity mapping lost with the copy");
+        }
+        vspace.free_virt_pages(page, 1)
+            .expect("vspace fork : free failed");
+    });
+    println!("vspace fork : kernel address space copied and destroyed");
+}
+
+/// Kernel task writing to its own code, which must panic on a page fault
+fn write_protect_task() {
+    let code = write_protect_task as *const u32 as *mut u8;
+    unsafe { core::ptr::write_volatile(code, 0xcc); }
+    panic!("write protect : kernel code at {:#p} is writable", code);
+}
+
+/// Kernel task queuing work from a software interrupt, and checking that
+/// the items run once each on the way out of it, that a full queue drops
+/// the extra items and that work queued by work runs too
+fn work_check_task() {
+    const VECTOR : u8 = 0x83;
+    const CHAIN : u32 = 5;
+
+    /// What the interrupt queues, given in eax
+    const QUEUE_ONE : u32 = 0;
+    const QUEUE_OVERFLOW : u32 = 1;
+    const QUEUE_CHAIN : u32 = 2;
+
+    static mut RUNS : u32 = 0;
+    static mut CHAIN_RUNS : u32 = 0;
+
+    fn count(_arg : u32) {
+        unsafe { RUNS += 1; }
+    }
+
+    fn chain(left : u32) {
+        unsafe { CHAIN_RUNS += 1; }
+        if left > 1 {
+            assert!(work::queue_work(chain, left - 1), "Work queue full");
+        }
+    }
+
+    let raise = |what : u32| unsafe {
+        asm!("int {}", const VECTOR, in("eax") what);
+    };
+    let runs = || unsafe { core::ptr::read_volatile(&RUNS) };
+
+    register_interrupt_handler(VECTOR, |ctx| {
+        let queued = match ctx.regs.eax {
+            QUEUE_ONE => 1,
+            QUEUE_OVERFLOW => work::MAX_WORK + 4,
+            _ => 0,
+        };
+        for _ in 0..queued {
+            work::queue_work(count, 0);
+        }
+        if ctx.regs.eax == QUEUE_CHAIN {
+            work::queue_work(chain, CHAIN);
+        }
+    });
+
+    // The item runs before the interrupt returns, and only once. The worker
+    // may take over the drain if the timer preempts it, it finishes these
+    // items at once too
+    raise(QUEUE_ONE);
+    raise(QUEUE_ONE);
+    if runs() != 2 {
+        panic!("work check : {} runs for 2 items", runs());
+    }
+
+    let dropped = work::work_dropped();
+    raise(QUEUE_OVERFLOW);
+    let dropped = work::work_dropped() - dropped;
+    if runs() != 2 + work::MAX_WORK as u32 || dropped != 4 {
+        panic!("work check : {} runs, {} dropped for a full queue",
+               runs() - 2, dropped);
+    }
+
+    // Each drain stops at the items queued before it, the rest of the chain
+    // runs at the next exits of interrupts or in the worker
+    raise(QUEUE_CHAIN);
+    tasks::kthread_sleep(10);
+    unregister_interrupt_handler(VECTOR);
+    let chain_runs = unsafe { core::ptr::read_volatile(&CHAIN_RUNS) };
+    if chain_runs != CHAIN {
+        panic!("work check : {} runs of a chain of {}", chain_runs, CHAIN);
+    }
+    println!("work check : items ran once, the full queue dropped the extra \
+              ones and the chain of {} ran", CHAIN);
+}
+
+/// Kernel task hitting int3 and a hardware write breakpoint, and checking
+/// that it keeps running after each of them
+fn breakpoint_check_task() {
+    static mut WATCHED : u32 = 0;
+
+    unsafe { asm!("int3"); }
+    println!("breakpoint check : resumed after int3");
+
+    let addr = core::ptr::addr_of_mut!(WATCHED);
+    debug::set_hw_breakpoint(0, addr as u32, debug::WatchKind::Write, 4)
+        .expect("Can't arm the hardware breakpoint");
+    let hits = debug::hw_breakpoint_hits();
+    for i in 1..=3 {
+        unsafe { core::ptr::write_volatile(addr, i); }
+    }
+    debug::clear_hw_breakpoint(0);
+    unsafe { core::ptr::write_volatile(addr, 0); }
+
+    let hits = debug::hw_breakpoint_hits() - hits;
+    if hits != 3 {
+        panic!("breakpoint check : {} hits of the watchpoint, expected 3",
+               hits);
+    }
+    println!("breakpoint check : the watchpoint caught 3 writes");
+}
+
+/// Kernel task recursing until it overflows its kernel stack
+fn stack_overflow_task() {
+    #[allow(unconditional_recursion)]
+    fn recurse(depth : u32) -> u32 {
+        let buf = core::hint::black_box([depth; 64]);
+        recurse(depth + 1) + buf[0]
+    }
+    recurse(0);
+}
+
+
+/// Create the check and bench kernel tasks, after the user tasks so that the
+/// first task keeps pid 1. The tasks never run before the first `schedule`
+pub fn spawn_selftest_tasks() {
+    tasks::check_task_lifecycle(100, userland_tasks::task6);
+
+    let kernel_tasks : &[tasks::BootTask] = &[
+        (b"paging_check", paging_check_task),
+        (b"virt_alloc_check", virt_alloc_check_task),
+        (b"vspace_fork_check", vspace_fork_check_task),
+        (b"vspace_cycle_check", vspace_cycle_check_task),
+        (b"borrowed_free", borrowed_free_check_task),
+        (b"intr_handler", intr_handler_check_task),
+        (b"irq_eoi", irq_eoi_check_task),
+        (b"nmi_check", nmi_check_task),
+        (b"irq_guard_check", interrupt_guard_check_task),
+        (b"phys_alloc_bench", phys_alloc_bench_task),
+        (b"buddy_stress", buddy_stress_task),
+        (b"fpu_switch_bench", fpu_switch_bench_task),
+        (b"dma_check", dma_check_task),
+        (b"pit_measure", pit_measure_task),
+        (b"breakpoint_check", breakpoint_check_task),
+        (b"work_check", work_check_task),
+        (b"switch_stress", switch_stress_task),
+        (b"switch_stress", switch_stress_task),
+        // Ends with a kernel stack overflow, which panics the kernel
+        //(b"overflow_task", stack_overflow_task),
+        //(b"write_protect", write_protect_task),
+        (b"reaper_check", reaper_check_task),
+    ];
+    for &(name, code_addr) in kernel_tasks.iter() {
+        tasks::Task::new_kernel(name, code_addr)
+            .expect("No memory for a kernel task");
+    }
+}
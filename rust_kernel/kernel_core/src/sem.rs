@@ -0,0 +1,171 @@
+//! Counting semaphores. Tasks name semaphores through their handles, and
+//! every handle holds a reference on its semaphore
+
+use crate::syscalls::*;
+use crate::handles::KernelObject;
+use crate::tasks::*;
+
+/// Max number of semaphores alive at the same time
+const MAX_SEMAPHORES : usize = 16;
+
+/// Max number of tasks blocked on a semaphore
+const MAX_SEM_WAITERS : usize = 32;
+
+/// A semaphore
+#[derive(Clone, Copy)]
+struct Semaphore {
+    /// Number of times the semaphore can be taken without blocking
+    count : u32,
+
+    /// Pids of the tasks blocked on the semaphore, oldest first
+    waiters : [Option<u32>; MAX_SEM_WAITERS],
+
+    /// Number of references on the semaphore
+    refs : usize,
+}
+
+impl Semaphore {
+    /// Queue the task `pid` if it is not already waiting. Returns false if
+    /// there is no room for it
+    fn add_waiter(&mut self, pid : u32) -> bool {
+        if self.waiters.contains(&Some(pid)) {
+            return true;
+        }
+        match self.waiters.iter_mut().find(|x| x.is_none()) {
+            Some(slot) => *slot = Some(pid),
+            None => return false,
+        }
+        true
+    }
+
+    /// Remove the task `pid` from the waiters
+    fn remove_waiter(&mut self, pid : u32) {
+        if let Some(idx) = self.waiters.iter().position(|&x| x == Some(pid)) {
+            self.waiters.copy_within(idx + 1.., idx);
+            self.waiters[MAX_SEM_WAITERS - 1] = None;
+        }
+    }
+}
+
+/// All the semaphores, the id of a semaphore is its index
+static mut SEMAPHORES : [Option<Semaphore>; MAX_SEMAPHORES] =
+    [None; MAX_SEMAPHORES];
+
+/// Register the semaphore syscalls
+pub fn sem_init() {
+    use SyscallArg::*;
+
+    register_syscall(SYS_SEM_CREATE, "sem_create", &[Uint], |ctx| {
+        sys_sem_create(ctx.regs.ecx)
+    });
+    register_syscall(SYS_SEM_WAIT, "sem_wait", &[Uint], |ctx| {
+        sys_sem_wait(ctx.regs.ecx)
+    });
+    register_syscall(SYS_SEM_POST, "sem_post", &[Uint], |ctx| {
+        sys_sem_post(ctx.regs.ecx)
+    });
+}
+
+/// Get the semaphore `id`
+fn get_semaphore(id : usize) -> &'static mut Semaphore {
+    unsafe { SEMAPHORES[id].as_mut() }.expect("Invalid semaphore")
+}
+
+/// Take a reference on the semaphore `id`
+pub fn sem_get(id : usize) {
+    get_semaphore(id).refs += 1;
+}
+
+/// Drop a reference on the semaphore `id`. The semaphore is freed when it
+/// was the last reference
+pub fn sem_put(id : usize) {
+    let sem = get_semaphore(id);
+
+    sem.refs -= 1;
+    if sem.refs == 0 {
+        unsafe { SEMAPHORES[id] = None; }
+    }
+}
+
+/// Get the id of the semaphore of the handle `handle` of the current task
+fn handle_semaphore(handle : u32) -> Result<usize, i32> {
+    match current_task().handles.get(handle) {
+        Some(KernelObject::Sem(id)) => Ok(id),
+        _ => Err(-EBADF),
+    }
+}
+
+/// Create a semaphore with the count `initial`. Returns a handle to it
+fn sys_sem_create(initial : u32) -> i32 {
+    let id = match unsafe { SEMAPHORES.iter().position(|x| x.is_none()) } {
+        Some(id) => id,
+        None => return -ENOMEM,
+    };
+    unsafe {
+        SEMAPHORES[id] = Some(Semaphore {
+            count : initial,
+            waiters : [None; MAX_SEM_WAITERS],
+            refs : 0,
+        });
+    }
+
+    match current_task().handles.insert(KernelObject::Sem(id)) {
+        Some(handle) => handle as i32,
+        None => {
+            // Nobody references the semaphore, free it right away
+            sem_get(id);
+            sem_put(id);
+            -EMFILE
+        }
+    }
+}
+
+/// Take the semaphore of `handle`, blocking until its count is not 0. Fails
+/// with ENOMEM if too many tasks already wait on it
+fn sys_sem_wait(handle : u32) -> i32 {
+    let id = match handle_semaphore(handle) {
+        Ok(id) => id,
+        Err(err) => return err,
+    };
+
+    // The handle keeps the semaphore alive while we sleep. We can be woken up
+    // by someone else, or by a post that another task was faster to use, so
+    // check the count again every time
+    loop {
+        let task = current_task();
+        let sem = get_semaphore(id);
+        if sem.count > 0 {
+            sem.count -= 1;
+            sem.remove_waiter(task.pid);
+            return 0;
+        }
+
+        if !sem.add_waiter(task.pid) {
+            return -ENOMEM;
+        }
+        block_current();
+    }
+}
+
+/// Release the semaphore of `handle` and wake up its oldest waiter
+fn sys_sem_post(handle : u32) -> i32 {
+    let id = match handle_semaphore(handle) {
+        Ok(id) => id,
+        Err(err) => return err,
+    };
+
+    let sem = get_semaphore(id);
+    sem.count = match sem.count.checked_add(1) {
+        Some(count) => count,
+        None => return -EINVAL,
+    };
+
+    if let Some(pid) = sem.waiters[0] {
+        sem.remove_waiter(pid);
+        if let Some(task) = find_task(pid) {
+            wake_up(task);
+        }
+    }
+
+    0
+}
@@ -1,8 +1,110 @@
-//! A basic 8250A serial driver for x86
+//! A basic 8250A serial driver for x86. Bytes are sent by polling, and
+//! received bytes are queued by the interrupt of COM1 and COM3
 
 use crate::cpu::{out8, in8};
+use crate::interrupts::{register_irq_handler, InterruptContext, IrqReturn};
+use crate::sync::InterruptGuard;
+use crate::tasks::{block_current, current_task, sleep_current};
+use crate::tasks::{find_task, wake_up, wake_up_deferred};
+use crate::work::queue_work;
 use crate::{PERIPHERALS, print, println};
 
+/// IRQ shared by COM1 and COM3
+pub const SERIAL_IRQ: u8 = 4;
+
+/// Indices of the COM ports raising `SERIAL_IRQ`, COM2 and COM4 use IRQ 3
+const SERIAL_IRQ_COMS: [usize; 2] = [0, 2];
+
+/// Size in bytes of the queue of received bytes
+const RX_BUFFER_SIZE: usize = 256;
+
+/// Max number of tasks waiting for a received byte
+const MAX_RX_WAITERS: usize = 8;
+
+/// Bytes received and not read yet, and the tasks waiting for them
+struct RxBuffer {
+    /// Ring buffer of bytes
+    buffer: [u8; RX_BUFFER_SIZE],
+
+    /// Index of the oldest byte
+    head: usize,
+
+    /// Number of bytes in the buffer
+    count: usize,
+
+    /// Number of bytes dropped because the buffer was full
+    dropped: u32,
+
+    /// Pids of the tasks blocked until a byte arrives
+    waiters: [Option<u32>; MAX_RX_WAITERS],
+}
+
+impl RxBuffer {
+    /// Create an empty buffer without waiters
+    const fn new() -> Self {
+        RxBuffer {
+            buffer: [0; RX_BUFFER_SIZE],
+            head: 0,
+            count: 0,
+            dropped: 0,
+            waiters: [None; MAX_RX_WAITERS],
+        }
+    }
+
+    /// Queue `byte`, or drop it if the buffer is full
+    fn push(&mut self, byte: u8) {
+        if self.count == RX_BUFFER_SIZE {
+            self.dropped += 1;
+            return;
+        }
+        self.buffer[(self.head + self.count) % RX_BUFFER_SIZE] = byte;
+        self.count += 1;
+    }
+
+    /// Take the oldest byte
+    fn pop(&mut self) -> Option<u8> {
+        if self.count == 0 {
+            return None;
+        }
+        let byte = self.buffer[self.head];
+        self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        self.count -= 1;
+        Some(byte)
+    }
+
+    /// Add the task `pid` to the waiters. Returns false if there is no room
+    /// for it
+    fn add_waiter(&mut self, pid: u32) -> bool {
+        if self.waiters.contains(&Some(pid)) {
+            return true;
+        }
+        match self.waiters.iter_mut().find(|waiter| waiter.is_none()) {
+            Some(slot) => *slot = Some(pid),
+            None => return false,
+        }
+        true
+    }
+
+    /// Wake up the waiters through the scheduler, when no work can be
+    /// queued. The ones it has no room for stay waiting until the next byte
+    fn wake_waiters_deferred(&mut self) {
+        for waiter in self.waiters.iter_mut() {
+            if let Some(pid) = *waiter {
+                if wake_up_deferred(pid) {
+                    *waiter = None;
+                }
+            }
+        }
+    }
+}
+
+/// Bytes received by the serial interrupt
+static mut RX: RxBuffer = RxBuffer::new();
+
+/// Ports raising `SERIAL_IRQ`, kept apart so that the interrupt handler
+/// doesn't need the serial driver, which may be locked
+static mut RX_PORTS: [Option<u16>; 2] = [None; 2];
+
 /// A collection of 4 8250A serial ports, as seen on IBM PC systems. These are
 /// the 4 serial ports which are identified by the BIOS, and thus it is limited
 /// to just COM1-COM4.
@@ -84,13 +186,7 @@ impl SerialPort {
 
         // Check if this COM port exists
         if let Some(&Some(port)) = self.devices.get(port) {
-            unsafe {
-                // Wait for the output buffer to be ready
-                while (in8(port + 5) & 0x20) == 0 {}
-
-                // Write the byte!
-                out8(port, byte);
-            }
+            write_polled(port, byte);
         }
     }
 
@@ -106,6 +202,37 @@ impl SerialPort {
     }
 }
 
+/// Write `byte` to the COM port at `port`, once it can take it
+fn write_polled(port: u16, byte: u8) {
+    unsafe {
+        // Wait for the output buffer to be ready
+        while (in8(port + 5) & 0x20) == 0 {}
+
+        // Write the byte!
+        out8(port, byte);
+    }
+}
+
+/// Ports of the serial devices found by `serial_init`, for `PanicWriter`
+static mut PANIC_PORTS: [Option<u16>; 4] = [None; 4];
+
+/// Writer sending bytes to the serial ports without locking `PERIPHERALS`,
+/// for the panic handler. The code that panicked may hold the lock, or have
+/// taken the port out of `PERIPHERALS` for good
+pub struct PanicWriter;
+
+impl core::fmt::Write for PanicWriter {
+    fn write_str(&mut self, st: &str) -> core::fmt::Result {
+        for &byte in st.as_bytes() {
+            for &port in unsafe { PANIC_PORTS.iter().flatten() } {
+                if byte == b'\n' { write_polled(port, b'\r'); }
+                write_polled(port, byte);
+            }
+        }
+        Ok(())
+    }
+}
+
 impl core::fmt::Write for SerialPort {
     fn write_str(&mut self, st: &str) -> core::fmt::Result {
         self.write(st.as_bytes());
@@ -113,11 +240,110 @@ impl core::fmt::Write for SerialPort {
     }
 }
 
+/// Make the serial ports of `SERIAL_IRQ` raise it when they receive a byte,
+/// and register its handler. Must be called after `serial_init`
+pub fn serial_irq_init() {
+    let devices = unsafe {
+        PERIPHERALS.serial.as_ref().map_or([None; 4], |serial| serial.devices)
+    };
+
+    for (rx_port, &com_id) in unsafe { RX_PORTS.iter_mut() }
+            .zip(SERIAL_IRQ_COMS.iter()) {
+        *rx_port = devices[com_id];
+        if let Some(port) = devices[com_id] {
+            unsafe {
+                out8(port + 2, 0x07); // Enable and clear the FIFOs
+                out8(port + 4, 0x0b); // RTS/DSR set, OUT2 routes the IRQ
+                out8(port + 1, 0x01); // Interrupt on received data
+            }
+        }
+    }
+    register_irq_handler(SERIAL_IRQ, handle_serial_intr);
+}
+
+/// Handle the serial interrupt, draining the received bytes of every port
+/// sharing it. This may interrupt a change of the queues of the scheduler,
+/// so blocked readers are woken up by deferred work. The IRQ is only claimed
+/// if a port had an interrupt pending, reading its identification register
+/// acknowledges the transmitter empty one
+fn handle_serial_intr(_ctx: &mut InterruptContext) -> IrqReturn {
+    let mut claimed = false;
+    unsafe {
+        for &port in RX_PORTS.iter().flatten() {
+            claimed |= in8(port + 2) & 1 == 0;
+            while (in8(port + 5) & 1) != 0 {
+                RX.push(in8(port));
+            }
+        }
+        let waiting = RX.waiters.iter().any(|waiter| waiter.is_some());
+        if RX.count != 0 && waiting && !queue_work(wake_rx_waiters, 0) {
+            RX.wake_waiters_deferred();
+        }
+    }
+    if claimed { IrqReturn::Handled } else { IrqReturn::NotMine }
+}
+
+/// Make the first port of `SERIAL_IRQ` also raise it when its transmitter
+/// is empty, which it is at once unless it is sending. Returns false if
+/// there is no such port
+pub fn set_tx_empty_irq(enable: bool) -> bool {
+    let port = match unsafe { RX_PORTS.iter().flatten().next() } {
+        Some(&port) => port,
+        None => return false,
+    };
+    let ier = if enable { 0x03 } else { 0x01 };
+    unsafe { out8(port + 1, ier); }
+    true
+}
+
+/// Work queued by the serial interrupt, waking up the tasks waiting for a
+/// received byte
+fn wake_rx_waiters(_arg: u32) {
+    let _guard = InterruptGuard::new();
+    for waiter in unsafe { RX.waiters.iter_mut() } {
+        if let Some(task) = waiter.take().and_then(find_task) {
+            wake_up(task);
+        }
+    }
+}
+
+/// Take the oldest byte received by the serial ports, without waiting
+pub fn read_byte() -> Option<u8> {
+    // The interrupt handler must not queue a byte meanwhile
+    let _guard = InterruptGuard::new();
+    unsafe { RX.pop() }
+}
+
+/// Take the oldest byte received by the serial ports, blocking the calling
+/// task until one arrives
+pub fn read_byte_blocking() -> u8 {
+    loop {
+        // Interrupts stay disabled from the check to the block, so that
+        // the byte can't arrive in between
+        let _guard = InterruptGuard::new();
+        if let Some(byte) = unsafe { RX.pop() } {
+            return byte;
+        }
+        if unsafe { RX.add_waiter(current_task().pid) } {
+            block_current();
+        } else {
+            sleep_current(1);
+        }
+    }
+}
+
+/// Get the number of received bytes dropped because nobody read them in
+/// time
+pub fn rx_dropped() -> u32 {
+    unsafe { RX.dropped }
+}
+
 /// Init the serial port and stores it in `PERIPHERALS`
 pub fn serial_init() {
     unsafe {
         // Safe if we call it only once
         let serial = SerialPort::new(0x400 as *const u16);
+        PANIC_PORTS = serial.devices;
         // Safe if we call it in a thread safe way
         PERIPHERALS.serial = Some(serial);
     }
@@ -0,0 +1,262 @@
+//! Shared memory objects. An object is a set of physical pages that tasks
+//! map in their address space. Tasks name objects through their handles.
+//! Every handle and every page table entry mapping a page of an object holds
+//! a reference on it, and the pages are freed when the last reference goes
+//! away
+
+use crate::virtmem::*;
+use crate::pagemem::*;
+use crate::physmem::*;
+use crate::syscalls::*;
+use crate::handles::KernelObject;
+use crate::tasks::current_task;
+use alloc::vec::Vec;
+
+/// Max number of shared memory objects alive at the same time
+const MAX_SHM_OBJECTS : usize = 16;
+
+/// Max size in pages of a shared memory object
+pub const MAX_SHM_PAGES : usize = 16;
+
+/// A shared memory object
+struct ShmObject {
+    /// Physical pages backing the object
+    pages : Vec<PhysAddr>,
+
+    /// Number of references on the object
+    refs : usize,
+}
+
+/// All the shared memory objects, the id of an object is its index. The
+/// table grows up to `MAX_SHM_OBJECTS` slots
+static mut SHM_OBJECTS : Vec<Option<ShmObject>> = Vec::new();
+
+/// Register the shm syscalls
+pub fn shm_init() {
+    use SyscallArg::*;
+
+    register_syscall(SYS_SHM_CREATE, "shm_create", &[Uint], |ctx| {
+        sys_shm_create(ctx.regs.ecx)
+    });
+    register_syscall(SYS_SHM_ATTACH, "shm_attach", &[Uint, Addr, Uint], |ctx| {
+        sys_shm_attach(ctx.regs.ecx, ctx.regs.edx, ctx.regs.ebx != 0)
+    });
+    register_syscall(SYS_SHM_DETACH, "shm_detach", &[Uint, Addr], |ctx| {
+        sys_shm_detach(ctx.regs.ecx, ctx.regs.edx)
+    });
+}
+
+/// Get the object `id`
+fn get_object(id : usize) -> &'static mut ShmObject {
+    unsafe { SHM_OBJECTS[id].as_mut() }.expect("Invalid shm object")
+}
+
+/// Create an object of `npages` zeroed pages. Returns the id of the object,
+/// which has no reference yet
+fn shm_create(npages : usize) -> Result<usize, i32> {
+    if npages == 0 || npages > MAX_SHM_PAGES {
+        return Err(-EINVAL);
+    }
+
+    let objects = unsafe { &mut SHM_OBJECTS };
+    let id = match objects.iter().position(|x| x.is_none()) {
+        Some(id) => id,
+        None if objects.len() < MAX_SHM_OBJECTS => {
+            objects.try_reserve(1).map_err(|_| -ENOMEM)?;
+            objects.push(None);
+            objects.len() - 1
+        }
+        None => return Err(-ENOMEM),
+    };
+
+    let mut pages = Vec::new();
+    pages.try_reserve_exact(npages).map_err(|_| -ENOMEM)?;
+    for _ in 0..npages {
+        match unsafe { PhysMem::try_alloc_phys_zeroed() } {
+            Ok(page) => pages.push(page),
+            Err(_) => {
+                for &page in pages.iter() {
+                    unsafe { PhysMem::free_phys(page); }
+                }
+                return Err(-ENOMEM);
+            }
+        }
+    }
+
+    objects[id] = Some(ShmObject {
+        pages : pages,
+        refs : 0,
+    });
+    Ok(id)
+}
+
+/// Take a reference on the object `id`
+pub fn shm_get(id : usize) {
+    get_object(id).refs += 1;
+}
+
+/// Drop a reference on the object `id`. The object is freed when it was the
+/// last reference
+pub fn shm_put(id : usize) {
+    let object = get_object(id);
+
+    object.refs -= 1;
+    if object.refs == 0 {
+        for page in &object.pages {
+            unsafe { PhysMem::free_phys(*page); }
+        }
+        unsafe { SHM_OBJECTS[id] = None; }
+    }
+}
+
+/// Get the id of the shm object of the handle `handle` of the current task
+fn handle_object(handle : u32) -> Result<usize, i32> {
+    match current_task().handles.get(handle) {
+        Some(KernelObject::Shm(id)) => Ok(id),
+        _ => Err(-EBADF),
+    }
+}
+
+/// Map the object `id` at `vaddr` in the current address space, or in the
+/// anonymous mappings area of the task if `vaddr` is 0. Returns the
+/// address of the mapping, below `USER_MAP_END` like the ones of mmap
+fn shm_attach(id : usize, vaddr : u32, writable : bool) -> Result<u32, i32> {
+    if vaddr & 0xfff != 0 {
+        return Err(-EINVAL);
+    }
+
+    let object = get_object(id);
+    let vspace = VirtMem::get_current();
+    let start = pick_user_range(&vspace, vaddr, object.pages.len())?;
+
+    let mut flags = PAGE_PRESENT | PAGE_USER | PAGE_SHARED;
+    if writable {
+        flags |= PAGE_WRITE;
+    }
+
+    for (i, page) in object.pages.iter().enumerate() {
+        let vaddr = VirtAddr(start + (i * PAGE_SIZE) as u32);
+        if vspace.map_raw(vaddr, page.0 | flags).is_err() {
+            // The pages mapped so far took no reference yet
+            if i > 0 {
+                vspace.unmap(VirtAddr(start), i)
+                    .expect("Shm pages vanished");
+            }
+            return Err(-EINVAL);
+        }
+    }
+    object.refs += object.pages.len();
+
+    Ok(start)
+}
+
+/// Unmap the object `id`, mapped at `vaddr` by `shm_attach`, from the
+/// current address space
+fn shm_detach(id : usize, vaddr : u32) -> Result<(), i32> {
+    if vaddr & 0xfff != 0 {
+        return Err(-EINVAL);
+    }
+
+    // Every page of the object must be mapped in order from `vaddr`
+    let object = get_object(id);
+    let vspace = VirtMem::get_current();
+    for (i, page) in object.pages.iter().enumerate() {
+        let mapped = vaddr.checked_add((i * PAGE_SIZE) as u32)
+            .and_then(|addr| vspace.get_pte(VirtAddr(addr)));
+        match mapped {
+            Some(pte) if pte.0 & PAGE_PRESENT != 0 &&
+                    pte.0 & PAGE_SHARED != 0 &&
+                    pte.get_paddr().0 == page.0 => {},
+            _ => return Err(-EINVAL),
+        }
+    }
+
+    let npages = object.pages.len();
+    vspace.unmap(VirtAddr(vaddr), npages).map_err(|_| -EINVAL)?;
+    for _ in 0..npages {
+        shm_put(id);
+    }
+
+    Ok(())
+}
+
+/// Find the object that contains the physical page `paddr`
+fn find_page_object(paddr : PhysAddr) -> usize {
+    unsafe { SHM_OBJECTS.iter() }.position(|object| match object {
+        Some(object) => object.pages.iter()
+            .any(|page| page.0 == paddr.0),
+        None => false,
+    }).expect("Shared page without shm object")
+}
+
+/// Take a reference on the object of the shared page `paddr`, because a new
+/// page table entry maps it
+pub fn shm_get_page(paddr : PhysAddr) {
+    shm_get(find_page_object(paddr));
+}
+
+/// Drop the reference of a page table entry mapping the shared page `paddr`
+pub fn shm_put_page(paddr : PhysAddr) {
+    shm_put(find_page_object(paddr));
+}
+
+/// Call `f` with the physical address of every shared page of `vspace`
+fn for_each_shared_page<F : FnMut(PhysAddr)>(vspace : &VirtMem, mut f : F) {
+    for (_, paddr, flags) in vspace.iter_mappings(VirtAddr(0),
+                                                  VirtAddr(u32::MAX)) {
+        if flags & PAGE_SHARED != 0 {
+            f(paddr);
+        }
+    }
+}
+
+/// Take a reference for every shared page of `vspace`, a forked address
+/// space that maps the same shared pages as its parent
+pub fn shm_get_vspace(vspace : &VirtMem) {
+    for_each_shared_page(vspace, shm_get_page);
+}
+
+/// Drop the references of all the shared pages of `vspace`, an address space
+/// that is being destroyed
+pub fn shm_put_vspace(vspace : &VirtMem) {
+    for_each_shared_page(vspace, shm_put_page);
+}
+
+/// Create a shared memory object of `npages` pages. Returns a handle to the
+/// object, or fails with EMFILE if the current task has no free handle
+fn sys_shm_create(npages : u32) -> i32 {
+    let id = match shm_create(npages as usize) {
+        Ok(id) => id,
+        Err(err) => return err,
+    };
+
+    match current_task().handles.insert(KernelObject::Shm(id)) {
+        Some(handle) => handle as i32,
+        None => {
+            // Nobody references the object, free it right away
+            shm_get(id);
+            shm_put(id);
+            -EMFILE
+        }
+    }
+}
+
+/// Map the object of `handle` at `vaddr`, or at an address chosen by the
+/// kernel if `vaddr` is 0. Returns the address of the mapping, which
+/// `shm_attach` keeps positive as an i32
+fn sys_shm_attach(handle : u32, vaddr : u32, writable : bool) -> i32 {
+    let attached = handle_object(handle)
+        .and_then(|id| shm_attach(id, vaddr, writable));
+    match attached {
+        Ok(vaddr) => vaddr as i32,
+        Err(err) => err,
+    }
+}
+
+/// Unmap the object of `handle` mapped at `vaddr`
+fn sys_shm_detach(handle : u32, vaddr : u32) -> i32 {
+    match handle_object(handle).and_then(|id| shm_detach(id, vaddr)) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
@@ -0,0 +1,90 @@
+//! Preemption control. Kernel tasks run with interrupts enabled, so the
+//! timer can schedule in the middle of an update of shared kernel state.
+//! Such updates disable preemption, and a schedule requested meanwhile is
+//! done when they are over
+
+use crate::cpu::*;
+use crate::tasks::schedule;
+
+/// Number of sections currently preventing preemption
+static mut PREEMPT_COUNT : u32 = 0;
+
+/// Set when the timer wanted to schedule while preemption was disabled
+static mut NEED_RESCHED : bool = false;
+
+/// Prevent the timer from scheduling until the matching `preempt_enable`.
+/// Calls can be nested
+pub fn preempt_disable() {
+    unsafe { PREEMPT_COUNT += 1; }
+}
+
+/// Allow preemption again once every `preempt_disable` was matched, and
+/// schedule if the timer asked for it meanwhile
+pub fn preempt_enable() {
+    unsafe {
+        assert!(PREEMPT_COUNT > 0, "Unbalanced preempt_enable");
+        PREEMPT_COUNT -= 1;
+        if PREEMPT_COUNT != 0 || !NEED_RESCHED {
+            return;
+        }
+
+        // schedule runs with interrupts disabled, like from the timer
+        let enabled = interrupts_enabled();
+        disable_interrupts();
+        schedule();
+        if enabled {
+            enable_interrupts();
+        }
+    }
+}
+
+/// Returns true if the timer can schedule right now
+pub fn preemptible() -> bool {
+    unsafe { PREEMPT_COUNT == 0 }
+}
+
+/// Ask for a schedule at the end of the current section without preemption
+pub fn set_need_resched() {
+    unsafe { NEED_RESCHED = true; }
+}
+
+/// Called by the scheduler, any schedule satisfies a pending request
+pub fn clear_need_resched() {
+    unsafe { NEED_RESCHED = false; }
+}
+
+/// Disables preemption while alive
+pub struct PreemptGuard;
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        preempt_disable();
+        PreemptGuard
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
+
+/// Disables interrupts while alive, and enables them again on drop if they
+/// were enabled before. Guards can be nested, only the outermost one
+/// enables interrupts again
+pub struct InterruptGuard {
+    /// eflags before the guard was taken
+    eflags : u32,
+}
+
+impl InterruptGuard {
+    pub fn new() -> Self {
+        InterruptGuard { eflags : save_and_disable_interrupts() }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        restore_interrupts(self.eflags);
+    }
+}
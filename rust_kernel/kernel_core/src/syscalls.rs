@@ -1,72 +1,728 @@
 //! All syscall handlers
 
-use crate::interrupts::InterruptContext;
+use crate::interrupts::{InterruptContext, ticks};
+use crate::interrupts::{register_interrupt_handler, SYSCALL_VECTOR};
+use crate::interrupts::dump_irq_stats;
 use crate::{println, print, PERIPHERALS};
 use crate::virtmem::*;
 use crate::pagemem::*;
 use crate::physmem::*;
+use crate::tasks::*;
+use crate::uaccess::*;
+use crate::shm::*;
+use crate::segmem::{set_tls_base, USER_TLS_SELECTOR, USER_TLS_SIZE};
+use crate::cpu::set_gs;
+use crate::watchdog::watchdog_syscall_done;
+use crate::bench::{bench_start, bench_syscall_done};
 
-/// Handle a syscall
-pub fn handle_syscall(ctx : &InterruptContext) {
-    match ctx.regs.eax {
-        // Exit syscall
-        1 => {
-            sys_exit();
-        },
-        // Write syscall
-        2 => {
-            sys_write(ctx.regs.ecx as *const u8, ctx.regs.edx);
+/// Invalid argument
+pub const EINVAL : i32 = 22;
+
+/// Too many open handles
+pub const EMFILE : i32 = 24;
+
+/// Out of memory
+pub const ENOMEM : i32 = 12;
+
+/// Bad address
+pub const EFAULT : i32 = 14;
+
+/// Operation not permitted
+pub const EPERM : i32 = 1;
+
+/// No such task
+pub const ESRCH : i32 = 3;
+
+/// Bad handle
+pub const EBADF : i32 = 9;
+
+/// No such child task
+pub const ECHILD : i32 = 10;
+
+/// Resource temporarily unavailable
+pub const EAGAIN : i32 = 11;
+
+/// Unimplemented syscall
+pub const ENOSYS : i32 = 38;
+
+/// Only these bits of the code given to sys_exit are kept, so that clean
+/// exits can't be mistaken for tasks killed by the kernel
+pub const EXIT_CODE_MASK : i32 = 0x7f;
+
+/// A task killed by the kernel on the exception `n` exits with the code
+/// `EXIT_KILLED + n`
+pub const EXIT_KILLED : i32 = 128;
+
+/// Syscall numbers. Userland puts them in eax before `int 0x80`
+pub const SYS_EXIT : u32 = 1;
+pub const SYS_WRITE : u32 = 2;
+pub const SYS_PRINT_NUMBER : u32 = 3;
+pub const SYS_MUNMAP : u32 = 11;
+pub const SYS_SBRK : u32 = 12;
+pub const SYS_GETPID : u32 = 13;
+pub const SYS_YIELD : u32 = 14;
+pub const SYS_SLEEP : u32 = 15;
+pub const SYS_FORK : u32 = 16;
+pub const SYS_MMAP : u32 = 17;
+pub const SYS_MPROTECT : u32 = 18;
+pub const SYS_SHM_CREATE : u32 = 19;
+pub const SYS_SHM_ATTACH : u32 = 20;
+pub const SYS_SHM_DETACH : u32 = 21;
+pub const SYS_GETTICKS : u32 = 22;
+pub const SYS_SEND : u32 = 23;
+pub const SYS_RECV : u32 = 24;
+pub const SYS_WAITPID : u32 = 25;
+pub const SYS_CLOSE : u32 = 26;
+pub const SYS_TRACE : u32 = 27;
+pub const SYS_UNAME : u32 = 28;
+pub const SYS_REBOOT : u32 = 29;
+pub const SYS_SEM_CREATE : u32 = 30;
+pub const SYS_SEM_WAIT : u32 = 31;
+pub const SYS_SEM_POST : u32 = 32;
+pub const SYS_FUTEX_WAIT : u32 = 33;
+pub const SYS_FUTEX_WAKE : u32 = 34;
+pub const SYS_TASK_STATS : u32 = 35;
+pub const SYS_SETPRIORITY : u32 = 36;
+pub const SYS_SET_TLS : u32 = 37;
+pub const SYS_BENCH : u32 = 38;
+pub const SYS_MEMINFO : u32 = 39;
+pub const SYS_VSPACE_DUMP : u32 = 40;
+pub const SYS_READ : u32 = 41;
+pub const SYS_GETTIMEOFDAY : u32 = 42;
+pub const SYS_IRQ_STATS : u32 = 43;
+
+/// Protection flags of `SYS_MMAP` and `SYS_MPROTECT`. Writable memory is
+/// always readable
+pub const PROT_READ : u32 = 1 << 0;
+pub const PROT_WRITE : u32 = 1 << 1;
+
+/// Max number of bytes written by one `SYS_WRITE`
+pub const MAX_WRITE_SIZE : usize = 4096;
+
+/// Number of entries in the syscall table
+const MAX_SYSCALLS : usize = 64;
+
+/// A syscall handler. It takes its arguments from the interrupt context of
+/// the calling task and returns the value to put in eax
+pub type SyscallHandler = fn(&mut InterruptContext) -> i32;
+
+/// How to print a syscall argument when tracing syscalls
+#[derive(Clone, Copy)]
+pub enum SyscallArg {
+    /// Signed number
+    Int,
+
+    /// Unsigned number, like a size, a pid or a handle
+    Uint,
+
+    /// Address, printed in hex
+    Addr,
+}
+
+/// A registered syscall
+#[derive(Clone, Copy)]
+struct Syscall {
+    /// Name of the syscall, for tracing
+    name : &'static str,
+
+    /// Arguments of the syscall, taken from ecx, edx and ebx in this order
+    args : &'static [SyscallArg],
+
+    /// Function implementing the syscall
+    handler : SyscallHandler,
+}
+
+/// Syscalls indexed by syscall number
+static mut SYSCALL_TABLE : [Option<Syscall>; MAX_SYSCALLS] = 
+    [None; MAX_SYSCALLS];
+
+/// Print every syscall with its arguments and its return value
+static mut TRACE_SYSCALLS : bool = false;
+
+/// Install `handler` for the syscall number `nr`. `name` and `args` describe
+/// the syscall for tracing
+pub fn register_syscall(nr : u32, name : &'static str, 
+                        args : &'static [SyscallArg], 
+                        handler : SyscallHandler) {
+    let nr = nr as usize;
+    if nr >= MAX_SYSCALLS {
+        panic!("Syscall number {} is too big", nr);
+    }
+    if args.len() > 3 {
+        panic!("Syscall {} takes too many arguments", name);
+    }
+    unsafe {
+        if SYSCALL_TABLE[nr].is_some() {
+            panic!("Syscall {} is already registered", nr);
         }
-        // Print_number syscall
-        3 => {
-            sys_print_number(ctx.regs.ecx);
+        SYSCALL_TABLE[nr] = Some(Syscall {
+            name : name,
+            args : args,
+            handler : handler,
+        });
+    }
+}
+
+/// Enable or disable syscall tracing
+pub fn set_syscall_trace(enable : bool) {
+    unsafe { TRACE_SYSCALLS = enable; }
+}
+
+/// Register the syscalls implemented in this module, and the handler of
+/// `int 0x80` dispatching them
+pub fn syscalls_init() {
+    use SyscallArg::*;
+
+    register_interrupt_handler(SYSCALL_VECTOR, handle_syscall);
+
+    register_syscall(SYS_EXIT, "exit", &[Int], |ctx| {
+        sys_exit(ctx.regs.ecx as i32)
+    });
+    register_syscall(SYS_WRITE, "write", &[Addr, Uint], |ctx| {
+        sys_write(ctx.regs.ecx, ctx.regs.edx as usize)
+    });
+    register_syscall(SYS_PRINT_NUMBER, "print_number", &[Uint], |ctx| {
+        sys_print_number(ctx.regs.ecx)
+    });
+    register_syscall(SYS_MUNMAP, "munmap", &[Addr, Uint], |ctx| {
+        sys_munmap(VirtAddr(ctx.regs.ecx), ctx.regs.edx as usize)
+    });
+    register_syscall(SYS_SBRK, "sbrk", &[Int], |ctx| {
+        sys_sbrk(ctx.regs.ecx as i32)
+    });
+    register_syscall(SYS_GETPID, "getpid", &[], |_| sys_getpid());
+    register_syscall(SYS_YIELD, "yield", &[], |_| sys_yield());
+    register_syscall(SYS_SLEEP, "sleep", &[Uint], |ctx| {
+        sys_sleep(ctx.regs.ecx)
+    });
+    register_syscall(SYS_FORK, "fork", &[], |ctx| sys_fork(ctx));
+    register_syscall(SYS_MMAP, "mmap", &[Addr, Uint, Uint], |ctx| {
+        sys_mmap(ctx.regs.ecx, ctx.regs.edx as usize, ctx.regs.ebx)
+    });
+    register_syscall(SYS_MPROTECT, "mprotect", &[Addr, Uint, Uint], |ctx| {
+        sys_mprotect(ctx.regs.ecx, ctx.regs.edx as usize, ctx.regs.ebx)
+    });
+    register_syscall(SYS_GETTICKS, "getticks", &[], sys_getticks);
+    register_syscall(SYS_IRQ_STATS, "irq_stats", &[], |_| {
+        dump_irq_stats();
+        0
+    });
+    register_syscall(SYS_WAITPID, "waitpid", &[Uint], |ctx| {
+        sys_waitpid(ctx.regs.ecx)
+    });
+    register_syscall(SYS_TASK_STATS, "task_stats", &[Addr, Uint], |ctx| {
+        sys_task_stats(ctx.regs.ecx, ctx.regs.edx as usize)
+    });
+    register_syscall(SYS_SETPRIORITY, "setpriority", &[Uint], |ctx| {
+        sys_setpriority(ctx.regs.ecx)
+    });
+    register_syscall(SYS_SET_TLS, "set_tls", &[Addr], |ctx| {
+        sys_set_tls(ctx.regs.ecx)
+    });
+    register_syscall(SYS_TRACE, "trace", &[Uint], |ctx| {
+        sys_trace(ctx.regs.ecx != 0)
+    });
+}
+
+/// Print a syscall made by the task `pid` with the arguments `args` and its
+/// return value `ret`. This goes straight to the serial port, so tracing
+/// doesn't make more syscalls
+fn trace_syscall(pid : u32, syscall : &Syscall, args : &[u32; 3], ret : i32) {
+    print!("[pid {}] {}(", pid, syscall.name);
+    for (i, (kind, &value)) in syscall.args.iter().zip(args).enumerate() {
+        if i != 0 {
+            print!(", ");
         }
-        // Mmap_shared syscall
-        10 => {
-            sys_mmap_shared(VirtAddr(ctx.regs.ecx), ctx.regs.edx as usize);
+        match kind {
+            SyscallArg::Int => print!("{}", value as i32),
+            SyscallArg::Uint => print!("{}", value),
+            SyscallArg::Addr => print!("{:#x}", value),
         }
-        _ => panic!("Unimplemented syscall : {:#x}", ctx.regs.eax),
+    }
+    println!(") = {}", ret);
+}
+
+/// Handle a syscall. The syscall number is in eax and the arguments in ecx,
+/// edx and ebx. The return value of the syscall, negative errno values on
+/// failure, is stored in eax. sys_exit never returns, so it is not traced
+pub fn handle_syscall(ctx : &mut InterruptContext) {
+    let bench = bench_start();
+    let nr = ctx.regs.eax as usize;
+    let syscall = if nr < MAX_SYSCALLS {
+        unsafe { SYSCALL_TABLE[nr] }
+    } else {
+        None
+    };
+
+    let syscall = match syscall {
+        Some(syscall) => syscall,
+        None => {
+            ctx.regs.eax = -ENOSYS as u32;
+            return;
+        }
+    };
+
+    // Handlers can modify the registers, keep the arguments for tracing
+    let task = current_task();
+    task.syscalls += 1;
+    let pid = task.pid;
+    let args = [ctx.regs.ecx, ctx.regs.edx, ctx.regs.ebx];
+
+    let ret = (syscall.handler)(ctx);
+    ctx.regs.eax = ret as u32;
+    watchdog_syscall_done();
+    bench_syscall_done(bench);
+
+    if unsafe { TRACE_SYSCALLS } {
+        trace_syscall(pid, &syscall, &args, ret);
     }
 }
 
-/// Exit syscall
-fn sys_exit() {
-    panic!("exit syscall");
+/// Exit syscall. The task becomes a zombie until its parent collects
+/// `exit_code` with sys_waitpid, or until the reaper frees it if it has no
+/// parent. Only the bits of `EXIT_CODE_MASK` are kept
+fn sys_exit(exit_code : i32) -> ! {
+    exit_current(exit_code & EXIT_CODE_MASK);
 }
 
-/// Write syscall
-fn sys_write(buffer : *const u8, size : u32) {
-    let buf = unsafe { core::slice::from_raw_parts(buffer, size as usize) };
-    print!("{}", core::str::from_utf8(buf)
-           .expect("couldn't translate to uft8"));
+/// Write syscall. Writes at most `MAX_WRITE_SIZE` bytes and returns the
+/// number of bytes written, or fails with EFAULT if the buffer is not
+/// readable by userland. Bytes that are not valid utf8 are printed as U+FFFD
+fn sys_write(buffer : u32, size : usize) -> i32 {
+    let size = core::cmp::min(size, MAX_WRITE_SIZE);
+    if let Err(err) = check_user_range(buffer, size, false) {
+        return err;
+    }
+
+    // Copy the buffer in chunks. A utf8 sequence cut at the end of a chunk
+    // is moved to the start of the buffer and completed by the next chunk
+    let mut chunk = [0u8; 128];
+    let mut pending = 0;
+    let mut done = 0;
+    while done < size {
+        let len = core::cmp::min(chunk.len() - pending, size - done);
+        if let Err(err) = copy_from_user(&mut chunk[pending..pending + len],
+                                         buffer + done as u32) {
+            return err;
+        }
+        done += len;
+
+        let filled = pending + len;
+        let mut start = 0;
+        while start < filled {
+            let rest = &chunk[start..filled];
+            let (valid, invalid) = match core::str::from_utf8(rest) {
+                Ok(_) => (rest.len(), None),
+                Err(err) => (err.valid_up_to(), err.error_len()),
+            };
+            let text = unsafe {
+                core::str::from_utf8_unchecked(&rest[..valid])
+            };
+            print!("{}", text);
+            start += valid;
+
+            match invalid {
+                Some(len) => {
+                    print!("{}", char::REPLACEMENT_CHARACTER);
+                    start += len;
+                },
+                // Nothing left, or a sequence that may continue in the next
+                // chunk
+                None => break,
+            }
+        }
+
+        chunk.copy_within(start..filled, 0);
+        pending = filled - start;
+    }
+
+    // The buffer ends in the middle of a utf8 sequence
+    if pending != 0 {
+        print!("{}", char::REPLACEMENT_CHARACTER);
+    }
+
+    size as i32
 }
 
 /// Print `num`
-fn sys_print_number(num : u32) {
+fn sys_print_number(num : u32) -> i32 {
     println!("{}", num);
+    0
 }
 
-/// Map a shared memory region identified by `id` at `vaddr`
-fn sys_mmap_shared(vaddr : VirtAddr, id : usize) {
-    const MAX_SHARED_MAPPINGS : usize = 10;
-    static mut MAPPINGS : [Option<PhysAddr>; MAX_SHARED_MAPPINGS] = 
-        [None; MAX_SHARED_MAPPINGS];
-    
-    if id < 0 || id > MAX_SHARED_MAPPINGS {
-        panic!("invalid shared mapping id : {}", id);
+/// Unmap `size` bytes of memory at `vaddr` in the current address space.
+/// Pages that are private to the task are freed and shared pages drop their
+/// reference on their shm object. Fails with EINVAL if the range is not page
+/// aligned, touches kernel memory or contains pages that are not mapped
+fn sys_munmap(vaddr : VirtAddr, size : usize) -> i32 {
+    if vaddr.0 & 0xfff != 0 || size == 0 {
+        return -EINVAL;
+    }
+    let npages = size.div_ceil(PAGE_SIZE);
+    let end = match (npages as u32).checked_mul(PAGE_SIZE as u32)
+            .and_then(|size| vaddr.0.checked_add(size)) {
+        Some(end) => end,
+        None => return -EINVAL,
+    };
+    if !is_user_range(vaddr.0, end) {
+        return -EINVAL;
     }
 
-    unsafe {
-        if MAPPINGS[id].is_none() {
-            let page = PhysMem::alloc_phys_zeroed();
-            MAPPINGS[id] = Some(page);
+    let vspace = VirtMem::get_current();
+
+    // Userland must not be able to remove kernel mappings such as its own
+    // kernel stack, or the identity mapping of its code
+    for i in 0..npages {
+        let page = VirtAddr(vaddr.0.wrapping_add((i * PAGE_SIZE) as u32));
+        match vspace.get_pte(page) {
+            Some(pte) if pte.0 & PAGE_USER != 0 && !is_kernel_owned(page) => {},
+            _ => return -EINVAL,
+        }
+    }
+
+    for i in 0..npages {
+        let page = VirtAddr(vaddr.0 + (i * PAGE_SIZE) as u32);
+        let pte = vspace.get_pte(page).expect("Page table vanished");
+        vspace.unmap(page, 1).expect("Couldn't unmap user page");
+        if is_private_page(page, pte.0) {
+            unsafe { PhysMem::free_phys(pte.get_paddr()); }
+        } else if pte.0 & PAGE_SHARED != 0 {
+            shm_put_page(pte.get_paddr());
+        }
+    }
+
+    0
+}
+
+/// Grow or shrink the heap of the current task by `increment` bytes.
+/// Returns the previous end of the heap, or fails with ENOMEM if the new end
+/// would be outside of the heap region and with EINVAL if a page of the new
+/// part of the heap is already mapped
+fn sys_sbrk(increment : i32) -> i32 {
+    let task = current_task();
+    let old_brk = task.brk;
+
+    let new_brk = old_brk as i64 + increment as i64;
+    if new_brk < task.heap_base as i64 || 
+            new_brk > (task.heap_base + USER_HEAP_MAX_SIZE) as i64 {
+        return -ENOMEM;
+    }
+    let new_brk = new_brk as u32;
+
+    // Pages in [heap_base, page_align_up(brk)) are mapped
+    let page_align_up = |addr : u32| {
+        (addr + PAGE_SIZE as u32 - 1) & !(PAGE_SIZE as u32 - 1)
+    };
+    let old_end = page_align_up(old_brk);
+    let new_end = page_align_up(new_brk);
+
+    let vspace = VirtMem::get_current();
+
+    if new_end > old_end {
+        // Add pages at the end of the heap, zeroed on their first access
+        let npages = ((new_end - old_end) as usize) / PAGE_SIZE;
+        if vspace.map_lazy_range(VirtAddr(old_end), npages,
+                                 PAGE_USER | PAGE_WRITE).is_err() {
+            return -EINVAL;
+        }
+    } else if new_end < old_end {
+        // Unmap the pages past the new end of the heap and free the ones
+        // that were written to
+        for page in (new_end..old_end).step_by(PAGE_SIZE) {
+            let pte = vspace.get_pte(VirtAddr(page))
+                .expect("Heap page without page table");
+            vspace.unmap(VirtAddr(page), 1)
+                .expect("Couldn't unmap heap page");
+            if is_private_page(VirtAddr(page), pte.0) {
+                unsafe { PhysMem::free_phys(pte.get_paddr()); }
+            }
         }
+    }
+
+    task.brk = new_brk;
+    old_brk as i32
+}
+
+/// Returns the pid of the current task
+fn sys_getpid() -> i32 {
+    current_task().pid as i32
+}
+
+/// Set the scheduling priority of the current task, from 0 to
+/// `MAX_PRIORITY`. Fails with EINVAL if `priority` is too high
+fn sys_setpriority(priority : u32) -> i32 {
+    if priority > MAX_PRIORITY as u32 {
+        return -EINVAL;
+    }
+    current_task().set_priority(priority as u8);
+    0
+}
 
-        let vspace = VirtMem::get_current();
-        vspace.map_raw(vaddr, MAPPINGS[id].unwrap().0
-                       | PAGE_PRESENT | PAGE_USER | PAGE_WRITE);
+/// Make the TLS segment of the current task, which userland reaches through
+/// gs, start at `base`. Fails with EINVAL if the segment is not in the user
+/// space or overlaps kernel areas
+fn sys_set_tls(base : u32) -> i32 {
+    let end = match base.checked_add(USER_TLS_SIZE) {
+        Some(end) => end,
+        None => return -EINVAL,
+    };
+    if !is_user_range(base, end) {
+        return -EINVAL;
+    }
+
+    // gs is not touched on the way back to userland, so loading it here is
+    // enough for the new base to be used
+    current_task().tls_base = base;
+    set_tls_base(base);
+    set_gs(USER_TLS_SELECTOR);
+    0
+}
+
+/// Give up the CPU to the next task. The interrupt context of the calling
+/// task stays on its kernel stack, so the syscall returns normally once the
+/// task is scheduled again
+fn sys_yield() -> i32 {
+    schedule();
+    0
+}
+
+/// Block the current task for `nticks` timer ticks
+fn sys_sleep(nticks : u32) -> i32 {
+    sleep_current(nticks);
+    0
+}
+
+/// Duplicate the current task. Returns the pid of the child to the parent, 0
+/// to the child, or fails with ENOMEM if the child couldn't be created
+fn sys_fork(ctx : &InterruptContext) -> i32 {
+    match current_task().fork(ctx) {
+        Ok(pid) => pid as i32,
+        Err(err) => err,
+    }
+}
+
+/// End of the user mappings placed by the syscalls. Their address is
+/// returned in an i32, which must stay positive to tell it from an error
+pub const USER_MAP_END : u32 = 0x8000_0000;
+
+/// Choose where to map `npages` new pages in the address space `vspace` of
+/// the current task. If `addr` is 0 the pages go to the anonymous mappings
+/// area of the task, otherwise `addr` is checked and returned. Fails with
+/// EINVAL if the range is not in the user space below `USER_MAP_END` or
+/// overlaps kernel areas, the heap or existing mappings,
+/// and with ENOMEM if there is no room left in the anonymous mappings area
+pub fn pick_user_range(vspace : &VirtMem, addr : u32, npages : usize) 
+        -> Result<u32, i32> {
+    if addr == 0 {
+        return vspace.find_free_range(USER_MMAP_BASE, 
+                                      USER_MMAP_BASE + USER_MMAP_SIZE, npages)
+            .map(|vaddr| vaddr.0)
+            .ok_or(-ENOMEM);
+    }
+
+    let end = (npages as u32).checked_mul(PAGE_SIZE as u32)
+        .and_then(|size| addr.checked_add(size))
+        .ok_or(-EINVAL)?;
+
+    // The unmapped part of the heap is reserved for sbrk
+    let task = current_task();
+    let heap_end = task.heap_base + USER_HEAP_MAX_SIZE;
+    if !is_user_range(addr, end) || end > USER_MAP_END ||
+            (addr < heap_end && end > task.heap_base) {
+        return Err(-EINVAL);
+    }
+
+    // Neither is the part of the stack window where the stack can grow
+    let stack_limit = task.user_sp - USER_STACK_MAX_SIZE;
+    if addr < task.user_sp && end > stack_limit {
+        return Err(-EINVAL);
+    }
+
+    if (addr..end).step_by(PAGE_SIZE)
+            .any(|page| vspace.is_mapped(VirtAddr(page))) {
+        return Err(-EINVAL);
+    }
+
+    Ok(addr)
+}
+
+/// Convert the protection `prot` of a user mapping to page table entry
+/// flags. Returns `None` if `prot` can't be applied to a page
+fn prot_to_flags(prot : u32) -> Option<u32> {
+    if prot & PROT_READ == 0 || prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return None;
+    }
+
+    let mut flags = PAGE_USER;
+    if prot & PROT_WRITE != 0 {
+        flags |= PAGE_WRITE;
+    }
+    Some(flags)
+}
+
+/// Map `len` bytes of zeroed memory with the protection `prot` at `addr` in
+/// the current task, or in its anonymous mappings area if `addr` is 0.
+/// Returns the address of the mapping, below `USER_MAP_END`, whose pages are
+/// only allocated when they are first accessed. Fails with EINVAL if the
+/// arguments are invalid or if the range overlaps kernel space, the heap or
+/// existing mappings, and with ENOMEM if there is no room left for the
+/// mapping
+fn sys_mmap(addr : u32, len : usize, prot : u32) -> i32 {
+    let flags = match prot_to_flags(prot) {
+        Some(flags) => flags,
+        None => return -EINVAL,
+    };
+    if len == 0 || addr & 0xfff != 0 {
+        return -EINVAL;
+    }
+    let npages = len.div_ceil(PAGE_SIZE);
+
+    let vspace = VirtMem::get_current();
+    let start = match pick_user_range(&vspace, addr, npages) {
+        Ok(start) => start,
+        Err(err) => return err,
+    };
+
+    match vspace.map_lazy_range(VirtAddr(start), npages, flags) {
+        Ok(()) => start as i32,
+        Err(_) => -EINVAL,
+    }
+}
+
+/// Change the protection of the pages in `[addr, addr + len)` to `prot`.
+/// Fails with EINVAL if the arguments are invalid or if the range touches
+/// kernel memory, and with EFAULT if a page of the range is not mapped
+fn sys_mprotect(addr : u32, len : usize, prot : u32) -> i32 {
+    let flags = match prot_to_flags(prot) {
+        Some(flags) => flags,
+        None => return -EINVAL,
+    };
+    if len == 0 || addr & 0xfff != 0 {
+        return -EINVAL;
+    }
+    let npages = len.div_ceil(PAGE_SIZE);
+    let end = match (npages as u32).checked_mul(PAGE_SIZE as u32)
+            .and_then(|size| addr.checked_add(size)) {
+        Some(end) => end,
+        None => return -EINVAL,
+    };
+    if !is_user_range(addr, end) {
+        return -EINVAL;
+    }
+
+    let vspace = VirtMem::get_current();
+
+    // Check the whole range before changing anything
+    for page in (addr..end).step_by(PAGE_SIZE) {
+        let page = VirtAddr(page);
+        if is_kernel_owned(page) {
+            return -EINVAL;
+        }
+        match vspace.get_pte(page) {
+            Some(pte) if pte.0 & (PAGE_PRESENT | PAGE_LAZY) == 0 => {
+                return -EFAULT;
+            }
+            Some(pte) if pte.0 & PAGE_USER == 0 => return -EINVAL,
+            Some(_) => {},
+            None => return -EFAULT,
+        }
+    }
+
+    for page in (addr..end).step_by(PAGE_SIZE) {
+        let page = VirtAddr(page);
+        let pte = vspace.get_pte(page).expect("Page table vanished");
+        let mut new_pte = (pte.0 & !(PAGE_USER | PAGE_WRITE | PAGE_COW)) | 
+            flags;
+
+        // A page still shared after a fork, or the zero page, is copied on
+        // the first write
+        if new_pte & PAGE_WRITE != 0 && (is_zero_page(pte.0) ||
+                (is_private_page(page, pte.0) &&
+                 PhysMem::refcount(pte.get_paddr()) > 1)) {
+            new_pte = (new_pte & !PAGE_WRITE) | PAGE_COW;
+        }
+        vspace.update_pte(page, new_pte);
+    }
+
+    0
+}
+
+/// Returns the number of timer ticks since boot, the low half in eax and the
+/// high half in edx
+fn sys_getticks(ctx : &mut InterruptContext) -> i32 {
+    let now = ticks();
+    ctx.regs.edx = (now >> 32) as u32;
+    now as u32 as i32
+}
+
+/// Wait for the child `pid` of the current task to exit and free it. Returns
+/// the exit code of the child, or fails with ECHILD if `pid` is not a child
+/// of the current task
+fn sys_waitpid(pid : u32) -> i32 {
+    let parent = current_task().pid;
+    loop {
+        let child = match find_task(pid) {
+            Some(task) if task.parent == parent => task,
+            _ => return -ECHILD,
+        };
+        if child.state() == TaskState::Zombie {
+            return reap_task(pid);
+        }
+
+        // sys_exit wakes us up when a child exits
+        block_current();
+    }
+}
+
+/// Fill the array of `count` `TaskStats` at `buf` with the stats of the
+/// live tasks. Returns the number of records written, or EFAULT if not even
+/// the first one could be. A null `buf` asks the scheduler to print the
+/// stats instead
+fn sys_task_stats(buf : u32, count : usize) -> i32 {
+    if buf == 0 {
+        request_task_stats();
+        return 0;
+    }
+
+    let record_size = core::mem::size_of::<TaskStats>();
+    let size = match count.checked_mul(record_size) {
+        Some(size) => size,
+        None => return -EINVAL,
+    };
+    if let Err(err) = check_user_range(buf, size, true) {
+        return err;
+    }
+
+    // Each copy checks its record again. A fault keeps the records written
+    // before it, like a short write
+    let mut written = 0;
+    let mut fault = None;
+    for_each_task_stats(|stats| {
+        if written == count || fault.is_some() {
+            return;
+        }
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&stats as *const TaskStats as *const u8,
+                                        record_size)
+        };
+        let dst = buf + (written * record_size) as u32;
+        match copy_to_user(dst, bytes) {
+            Ok(()) => written += 1,
+            Err(err) => fault = Some(err),
+        }
+    });
+    match fault {
+        Some(err) if written == 0 => err,
+        _ => written as i32,
+    }
+}
 
-        println!("Mapped phys page {:#x} at {:#x}", MAPPINGS[id].unwrap().0,
-            vaddr.0);
+/// Enable or disable syscall tracing. Only the first task, pid 1, is allowed
+/// to do it, other tasks get EPERM
+fn sys_trace(enable : bool) -> i32 {
+    if current_task().pid != 1 {
+        return -EPERM;
     }
+    set_syscall_trace(enable);
+    0
 }
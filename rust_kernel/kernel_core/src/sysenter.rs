@@ -0,0 +1,140 @@
+//! Fast syscall path with the sysenter and sysexit instructions. The entry
+//! stub builds the same interrupt context as `int 0x80` so both paths end up
+//! in `handle_syscall`.
+//!
+//! sysenter overwrites esp and eip but leaves the other registers alone, so
+//! userland gives its stack pointer in ecx and its return address in edx.
+//! The syscall number stays in eax, the first two arguments go in esi and
+//! edi instead of ecx and edx, and the third one stays in ebx. The values
+//! a syscall leaves in ecx and edx are returned in esi and edi.
+//!
+//! sysenter doesn't clear the trap flag. The single step of the entry stub
+//! is stopped by the debug exception handler, and a task that single steps
+//! gets its flags back from iret instead of sysexit
+
+#[cfg(not(test))]
+use core::arch::global_asm;
+use crate::cpu::*;
+use crate::cpuid::cpu_features;
+use crate::{print, println, PERIPHERALS};
+
+/// Set once sysenter is configured
+static mut SYSENTER_ENABLED : bool = false;
+
+/// Check if the CPU supports sysenter. Early Pentium Pro report SEP without
+/// supporting the instructions
+fn sep_supported() -> bool {
+    let cpu = cpu_features();
+    cpu.sep && !(cpu.family == 6 && cpu.model < 3 && cpu.stepping < 3)
+}
+
+/// Configure sysenter if the CPU supports it. Our GDT has the kernel code
+/// and data segments then the user ones, as sysenter and sysexit expect
+pub fn sysenter_init() {
+    if !sep_supported() {
+        println!("sysenter is not supported, syscalls use int 0x80");
+        return;
+    }
+
+    // The stack is set by switch_to() before a task runs
+    let entry = sysenter_entry as *const u32 as u64;
+    let configured = unsafe {
+        write_msr(IA32_SYSENTER_CS, 0x8)
+            .and_then(|_| write_msr(IA32_SYSENTER_ESP, 0))
+            .and_then(|_| write_msr(IA32_SYSENTER_EIP, entry))
+    };
+    if let Err(err) = configured {
+        println!("sysenter can't be configured ({:?}), syscalls use int 0x80",
+                 err);
+        return;
+    }
+
+    unsafe {
+        SYSENTER_ENABLED = true;
+
+        println!("sysenter entry : {:#x}", rdmsr(IA32_SYSENTER_EIP));
+    }
+}
+
+/// Check if userland can make syscalls with sysenter
+pub fn sysenter_enabled() -> bool {
+    unsafe { SYSENTER_ENABLED }
+}
+
+/// Set the stack used by sysenter. Like esp0 in the TSS, it is the kernel
+/// stack top of the task that runs next
+#[inline]
+pub fn set_sysenter_stack(esp : u32) {
+    unsafe {
+        if SYSENTER_ENABLED {
+            wrmsr(IA32_SYSENTER_ESP, esp as u64);
+        }
+    }
+}
+
+/// Returns true if `ip` is in the sysenter entry stub
+pub fn in_sysenter_entry(ip : u32) -> bool {
+    let start = sysenter_entry as *const u32 as u32;
+    let end = sysenter_entry_end as *const u32 as u32;
+    ip >= start && ip < end
+}
+
+extern {
+    fn sysenter_entry();
+    fn sysenter_entry_end();
+}
+
+#[cfg(not(test))]
+global_asm!(r#"
+.extern interrupt_handler
+
+.global sysenter_entry
+sysenter_entry:
+    // Build the interrupt frame of an interrupt from ring 3. sysenter
+    // disabled interrupts, but they are enabled in userland
+    push 0x20 | 3   // push user ss
+    push ecx        // push user esp
+    pushfd
+    or dword ptr [esp], 0x200
+    push 0x18 | 3   // push user cs
+    push edx        // push user eip
+    push -1         // push error code
+    push 0x80       // push interrupt number
+
+    mov ecx, esi    // move the arguments where handle_syscall reads them
+    mov edx, edi
+    pusha           // save gprs
+    mov ecx, esp    // set ecx to the @ of the interrupt_context structure
+    call interrupt_handler
+
+    // sysexit takes the user stack in ecx and the return address in edx,
+    // so the ecx and edx of the context go back in esi and edi
+    mov eax, [esp + 24]
+    mov [esp + 4], eax
+    mov eax, [esp + 20]
+    mov [esp], eax
+    popa            // restore gprs
+    add esp, 8      // pop interrupt number and error code
+
+    // With TF in the user flags, popfd would single step the end of the
+    // stub in ring 0. iret restores them in userland only
+    test dword ptr [esp + 8], 0x100
+    jnz 1f
+
+    mov edx, [esp]      // user eip
+    mov ecx, [esp + 12] // user esp
+
+    // Restore the user flags with interrupts still disabled. sti only
+    // takes effect after sysexit, so no interrupt can happen on the way
+    and dword ptr [esp + 8], 0xfffffdff
+    add esp, 8
+    popfd
+    add esp, 8
+    sti
+    sysexit
+1:
+    iretd
+
+.global sysenter_entry_end
+sysenter_entry_end:
+"#);
@@ -5,187 +5,1549 @@ use crate::segmem::*;
 use crate::paging::*;
 use crate::paging::virtmem::*;
 use crate::paging::pagemem::*;
+use crate::paging::physmem::*;
 use crate::interrupts::InterruptContext;
 use crate::interrupts::resume_from_intr;
+use crate::interrupts::ticks;
+use crate::shm::*;
+use crate::ipc::Mailbox;
+use crate::handles::HandleTable;
+use crate::sysenter::set_sysenter_stack;
+use crate::vsys::vsys_map;
+use crate::pit::QUANTUM_TICKS;
+use crate::fpu::*;
+use crate::syscalls::ENOMEM;
+use crate::sync::*;
+use crate::watchdog::watchdog_schedule;
+use crate::bench::{bench_switch_start, bench_switch_done};
 use core::mem::size_of;
 use core::arch::asm;
+#[cfg(not(test))]
+use core::arch::global_asm;
 use crate::{print, println, PERIPHERALS};
 
 /// Size in pages of the kernel stack for a task
 const KERNEL_STACK_SIZE : usize = 1;
 
-/// Size in pages of the user stack for a task
+/// Size in pages of the unmapped guard area below the kernel stack of a task
+const KERNEL_STACK_GUARD_SIZE : usize = 1;
+
+/// Size in pages of the user stack when a task is created
 const USER_STACK_SIZE : usize = 1;
 
+/// Virtual address of the top of the user stack of a task
+pub const USER_STACK_TOP : u32 = 0x8000_0000;
+
+/// Max size in bytes of the user stack, which grows on page faults
+pub const USER_STACK_MAX_SIZE : u32 = 0x1_0000;
+
+/// Virtual address of the TLS page of a task, below the stack window with
+/// an unmapped page in between
+pub const USER_TLS_BASE : u32 = USER_STACK_TOP - USER_STACK_MAX_SIZE - 
+    2 * PAGE_SIZE as u32;
+
 /// Size in pages of the user code for a task
 const USER_CODE_SIZE : usize = 1;
 
-/// Max number of tasks that can run simultaneously on the system
-const MAX_TASKS : usize = 10;
+/// Base virtual address of the heap of a task
+pub const USER_HEAP_BASE : u32 = 0x4000_0000;
+
+/// Max size in bytes of the heap of a task
+pub const USER_HEAP_MAX_SIZE : u32 = 0x100_0000;
+
+/// Base virtual address of the area where anonymous mappings of a task are
+/// placed when it doesn't choose their address
+pub const USER_MMAP_BASE : u32 = 0x6000_0000;
+
+/// Size in bytes of the anonymous mappings area
+pub const USER_MMAP_SIZE : u32 = 0x1000_0000;
 
-/// Used to init the `TASKS` array
-const INIT_TASK : Option<Task> = None;
+/// Number of task slots in a page of the task table
+const TASKS_PER_PAGE : usize = PAGE_SIZE / size_of::<Option<Task>>();
+const _ : () = assert!(TASKS_PER_PAGE > 0, "A task doesn't fit in a page");
 
-/// Contains all running tasks
-static mut TASKS : [Option<Task>; MAX_TASKS] = [INIT_TASK; MAX_TASKS];
+/// Max number of pages in the task table
+const MAX_TASK_PAGES : usize = 256;
+
+/// Max number of tasks that can exist at the same time, if there is enough
+/// memory for all of them
+pub const MAX_TASKS : usize = TASKS_PER_PAGE * MAX_TASK_PAGES;
+
+/// Physical pages of the task table, each holding `TASKS_PER_PAGE` slots. A
+/// page is added when all the slots are used, and never freed, so that the
+/// index of a task stays valid
+static mut TASK_PAGES : [PhysAddr; MAX_TASK_PAGES] =
+    [PhysAddr(0); MAX_TASK_PAGES];
+
+/// Number of pages in `TASK_PAGES`
+static mut TASK_PAGE_COUNT : usize = 0;
 
 /// Index of currently executed task
 static mut CURRENT_TASK_IDX : usize = usize::MAX;
 
+/// Marks the end of a `TaskQueue`, and the links of a task in no queue
+const NO_TASK : usize = usize::MAX;
+
+/// Ready tasks of each priority, in the order they became ready
+static mut READY_QUEUES : [TaskQueue; MAX_PRIORITY as usize + 1] =
+    [TaskQueue::new(); MAX_PRIORITY as usize + 1];
+
+/// Sleeping tasks, sorted by `wakeup_tick`
+static mut SLEEP_QUEUE : TaskQueue = TaskQueue::new();
+
+/// Max number of wake ups asked by interrupt handlers between two schedules
+const MAX_DEFERRED_WAKES : usize = 16;
+
+/// Pids of the tasks interrupt handlers asked to wake up, in the order of
+/// the requests
+static mut DEFERRED_WAKES : [u32; MAX_DEFERRED_WAKES] =
+    [0; MAX_DEFERRED_WAKES];
+
+/// Number of pids in `DEFERRED_WAKES`
+static mut DEFERRED_WAKE_COUNT : usize = 0;
+
+/// Pid given to the next created task
+static mut NEXT_PID : u32 = 1;
+
+/// Highest scheduling priority, the lowest one is 0
+pub const MAX_PRIORITY : u8 = 7;
+
+/// Priority of the tasks created by the kernel
+const DEFAULT_PRIORITY : u8 = 3;
+
+/// Number of ticks a runnable task can wait before getting boosted to
+/// `MAX_PRIORITY`, so that low priority tasks still run
+const AGING_TICKS : u64 = 4 * QUANTUM_TICKS as u64;
+
+/// Print every scheduling decision
+const SCHED_DEBUG : bool = false;
+
+/// Index of the idle task, which runs when no other task is runnable
+static mut IDLE_TASK_IDX : usize = usize::MAX;
+
+/// Index of the reaper task, which frees the resources of exited tasks
+static mut REAPER_TASK_IDX : usize = usize::MAX;
+
+/// Code selector of the kernel
+const KERNEL_CS : u32 = 0x8;
+
+/// Data selector of the kernel
+const KERNEL_DS : u32 = 0x10;
+
+/// Code selector of userland
+const USER_CS : u32 = 0x18 | 3;
+
+/// Data selector of userland
+const USER_DS : u32 = 0x20 | 3;
+
+/// Set to print the stats of every task at the next schedule
+static mut PRINT_STATS : bool = false;
+
+/// State of a task
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TaskState {
+    /// The task can be scheduled
+    Ready,
+
+    /// The task is currently executing
+    Running,
+
+    /// The task waits for `wakeup_tick`
+    Sleeping,
+
+    /// The task waits for an event, like a message or the exit of a child
+    Blocked,
+
+    /// The task exited. Its resources are freed once its parent collected
+    /// its exit code, or by the scheduler if it has no parent anymore
+    Zombie,
+}
+
+impl TaskState {
+    /// Name of the state, for the task table dump
+    fn name(self) -> &'static str {
+        match self {
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Sleeping => "sleeping",
+            TaskState::Blocked => "blocked",
+            TaskState::Zombie => "zombie",
+        }
+    }
+
+    /// Returns true if a task in this state can go to the state `next`. Only
+    /// the running task can wait or exit, and a task runs once it is ready
+    fn can_become(self, next : TaskState) -> bool {
+        use TaskState::*;
+        matches!((self, next),
+                 (Ready, Running) |
+                 (Running, Ready | Sleeping | Blocked | Zombie) |
+                 (Sleeping | Blocked, Ready))
+    }
+}
+
+/// Doubly linked list of tasks, identified by their index in the task
+/// table and linked through their `queue_prev` and `queue_next` fields. A
+/// task is in at most one queue, which depends on its state
+#[derive(Clone, Copy)]
+struct TaskQueue {
+    head : usize,
+    tail : usize,
+}
+
+impl TaskQueue {
+    const fn new() -> Self {
+        Self { head : NO_TASK, tail : NO_TASK }
+    }
+
+    /// Get the first task of the queue
+    fn front(&self) -> Option<usize> {
+        if self.head == NO_TASK { None } else { Some(self.head) }
+    }
+
+    /// Insert the task `idx` before the task `before` of the queue, or at
+    /// the end if `before` is `NO_TASK`
+    fn insert_before(&mut self, idx : usize, before : usize) {
+        let prev = if before == NO_TASK {
+            self.tail
+        } else {
+            task_at(before).queue_prev
+        };
+
+        let task = task_at(idx);
+        assert!(task.queue_prev == NO_TASK && task.queue_next == NO_TASK &&
+                self.head != idx, "Task {} is already queued", task.pid);
+        task.queue_prev = prev;
+        task.queue_next = before;
+
+        match prev {
+            NO_TASK => self.head = idx,
+            _ => task_at(prev).queue_next = idx,
+        }
+        match before {
+            NO_TASK => self.tail = idx,
+            _ => task_at(before).queue_prev = idx,
+        }
+    }
+
+    /// Add the task `idx` at the end of the queue
+    fn push_back(&mut self, idx : usize) {
+        self.insert_before(idx, NO_TASK);
+    }
+
+    /// Remove the task `idx` from the queue
+    fn remove(&mut self, idx : usize) {
+        let task = task_at(idx);
+        let (prev, next) = (task.queue_prev, task.queue_next);
+        task.queue_prev = NO_TASK;
+        task.queue_next = NO_TASK;
+
+        match prev {
+            NO_TASK => self.head = next,
+            _ => task_at(prev).queue_next = next,
+        }
+        match next {
+            NO_TASK => self.tail = prev,
+            _ => task_at(next).queue_prev = prev,
+        }
+    }
+}
+
+/// CPU usage of a task, returned by `SYS_TASK_STATS`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStats {
+    /// Pid of the task
+    pub pid : u32,
+
+    /// Name of the task, nul terminated if shorter than 16 bytes
+    pub name : [u8; 16],
+
+    /// Timer ticks during which the task was running
+    pub ticks : u32,
+
+    /// Number of times the task was switched to
+    pub switches : u32,
+
+    /// Number of syscalls made by the task
+    pub syscalls : u32,
+
+    /// Physical pages private to the task that are mapped in its userland
+    pub pages : u32,
+}
+
+/// Name and code of a task created at boot
+pub type BootTask = (&'static [u8], fn());
+
 /// All information needed to represent a task
 #[derive(Debug)]
 pub struct Task {
+    /// Unique identifier of the task, never reused
+    pub pid : u32,
+
+    /// Pid of the task that forked this one, 0 for tasks created by the
+    /// kernel
+    pub parent : u32,
+
+    /// Exit code given to sys_exit
+    pub exit_code : i32,
+
     /// The name of the task
     name : [u8; 16],
+
+    /// Scheduling state of the task, only changed through `set_state`
+    state : TaskState,
+
+    /// Index of the task in the task table
+    idx : usize,
+
+    /// Previous and next tasks in the queue of the task, if any
+    queue_prev : usize,
+    queue_next : usize,
+
+    /// Timer tick at which a sleeping task becomes ready again
+    pub wakeup_tick : u64,
     
     /// CR3 value
     vspace : VirtMem,
 
-    /// Kernel stack top
+    /// Saved kernel stack pointer of the task while it is not running
     pub kernel_sp : u32,
 
+    /// Kernel stack top, loaded in the TSS when switching to this task
+    kernel_stack_top : u32,
+
+    /// Base of the unmapped guard area below the kernel stack
+    kernel_stack_guard : u32,
+
     /// User stack top
-    user_sp : u32,
-}
+    pub user_sp : u32,
 
-impl Task {
-    /// Create a new task
-    pub fn new(name : &[u8], code_addr : fn()) {
-        let orig_vspace = VirtMem::get_current();
+    /// Lowest mapped page of the user stack
+    user_stack_bottom : u32,
 
-        if name.len() > 16 {
-            panic!("Task name len > 16");
-        }
-        let mut task_name : [u8 ; 16] = [0; 16];
-        task_name[..name.len()].copy_from_slice(name);
+    /// Base of the TLS segment of the task
+    pub tls_base : u32,
 
-        let mut vspace = VirtMem::new();
+    /// Registers of the FPU while the task doesn't own them
+    pub fpu_state : FpuState,
 
-        setup_identity_mapping(&vspace);
-        switch_vspace(&vspace);
+    /// Base address of the heap
+    pub heap_base : u32,
 
-        let kernel_stack = vspace.alloc_virt_pages(KERNEL_STACK_SIZE, 
-                                                   true, false);
-        println!("kernel_stack : {:#x}", kernel_stack.0);
-        let mut kernel_sp = kernel_stack.0 + 
-            (KERNEL_STACK_SIZE * PAGE_SIZE) as u32;
+    /// Current end of the heap
+    pub brk : u32,
 
-        let user_stack = vspace.alloc_virt_pages(USER_STACK_SIZE, true, true);
-        println!("user_stack : {:#x}", user_stack.0);
-        let user_sp = user_stack.0 + (USER_STACK_SIZE * PAGE_SIZE) as u32;
+    /// Messages sent to the task
+    pub mailbox : Mailbox,
+
+    /// Kernel objects the task can use
+    pub handles : HandleTable,
+
+    /// Timer ticks during which the task was running
+    ticks : u32,
+
+    /// Number of times the task was switched to
+    switches : u32,
+
+    /// Number of syscalls made by the task
+    pub syscalls : u32,
+
+    /// Scheduling priority, from 0 to `MAX_PRIORITY`
+    priority : u8,
+
+    /// Tick at which the task was last picked by the scheduler
+    last_run : u64,
+
+    /// Timer ticks the task ran since it was last picked by the scheduler
+    slice_ticks : u32,
+
+    /// The task runs in ring 0 in the kernel address space
+    kernel : bool,
+
+    /// The memory of the exited task was freed, only its exit code is left
+    released : bool,
+}
+
+impl Task {
+    /// Create a new task executing `code_addr` in userland. Returns the pid
+    /// of the task, or fails with ENOMEM and frees what was allocated. The
+    /// address space of the task is built through the physical memory
+    /// window, without switching to it
+    pub fn new(name : &[u8], code_addr : fn()) -> Result<u32, i32> {
+        let task_name = Self::make_name(name);
+
+        // The task maps its stack, heap and anonymous mappings through
+        // syscalls, which only accept user ranges
+        assert!(is_user_range(USER_TLS_BASE, USER_STACK_TOP) &&
+                is_user_range(USER_HEAP_BASE,
+                              USER_HEAP_BASE + USER_HEAP_MAX_SIZE) &&
+                is_user_range(USER_MMAP_BASE, USER_MMAP_BASE + USER_MMAP_SIZE),
+                "User areas outside of the user space");
+
+        let vspace = VirtMem::new().map_err(|_| -ENOMEM)?;
+        if let Err(err) = Self::build_user_vspace(&vspace) {
+            free_user_vspace(vspace);
+            return Err(err);
+        }
+        let user_sp = USER_STACK_TOP;
         println!("user sp : {:#x}", user_sp);
 
         let code_addr = code_addr as *const u32 as u32;
 
-        // Map user code as user accessible in virtual memory
-        vspace.map_raw(VirtAddr(code_addr),
-            code_addr | PAGE_USER | PAGE_PRESENT);
-
         // Create a fake interrupt context. This intr context will be used
         // to call switch_to() on this task and jump to userland
         let mut context = InterruptContext::default();
         context.frame.ip = code_addr;
-        context.frame.cs = 0x18 | 3;
-        context.frame.eflags = 0x200; // To enable interrupts on context switch
+        context.frame.cs = USER_CS;
+        // To enable interrupts on context switch
+        context.frame.eflags = EFLAGS_IF;
         context.frame.sp = user_sp;
-        context.frame.ss = 0x20 | 3;
+        context.frame.ss = USER_DS;
+
+        Self::from_context(task_name, 0, vspace, &context, user_sp,
+                           USER_HEAP_BASE, USER_HEAP_BASE)
+    }
+
+    /// Map the identity mapping, the user stack, the user code, the info
+    /// page and the TLS page of a new task in `vspace`. Fails with ENOMEM,
+    /// leaving what was mapped to `free_user_vspace`
+    fn build_user_vspace(vspace : &VirtMem) -> Result<(), i32> {
+        setup_identity_mapping(vspace).map_err(|_| -ENOMEM)?;
+
+        let user_stack = VirtAddr(USER_STACK_TOP - 
+                                  (USER_STACK_SIZE * PAGE_SIZE) as u32);
+        vspace.map(user_stack, USER_STACK_SIZE * PAGE_SIZE, true, true)
+            .map_err(|_| -ENOMEM)?;
+        println!("user_stack : {:#x}", user_stack.0);
+
+        // Map user code as user accessible in virtual memory. All userland
+        // functions live in the .user_task section, so map all of it. The
+        // kernel image runs at its physical address, the user pages replace
+        // its identity mapping
+        let (user_code_start, user_code_end) = user_code_range();
+        for page in (user_code_start..user_code_end).step_by(PAGE_SIZE) {
+            vspace.try_update_pte(VirtAddr(page), page | PAGE_USER |
+                                  PAGE_PRESENT | PAGE_BORROWED)
+                .map_err(|_| -ENOMEM)?;
+        }
+        vsys_map(vspace).map_err(|_| -ENOMEM)?;
+
+        // A zeroed page for the TLS, which gs points to
+        let tls_page = unsafe { PhysMem::try_alloc_phys_zeroed() }
+            .map_err(|_| -ENOMEM)?;
+        vspace.map_raw(VirtAddr(USER_TLS_BASE), 
+                       tls_page.0 | PAGE_USER | PAGE_WRITE | PAGE_PRESENT)
+            .map_err(|_| {
+                unsafe { PhysMem::free_phys(tls_page); }
+                -ENOMEM
+            })
+    }
+
+    /// Create a new task executing `code_addr` in ring 0, on its own kernel
+    /// stack in the kernel address space. The task exits when `code_addr`
+    /// returns. Returns the pid of the task, or fails with ENOMEM
+    pub fn new_kernel(name : &[u8], code_addr : fn()) -> Result<u32, i32> {
+        let task_name = Self::make_name(name);
+
+        // There is no privilege change when returning to ring 0, so the
+        // stack of the task is the kernel stack and the frame has no sp/ss.
+        // `kthread_start` gets `code_addr` in ecx
+        let mut context = InterruptContext::default();
+        context.regs.ecx = code_addr as *const u32 as u32;
+        context.frame.ip = kthread_start as *const u32 as u32;
+        context.frame.cs = KERNEL_CS;
+        context.frame.eflags = EFLAGS_IF;
+
+        Self::from_context(task_name, 0, kernel_vspace(), &context, 0, 0, 0)
+    }
+
+    /// Reserve a kernel stack and the guard area below it in `vspace`, and
+    /// map the stack. The guard area is left unmapped, so that an overflow
+    /// faults instead of corrupting the memory below. Returns the address
+    /// of the guard area, or fails with ENOMEM and releases the reservation
+    fn map_kernel_stack(vspace : &mut VirtMem) -> Result<VirtAddr, i32> {
+        let npages = KERNEL_STACK_GUARD_SIZE + KERNEL_STACK_SIZE;
+        let guard = vspace.try_reserve_virt_pages(npages)
+            .map_err(|_| -ENOMEM)?;
+        let kernel_stack = VirtAddr(guard.0 + 
+            (KERNEL_STACK_GUARD_SIZE * PAGE_SIZE) as u32);
+        if vspace.map(kernel_stack, KERNEL_STACK_SIZE * PAGE_SIZE, true, false)
+                .is_err() {
+            vspace.release_virt_pages(guard, npages);
+            return Err(-ENOMEM);
+        }
+        Ok(guard)
+    }
+
+    /// Pad `name` to the size of a task name
+    fn make_name(name : &[u8]) -> [u8; 16] {
+        if name.len() > 16 {
+            panic!("Task name len > 16");
+        }
+        let mut task_name : [u8 ; 16] = [0; 16];
+        task_name[..name.len()].copy_from_slice(name);
+        task_name
+    }
 
-        // Push the "fake" interrupt context
-        kernel_sp -= size_of::<InterruptContext>() as u32;
-        unsafe { core::ptr::copy(&context, kernel_sp as *mut _, 
-                                 size_of::<InterruptContext>()); }
+    /// Create a child of the task `parent` that resumes from the interrupt
+    /// context `context` with the address space `vspace`, which must already
+    /// contain the user stack and the heap described by `user_sp`,
+    /// `heap_base` and `brk`. Returns the pid of the task
+    pub fn from_context(name : [u8; 16], parent : u32, mut vspace : VirtMem, 
+                        context : &InterruptContext, user_sp : u32, 
+                        heap_base : u32, brk : u32) -> Result<u32, i32> {
+        // Kernel tasks create tasks with interrupts enabled, the task table
+        // and the ready queues must not change under us
+        let _guard = PreemptGuard::new();
 
-        // Push the address of resume_from_intr
+        // Find an empty task slot before allocating anything. Without one,
+        // the address space built for a user task is freed with its pages
+        let user = context.frame.cs & 3 != 0;
+        let empty_spot = match alloc_task_slot() {
+            Ok(spot) => spot,
+            Err(err) => {
+                if user {
+                    free_user_vspace(vspace);
+                }
+                return Err(err);
+            }
+        };
+
+        // Same without memory for the kernel stack
+        let kernel_stack_guard = match Self::map_kernel_stack(&mut vspace) {
+            Ok(guard) => guard,
+            Err(err) => {
+                if user {
+                    free_user_vspace(vspace);
+                }
+                return Err(err);
+            }
+        };
+        let kernel_stack = VirtAddr(kernel_stack_guard.0 + 
+            (KERNEL_STACK_GUARD_SIZE * PAGE_SIZE) as u32);
+        println!("kernel_stack : {:#x}", kernel_stack.0);
+        let kernel_stack_top = kernel_stack.0 + 
+            (KERNEL_STACK_SIZE * PAGE_SIZE) as u32;
+        let mut kernel_sp = kernel_stack_top;
+
+        // The kernel stack is only mapped in `vspace`, so the initial frame
+        // is written in its top page through the physical memory window.
+        // `alias` gives the address in the window of a stack address
+        let top_page = vspace.get_pte(VirtAddr(kernel_stack_top - 
+                                               PAGE_SIZE as u32))
+            .expect("Kernel stack without page table").get_paddr();
+        let top_alias = PhysMem::translate(top_page, PAGE_SIZE)
+            .expect("Kernel stack outside of RAM") as u32;
+        let alias = |sp : u32| top_alias + PAGE_SIZE as u32 - 
+            (kernel_stack_top - sp);
+
+        // Push the interrupt context. When resuming in ring 0, iret doesn't
+        // pop sp and ss, so they are left out
+        let ring0 = context.frame.cs & 3 == 0;
+        let mut context_size = size_of::<InterruptContext>();
+        if ring0 {
+            context_size -= 2 * size_of::<u32>();
+        }
+        kernel_sp -= context_size as u32;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                context as *const InterruptContext as *const u8,
+                alias(kernel_sp) as *mut u8, context_size);
+        }
+
+        // Push the address of resume_from_intr, where switch_to() will
+        // return the first time this task is scheduled
         kernel_sp -= size_of::<u32>() as u32;
-        unsafe { core::ptr::write(kernel_sp as *mut _, 
+        unsafe { core::ptr::write(alias(kernel_sp) as *mut _, 
                                   resume_from_intr as *const u32 as u32); }
         
-        // Push padding values
-        for _ in 0..3 {
-            // Push the address of resume_from_intr
+        // Push initial values for the ebp, ebx, esi and edi registers
+        for _ in 0..4 {
             kernel_sp -= size_of::<u32>() as u32;
-            unsafe { core::ptr::write(kernel_sp as *mut _, 
+            unsafe { core::ptr::write(alias(kernel_sp) as *mut _, 
                                       0 as *const u32 as u32); }
         }
 
-        // Push user data segment selector
+        // Push the data segment selector, then the gs one
         kernel_sp -= size_of::<u32>() as u32;
-        unsafe { core::ptr::write(kernel_sp as *mut _, 
-                                  0x20 | 3 as u32); }
-        
-        // Find an empty task spot 
-        let empty_spot = unsafe {
-            TASKS.iter().position(|x| x.is_none())
-                .expect("Too many running tasks")
+        let data_selector = if ring0 { KERNEL_DS } else { USER_DS };
+        unsafe { core::ptr::write(alias(kernel_sp) as *mut _, 
+                                  data_selector); }
+        kernel_sp -= size_of::<u32>() as u32;
+        let tls_selector = if ring0 {
+            KERNEL_DS
+        } else {
+            USER_TLS_SELECTOR as u32
         };
+        unsafe { core::ptr::write(alias(kernel_sp) as *mut _, 
+                                  tls_selector); }
         
+        let pid = unsafe {
+            let pid = NEXT_PID;
+            NEXT_PID += 1;
+            pid
+        };
+        println!("pid : {}", pid);
+
         let task = Self {
-            name : task_name,
+            pid : pid,
+            parent : parent,
+            exit_code : 0,
+            name : name,
+            state : TaskState::Ready,
+            idx : empty_spot,
+            queue_prev : NO_TASK,
+            queue_next : NO_TASK,
+            wakeup_tick : 0,
             vspace : vspace,
             kernel_sp : kernel_sp,
+            kernel_stack_top : kernel_stack_top,
+            kernel_stack_guard : kernel_stack_guard.0,
             user_sp : user_sp,
+            user_stack_bottom : user_sp.saturating_sub(
+                (USER_STACK_SIZE * PAGE_SIZE) as u32),
+            fpu_state : FpuState::initial(),
+            tls_base : if ring0 { 0 } else { USER_TLS_BASE },
+            heap_base : heap_base,
+            brk : brk,
+            mailbox : Mailbox::new(),
+            handles : HandleTable::new(),
+            ticks : 0,
+            switches : 0,
+            syscalls : 0,
+            priority : DEFAULT_PRIORITY,
+            last_run : ticks(),
+            slice_ticks : 0,
+            kernel : ring0,
+            released : false,
         };
 
-        // Add the task to the TASKS array
-        unsafe { TASKS[empty_spot] = Some(task); }
-        switch_vspace(&orig_vspace);
+        // Add the task to the task table, it can run right away. The timer
+        // and the other interrupt handlers look at the running task, they
+        // must not find the slot half written
+        let _irq_guard = InterruptGuard::new();
+        *task_slot(empty_spot) = Some(task);
+        enqueue(empty_spot);
+
+        Ok(pid)
+    }
+
+    /// Create a copy of this task resuming from `context`, where the child
+    /// gets 0 as the syscall return value. Returns the pid of the child, or
+    /// fails with ENOMEM if there is no room for another task or no memory
+    /// for its address space
+    pub fn fork(&self, context : &InterruptContext) -> Result<u32, i32> {
+        // Fail before copying the address space
+        alloc_task_slot()?;
+
+        let vspace = self.vspace.fork().map_err(|_| -ENOMEM)?;
+        shm_get_vspace(&vspace);
+
+        let mut context = *context;
+        context.regs.eax = 0;
+
+        let pid = Self::from_context(self.name, self.pid, vspace, &context, 
+                                     self.user_sp, self.heap_base, self.brk)?;
+
+        // The child inherits the handles and the priority of its parent,
+        // and its stack has the same size, and a copy of its FPU state
+        let child = find_task(pid).unwrap();
+        child.handles = self.handles.dup();
+        child.set_priority(self.priority);
+        child.user_stack_bottom = self.user_stack_bottom;
+        child.tls_base = self.tls_base;
+        child.fpu_state = fpu_state_of(self.pid, &self.fpu_state);
+
+        Ok(pid)
     }
 }
 
-/// Switch task context from `prev` to `next`
-pub fn switch_to(prev : &Task, next : &Task) {
-    unsafe { 
-        // Update the esp0 field of the TSS
-        TSS.update_esp0(next.kernel_sp + 20 + 
-                        size_of::<InterruptContext>() as u32);
-    
-        asm!("mov eax, ds    // Save data segment registers on kernel stack
-              push eax
-              
-              cmp ecx, edx
-              je 2f
+impl Task {
+    /// Free the kernel stack, the user memory and the address space of the
+    /// task, once. Must not be called on the running task, since we would
+    /// free the kernel stack we are running on. The task is left with the
+    /// kernel address space
+    fn free_resources(&mut self) {
+        if self.released {
+            return;
+        }
+        self.released = true;
+
+        // A task that exits while owning the FPU leaves its registers there,
+        // they must not be saved in a freed task at the next #NM
+        fpu_release(self.pid);
+
+        let kernel_stack = self.kernel_stack_top - 
+            (KERNEL_STACK_SIZE * PAGE_SIZE) as u32;
+        let mut vspace = core::mem::replace(&mut self.vspace, 
+                                            kernel_vspace());
+
+        // Kernel tasks share the kernel address space, only their stack
+        // belongs to them
+        if self.kernel {
+            let kernel_stack = VirtAddr(kernel_stack);
+            vspace.free_virt_pages(kernel_stack, KERNEL_STACK_SIZE)
+                .expect("Kernel stack of a task not allocated");
+            vspace.release_virt_pages(VirtAddr(self.kernel_stack_guard),
+                                      KERNEL_STACK_GUARD_SIZE);
+            return;
+        }
+
+        for page in (kernel_stack..self.kernel_stack_top).step_by(PAGE_SIZE) {
+            let pte = vspace.get_pte(VirtAddr(page))
+                .expect("Task page without page table");
+            unsafe { PhysMem::free_phys(pte.get_paddr()); }
+        }
+
+        self.handles.close_all();
+        free_user_vspace(vspace);
+    }
+
+    /// Map the pages of the user stack from the page of `addr` to the ones
+    /// already mapped, if `addr` is in the window where the stack can grow.
+    /// Returns false if it is not, if a page of the window is already mapped
+    /// or if there is no memory left for the stack
+    pub fn grow_stack(&mut self, addr : u32) -> bool {
+        let stack_limit = self.user_sp - USER_STACK_MAX_SIZE;
+        if self.kernel || addr < stack_limit || addr >= self.user_stack_bottom {
+            return false;
+        }
+
+        // From the top, so that the pages mapped stay part of the stack if
+        // something else is mapped in the window
+        let page = addr & !0xfff;
+        while self.user_stack_bottom > page {
+            let vaddr = self.user_stack_bottom - PAGE_SIZE as u32;
+            let paddr = match unsafe { PhysMem::try_alloc_phys_zeroed() } {
+                Ok(paddr) => paddr,
+                Err(OutOfMemory) => return false,
+            };
+            let flags = PAGE_PRESENT | PAGE_WRITE | PAGE_USER;
+            if self.vspace.map_raw(VirtAddr(vaddr), paddr.0 | flags).is_err() {
+                unsafe { PhysMem::free_phys(paddr); }
+                return false;
+            }
+            self.user_stack_bottom = vaddr;
+        }
+        true
+    }
+
+    /// Get the priority the scheduler uses for the task at the tick `now`.
+    /// Tasks that didn't run for `AGING_TICKS` get the highest priority
+    fn effective_priority(&self, now : u64) -> u8 {
+        if now.saturating_sub(self.last_run) >= AGING_TICKS {
+            MAX_PRIORITY
+        } else {
+            self.priority
+        }
+    }
+
+    /// Get the scheduling state of the task
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+
+    /// Get the address space of the task
+    pub fn vspace(&self) -> &VirtMem {
+        &self.vspace
+    }
+
+    /// Move the task to the state `state`. Panics if the task can't go
+    /// there from its current state
+    fn set_state(&mut self, state : TaskState) {
+        assert!(self.state.can_become(state),
+                "Task {} (pid {}) can't go from {:?} to {:?}",
+                self.name(), self.pid, self.state, state);
+        self.state = state;
+    }
+
+    /// Change the scheduling priority of the task, moving it to the ready
+    /// queue of its new priority if it waits in one
+    pub fn set_priority(&mut self, priority : u8) {
+        let queued = self.state == TaskState::Ready;
+        if queued {
+            dequeue(self.idx);
+        }
+        self.priority = priority;
+        if queued {
+            enqueue(self.idx);
+        }
+    }
+
+    /// Get the CPU usage of the task
+    pub fn stats(&self) -> TaskStats {
+        TaskStats {
+            pid : self.pid,
+            name : self.name,
+            ticks : self.ticks,
+            switches : self.switches,
+            syscalls : self.syscalls,
+            pages : self.vspace.private_pages() as u32,
+        }
+    }
+
+    /// User eip saved in the interrupt context at the top of the kernel
+    /// stack, the last time the task entered the kernel. Kernel tasks don't
+    /// have one, their interrupt contexts are anywhere on their stack
+    fn saved_user_ip(&self) -> Option<u32> {
+        if self.kernel || self.released {
+            return None;
+        }
+        let top_page = self.vspace.get_pte(VirtAddr(self.kernel_stack_top - 
+                                                    PAGE_SIZE as u32))?
+            .get_paddr();
+        let top_alias = PhysMem::translate(top_page, PAGE_SIZE).ok()?
+            as usize;
+        let context = top_alias + PAGE_SIZE - size_of::<InterruptContext>();
+        let context = unsafe { &*(context as *const InterruptContext) };
+        Some(context.frame.ip)
+    }
+
+    /// Name of the task for printing, bytes that are not valid utf8 are
+    /// shown as U+FFFD
+    pub fn display_name(&self) -> DisplayName<'_> {
+        let len = self.name.iter().position(|&x| x == 0)
+            .unwrap_or(self.name.len());
+        DisplayName(&self.name[..len])
+    }
+
+    /// Get the name of the task
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&x| x == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("<invalid>")
+    }
+}
+
+/// Task name that prints bytes that are not valid utf8 as U+FFFD. It is
+/// padded to the width of the format, but never truncated
+pub struct DisplayName<'a>(&'a [u8]);
+
+impl core::fmt::Display for DisplayName<'_> {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        use core::fmt::Write;
+
+        let mut rest = self.0;
+        let mut chars = 0;
+        while !rest.is_empty() {
+            let (valid, invalid) = match core::str::from_utf8(rest) {
+                Ok(_) => (rest.len(), 0),
+                Err(err) => (err.valid_up_to(), err.error_len()
+                             .unwrap_or(rest.len() - err.valid_up_to())),
+            };
+            let text = unsafe {
+                core::str::from_utf8_unchecked(&rest[..valid])
+            };
+            f.write_str(text)?;
+            chars += text.chars().count();
+            if invalid != 0 {
+                f.write_char(char::REPLACEMENT_CHARACTER)?;
+                chars += 1;
+            }
+            rest = &rest[valid + invalid..];
+        }
+
+        for _ in chars..f.width().unwrap_or(0) {
+            f.write_char(' ')?;
+        }
+        Ok(())
+    }
+}
 
-              mov dword ptr [edi], esp  // Save task kernel_sp before switching
+/// Get the task currently running
+pub fn current_task() -> &'static mut Task {
+    unsafe {
+        task_slot(CURRENT_TASK_IDX).as_mut().expect("No task is running")
+    }
+}
+
+/// Get the bounds of the kernel stack of the running task, `None` before the
+/// first one is scheduled
+pub fn kernel_stack_bounds() -> Option<(u32, u32)> {
+    running_task().map(|task| {
+        let size = (KERNEL_STACK_SIZE * PAGE_SIZE) as u32;
+        (task.kernel_stack_top - size, task.kernel_stack_top)
+    })
+}
+
+/// Get the task currently running, `None` before the first one is scheduled
+pub fn running_task() -> Option<&'static Task> {
+    unsafe {
+        if CURRENT_TASK_IDX == usize::MAX {
+            return None;
+        }
+        task_slot(CURRENT_TASK_IDX).as_ref()
+    }
+}
+
+/// Get the slot at index `idx` in the task table
+fn task_slot(idx : usize) -> &'static mut Option<Task> {
+    assert!(idx < task_slot_count(), "Task slot {} doesn't exist", idx);
+    unsafe { &mut *task_slot_ptr(idx) }
+}
+
+/// Get the address of the slot at index `idx` in the task table, through
+/// the physical memory window
+fn task_slot_ptr(idx : usize) -> *mut Option<Task> {
+    unsafe {
+        let page = TASK_PAGES[idx / TASKS_PER_PAGE];
+        let slots = PhysMem::translate_mut(page, PAGE_SIZE)
+            .expect("Task slots outside of RAM") as *mut Option<Task>;
+        slots.add(idx % TASKS_PER_PAGE)
+    }
+}
+
+/// Get the number of slots in the task table
+fn task_slot_count() -> usize {
+    unsafe { TASK_PAGE_COUNT * TASKS_PER_PAGE }
+}
+
+/// Iterate over the tasks of the task table
+fn all_tasks() -> impl Iterator<Item = &'static mut Task> {
+    (0..task_slot_count()).filter_map(|idx| task_slot(idx).as_mut())
+}
+
+/// Find an empty slot in the task table, adding a page to the table if
+/// every slot is used. Fails with ENOMEM if the table can't grow
+fn alloc_task_slot() -> Result<usize, i32> {
+    let count = task_slot_count();
+    if let Some(idx) = (0..count).find(|&idx| task_slot(idx).is_none()) {
+        return Ok(idx);
+    }
+
+    unsafe {
+        if TASK_PAGE_COUNT == MAX_TASK_PAGES {
+            return Err(-ENOMEM);
+        }
+        TASK_PAGES[TASK_PAGE_COUNT] = PhysMem::try_alloc_phys()
+            .map_err(|_| -ENOMEM)?;
+        TASK_PAGE_COUNT += 1;
+    }
+
+    // The content of the new page is garbage, so the slots are written
+    // without reading them
+    for idx in count..task_slot_count() {
+        unsafe { core::ptr::write(task_slot_ptr(idx), None); }
+    }
+    Ok(count)
+}
+
+/// Get the task at index `idx` in the task table
+fn task_at(idx : usize) -> &'static mut Task {
+    task_slot(idx).as_mut().expect("No task at this index")
+}
+
+/// Add the ready task `idx` to the ready queue of its priority. The idle
+/// task is never queued, the scheduler picks it when the queues are empty
+fn enqueue(idx : usize) {
+    if idx == unsafe { IDLE_TASK_IDX } {
+        return;
+    }
+    let priority = task_at(idx).priority as usize;
+    unsafe { READY_QUEUES[priority].push_back(idx); }
+}
+
+/// Remove the ready task `idx` from the ready queue of its priority
+fn dequeue(idx : usize) {
+    if idx == unsafe { IDLE_TASK_IDX } {
+        return;
+    }
+    let priority = task_at(idx).priority as usize;
+    unsafe { READY_QUEUES[priority].remove(idx); }
+}
+
+/// Make `task` ready and add it to the ready queue of its priority
+fn make_ready(task : &mut Task) {
+    task.set_state(TaskState::Ready);
+    enqueue(task.idx);
+}
 
-              2:
-              mov cr3, edx   // Switch vspace
+/// Wake up `task` if it is blocked. Returns false if it was not blocked
+pub fn wake_up(task : &mut Task) -> bool {
+    if task.state != TaskState::Blocked {
+        return false;
+    }
+    make_ready(task);
+    true
+}
 
-              mov esp, {}    // Switch kernel stack
+/// Ask the scheduler to wake up the task `pid` the next time it runs, if it
+/// is still blocked then. An interrupt may come while the queues are being
+/// changed, so interrupt handlers use this instead of `wake_up`. Returns
+/// false if too many wake ups are pending already
+pub fn wake_up_deferred(pid : u32) -> bool {
+    unsafe {
+        let pending = &DEFERRED_WAKES[..DEFERRED_WAKE_COUNT];
+        if pending.contains(&pid) {
+            return true;
+        }
+        if DEFERRED_WAKE_COUNT == MAX_DEFERRED_WAKES {
+            return false;
+        }
+        DEFERRED_WAKES[DEFERRED_WAKE_COUNT] = pid;
+        DEFERRED_WAKE_COUNT += 1;
+    }
+    true
+}
 
-              pop eax        // Restore data segment registers
-              mov ds, ax
-              mov es, ax
-              mov gs, ax
-              mov fs, ax
-             ", 
-             in(reg) next.kernel_sp,
-             in("edi") &prev.kernel_sp,
-             in("ecx") prev.vspace.get_pgd_paddr().0,
-             in("edx") next.vspace.get_pgd_paddr().0,
-        );
+/// Wake up the tasks of the wake ups asked by interrupt handlers
+fn run_deferred_wakes() {
+    unsafe {
+        for &pid in &DEFERRED_WAKES[..DEFERRED_WAKE_COUNT] {
+            if let Some(task) = find_task(pid) {
+                wake_up(task);
+            }
+        }
+        DEFERRED_WAKE_COUNT = 0;
     }
 }
 
-/// Find the next task to execute in the `TASKS` array
+/// Block the current task until `wake_up` is called on it
+pub fn block_current() {
+    current_task().set_state(TaskState::Blocked);
+    schedule();
+}
+
+/// Put the current task to sleep for `nticks` timer ticks
+pub fn sleep_current(nticks : u32) {
+    let task = current_task();
+    task.wakeup_tick = ticks() + nticks as u64;
+    task.set_state(TaskState::Sleeping);
+
+    // Keep the sleep queue sorted, tasks with the same deadline wake up in
+    // the order they went to sleep
+    unsafe {
+        let mut before = SLEEP_QUEUE.head;
+        while before != NO_TASK &&
+                task_at(before).wakeup_tick <= task.wakeup_tick {
+            before = task_at(before).queue_next;
+        }
+        SLEEP_QUEUE.insert_before(task.idx, before);
+    }
+    schedule();
+}
+
+/// Make ready the sleeping tasks whose deadline has passed
+fn wake_sleepers() {
+    let now = ticks();
+    unsafe {
+        while let Some(idx) = SLEEP_QUEUE.front() {
+            let task = task_at(idx);
+            if task.wakeup_tick > now {
+                break;
+            }
+            SLEEP_QUEUE.remove(idx);
+            make_ready(task);
+        }
+    }
+}
+
+/// Remove the task `idx` from the task table and from its queue
+fn remove_task(idx : usize) -> Task {
+    match task_at(idx).state {
+        TaskState::Ready => dequeue(idx),
+        TaskState::Sleeping => unsafe { SLEEP_QUEUE.remove(idx) },
+        _ => {},
+    }
+    task_slot(idx).take().unwrap()
+}
+
+/// Get the task identified by `pid`
+pub fn find_task(pid : u32) -> Option<&'static mut Task> {
+    all_tasks().find(|task| task.pid == pid)
+}
+
+/// Make the kernel stack of `next` the one used on entry from userland
+fn set_kernel_stack(next : &Task) {
+    unsafe {
+        // Update the esp0 field of the TSS. The kernel stack of a task is
+        // always empty when it runs in userland, so the next interrupt from
+        // ring 3 must start at the top of it. The same goes for sysenter.
+        // Kernel tasks are never interrupted in ring 3, so this is unused
+        // while they run, and switching back to a user task sets it again
+        TSS.update_esp0(next.kernel_stack_top);
+        set_sysenter_stack(next.kernel_stack_top);
+    }
+}
+
+/// Switch task context from `prev` to `next`. Does nothing if they are the
+/// same task
+pub fn switch_to(prev : &mut Task, next : &Task) {
+    if core::ptr::eq(prev, next) {
+        return;
+    }
+
+    // The FPU is switched lazily, at the first FPU instruction of `next`
+    fpu_switch(next.pid);
+
+    set_kernel_stack(next);
+    set_tls_base(next.tls_base);
+    unsafe {
+        context_switch(&mut prev.kernel_sp, next.kernel_sp,
+                       next.vspace.get_pgd_paddr().0);
+    }
+}
+
+/// Switch to `next` from the boot code, which is never resumed, so nothing
+/// is saved
+fn switch_to_first(next : &Task) -> ! {
+    fpu_switch(next.pid);
+
+    set_kernel_stack(next);
+    set_tls_base(next.tls_base);
+    unsafe { context_restore(next.kernel_sp, next.vspace.get_pgd_paddr().0) }
+}
+
+extern "C" {
+    /// Save the callee-saved registers and the data segments on the current
+    /// stack, store the stack pointer in `prev_sp`, then load `next_cr3` and
+    /// resume the task whose stack pointer is `next_sp`. Returns when the
+    /// saved task is switched to again
+    fn context_switch(prev_sp : *mut u32, next_sp : u32, next_cr3 : u32);
+
+    /// Same as `context_switch`, without saving the current context
+    fn context_restore(next_sp : u32, next_cr3 : u32) -> !;
+}
+
+// Both functions use the cdecl convention, so eax, ecx and edx are free to
+// use. A saved kernel stack holds, from its top, the address to return to,
+// ebp, ebx, esi, edi, the data segment selector and the gs selector.
+// `from_context` builds the same layout for new tasks, returning to
+// `resume_from_intr`. gs is loaded again on every switch, since the base of
+// the TLS segment changes
+#[cfg(not(test))]
+global_asm!(r#"
+.global context_switch
+context_switch:
+    mov eax, [esp + 4]  // prev_sp
+    mov ecx, [esp + 8]  // next_sp
+    mov edx, [esp + 12] // next_cr3
+
+    push ebp
+    push ebx
+    push esi
+    push edi
+    mov ebx, ds         // Save the data segment
+    push ebx
+    mov ebx, gs         // Save the TLS segment
+    push ebx
+    mov [eax], esp      // Save the kernel stack pointer of prev
+    jmp .Lcontext_resume
+
+.global context_restore
+context_restore:
+    mov ecx, [esp + 4]  // next_sp
+    mov edx, [esp + 8]  // next_cr3
+
+.Lcontext_resume:
+    mov cr3, edx        // Switch vspace
+    mov esp, ecx        // Switch kernel stack
+
+    pop ebx             // Restore the TLS segment
+    mov gs, bx
+    pop ebx             // Restore the data segment
+    mov ds, bx
+    mov es, bx
+    mov fs, bx
+    pop edi
+    pop esi
+    pop ebx
+    pop ebp
+    ret                 // Resume next
+"#);
+
+/// Pick the next task to execute from the ready queues
 #[inline(never)]
 pub fn schedule() {
+    assert!(preemptible(), "Scheduling with preemption disabled");
+    clear_need_resched();
+    watchdog_schedule();
+
     unsafe {
-        let prev_task;
-        if CURRENT_TASK_IDX == usize::MAX {
-            prev_task = TASKS[0].as_ref().unwrap();
+        wake_sleepers();
+        run_deferred_wakes();
+
+        // There is no previous task the first time, when we come from the
+        // boot code
+        let prev_idx = if CURRENT_TASK_IDX == usize::MAX {
+            None
         } else {
-            prev_task = TASKS[CURRENT_TASK_IDX].as_ref().unwrap();
+            Some(CURRENT_TASK_IDX)
+        };
+
+        // The previous task gives up the CPU, but stays runnable unless it
+        // went to sleep
+        let prev_task = prev_idx.and_then(|idx| task_slot(idx).as_mut());
+        if let Some(prev_task) = prev_task {
+            if prev_task.state == TaskState::Running {
+                make_ready(prev_task);
+            }
         }
 
-        // Find the next task in the task array
-        loop {
-            CURRENT_TASK_IDX = (CURRENT_TASK_IDX + 1) % MAX_TASKS;
-            if !TASKS[CURRENT_TASK_IDX].is_none() {
-                break;
+        // Take the next task from the ready queues. If every task is
+        // waiting, run the idle task until an interrupt wakes one up
+        let next_idx = match pop_ready_task() {
+            Some(idx) => idx,
+            None => {
+                if all_tasks().next().is_none() {
+                    println!("No task to run");
+                    halt();
+                }
+                let alive = all_tasks().any(|task| {
+                    task.idx != IDLE_TASK_IDX &&
+                        task.idx != REAPER_TASK_IDX &&
+                        task.state != TaskState::Zombie
+                });
+                if !alive {
+                    println!("All tasks exited");
+                    halt();
+                }
+                assert!(IDLE_TASK_IDX != usize::MAX, "No idle task");
+                IDLE_TASK_IDX
+            }
+        };
+        CURRENT_TASK_IDX = next_idx;
+        let next_task = task_at(next_idx);
+        next_task.set_state(TaskState::Running);
+        next_task.last_run = ticks();
+        next_task.slice_ticks = 0;
+
+        if PRINT_STATS {
+            PRINT_STATS = false;
+            print_task_stats();
+        }
+
+        // Nothing to switch if the previous task was picked again
+        if prev_idx == Some(next_idx) {
+            return;
+        }
+        task_at(next_idx).switches += 1;
+
+        let next_task = task_at(next_idx);
+        let prev_task = match prev_idx {
+            Some(idx) => task_at(idx),
+            None => {
+                if SCHED_DEBUG {
+                    println!("schedule : first task {} (pid {})",
+                             next_task.name(), next_task.pid);
+                }
+                switch_to_first(next_task);
             }
+        };
+        if SCHED_DEBUG {
+            println!("schedule : {} (pid {}) -> {} (pid {})", 
+                     prev_task.name(), prev_task.pid,
+                     next_task.name(), next_task.pid);
+        }
+
+        bench_switch_start();
+        switch_to(prev_task, next_task);
+        bench_switch_done();
+    }
+}
+
+/// Free the resources of every exited task, except the current one since we
+/// are still running on its kernel stack. The zombies then only hold their
+/// exit code, and wait for their parent to collect it with `reap_task`.
+/// Those whose parent exited too are removed from the task table
+fn reap_zombies() {
+    for idx in 0..task_slot_count() {
+        if idx == unsafe { CURRENT_TASK_IDX } {
+            continue;
+        }
+        let task = match task_slot(idx).as_mut() {
+            Some(task) if task.state == TaskState::Zombie => task,
+            _ => continue,
+        };
+        task.free_resources();
+
+        let orphan = match find_task(task.parent) {
+            Some(parent) => parent.state == TaskState::Zombie,
+            None => true,
+        };
+        if orphan {
+            remove_task(idx);
+        }
+    }
+}
+
+/// Body of the reaper task, which frees the resources of the exited tasks
+/// each time `exit_current` wakes it up
+fn reaper_task() {
+    loop {
+        disable_interrupts();
+        reap_zombies();
+        block_current();
+        enable_interrupts();
+    }
+}
+
+/// Create the reaper task. Without it, exited tasks are only freed when
+/// their parent collects them
+pub fn spawn_reaper_task() {
+    let pid = Task::new_kernel(b"reaper", reaper_task)
+        .expect("No memory for the reaper task");
+    unsafe { REAPER_TASK_IDX = find_task(pid).unwrap().idx; }
+}
+
+/// Remove the exited task `pid` from the task table and returns its exit
+/// code. Its resources are freed if the reaper didn't do it yet
+pub fn reap_task(pid : u32) -> i32 {
+    let task = find_task(pid).expect("Reaping a task that doesn't exist");
+    assert!(task.state == TaskState::Zombie, "Reaping a running task");
+    let mut task = remove_task(task.idx);
+
+    task.free_resources();
+    task.exit_code
+}
+
+/// Get the number of tasks in the task table, exited ones included
+pub fn task_count() -> usize {
+    all_tasks().count()
+}
+
+/// Create and destroy `count` tasks running `code_addr`, and panic if that
+/// leaks physical memory. The tasks never run, so this must be called before
+/// the first `schedule`
+#[cfg(all(feature = "selftest", not(test)))]
+pub fn check_task_lifecycle(count : usize, code_addr : fn()) {
+    // The pages of the task table are never freed, make room first
+    alloc_task_slot().expect("No room for a task");
+
+    crate::selftest::check_leaks("task lifecycle", || for _ in 0..count {
+        let pid = Task::new(b"lifecycle_task", code_addr)
+            .expect("No memory for a task");
+        let idx = find_task(pid).unwrap().idx;
+        remove_task(idx).free_resources();
+    });
+    println!("{} tasks created and destroyed without leaking memory", count);
+}
+
+/// Remove the ready task with the highest priority from the ready queues.
+/// The first task of a queue is the one that waited the longest, so only it
+/// can have been boosted by aging, and looking at the first task of every
+/// queue is enough. Tasks of the same priority run in turn
+fn pop_ready_task() -> Option<usize> {
+    let now = ticks();
+    let mut best : Option<(usize, u8)> = None;
+    for queue in unsafe { READY_QUEUES.iter().rev() } {
+        let idx = match queue.front() {
+            Some(idx) => idx,
+            None => continue,
+        };
+
+        let priority = task_at(idx).effective_priority(now);
+        match best {
+            Some((_, best_priority)) if best_priority >= priority => {},
+            _ => best = Some((idx, priority)),
         }
+    }
+
+    let (idx, _) = best?;
+    dequeue(idx);
+    Some(idx)
+}
+
+/// Entry point of the kernel tasks, runs `code` then exits the task
+#[cfg(not(test))]
+extern "fastcall" fn kthread_start(code : fn()) -> ! {
+    code();
+    kthread_exit();
+}
+
+/// fastcall only exists on i386, and the host tests start no kernel task
+#[cfg(test)]
+extern "C" fn kthread_start(_code : fn()) -> ! {
+    unreachable!("Kernel task started by a host test");
+}
+
+/// Exit the current kernel task. Its stack is freed by the scheduler
+pub fn kthread_exit() -> ! {
+    disable_interrupts();
+    exit_current(0);
+}
+
+/// Terminate the current task. It becomes a zombie until its parent collects
+/// `exit_code` with `reap_task`, or until the reaper removes it if it has no
+/// parent. The reaper frees its memory once we switched away from it
+pub fn exit_current(exit_code : i32) -> ! {
+    let task = current_task();
+    task.exit_code = exit_code;
+    task.set_state(TaskState::Zombie);
+
+    // Wake up the parent in case it waits for us
+    if let Some(parent) = find_task(task.parent) {
+        wake_up(parent);
+    }
+    if unsafe { REAPER_TASK_IDX } != usize::MAX {
+        wake_up(task_at(unsafe { REAPER_TASK_IDX }));
+    }
+
+    schedule();
+    panic!("Zombie task was scheduled");
+}
+
+/// Put the current kernel task to sleep for `nticks` timer ticks
+pub fn kthread_sleep(nticks : u32) {
+    // Interrupts are enabled in kernel tasks, and the timer interrupt must
+    // not schedule in the middle of this one
+    disable_interrupts();
+    sleep_current(nticks);
+    enable_interrupts();
+}
+
+/// Give up the CPU to the other ready tasks from a kernel task
+pub fn kthread_yield() {
+    disable_interrupts();
+    schedule();
+    enable_interrupts();
+}
+
+/// Body of the idle task, halt until the next interrupt forever
+fn idle_task() {
+    loop {
+        wait_for_interrupt();
+    }
+}
+
+/// Create the idle task, which the scheduler only picks when no other task
+/// is runnable
+pub fn spawn_idle_task() {
+    let pid = Task::new_kernel(b"idle", idle_task)
+        .expect("No memory for the idle task");
+    let task = find_task(pid).unwrap();
+    task.set_priority(0);
+    dequeue(task.idx);
+    unsafe { IDLE_TASK_IDX = task.idx; }
+}
+
+/// Charge a timer tick to the running task. Called from the timer interrupt
+pub fn account_tick() {
+    unsafe {
+        if CURRENT_TASK_IDX == usize::MAX {
+            return;
+        }
+        if let Some(task) = task_slot(CURRENT_TASK_IDX).as_mut() {
+            task.ticks += 1;
+            task.slice_ticks += 1;
+        }
+    }
+}
+
+/// Panic if `addr`, accessed by the kernel with the stack pointer `esp`,
+/// is in the guard area below the kernel stack of the current task
+pub fn check_kernel_stack_overflow(addr : u32, esp : u32) {
+    unsafe {
+        if CURRENT_TASK_IDX == usize::MAX {
+            return;
+        }
+        if let Some(task) = task_slot(CURRENT_TASK_IDX).as_ref() {
+            let guard_end = task.kernel_stack_guard + 
+                (KERNEL_STACK_GUARD_SIZE * PAGE_SIZE) as u32;
+            if addr >= task.kernel_stack_guard && addr < guard_end {
+                panic!("kernel stack overflow in task {} (esp {:#x})",
+                       task.name(), esp);
+            }
+        }
+    }
+}
+
+/// Returns true if the running task is a user task
+pub fn user_task_running() -> bool {
+    unsafe {
+        if CURRENT_TASK_IDX == usize::MAX {
+            return false;
+        }
+        task_slot(CURRENT_TASK_IDX).as_ref().is_some_and(|task| !task.kernel)
+    }
+}
+
+/// Returns true if the running task used its time slice and must be
+/// preempted. The idle task is always preempted, so that tasks woken up by
+/// the timer run right away
+pub fn quantum_expired() -> bool {
+    unsafe {
+        if CURRENT_TASK_IDX == usize::MAX {
+            return false;
+        }
+        if CURRENT_TASK_IDX == IDLE_TASK_IDX {
+            return true;
+        }
+        match task_slot(CURRENT_TASK_IDX).as_ref() {
+            Some(task) => task.slice_ticks >= QUANTUM_TICKS,
+            None => true,
+        }
+    }
+}
+
+/// Call `f` with the stats of every task
+pub fn for_each_task_stats<F : FnMut(TaskStats)>(mut f : F) {
+    for task in all_tasks() {
+        f(task.stats());
+    }
+}
+
+/// Call `f` with the pid, the name and the address space of every task
+pub fn for_each_task_vspace<F : FnMut(u32, &str, &VirtMem)>(mut f : F) {
+    for task in all_tasks() {
+        f(task.pid, task.name(), &task.vspace);
+    }
+}
+
+/// Free the user stack, heap and anonymous mappings of the address space of
+/// a user task, drop the references of its shared pages, then free its page
+/// directory, page tables and allocator bitmap
+pub fn free_user_vspace(vspace : VirtMem) {
+    vspace.free_private_pages();
+    shm_put_vspace(&vspace);
+    vspace.destroy();
+}
+
+/// Print the mappings of `[start, end)` in the address space of the task
+/// `pid`. Returns false if there is no such task
+pub fn dump_task_vspace(pid : u32, start : VirtAddr, end : VirtAddr) -> bool {
+    match find_task(pid) {
+        Some(task) => {
+            println!("address space of {} ({}) :", pid, task.name());
+            task.vspace.dump(start, end);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Print the stats of every task at the next schedule
+pub fn request_task_stats() {
+    unsafe { PRINT_STATS = true; }
+}
+
+/// Print the table of tasks, with the user eip saved when user tasks last
+/// entered the kernel. Can be called at any time, or raised from any ring
+/// with `int TASK_DUMP_VECTOR`
+pub fn dump_tasks() {
+    println!("{:>4} {:<16} {:<8} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:>10}",
+             "pid", "name", "state", "kernel_sp", "user_sp", "pgd", "ticks",
+             "switches", "syscalls", "eip");
+    for task in all_tasks() {
+        print!("{:>4} {:<16} {:<8} {:>#10x} {:>#10x} {:>#10x} {:>8} {:>8} \
+                {:>8} ", task.pid, task.display_name(), task.state.name(),
+               task.kernel_sp, task.user_sp, task.vspace.get_pgd_paddr().0,
+               task.ticks, task.switches, task.syscalls);
+        match task.saved_user_ip() {
+            Some(ip) => println!("{:>#10x}", ip),
+            None => println!("{:>10}", "-"),
+        }
+    }
+}
 
-        switch_to(prev_task, TASKS[CURRENT_TASK_IDX].as_ref().unwrap());
+/// Print the CPU usage of every task
+fn print_task_stats() {
+    println!("{:>4} {:<16} {:>8} {:>8} {:>8}",
+             "pid", "name", "ticks", "switches", "syscalls");
+    for task in all_tasks() {
+        println!("{:>4} {:<16} {:>8} {:>8} {:>8}", task.pid, task.name(),
+                 task.ticks, task.switches, task.syscalls);
     }
 }
 
@@ -0,0 +1,79 @@
+//! Time stamp counter, calibrated against channel 2 of the PIT at boot, for
+//! timing finer than the ticks. Its frequency is given to userland by
+//! `SYS_UNAME` and the info page, to convert the cycles of benchmarks
+
+use crate::cpu::rdtsc;
+use crate::cpuid::cpu_features;
+use crate::pit::delay_us;
+use crate::{print, println, PERIPHERALS};
+
+/// Length in microseconds of a calibration run
+const CALIBRATION_US : u32 = 10_000;
+
+/// Number of runs of a calibration, the median gives the frequency
+const CALIBRATION_RUNS : usize = 5;
+
+/// Max number of calibrations until their runs agree
+const CALIBRATION_TRIES : usize = 4;
+
+/// Max spread of the runs of a calibration, in thousandths of the median
+const CALIBRATION_SPREAD : u64 = 10;
+
+/// Cycles of the TSC per millisecond, 0 before `calibrate_tsc`
+static mut TSC_KHZ : u64 = 0;
+
+/// Value of the TSC when it was calibrated
+static mut BOOT_TSC : u64 = 0;
+
+/// Measure the frequency of the TSC with runs of `CALIBRATION_US` on
+/// channel 2 of the PIT, which doesn't need interrupts. Runs interrupted by
+/// something like an SMI are longer, so if they spread too much the runs
+/// start again. Must be called with interrupts disabled. Panics if the CPU
+/// has no TSC, the kernel times with it
+pub fn calibrate_tsc() {
+    assert!(cpu_features().tsc,
+            "The kernel needs a CPU with a time stamp counter");
+
+    let mut khz = 0;
+    for _ in 0..CALIBRATION_TRIES {
+        let mut runs = [0u64; CALIBRATION_RUNS];
+        for run in runs.iter_mut() {
+            let start = rdtsc();
+            delay_us(CALIBRATION_US);
+            *run = (rdtsc() - start) * 1000 / CALIBRATION_US as u64;
+        }
+        runs.sort_unstable();
+        khz = runs[CALIBRATION_RUNS / 2];
+
+        let (min, max) = (runs[0], runs[CALIBRATION_RUNS - 1]);
+        if (max - min) * 1000 <= khz * CALIBRATION_SPREAD {
+            break;
+        }
+        println!("tsc : inconsistent runs from {} to {} kHz", min, max);
+    }
+
+    unsafe {
+        TSC_KHZ = core::cmp::max(khz, 1);
+        BOOT_TSC = rdtsc();
+    }
+    println!("tsc : {}.{:03} MHz", khz / 1000, khz % 1000);
+}
+
+/// Get the frequency of the TSC in kHz. Panics if it is not calibrated yet
+pub fn tsc_khz() -> u64 {
+    let khz = unsafe { TSC_KHZ };
+    assert!(khz != 0, "TSC frequency read before calibrate_tsc");
+    khz
+}
+
+/// Convert `cycles` of the TSC to nanoseconds
+pub fn cycles_to_ns(cycles : u64) -> u64 {
+    // In two parts, so that the product doesn't overflow after hours
+    let khz = tsc_khz();
+    cycles / khz * 1_000_000 + cycles % khz * 1_000_000 / khz
+}
+
+/// Get the nanoseconds elapsed since the TSC was calibrated, at boot
+pub fn ns_since_boot() -> u64 {
+    cycles_to_ns(rdtsc() - unsafe { BOOT_TSC })
+}
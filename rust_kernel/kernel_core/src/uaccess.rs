@@ -0,0 +1,197 @@
+//! Helpers to access userland memory from syscalls. Pointers given by
+//! userland must never be dereferenced directly, they could point to kernel
+//! memory or to memory that is not mapped at all
+
+use crate::paging::pagemem::*;
+use crate::paging::physmem::PhysMem;
+use crate::paging::virtmem::*;
+use crate::syscalls::EFAULT;
+use crate::tasks::current_task;
+
+/// Check that every page touched by the `len` bytes at `addr` is present and
+/// user accessible in the current address space, and also writable if
+/// `write` is set. Fails with -EFAULT otherwise. Missing pages of the user
+/// stack and lazy pages are mapped, and copy-on-write pages are copied
+/// before a write, as a page fault would. With CR0.WP set, a kernel write
+/// to a read-only user page faults, so writes must be checked here first
+pub fn check_user_range(addr : u32, len : usize, write : bool)
+        -> Result<(), i32> {
+    if len == 0 {
+        return Ok(());
+    }
+    let last = addr.checked_add(len as u32 - 1).ok_or(-EFAULT)?;
+
+    // The recursive mapping shows the page directory entries, which are
+    // user accessible, as page table entries
+    if in_paging_area(VirtAddr(last)) {
+        return Err(-EFAULT);
+    }
+
+    let mut flags = PAGE_PRESENT | PAGE_USER;
+    if write {
+        flags |= PAGE_WRITE;
+    }
+
+    // Page directory entries are always created with PAGE_USER and
+    // PAGE_WRITE, so the page table entries are enough to know the access
+    // rights of a page
+    let vspace = VirtMem::get_current();
+    for page in ((addr & !0xfff)..=last).step_by(PAGE_SIZE) {
+        vspace.fill_lazy(VirtAddr(page), write);
+        match vspace.get_pte(VirtAddr(page)) {
+            Some(pte) if pte.0 & flags == flags => {},
+            Some(_) if write && vspace.break_cow(VirtAddr(page)) => {},
+            Some(pte) if pte.0 & PAGE_PRESENT != 0 => return Err(-EFAULT),
+            _ if current_task().grow_stack(page) => {},
+            _ => return Err(-EFAULT),
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes from the userland address `src` into `dst`
+pub fn copy_from_user(dst : &mut [u8], src : u32) -> Result<(), i32> {
+    check_user_range(src, dst.len(), false)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(),
+                                       dst.len());
+    }
+    Ok(())
+}
+
+/// Copy `src` to the userland address `dst`
+pub fn copy_to_user(dst : u32, src : &[u8]) -> Result<(), i32> {
+    check_user_range(dst, src.len(), true)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8,
+                                       src.len());
+    }
+    Ok(())
+}
+
+/// Split the `len` bytes at the userland address `addr` at page boundaries.
+/// Yields the address of each piece, its offset in the bytes and its size
+pub fn page_chunks(addr : u32, len : usize)
+        -> impl Iterator<Item = (u32, usize, usize)> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        if offset == len {
+            return None;
+        }
+        let vaddr = addr.wrapping_add(offset as u32);
+        let size = core::cmp::min(PAGE_SIZE - (vaddr & 0xfff) as usize,
+                                  len - offset);
+        let chunk = (vaddr, offset, size);
+        offset += size;
+        Some(chunk)
+    })
+}
+
+/// Get the physical page holding the userland address `vaddr` in `vspace`,
+/// which doesn't have to be the one in use. Fails with -EFAULT if the page
+/// is missing, not user accessible, or not writable while `write` is set.
+/// Lazy and copy-on-write pages are handled like in `check_user_range`
+fn user_page(vspace : &VirtMem, vaddr : u32, write : bool)
+        -> Result<PhysAddr, i32> {
+    let page = VirtAddr(vaddr & !0xfff);
+    if in_paging_area(page) {
+        return Err(-EFAULT);
+    }
+    vspace.fill_lazy(page, write);
+    if write {
+        vspace.break_cow(page);
+    }
+
+    let mut flags = PAGE_PRESENT | PAGE_USER;
+    if write {
+        flags |= PAGE_WRITE;
+    }
+    match vspace.translate(page) {
+        Some(Mapping { page : Some(paddr), flags : pte, .. })
+            if pte & flags == flags => Ok(paddr),
+        _ => Err(-EFAULT),
+    }
+}
+
+/// Check the `len` bytes at `addr` in `vspace` like `check_user_range`.
+/// Missing stack pages are not grown
+fn check_vspace_range(vspace : &VirtMem, addr : u32, len : usize,
+                      write : bool) -> Result<(), i32> {
+    if len != 0 && addr.checked_add(len as u32 - 1).is_none() {
+        return Err(-EFAULT);
+    }
+    for (vaddr, _, _) in page_chunks(addr, len) {
+        user_page(vspace, vaddr, write)?;
+    }
+    Ok(())
+}
+
+/// Copy `src` to the userland address `dst` of the address space `vspace`,
+/// through the physical window. Nothing is copied if a page fails like in
+/// `check_user_range`
+pub fn copy_to_vspace(vspace : &VirtMem, dst : u32, src : &[u8])
+        -> Result<(), i32> {
+    check_vspace_range(vspace, dst, src.len(), true)?;
+    for (vaddr, offset, size) in page_chunks(dst, src.len()) {
+        let page = user_page(vspace, vaddr, true)?;
+        let paddr = PhysAddr(page.0 | (vaddr & 0xfff));
+        let alias = PhysMem::translate_mut(paddr, size)
+            .map_err(|_| -EFAULT)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(src[offset..].as_ptr(), alias,
+                                           size);
+        }
+    }
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes from the userland address `src` of the address
+/// space `vspace` into `dst`, like `copy_to_vspace`
+pub fn copy_from_vspace(dst : &mut [u8], vspace : &VirtMem, src : u32)
+        -> Result<(), i32> {
+    check_vspace_range(vspace, src, dst.len(), false)?;
+    for (vaddr, offset, size) in page_chunks(src, dst.len()) {
+        let page = user_page(vspace, vaddr, false)?;
+        let paddr = PhysAddr(page.0 | (vaddr & 0xfff));
+        let alias = PhysMem::translate(paddr, size).map_err(|_| -EFAULT)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(alias, dst[offset..].as_mut_ptr(),
+                                           size);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(addr : u32, len : usize) -> Vec<(u32, usize, usize)> {
+        page_chunks(addr, len).collect()
+    }
+
+    #[test]
+    fn page_chunks_empty() {
+        assert_eq!(chunks(0x1234, 0), []);
+    }
+
+    #[test]
+    fn page_chunks_in_one_page() {
+        assert_eq!(chunks(0x1000, PAGE_SIZE), [(0x1000, 0, PAGE_SIZE)]);
+        assert_eq!(chunks(0x1ff0, 0x10), [(0x1ff0, 0, 0x10)]);
+    }
+
+    #[test]
+    fn page_chunks_straddling() {
+        assert_eq!(chunks(0x1ffc, 8), [(0x1ffc, 0, 4), (0x2000, 4, 4)]);
+        assert_eq!(chunks(0x1800, 2 * PAGE_SIZE),
+                   [(0x1800, 0, 0x800), (0x2000, 0x800, PAGE_SIZE),
+                    (0x3000, 0x1800, 0x800)]);
+    }
+
+    #[test]
+    fn page_chunks_wrap_around() {
+        assert_eq!(chunks(0xffff_fffe, 4), [(0xffff_fffe, 0, 2), (0, 2, 2)]);
+    }
+}
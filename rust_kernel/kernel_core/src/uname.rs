@@ -0,0 +1,110 @@
+//! Information about the kernel given to userland by `SYS_UNAME`. The
+//! userland tasks use the definitions of this module too, so both sides
+//! always agree on the layout of `Utsname`
+
+use crate::pit::frequency;
+use crate::sysenter::sysenter_enabled;
+use crate::syscalls::*;
+use crate::time::tsc_khz;
+use crate::uaccess::*;
+use crate::vsys::*;
+
+/// The kernel handles syscalls made with sysenter
+pub const UNAME_SYSENTER : u32 = 1 << 0;
+
+/// The kernel has shared memory objects
+pub const UNAME_SHM : u32 = 1 << 1;
+
+/// Features that come with the kernel configuration, the other ones depend
+/// on the CPU
+const BUILTIN_FEATURES : u32 = UNAME_SHM;
+
+/// Name of the kernel
+const SYSNAME : &str = "secos";
+
+/// Identifier of the build, given by the build script
+const BUILD_ID : &str = match option_env!("SECOS_BUILD_ID") {
+    Some(id) if !id.is_empty() => id,
+    _ => "unknown",
+};
+
+/// Information about the kernel returned by `SYS_UNAME`. Strings are nul
+/// terminated
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Utsname {
+    /// Name of the kernel
+    pub sysname : [u8; 16],
+
+    /// Version of the kernel
+    pub release : [u8; 16],
+
+    /// Identifier of the build of the kernel
+    pub build_id : [u8; 32],
+
+    /// `UNAME_*` features supported by the kernel
+    pub features : u32,
+
+    /// Frequency of the timer ticks in Hz
+    pub tick_frequency : u32,
+
+    /// Frequency of the TSC in kHz, its cycles per millisecond
+    pub tsc_khz : u32,
+}
+
+impl Utsname {
+    /// Create an empty `Utsname` for userland to pass to `SYS_UNAME`
+    pub const fn empty() -> Self {
+        Self {
+            sysname : [0; 16],
+            release : [0; 16],
+            build_id : [0; 32],
+            features : 0,
+            tick_frequency : 0,
+            tsc_khz : 0,
+        }
+    }
+}
+
+/// Detect the features of the kernel, publish them in the info page and
+/// register the uname syscall. Must be called after the features are
+/// configured, like sysenter
+pub fn uname_init() {
+    let mut features = BUILTIN_FEATURES;
+    if sysenter_enabled() {
+        features |= UNAME_SYSENTER;
+    }
+    vsys_set_features(features);
+
+    register_syscall(SYS_UNAME, "uname", &[SyscallArg::Addr], |ctx| {
+        sys_uname(ctx.regs.ecx)
+    });
+}
+
+/// Copy `src` to `dst` as a nul terminated string, truncating it if needed
+fn copy_str(dst : &mut [u8], src : &str) {
+    let len = core::cmp::min(src.len(), dst.len() - 1);
+    dst[..len].copy_from_slice(&src.as_bytes()[..len]);
+    dst[len..].fill(0);
+}
+
+/// Fill the `Utsname` at `buf` with information about the kernel. Fails with
+/// EFAULT if the buffer is not writable
+fn sys_uname(buf : u32) -> i32 {
+    let mut uts = Utsname::empty();
+    copy_str(&mut uts.sysname, SYSNAME);
+    copy_str(&mut uts.release, env!("CARGO_PKG_VERSION"));
+    copy_str(&mut uts.build_id, BUILD_ID);
+    uts.features = vsys_features();
+    uts.tick_frequency = frequency();
+    uts.tsc_khz = tsc_khz() as u32;
+
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&uts as *const Utsname as *const u8,
+                                    core::mem::size_of::<Utsname>())
+    };
+    match copy_to_user(buf, bytes) {
+        Ok(()) => 0,
+        Err(err) => err,
+    }
+}
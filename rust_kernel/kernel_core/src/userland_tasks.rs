@@ -1,79 +1,1496 @@
 use core::arch::asm;
+use crate::pit::TIMER_FREQUENCY;
+use crate::syscalls::*;
+use crate::uname::*;
+use crate::vsys::VsysInfo;
+use crate::paging::{USER_SPACE_BASE, VSYS_PAGE_ADDR};
+use crate::tasks::{TaskStats, MAX_PRIORITY};
+use crate::bench::*;
+use crate::meminfo::MemInfo;
+use crate::interrupts::TASK_DUMP_VECTOR;
+use crate::rtc::Timeval;
+
+/// Place a string literal in the .user_task section. Plain literals end up
+/// in the kernel .rodata, which is not accessible from userland, so the
+/// kernel refuses to read them when they are passed to a syscall
+macro_rules! ustr {
+    ($s:literal) => {{
+        #[link_section=".user_task"]
+        static STR : [u8; $s.len()] = str_to_array($s);
+        unsafe { core::str::from_utf8_unchecked(&STR) }
+    }};
+}
+
+/// Convert `s` to an array at compile time for `ustr!`
+const fn str_to_array<const N : usize>(s : &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    let mut array = [0u8; N];
+    let mut i = 0;
+    while i < N {
+        array[i] = bytes[i];
+        i += 1;
+    }
+    array
+}
 
 #[no_mangle]
 #[link_section=".user_task"]
 pub fn task1() {
-    mmap_shared(0x1000_0000, 0);
-    print("hello from userland task1!\n");
+    print(ustr!("hello from userland task1! pid : "));
+    print_number(getpid() as u32);
+
+    // Run a worker and wait for its result, tracing the syscalls it takes
+    trace(true);
+    let worker = fork();
+    if worker == 0 {
+        let mut sum : i32 = 0;
+        for i in 1..=10 {
+            sum += i;
+        }
+        exit(sum);
+    }
+    let code = waitpid(worker as u32);
+    trace(false);
+    print(ustr!("task 1 : worker exited with code "));
+    print_number(code as u32);
+
+    // The consumer is our child, so we know where to send the messages
+    let consumer = fork();
+    if consumer == 0 {
+        task2();
+    }
+
     let mut ctr : u32 = 0;
     loop {
         ctr += 1;
-        unsafe { 
-            core::ptr::write_volatile(0x1000_0000 as *mut u32, ctr); 
+        let msg = ctr.to_le_bytes();
+        while send(consumer as u32, msg.as_ptr(), msg.len()) == -EAGAIN {
+            sched_yield();
         }
-        //print("task 1 : ");
-        //print_number(tmp);
+        sleep(TIMER_FREQUENCY);
     }
 }
 
 #[no_mangle]
 #[link_section=".user_task"]
-pub fn task2() {
-    mmap_shared(0x2000_0000, 0);
-    print("hello from userland task2!\n");
-    let mut num : u32 = 0;
+pub fn task2() -> ! {
+    print(ustr!("hello from userland task2! pid : "));
+    print_number(getpid() as u32);
     loop {
-        let tmp : u32 = unsafe {
-            core::ptr::read_volatile(0x2000_0000 as *const u32)
+        let mut msg = [0u8; 4];
+        let sender = recv(msg.as_mut_ptr(), msg.len());
+        if sender < 0 {
+            continue;
+        }
+        print(ustr!("task 2 : got "));
+        print_number(u32::from_le_bytes(msg));
+        print(ustr!("task 2 : from pid "));
+        print_number(sender as u32);
+    }
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task3() {
+    const HEAP_SIZE : usize = 3 * 4096;
+
+    let heap = sbrk(HEAP_SIZE as i32);
+    if heap < 0 {
+        print(ustr!("task 3 : sbrk failed\n"));
+        exit(1);
+    }
+
+    // Write a pattern through the whole allocation and check it back
+    for i in 0..HEAP_SIZE / 4 {
+        unsafe {
+            core::ptr::write_volatile((heap as *mut u32).add(i), 
+                                      0xcafe_0000 | i as u32);
+        }
+    }
+    let mut errors : u32 = 0;
+    for i in 0..HEAP_SIZE / 4 {
+        let val = unsafe {
+            core::ptr::read_volatile((heap as *const u32).add(i))
         };
-        if tmp != num {
-            num = tmp;
-            print("task 2 : ");
-            print_number(num);
+        if val != 0xcafe_0000 | i as u32 {
+            errors += 1;
         }
     }
+    print(ustr!("task 3 : heap pattern errors : "));
+    print_number(errors);
+
+    // Give the memory back
+    sbrk(-(HEAP_SIZE as i32));
+    print(ustr!("task 3 : heap released\n"));
+    exit(0);
 }
 
 #[no_mangle]
 #[link_section=".user_task"]
-#[inline(never)]
-fn print(data : &str) {
-    write(data.as_ptr(), data.len());
+pub fn task4() {
+    print(ustr!("hello from userland task4!\n"));
+    let mut ctr : u32 = 0;
+    loop {
+        ctr += 1;
+        if ctr.is_multiple_of(1000) {
+            print(ustr!("task 4 : yielded "));
+            print_number(ctr);
+        }
+        sched_yield();
+    }
 }
 
 #[no_mangle]
 #[link_section=".user_task"]
-#[inline(never)]
-fn print_number(num : u32) {
-    unsafe {
-        asm!("mov eax, 3
-              int 0x80",
-              in("ecx") num);
+pub fn task5() {
+    let mut seconds : u32 = 0;
+    loop {
+        sleep(TIMER_FREQUENCY);
+        seconds += 1;
+        print(ustr!("task 5 : seconds elapsed : "));
+        print_number(seconds);
     }
 }
 
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task6() {
+    print(ustr!("task 6 : pid "));
+    print_number(getpid() as u32);
+    print(ustr!("task 6 : exiting\n"));
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task7() {
+    // Put a value on the heap before forking, the child gets its own copy
+    let value = sbrk(4096) as *mut u32;
+    unsafe { core::ptr::write_volatile(value, 1); }
+
+    let pid = fork();
+    if pid == 0 {
+        unsafe { core::ptr::write_volatile(value, 2); }
+        print(ustr!("task 7 child : pid "));
+        print_number(getpid() as u32);
+        print(ustr!("task 7 child : value "));
+        print_number(unsafe { core::ptr::read_volatile(value) });
+        exit(0);
+    }
+
+    print(ustr!("task 7 parent : forked child "));
+    print_number(pid as u32);
+    sleep(TIMER_FREQUENCY);
+    print(ustr!("task 7 parent : value "));
+    print_number(unsafe { core::ptr::read_volatile(value) });
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task8() {
+    // Kernel image, not accessible from userland
+    check_efault(ustr!("write kernel memory"),
+                 write(0x30_0000 as *const u8, 16));
+
+    // Nothing is mapped there
+    check_efault(ustr!("write unmapped memory"),
+                 write(0x5000_0000 as *const u8, 16));
+
+    // The end of the buffer wraps around the address space
+    check_efault(ustr!("write wrapping buffer"),
+                 write(0xffff_fff0 as *const u8, 0x20));
+
+    // Huge lengths are cut to what the kernel writes in one call. The
+    // mapping is zeroed, so nothing shows on the console
+    let buf = mmap(0, 2 * MAX_WRITE_SIZE, PROT_READ);
+    if buf > 0 {
+        let written = write(buf as *const u8, 0x7fff_ffff);
+        print(ustr!("task 8 : huge write wrote "));
+        print_number(written as u32);
+        munmap(buf as u32, 2 * MAX_WRITE_SIZE);
+    }
+
+    // Invalid utf8 is printed as replacement characters, including a
+    // sequence cut by the end of the buffer
+    let garbage = [b'a', 0xff, 0xfe, b'b', 0xc3, b'c', 0xe2, 0x82];
+    print(ustr!("task 8 : garbage : "));
+    write(garbage.as_ptr(), garbage.len());
+    print(ustr!("\n"));
+
+    print(ustr!("task 8 : still alive\n"));
+    exit(0);
+}
+
+/// Report whether a syscall made by `task8` failed with EFAULT
 #[no_mangle]
 #[link_section=".user_task"]
 #[inline(never)]
-fn write(addr : *const u8, len : usize) {
+fn check_efault(what : &str, ret : i32) {
+    print(ustr!("task 8 : "));
+    print(what);
+    if ret == -EFAULT {
+        print(ustr!(" -> EFAULT\n"));
+    } else {
+        print(ustr!(" -> NOT REFUSED\n"));
+    }
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task9() {
+    // Let the kernel pick the address of a writable mapping
+    let addr = mmap(0, 2 * 4096, PROT_READ | PROT_WRITE);
+    if addr < 0 {
+        print(ustr!("task 9 : mmap failed\n"));
+        exit(0);
+    }
+    print(ustr!("task 9 : mapped 2 pages at "));
+    print_number(addr as u32);
+
+    let mem = addr as *mut u32;
     unsafe {
-        asm!("mov eax, 2
-              int 0x80",
-              in("ecx") addr,
-              in("edx") len);
+        core::ptr::write_volatile(mem, 0x1234);
+        core::ptr::write_volatile(mem.add(4096 / 4), 0x5678);
+    }
+    print(ustr!("task 9 : read back "));
+    print_number(unsafe { core::ptr::read_volatile(mem.add(4096 / 4)) });
+
+    // Mapping over an existing mapping or kernel memory must fail
+    if mmap(addr as u32, 4096, PROT_READ) == -EINVAL {
+        print(ustr!("task 9 : overlapping mmap refused\n"));
+    }
+    if mmap(0x30_0000, 4096, PROT_READ) == -EINVAL {
+        print(ustr!("task 9 : kernel mmap refused\n"));
+    }
+
+    // Read-only mapping at a fixed address
+    let ro = mmap(0x3000_0000, 4096, PROT_READ);
+    print(ustr!("task 9 : read-only page at "));
+    print_number(ro as u32);
+    print(ustr!("task 9 : read-only page contains "));
+    print_number(unsafe { core::ptr::read_volatile(ro as *const u32) });
+
+    munmap(addr as u32, 2 * 4096);
+    munmap(ro as u32, 4096);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task10() {
+    let addr = mmap(0, 4096, PROT_READ | PROT_WRITE);
+    let page = addr as *mut u32;
+    unsafe { core::ptr::write_volatile(page, 42); }
+
+    if mprotect(addr as u32, 4096, PROT_READ) != 0 {
+        user_panic(ustr!("task 10 : mprotect failed"));
+    }
+    print(ustr!("task 10 : page is read-only, value "));
+    print_number(unsafe { core::ptr::read_volatile(page) });
+
+    // The kernel must report a page fault at exactly this address, and kill
+    // the child that does the write
+    let pid = fork();
+    if pid == 0 {
+        print(ustr!("task 10 : writing to read-only page at "));
+        print_number(addr as u32 + 8);
+        unsafe { core::ptr::write_volatile(page.add(2), 43); }
+
+        print(ustr!("task 10 : write to read-only page succeeded\n"));
+        exit(0);
+    }
+    print(ustr!("task 10 : child exit code (expected 142) "));
+    print_number(waitpid(pid as u32) as u32);
+
+    // Same with an invalid opcode
+    let pid = fork();
+    if pid == 0 {
+        unsafe { asm!("ud2"); }
+        exit(0);
+    }
+    print(ustr!("task 10 : child exit code (expected 134) "));
+    print_number(waitpid(pid as u32) as u32);
+
+    // A null pointer dereference, in asm since the compiler assumes it
+    // never happens. The first page is only mapped for the kernel
+    let pid = fork();
+    if pid == 0 {
+        unsafe { asm!("mov {0}, dword ptr [{0}]", inout(reg) 0 => _); }
+        exit(0);
+    }
+    print(ustr!("task 10 : child exit code (expected 142) "));
+    print_number(waitpid(pid as u32) as u32);
+
+    // And a division by zero
+    let pid = fork();
+    if pid == 0 {
+        unsafe {
+            asm!("div ecx", inout("eax") 1 => _, inout("edx") 0 => _,
+                 in("ecx") 0);
+        }
+        exit(0);
+    }
+    print(ustr!("task 10 : child exit code (expected 128) "));
+    print_number(waitpid(pid as u32) as u32);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task11() {
+    let handle = shm_create(1) as u32;
+    let addr = shm_attach(handle, 0, true);
+    if addr < 0 {
+        print(ustr!("task 11 : couldn't create shared memory\n"));
+        exit(0);
+    }
+    let shared = addr as *mut u32;
+
+    // The shared page holds one value at a time. `empty` counts the free
+    // slots and `full` the values waiting to be consumed
+    let empty = sem_create(1) as u32;
+    let full = sem_create(0) as u32;
+
+    // The child inherits the mapping and the handles and consumes the values
+    let consumer = fork();
+    if consumer == 0 {
+        for _ in 0..5 {
+            sem_wait(full);
+            print(ustr!("task 11 : consumed "));
+            print_number(unsafe { core::ptr::read_volatile(shared) });
+            sem_post(empty);
+        }
+        exit(0);
+    }
+
+    for value in 1..=5 {
+        sem_wait(empty);
+        unsafe { core::ptr::write_volatile(shared, value * 0x1337); }
+        sem_post(full);
+    }
+    waitpid(consumer as u32);
+
+    // A semaphore handle is not a shm handle
+    if shm_attach(empty, 0, true) == -EBADF {
+        print(ustr!("task 11 : semaphore handle refused by shm\n"));
+    }
+    close(empty);
+    close(full);
+
+    // This drops the last references, so the object is freed
+    shm_detach(handle, addr as u32);
+    close(handle);
+    if shm_attach(handle, 0, true) == -EBADF {
+        print(ustr!("task 11 : handle closed\n"));
+    }
+
+    // Handles can't be guessed, this one was never created by this task
+    if shm_attach(handle + 1, 0, true) == -EBADF {
+        print(ustr!("task 11 : foreign handle refused\n"));
+    }
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task12() {
+    let mut uts = Utsname::empty();
+    uname(&mut uts);
+    print(ustr!("task 12 : running on "));
+    print_cstr(&uts.sysname);
+    print(ustr!(" "));
+    print_cstr(&uts.release);
+    print(ustr!(" build "));
+    print_cstr(&uts.build_id);
+    if uts.features & UNAME_SYSENTER != 0 {
+        print(ustr!(", syscalls use sysenter\n"));
+    } else {
+        print(ustr!(", syscalls use int 0x80\n"));
+    }
+    if uts.features & UNAME_SHM != 0 {
+        print(ustr!("task 12 : shared memory is available\n"));
+    }
+    print(ustr!("task 12 : ticks per second : "));
+    print_number(uts.tick_frequency);
+    print(ustr!("task 12 : TSC kHz : "));
+    print_number(uts.tsc_khz);
+
+    loop {
+        // Start counting on a tick boundary
+        let start = getticks();
+        while getticks() == start {}
+
+        let mut iterations : u32 = 0;
+        let tick = start + 1;
+        while getticks() <= tick {
+            iterations += 1;
+        }
+
+        print(ustr!("task 12 : loop iterations per tick : "));
+        print_number(iterations);
+        print_own_stats();
+        sleep(5 * TIMER_FREQUENCY);
+    }
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task13() {
+    let handle = shm_create(1) as u32;
+    let addr = shm_attach(handle, 0, true);
+    if addr < 0 {
+        print(ustr!("task 13 : couldn't create shared memory\n"));
+        exit(0);
+    }
+
+    // The value at the start of the page says whose turn it is, 0 for the
+    // parent and 1 for the child
+    let pong = fork();
+    if pong == 0 {
+        // Map the page a second time, the futex is found through the
+        // physical address so it doesn't matter where we wait on it
+        let addr = shm_attach(handle, 0, true);
+        let turn = addr as *mut u32;
+        for _ in 0..3 {
+            futex_wait_for(turn, 1);
+            print(ustr!("task 13 : pong\n"));
+            unsafe { core::ptr::write_volatile(turn, 0); }
+            futex_wake(turn, 1);
+        }
+        exit(0);
+    }
+
+    let turn = addr as *mut u32;
+    for _ in 0..3 {
+        futex_wait_for(turn, 0);
+        print(ustr!("task 13 : ping\n"));
+        unsafe { core::ptr::write_volatile(turn, 1); }
+        futex_wake(turn, 1);
+    }
+    waitpid(pong as u32);
+
+    shm_detach(handle, addr as u32);
+    close(handle);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task14() {
+    // Two spinners, the one with the highest priority should get most of the
+    // CPU. Aging still gives some of it to the low priority one and to the
+    // other tasks
+    let low = fork();
+    let priority = if low == 0 { 1 } else { MAX_PRIORITY as u32 - 1 };
+    setpriority(priority);
+
+    let end = getticks() + 3 * TIMER_FREQUENCY as u64;
+    while getticks() < end {}
+
+    if low == 0 {
+        print(ustr!("task 14 : low priority spinner ticks : "));
+        print_number(own_stats().ticks);
+        exit(0);
     }
+    print(ustr!("task 14 : high priority spinner ticks : "));
+    print_number(own_stats().ticks);
+    // The kernel prints the task table, with the stats of both spinners
+    unsafe { asm!("int {}", const TASK_DUMP_VECTOR); }
+    waitpid(low as u32);
+    exit(0);
 }
 
-/// Wrapper to use the mmap_shared syscall
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task15() {
+    // Fork and collect 100 children that grow their heap, map anonymous
+    // memory and attach a shared page before exiting. Their memory is freed
+    // when they are collected, so this doesn't run out of memory
+    let handle = shm_create(1);
+    let mut done = 0;
+    while done < 100 {
+        let pid = fork();
+        if pid < 0 {
+            // No room for another task yet
+            sched_yield();
+            continue;
+        }
+
+        if pid == 0 {
+            let heap = sbrk(4096);
+            let anon = mmap(0, 2 * 4096, PROT_READ | PROT_WRITE);
+            let shared = shm_attach(handle as u32, 0, true);
+            if heap < 0 || anon < 0 || shared < 0 {
+                user_panic(ustr!("task 15 : child allocation failed"));
+            }
+            unsafe {
+                core::ptr::write_volatile(heap as *mut u32, 1);
+                core::ptr::write_volatile(anon as *mut u32, 2);
+                core::ptr::write_volatile(shared as *mut u32, 3);
+            }
+            exit(0);
+        }
+
+        if waitpid(pid as u32) != 0 {
+            print(ustr!("task 15 : child failed\n"));
+        }
+        done += 1;
+    }
+
+    close(handle as u32);
+    print(ustr!("task 15 : forked and collected children : "));
+    print_number(done);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task16() {
+    // The array needs more stack than the page mapped at creation, the stack
+    // grows when the array is filled
+    print(ustr!("task 16 : sum of an 8 KiB stack array : "));
+    print_number(stack_array_sum());
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task17() {
+    // Parent and child accumulate in the FPU and yield between additions,
+    // they only get the right sum if their registers are kept apart
+    const ROUNDS : u32 = 1000;
+    let pid = fork();
+    let increment = if pid == 0 { 2 } else { 1 };
+    let sum = fpu_accumulate(increment, ROUNDS);
+
+    if pid == 0 {
+        print(ustr!("task 17 child : FPU sum (expected 2000) "));
+        print_number(sum as u32);
+        exit(0);
+    }
+
+    waitpid(pid as u32);
+    print(ustr!("task 17 parent : FPU sum (expected 1000) "));
+    print_number(sum as u32);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task18() {
+    // Parent and child count their rounds at the same offset of their TLS,
+    // each one in its own page
+    tls_write(0, 0);
+    let pid = fork();
+    let rounds = if pid == 0 { 3 } else { 5 };
+    for _ in 0..rounds {
+        tls_write(0, tls_read(0) + 1);
+        sched_yield();
+    }
+
+    if pid == 0 {
+        print(ustr!("task 18 child : TLS counter (expected 3) "));
+        print_number(tls_read(0));
+        exit(0);
+    }
+    waitpid(pid as u32);
+    print(ustr!("task 18 parent : TLS counter (expected 5) "));
+    print_number(tls_read(0));
+
+    // Move the TLS to a page of our own, gs then reads from it
+    let page = mmap(0, 4096, PROT_READ | PROT_WRITE);
+    unsafe { core::ptr::write_volatile((page as *mut u32).add(1), 0x1234); }
+    set_tls(page as u32);
+    print(ustr!("task 18 : read through gs after set_tls (expected 4660) "));
+    print_number(tls_read(4));
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task19() {
+    // Time null syscalls from userland, while the kernel times them from
+    // the entry of its handler. The max also counts the preemptions
+    const ROUNDS_SHIFT : u32 = 12;
+    const ROUNDS : u32 = 1 << ROUNDS_SHIFT;
+    let mut min = u64::MAX;
+    let mut max = 0;
+    let mut total = 0;
+
+    bench(BENCH_START);
+    for _ in 0..ROUNDS {
+        let start = read_tsc();
+        getpid();
+        let cycles = read_tsc() - start;
+        if cycles < min {
+            min = cycles;
+        }
+        if cycles > max {
+            max = cycles;
+        }
+        total += cycles;
+    }
+    bench(BENCH_STOP);
+
+    print(ustr!("task 19 : getpid round trip cycles, min "));
+    print_number(min as u32);
+    print(ustr!("task 19 : getpid round trip cycles, avg "));
+    print_number((total >> ROUNDS_SHIFT) as u32);
+    print(ustr!("task 19 : getpid round trip cycles, max "));
+    print_number(max as u32);
+
+    // The cycles per microsecond of the info page give the time, with u32
+    // divisions since the u64 ones are in the kernel
+    let mhz = core::cmp::max(vsys_tsc_khz() / 1000, 1);
+    let avg_ns = ((total >> ROUNDS_SHIFT) as u32).saturating_mul(1000) / mhz;
+    print(ustr!("task 19 : getpid round trip ns, avg "));
+    print_number(avg_ns);
+    bench(BENCH_DUMP);
+
+    // Yield to the other tasks, with the kernel mappings global and then
+    // flushed on every switch, as before global pages. The context switch
+    // measures of both dumps compare them
+    print(ustr!("task 19 : switches with global pages\n"));
+    bench_yields(BENCH_START, ROUNDS);
+    print(ustr!("task 19 : switches without global pages\n"));
+    bench_yields(BENCH_START_NO_GLOBAL, ROUNDS);
+    exit(0);
+}
+
+/// Measure `rounds` yields with the bench command `start`, then print the
+/// measures
 #[no_mangle]
 #[link_section=".user_task"]
 #[inline(never)]
-fn mmap_shared(addr : u32, id : usize) {
-    unsafe {
-        asm!("mov eax, 10
-              int 0x80",
-              in("ecx") addr,
-              in("edx") id as u32);
+fn bench_yields(start : u32, rounds : u32) {
+    bench(start);
+    for _ in 0..rounds {
+        sched_yield();
+    }
+    bench(BENCH_STOP);
+    bench(BENCH_DUMP);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task20() {
+    // After the fork, parent and child share the page copy-on-write. Each
+    // one writes its own value at the same address, and must read it back
+    // along with the value written before the fork
+    let addr = mmap(0, 4096, PROT_READ | PROT_WRITE);
+    let page = addr as *mut u32;
+    unsafe { core::ptr::write_volatile(page, 1); }
+
+    let pid = fork();
+    let value = if pid == 0 { 2 } else { 3 };
+    unsafe { core::ptr::write_volatile(page.add(1), value); }
+    for _ in 0..3 {
+        sched_yield();
+    }
+    let (before, own) = unsafe {
+        (core::ptr::read_volatile(page), core::ptr::read_volatile(page.add(1)))
+    };
+
+    if pid == 0 {
+        print(ustr!("task 20 child : value before fork (expected 1) "));
+        print_number(before);
+        print(ustr!("task 20 child : own value (expected 2) "));
+        print_number(own);
+        exit(0);
+    }
+    waitpid(pid as u32);
+    print(ustr!("task 20 parent : value before fork (expected 1) "));
+    print_number(before);
+    print(ustr!("task 20 parent : own value (expected 3) "));
+    print_number(own);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task21() {
+    // Reserve 1 MB but only touch four pages of it, the last one from the
+    // kernel. Only these pages must be allocated
+    const SIZE : usize = 1024 * 1024;
+    let before = own_stats().pages;
+    let addr = mmap(0, SIZE, PROT_READ | PROT_WRITE);
+    print(ustr!("task 21 : pages used after mmap of 1 MB (expected 0) "));
+    print_number(own_stats().pages - before);
+
+    for i in 0..3 {
+        let page = (addr as u32 + i * 0x10000) as *mut u32;
+        unsafe { core::ptr::write_volatile(page, i); }
+    }
+    let uts = (addr as u32 + 0x80000) as *mut Utsname;
+    uname(unsafe { &mut *uts });
+    print(ustr!("task 21 : pages used after touching 4 (expected 4) "));
+    print_number(own_stats().pages - before);
+
+    // Untouched pages read as zero once allocated
+    let untouched = (addr as usize + SIZE - 4) as *const u32;
+    print(ustr!("task 21 : last word of the mapping (expected 0) "));
+    print_number(unsafe { core::ptr::read_volatile(untouched) });
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task22() {
+    // Map and touch 16 pages, they are private to the task until they are
+    // unmapped. The whole memory usage is printed by the kernel at the end
+    const NPAGES : u32 = 16;
+    let mut info = MemInfo::empty();
+    meminfo(&mut info);
+    let before = info.private_pages;
+
+    let addr = mmap(0, NPAGES as usize * 4096, PROT_READ | PROT_WRITE) as u32;
+    for i in 0..NPAGES {
+        unsafe { core::ptr::write_volatile((addr + i * 4096) as *mut u32, i); }
+    }
+    meminfo(&mut info);
+    print(ustr!("task 22 : private pages after touching 16 (expected 16) "));
+    print_number(info.private_pages - before);
+    vspace_dump(0, addr, addr + NPAGES * 4096);
+
+    munmap(addr, NPAGES as usize * 4096);
+    meminfo(&mut info);
+    print(ustr!("task 22 : private pages after munmap (expected 0) "));
+    print_number(info.private_pages - before);
+
+    meminfo(core::ptr::null_mut());
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task23() {
+    // Reading 1 MB of fresh memory maps the zero page everywhere, only the
+    // page table is allocated. The first write gives the page its own copy
+    const SIZE : usize = 1024 * 1024;
+    let mut before = MemInfo::empty();
+    meminfo(&mut before);
+    let addr = mmap(0, SIZE, PROT_READ | PROT_WRITE) as u32;
+
+    let mut sum = 0;
+    for page in (addr..addr + SIZE as u32).step_by(4096) {
+        sum += unsafe { core::ptr::read_volatile(page as *const u32) };
+    }
+    let mut after = MemInfo::empty();
+    meminfo(&mut after);
+    print(ustr!("task 23 : sum of 1 MB of read pages (expected 0) "));
+    print_number(sum);
+    print(ustr!("task 23 : private pages after reading 1 MB (expected 0) "));
+    print_number(after.private_pages - before.private_pages);
+    print(ustr!("task 23 : physical pages used by the reads (about 1) "));
+    print_number(after.used_pages.wrapping_sub(before.used_pages));
+
+    unsafe { core::ptr::write_volatile(addr as *mut u32, 23); }
+    meminfo(&mut after);
+    print(ustr!("task 23 : private pages after a write (expected 1) "));
+    print_number(after.private_pages - before.private_pages);
+    print(ustr!("task 23 : next page still reads (expected 0) "));
+    print_number(unsafe {
+        core::ptr::read_volatile((addr + 4096) as *const u32)
+    });
+    munmap(addr, SIZE);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task24() {
+    // sidt is allowed in ring 3, so the address of the IDT is no secret.
+    // Every way of mapping memory over it must fail
+    let mut idtr = [0u8; 6];
+    unsafe { asm!("sidt [{}]", in(reg) idtr.as_mut_ptr()); }
+    let idt = u32::from_le_bytes([idtr[2], idtr[3], idtr[4], idtr[5]]);
+    let page = idt & !0xfff;
+    print(ustr!("task 24 : IDT at "));
+    print_number(idt);
+
+    if mmap(page, 4096, PROT_READ | PROT_WRITE) != -EINVAL {
+        user_panic(ustr!("task 24 : mmap over the IDT"));
+    }
+    let handle = shm_create(1);
+    if handle < 0 || shm_attach(handle as u32, page, true) != -EINVAL {
+        user_panic(ustr!("task 24 : shm_attach over the IDT"));
+    }
+    if shm_attach(handle as u32, 0x9000_0000, true) != -EINVAL {
+        user_panic(ustr!("task 24 : shm_attach at a negative address"));
+    }
+    close(handle as u32);
+    if mprotect(page, 4096, PROT_READ | PROT_WRITE) != -EINVAL {
+        user_panic(ustr!("task 24 : mprotect of the IDT"));
+    }
+    if munmap(page, 4096) != -EINVAL {
+        user_panic(ustr!("task 24 : munmap of the IDT"));
+    }
+    if mmap(USER_SPACE_BASE - 4096, 4096, PROT_READ) != -EINVAL {
+        user_panic(ustr!("task 24 : mmap below the user space"));
+    }
+
+    // Its address would look like an error
+    if mmap(0x9000_0000, 4096, PROT_READ) != -EINVAL {
+        user_panic(ustr!("task 24 : mmap at a negative address"));
+    }
+    print(ustr!("task 24 : mappings over the IDT refused\n"));
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task25() {
+    // The child waits for a message in a buffer straddling two lazy pages,
+    // so the kernel copies it across the page boundary of another address
+    // space
+    let msg = ustr!("hello, child").as_bytes();
+    let child = fork();
+    if child == 0 {
+        let addr = mmap(0, 2 * 4096, PROT_READ | PROT_WRITE) as u32;
+        let buf = (addr + 4096 - 5) as *mut u8;
+        if recv(buf, msg.len()) < 0 {
+            user_panic(ustr!("task 25 : recv failed"));
+        }
+        for (i, &byte) in msg.iter().enumerate() {
+            if unsafe { core::ptr::read_volatile(buf.add(i)) } != byte {
+                user_panic(ustr!("task 25 : message corrupted"));
+            }
+        }
+        exit(0);
+    }
+
+    // Give the child the time to block in recv
+    sleep(TIMER_FREQUENCY / 10);
+    if send(child as u32, msg.as_ptr(), msg.len()) != 0 {
+        user_panic(ustr!("task 25 : send failed"));
+    }
+    print(ustr!("task 25 : child got the message (expected 0) "));
+    print_number(waitpid(child as u32) as u32);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task26() {
+    // Print back what is typed on the keyboard
+    let mut buf = [0u8; 16];
+    loop {
+        let len = read(buf.as_mut_ptr(), buf.len());
+        if len < 0 {
+            user_panic(ustr!("task 26 : read failed"));
+        }
+        write(buf.as_ptr(), len as usize);
+    }
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task27() {
+    // Print the wall time every few seconds, it must keep going forward
+    let mut last = Timeval::empty();
+    for _ in 0..3 {
+        let mut tv = Timeval::empty();
+        if gettimeofday(&mut tv) != 0 {
+            user_panic(ustr!("task 27 : gettimeofday failed"));
+        }
+        if tv.sec < last.sec || (tv.sec == last.sec && tv.usec < last.usec) {
+            user_panic(ustr!("task 27 : the time went backwards"));
+        }
+        last = tv;
+
+        print(ustr!("task 27 : unix time "));
+        print_number(tv.sec as u32);
+        print(ustr!(" s "));
+        print_number(tv.usec);
+        print(ustr!(" us\n"));
+        sleep(2 * TIMER_FREQUENCY);
+    }
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task28() {
+    // The gate of vector 0x90 is only for the kernel, so raising it from
+    // userland is a general protection fault that kills the task
+    print(ustr!("task 28 : raising the kernel only vector 0x90\n"));
+    unsafe { asm!("int 0x90"); }
+    user_panic(ustr!("task 28 : still alive after the fault"));
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task29() {
+    // int3 only prints the registers, then the task goes on
+    unsafe { asm!("int3"); }
+    print(ustr!("task 29 : resumed after int3\n"));
+
+    // Its gate allows ring 3, the child would be killed by a GPF else
+    let pid = fork();
+    if pid == 0 {
+        unsafe { asm!("int3"); }
+        exit(0);
     }
+    print(ustr!("task 29 : child exit code after int3 (expected 0) "));
+    print_number(waitpid(pid as u32) as u32);
+    exit(0);
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+pub fn task30() {
+    // Let the other demos run a while, then show which interrupts fired
+    sleep(10 * TIMER_FREQUENCY);
+    irq_stats();
+    exit(0);
+}
+
+/// Read the time stamp counter, allowed in userland
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn read_tsc() -> u64 {
+    let (low, high) : (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") low, out("edx") high); }
+    (high as u64) << 32 | low as u64
 }
 
+/// Read the u32 at `offset` in the TLS of the task
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn tls_read(offset : u32) -> u32 {
+    let val : u32;
+    unsafe { asm!("mov {}, dword ptr gs:[{}]", out(reg) val, in(reg) offset); }
+    val
+}
+
+/// Write `val` at `offset` in the TLS of the task
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn tls_write(offset : u32, val : u32) {
+    unsafe { asm!("mov dword ptr gs:[{}], {}", in(reg) offset, in(reg) val); }
+}
+
+/// Add `increment` `rounds` times to an accumulator kept in st(0), yielding
+/// the CPU after each addition
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn fpu_accumulate(increment : i32, rounds : u32) -> i32 {
+    let mut sum : i32 = 0;
+    unsafe {
+        asm!("fild dword ptr [{inc}]
+              fldz
+              2:
+              fadd st(0), st(1)
+              mov eax, {yield_nr}
+              int 0x80
+              dec ecx
+              jnz 2b
+              fistp dword ptr [{sum}]
+              fstp st(0)",
+             inc = in(reg) &increment,
+             sum = in(reg) &mut sum,
+             yield_nr = const SYS_YIELD,
+             inout("ecx") rounds => _,
+             out("eax") _,
+             out("st(0)") _, out("st(1)") _, out("st(2)") _, out("st(3)") _,
+             out("st(4)") _, out("st(5)") _, out("st(6)") _, out("st(7)") _,
+        );
+    }
+    sum
+}
+
+/// Fill an 8 KiB array on the stack and sum it back
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn stack_array_sum() -> u32 {
+    const LEN : usize = 8192 / 4;
+
+    // Not zeroed, that would be done by memset in kernel code
+    let mut array = core::mem::MaybeUninit::<[u32; LEN]>::uninit();
+    let array = array.as_mut_ptr() as *mut u32;
+    for i in 0..LEN {
+        unsafe { core::ptr::write_volatile(array.add(i), i as u32); }
+    }
+
+    let mut sum : u32 = 0;
+    for i in 0..LEN {
+        sum += unsafe { core::ptr::read_volatile(array.add(i)) };
+    }
+    sum
+}
+
+/// Sleep on the futex `addr` until it contains `value`
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn futex_wait_for(addr : *mut u32, value : u32) {
+    loop {
+        let current = unsafe { core::ptr::read_volatile(addr) };
+        if current == value {
+            return;
+        }
+        futex_wait(addr, current);
+    }
+}
+
+/// Print the CPU usage of the current task, then ask the scheduler to print
+/// the one of every task
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn print_own_stats() {
+    let stats = own_stats();
+    print(ustr!("task 12 : ticks running : "));
+    print_number(stats.ticks);
+    print(ustr!("task 12 : switches : "));
+    print_number(stats.switches);
+    print(ustr!("task 12 : syscalls : "));
+    print_number(stats.syscalls);
+
+    task_stats(core::ptr::null_mut(), 0);
+}
+
+/// Get the CPU usage of the current task
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn own_stats() -> TaskStats {
+    const MAX_RECORDS : usize = 64;
+
+    // Only the records written by the kernel are read, so the buffer doesn't
+    // need to be initialized
+    let mut records = core::mem::MaybeUninit::<[TaskStats; MAX_RECORDS]>
+        ::uninit();
+    let records = records.as_mut_ptr() as *mut TaskStats;
+    let count = task_stats(records, MAX_RECORDS);
+    let pid = getpid() as u32;
+    for i in 0..count.max(0) as usize {
+        let stats = unsafe { records.add(i).read() };
+        if stats.pid == pid {
+            return stats;
+        }
+    }
+
+    // Only an error of the kernel can bring us here
+    TaskStats {
+        pid : pid,
+        name : [0; 16],
+        ticks : 0,
+        switches : 0,
+        syscalls : 0,
+        pages : 0,
+    }
+}
+
+/// Print the nul terminated string `s`
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn print_cstr(s : &[u8]) -> i32 {
+    let mut len = 0;
+    while len < s.len() && s[len] != 0 {
+        len += 1;
+    }
+    write(s.as_ptr(), len)
+}
+
+/// Make the syscall `nr` with the arguments `arg1`, `arg2` and `arg3`.
+/// Returns the values the kernel left in eax and edx. sysenter is used if
+/// the kernel advertises it, int 0x80 otherwise
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn syscall(nr : u32, arg1 : u32, arg2 : u32, arg3 : u32) -> (i32, u32) {
+    let ret : i32;
+    let edx : u32;
+    unsafe {
+        let info = VSYS_PAGE_ADDR as *const VsysInfo;
+        let features = core::ptr::read_volatile(
+            core::ptr::addr_of!((*info).features));
+        if features & UNAME_SYSENTER != 0 {
+            // The kernel takes our stack in ecx and the address to return
+            // to in edx, the first two arguments go in esi and edi. esi
+            // can't be used as an operand, so it is saved by hand. Label
+            // arithmetic doesn't work in intel syntax, so use at&t here
+            asm!("push %esi
+                  mov %ecx, %esi
+                  call 2f
+                  2:
+                  pop %edx
+                  add $(3f - 2b), %edx
+                  mov %esp, %ecx
+                  sysenter
+                  3:
+                  mov %edi, %edx
+                  pop %esi",
+                  inout("eax") nr => ret,
+                  inout("ecx") arg1 => _,
+                  inout("edi") arg2 => _,
+                  out("edx") edx,
+                  in("ebx") arg3,
+                  options(att_syntax));
+        } else {
+            asm!("int 0x80",
+                  inout("eax") nr => ret,
+                  in("ecx") arg1,
+                  inout("edx") arg2 => edx,
+                  in("ebx") arg3);
+        }
+    }
+    (ret, edx)
+}
+
+/// Syscall wrappers return the value the kernel left in eax, negative errno
+/// values on failure
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn print(data : &str) -> i32 {
+    write(data.as_ptr(), data.len())
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn print_number(num : u32) -> i32 {
+    syscall(SYS_PRINT_NUMBER, num, 0, 0).0
+}
+
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn write(addr : *const u8, len : usize) -> i32 {
+    syscall(SYS_WRITE, addr as u32, len as u32, 0).0
+}
+
+/// Wrapper to use the munmap syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn munmap(addr : u32, size : usize) -> i32 {
+    syscall(SYS_MUNMAP, addr, size as u32, 0).0
+}
+
+/// Wrapper to use the sbrk syscall. Returns the previous end of the heap
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn sbrk(increment : i32) -> i32 {
+    syscall(SYS_SBRK, increment as u32, 0, 0).0
+}
+
+/// Wrapper to use the getpid syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn getpid() -> i32 {
+    syscall(SYS_GETPID, 0, 0, 0).0
+}
+
+/// Wrapper to use the yield syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn sched_yield() -> i32 {
+    syscall(SYS_YIELD, 0, 0, 0).0
+}
+
+/// Wrapper to use the sleep syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn sleep(ticks : u32) -> i32 {
+    syscall(SYS_SLEEP, ticks, 0, 0).0
+}
+
+/// Wrapper to use the exit syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn exit(code : i32) -> ! {
+    syscall(SYS_EXIT, code as u32, 0, 0);
+
+    // The kernel never comes back here
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Exit code of a task that called `user_panic`
+const USER_PANIC_EXIT_CODE : i32 = EXIT_CODE_MASK;
+
+/// Print `msg` and exit with `USER_PANIC_EXIT_CODE`. Userland can't use the
+/// panic handler, which is kernel code outside of .user_task
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn user_panic(msg : &str) -> ! {
+    print(ustr!("panic : "));
+    print(msg);
+    print(ustr!("\n"));
+    exit(USER_PANIC_EXIT_CODE);
+}
+
+/// Wrapper to use the set_tls syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn set_tls(base : u32) -> i32 {
+    syscall(SYS_SET_TLS, base, 0, 0).0
+}
+
+/// Wrapper to use the bench syscall, `cmd` is one of the `BENCH_*` commands
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn bench(cmd : u32) -> i32 {
+    syscall(SYS_BENCH, cmd, 0, 0).0
+}
+
+/// Wrapper to use the fork syscall. Returns the pid of the child in the
+/// parent and 0 in the child
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn fork() -> i32 {
+    syscall(SYS_FORK, 0, 0, 0).0
+}
+
+/// Wrapper to use the mmap syscall. Returns the address of the mapping
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn mmap(addr : u32, len : usize, prot : u32) -> i32 {
+    syscall(SYS_MMAP, addr, len as u32, prot).0
+}
+
+/// Wrapper to use the mprotect syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn mprotect(addr : u32, len : usize, prot : u32) -> i32 {
+    syscall(SYS_MPROTECT, addr, len as u32, prot).0
+}
+
+/// Wrapper to use the shm_create syscall. Returns the handle of the object
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn shm_create(npages : u32) -> i32 {
+    syscall(SYS_SHM_CREATE, npages, 0, 0).0
+}
+
+/// Wrapper to use the shm_attach syscall. Returns the address of the mapping
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn shm_attach(handle : u32, addr : u32, writable : bool) -> i32 {
+    syscall(SYS_SHM_ATTACH, handle, addr, writable as u32).0
+}
+
+/// Wrapper to use the read syscall, waiting for characters typed on the
+/// keyboard
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn read(buf : *mut u8, len : usize) -> i32 {
+    syscall(SYS_READ, buf as u32, len as u32, 0).0
+}
+
+/// Wrapper to use the irq stats syscall, the kernel prints the number of
+/// interrupts received by each vector
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn irq_stats() -> i32 {
+    syscall(SYS_IRQ_STATS, 0, 0, 0).0
+}
+
+/// Wrapper to use the gettimeofday syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn gettimeofday(tv : &mut Timeval) -> i32 {
+    syscall(SYS_GETTIMEOFDAY, tv as *mut Timeval as u32, 0, 0).0
+}
+
+/// Wrapper to use the shm_detach syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn shm_detach(handle : u32, addr : u32) -> i32 {
+    syscall(SYS_SHM_DETACH, handle, addr, 0).0
+}
+
+/// Get the frequency of the TSC in kHz from the info page
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn vsys_tsc_khz() -> u32 {
+    let info = VSYS_PAGE_ADDR as *const VsysInfo;
+    unsafe { core::ptr::read_volatile(core::ptr::addr_of!((*info).tsc_khz)) }
+}
+
+/// Get the number of timer ticks since boot from the info page, without
+/// making a syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn getticks() -> u64 {
+    let info = VSYS_PAGE_ADDR as *const VsysInfo;
+    loop {
+        unsafe {
+            let seq = core::ptr::read_volatile(
+                core::ptr::addr_of!((*info).seq));
+            let ticks = core::ptr::read_volatile(
+                core::ptr::addr_of!((*info).ticks));
+            let seq2 = core::ptr::read_volatile(
+                core::ptr::addr_of!((*info).seq));
+            if seq & 1 == 0 && seq == seq2 {
+                return ticks;
+            }
+        }
+    }
+}
+
+/// Wrapper to use the send syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn send(pid : u32, buf : *const u8, len : usize) -> i32 {
+    syscall(SYS_SEND, pid, buf as u32, len as u32).0
+}
+
+/// Wrapper to use the recv syscall. Returns the pid of the sender
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn recv(buf : *mut u8, len : usize) -> i32 {
+    syscall(SYS_RECV, buf as u32, len as u32, 0).0
+}
+
+/// Wrapper to use the waitpid syscall. Returns the exit code of the child
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn waitpid(pid : u32) -> i32 {
+    syscall(SYS_WAITPID, pid, 0, 0).0
+}
+
+/// Wrapper to use the close syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn close(handle : u32) -> i32 {
+    syscall(SYS_CLOSE, handle, 0, 0).0
+}
+
+/// Wrapper to use the trace syscall, only allowed for pid 1
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn trace(enable : bool) -> i32 {
+    syscall(SYS_TRACE, enable as u32, 0, 0).0
+}
+
+/// Wrapper to use the uname syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn uname(uts : &mut Utsname) -> i32 {
+    syscall(SYS_UNAME, uts as *mut Utsname as u32, 0, 0).0
+}
+
+/// Wrapper to use the reboot syscall, only allowed for pid 1. `cmd` is one of
+/// the `REBOOT_*` commands of the power module, `code` is the exit status
+/// when powering off
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn reboot(cmd : u32, code : i32) -> i32 {
+    syscall(SYS_REBOOT, cmd, code as u32, 0).0
+}
+
+/// Wrapper to use the sem_create syscall. Returns the handle of the
+/// semaphore
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn sem_create(initial : u32) -> i32 {
+    syscall(SYS_SEM_CREATE, initial, 0, 0).0
+}
+
+/// Wrapper to use the sem_wait syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn sem_wait(handle : u32) -> i32 {
+    syscall(SYS_SEM_WAIT, handle, 0, 0).0
+}
+
+/// Wrapper to use the sem_post syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn sem_post(handle : u32) -> i32 {
+    syscall(SYS_SEM_POST, handle, 0, 0).0
+}
+
+/// Wrapper to use the futex_wait syscall. Returns -EAGAIN if `addr` doesn't
+/// contain `expected` anymore
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn futex_wait(addr : *mut u32, expected : u32) -> i32 {
+    syscall(SYS_FUTEX_WAIT, addr as u32, expected, 0).0
+}
+
+/// Wrapper to use the futex_wake syscall. Returns the number of tasks woken
+/// up
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn futex_wake(addr : *mut u32, count : u32) -> i32 {
+    syscall(SYS_FUTEX_WAKE, addr as u32, count, 0).0
+}
+
+/// Wrapper to use the task_stats syscall. Returns the number of records
+/// written to `buf`, a null `buf` makes the kernel print the stats
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn task_stats(buf : *mut TaskStats, count : usize) -> i32 {
+    syscall(SYS_TASK_STATS, buf as u32, count as u32, 0).0
+}
+
+/// Wrapper to use the setpriority syscall
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn setpriority(priority : u32) -> i32 {
+    syscall(SYS_SETPRIORITY, priority, 0, 0).0
+}
+
+/// Wrapper to use the meminfo syscall, a null `info` makes the kernel print
+/// the memory usage of every task
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn meminfo(info : *mut MemInfo) -> i32 {
+    syscall(SYS_MEMINFO, info as u32, 0, 0).0
+}
+
+/// Wrapper to use the vspace dump syscall, the kernel prints the mappings of
+/// `[start, end)` in the address space of `pid`, 0 for the calling task
+#[no_mangle]
+#[link_section=".user_task"]
+#[inline(never)]
+fn vspace_dump(pid : u32, start : u32, end : u32) -> i32 {
+    syscall(SYS_VSPACE_DUMP, pid, start, end).0
+}
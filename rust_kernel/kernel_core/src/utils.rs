@@ -18,3 +18,16 @@ macro_rules! println {
     () => (print!("\n"));
     ($($arg:tt)*) => (print!("{}\n", format_args!($($arg)*)));
 }
+
+/// Print the bytes of `data` in hex, 16 per line, each line starting with
+/// the address of its first byte counting from `base`
+pub fn hexdump(data : &[u8], base : u32) {
+    use crate::PERIPHERALS;
+    for (i, line) in data.chunks(16).enumerate() {
+        print!("{:08x} :", base as usize + i * 16);
+        for byte in line {
+            print!(" {:02x}", byte);
+        }
+        println!();
+    }
+}
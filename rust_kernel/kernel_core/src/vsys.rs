@@ -0,0 +1,81 @@
+//! Page of kernel information mapped read-only at `VSYS_PAGE_ADDR` in every
+//! task, so userland can read data like the tick counter without making a
+//! syscall. The kernel writes it through the physical memory window
+
+use crate::pit::frequency;
+use crate::time::tsc_khz;
+use crate::paging::*;
+use crate::paging::pagemem::*;
+use crate::paging::physmem::*;
+use crate::paging::virtmem::*;
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+
+/// Layout of the info page
+#[repr(C)]
+pub struct VsysInfo {
+    /// Incremented before and after every update of `ticks`, so it is odd
+    /// while an update is in progress. Readers retry if it changed while
+    /// they were reading
+    pub seq : u32,
+
+    /// Number of timer interrupts since boot
+    pub ticks : u64,
+
+    /// Frequency of the timer ticks in Hz
+    pub tick_frequency : u32,
+
+    /// `UNAME_*` features supported by the kernel
+    pub features : u32,
+
+    /// Frequency of the TSC in kHz, its cycles per millisecond
+    pub tsc_khz : u32,
+}
+
+/// Physical page holding the `VsysInfo`
+static mut VSYS_PAGE : PhysAddr = PhysAddr(0);
+
+/// Get the kernel alias of the info page
+fn info() -> *mut VsysInfo {
+    unsafe {
+        PhysMem::translate_mut(VSYS_PAGE, core::mem::size_of::<VsysInfo>())
+            .expect("Info page used before vsys_init") as *mut VsysInfo
+    }
+}
+
+/// Allocate the info page. Must be called before any task is created, and
+/// once the clocks are calibrated
+pub fn vsys_init() {
+    unsafe {
+        VSYS_PAGE = PhysMem::alloc_phys_zeroed();
+        write_volatile(addr_of_mut!((*info()).tick_frequency), frequency());
+        write_volatile(addr_of_mut!((*info()).tsc_khz), tsc_khz() as u32);
+    }
+}
+
+/// Map the info page in `vspace`, readable but not writable by userland
+pub fn vsys_map(vspace : &VirtMem) -> Result<(), MappingError> {
+    let paddr = unsafe { VSYS_PAGE.0 };
+    vspace.map_raw(VirtAddr(VSYS_PAGE_ADDR), paddr | PAGE_USER | PAGE_PRESENT |
+                                               PAGE_BORROWED)
+}
+
+/// Publish the tick counter. Called from the timer interrupt
+pub fn vsys_update_ticks(ticks : u64) {
+    unsafe {
+        let info = info();
+        let seq = read_volatile(addr_of!((*info).seq));
+        write_volatile(addr_of_mut!((*info).seq), seq.wrapping_add(1));
+        write_volatile(addr_of_mut!((*info).ticks), ticks);
+        write_volatile(addr_of_mut!((*info).seq), seq.wrapping_add(2));
+    }
+}
+
+/// Publish the features of the kernel
+pub fn vsys_set_features(features : u32) {
+    unsafe { write_volatile(addr_of_mut!((*info()).features), features); }
+}
+
+/// Get the features of the kernel
+pub fn vsys_features() -> u32 {
+    unsafe { read_volatile(addr_of!((*info()).features)) }
+}
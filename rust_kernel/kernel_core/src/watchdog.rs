@@ -0,0 +1,65 @@
+//! Watchdog reporting a kernel that stopped scheduling, or a user task stuck
+//! in the kernel. It is checked from the timer interrupt and only prints the
+//! task table, to tell where the kernel hangs.
+//! Syscalls run with interrupts disabled, so one that spins there can't be
+//! seen, only the ones that enabled interrupts again
+
+use crate::interrupts::ticks;
+use crate::pit::ms_to_ticks;
+use crate::tasks::dump_tasks;
+use crate::{print, println, PERIPHERALS};
+
+/// Milliseconds without a schedule, or spent in the kernel by a user task
+/// without completing a syscall, after which the watchdog fires
+const WATCHDOG_MS : u64 = 5000;
+
+/// Tick of the last schedule
+static mut LAST_SCHEDULE_TICK : u64 = 0;
+
+/// Consecutive timer ticks that found the running user task in the kernel
+/// since it last completed a syscall
+static mut KERNEL_TICKS : u64 = 0;
+
+/// The task table was already dumped for the current stall
+static mut FIRED : bool = false;
+
+/// Called by the scheduler each time it runs
+pub fn watchdog_schedule() {
+    unsafe {
+        LAST_SCHEDULE_TICK = ticks();
+        KERNEL_TICKS = 0;
+    }
+}
+
+/// Called when a syscall returns to userland
+pub fn watchdog_syscall_done() {
+    unsafe { KERNEL_TICKS = 0; }
+}
+
+/// Called from the timer interrupt. `user_in_kernel` tells if it interrupted
+/// a user task running in the kernel. The task table is dumped once per
+/// stall, as soon as the serial port is not locked by the stalled code
+pub fn watchdog_tick(user_in_kernel : bool) {
+    unsafe {
+        if user_in_kernel {
+            KERNEL_TICKS += 1;
+        } else {
+            KERNEL_TICKS = 0;
+        }
+
+        let limit = ms_to_ticks(WATCHDOG_MS);
+        let unscheduled = ticks() - LAST_SCHEDULE_TICK;
+        if unscheduled <= limit && KERNEL_TICKS <= limit {
+            FIRED = false;
+            return;
+        }
+        if FIRED || !PERIPHERALS.serial_available() {
+            return;
+        }
+        FIRED = true;
+
+        println!("watchdog : {} ticks without schedule, {} ticks in a syscall",
+                 unscheduled, KERNEL_TICKS);
+        dump_tasks();
+    }
+}
@@ -0,0 +1,164 @@
+//! Work deferred by the interrupt handlers. A handler only does what can't
+//! wait, like reading the device, and queues the rest. The queue is drained
+//! with interrupts enabled, on the way out of an interrupt that came from
+//! code that had them enabled, or else by the worker kernel task
+
+use crate::cpu::*;
+use crate::sync::{preemptible, InterruptGuard};
+use crate::tasks::{block_current, wake_up_deferred, Task};
+
+/// Function run by a work item, with the argument it was queued with
+pub type WorkFn = fn(u32);
+
+/// Max number of work items waiting to run
+pub const MAX_WORK : usize = 32;
+
+/// Work items waiting to run, in the order they were queued
+struct WorkQueue {
+    /// Ring buffer of work items
+    items : [Option<(WorkFn, u32)>; MAX_WORK],
+
+    /// Index of the oldest item
+    head : usize,
+
+    /// Number of items in the queue
+    count : usize,
+
+    /// Number of items dropped because the queue was full
+    dropped : u32,
+}
+
+impl WorkQueue {
+    const fn new() -> Self {
+        Self {
+            items : [None; MAX_WORK],
+            head : 0,
+            count : 0,
+            dropped : 0,
+        }
+    }
+
+    /// Queue `item`. Returns false if the queue is full
+    fn push(&mut self, item : (WorkFn, u32)) -> bool {
+        if self.count == MAX_WORK {
+            self.dropped += 1;
+            return false;
+        }
+        self.items[(self.head + self.count) % MAX_WORK] = Some(item);
+        self.count += 1;
+        true
+    }
+
+    /// Take the oldest item out of the queue
+    fn pop(&mut self) -> Option<(WorkFn, u32)> {
+        if self.count == 0 {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % MAX_WORK;
+        self.count -= 1;
+        item
+    }
+}
+
+/// The queue, only changed with interrupts disabled
+static mut WORK_QUEUE : WorkQueue = WorkQueue::new();
+
+/// Set while work items run on the way out of an interrupt, so that the
+/// interrupts coming meanwhile don't drain the queue too
+static mut DRAINING : bool = false;
+
+/// Pid of the worker task, 0 before `spawn_worker_task`
+static mut WORKER_PID : u32 = 0;
+
+/// Queue `func` to run later with `arg`. Can be called from interrupt
+/// handlers. Each item runs once, an item queued twice runs twice. Returns
+/// false if the queue is full, the item is dropped then
+pub fn queue_work(func : WorkFn, arg : u32) -> bool {
+    let _guard = InterruptGuard::new();
+    if !unsafe { WORK_QUEUE.push((func, arg)) } {
+        return false;
+    }
+    let worker = unsafe { WORKER_PID };
+    if worker != 0 {
+        wake_up_deferred(worker);
+    }
+    true
+}
+
+/// Run the work items queued until now, with interrupts enabled. The items
+/// they queue themselves run at the next drain, so that work queuing work
+/// can't run forever here. Returns the number of items run
+pub fn run_work() -> usize {
+    let pending = {
+        let _guard = InterruptGuard::new();
+        unsafe { WORK_QUEUE.count }
+    };
+
+    let mut done = 0;
+    while done < pending {
+        // Each item is taken out of the queue before it runs, so it runs
+        // once whoever drains the queue
+        let item = {
+            let _guard = InterruptGuard::new();
+            unsafe { WORK_QUEUE.pop() }
+        };
+        let (func, arg) = match item {
+            Some(item) => item,
+            None => break,
+        };
+
+        let enabled = interrupts_enabled();
+        enable_interrupts();
+        func(arg);
+        if !enabled {
+            disable_interrupts();
+        }
+        done += 1;
+    }
+    done
+}
+
+/// Called by `interrupt_handler` once the handler returned. The queue is
+/// drained if the interrupted code had interrupts enabled and could be
+/// preempted, so that it was not in another handler nor changing state the
+/// work items may use. Interrupts are disabled again on return
+pub fn run_work_on_exit(eflags : u32) {
+    unsafe {
+        if WORK_QUEUE.count == 0 || DRAINING || eflags & EFLAGS_IF == 0 ||
+                !preemptible() {
+            return;
+        }
+        DRAINING = true;
+        run_work();
+        disable_interrupts();
+        DRAINING = false;
+    }
+}
+
+/// Get the number of work items dropped because the queue was full
+pub fn work_dropped() -> u32 {
+    unsafe { WORK_QUEUE.dropped }
+}
+
+/// Body of the worker task, running the queued work when no interrupt exit
+/// could
+fn worker_task() {
+    loop {
+        run_work();
+
+        // Interrupts stay disabled from the check to the block, so that an
+        // item can't be queued in between without waking us up
+        let _guard = InterruptGuard::new();
+        if unsafe { WORK_QUEUE.count } == 0 {
+            block_current();
+        }
+    }
+}
+
+/// Create the worker task
+pub fn spawn_worker_task() {
+    let pid = Task::new_kernel(b"worker", worker_task)
+        .expect("No memory for the worker task");
+    unsafe { WORKER_PID = pid; }
+}
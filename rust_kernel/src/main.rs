@@ -2,7 +2,9 @@ use std::process::Command;
 use std::error::Error;
 use std::path::Path;
 
-fn spawn_qemu(kvm : bool, debug : bool) -> Result<(), Box<dyn Error>> {
+/// Run the kernel, returns the code it gave when powering off the VM, or 0
+/// if qemu exited another way
+fn spawn_qemu(kvm : bool, debug : bool) -> Result<i32, Box<dyn Error>> {
     if !Command::new("cp").args(
         &["build/kernel.elf", "."]).status()?.success() {
         return Err("Couldn't find kernel.elf in build".into());
@@ -19,6 +21,7 @@ fn spawn_qemu(kvm : bool, debug : bool) -> Result<(), Box<dyn Error>> {
         "-drive", "media=disk,format=raw,if=ide,index=0,file=fat:rw:.",
         "-serial", "mon:stdio",
         "-d", "int,pcall,cpu_reset,unimp,guest_errors",
+        "-device", "isa-debug-exit,iobase=0xf4,iosize=0x04",
         "-boot", "a",
         "-nographic"]
     );
@@ -27,10 +30,18 @@ fn spawn_qemu(kvm : bool, debug : bool) -> Result<(), Box<dyn Error>> {
         args.extend_from_slice(&["-s", "-S"]);
     }
 
-    Command::new(command).args(
+    let status = Command::new(command).args(
         args).status()?;
 
-    Ok(())
+    // When the kernel writes its code to the isa-debug-exit device, qemu
+    // exits with (code << 1) | 1
+    match status.code() {
+        Some(code) if code & 1 == 1 => {
+            println!("kernel powered off with code {}", code >> 1);
+            Ok(code >> 1)
+        }
+        _ => Ok(0),
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>>{
@@ -44,13 +55,13 @@ fn main() -> Result<(), Box<dyn Error>>{
                 }
             }
             "qemu" => {
-                spawn_qemu(false, false)?;
+                std::process::exit(spawn_qemu(false, false)?);
             }
             "kvm" => {
-                spawn_qemu(true, false)?;
+                std::process::exit(spawn_qemu(true, false)?);
             }
             "debug" => {
-                spawn_qemu(false, true)?;
+                std::process::exit(spawn_qemu(false, true)?);
             }
             _ => {
                 return Err("usage : cargo run {qemu, kvm, clean}".into());